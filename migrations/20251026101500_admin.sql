@@ -0,0 +1,4 @@
+-- Add migration script here
+-- Locked accounts can't log in and have all of their sessions expired
+ALTER TABLE users
+    ADD COLUMN IF NOT EXISTS locked_at timestamptz;
@@ -0,0 +1,9 @@
+-- Add migration script here
+-- A second envelope of the user's content key, encrypted with a key derived from a random,
+-- printable recovery key that only the user knows:
+-- content_key_encr = ChaCha20Poly1305::new(Hkdf(recovery_key, "recovery key"))
+--     .encrypt(recovery_key_salt, content_key)
+-- Both are null when the user has not generated a recovery key yet
+ALTER TABLE users
+    ADD COLUMN IF NOT EXISTS recovery_key_salt bytea,
+    ADD COLUMN IF NOT EXISTS recovery_key_encr bytea;
@@ -0,0 +1,24 @@
+-- Add migration script here
+-- RFC 6238 TOTP secret, encrypted with the user's content key so that it can only be read
+-- after the password step of the login:
+-- totp_secret_encr = ChaCha20Poly1305::new(content_key)
+--     .encrypt(totp_secret_salt, totp_secret)
+-- totp_enabled_at stays null until the user confirms the enrolment with a valid code
+ALTER TABLE users
+    ADD COLUMN IF NOT EXISTS totp_secret_salt bytea,
+    ADD COLUMN IF NOT EXISTS totp_secret_encr bytea,
+    ADD COLUMN IF NOT EXISTS totp_enabled_at  timestamptz;
+
+CREATE TABLE IF NOT EXISTS user_backup_codes
+(
+    user_id   INT REFERENCES users (id) ON DELETE CASCADE NOT NULL,
+    -- Sha256 hash of the backup code
+    code_hash CHAR(64)                                    NOT NULL,
+    used_at   timestamptz,
+    PRIMARY KEY (user_id, code_hash)
+);
+
+-- Sessions that passed the password step but still wait for a second factor.
+-- They are never accepted by authenticate_user.
+ALTER TABLE user_sessions
+    ADD COLUMN IF NOT EXISTS second_factor_pending bool DEFAULT false NOT NULL;
@@ -0,0 +1,8 @@
+-- Add migration script here
+-- Details shown on the account page so that users can recognise and revoke their sessions.
+-- The token hash never leaves the server, sessions are referred to by their public id instead.
+ALTER TABLE user_sessions
+    ADD COLUMN IF NOT EXISTS id           uuid        DEFAULT gen_random_uuid() NOT NULL UNIQUE,
+    ADD COLUMN IF NOT EXISTS last_seen_at timestamptz DEFAULT now()             NOT NULL,
+    ADD COLUMN IF NOT EXISTS user_agent   text,
+    ADD COLUMN IF NOT EXISTS ip_address   text;
@@ -0,0 +1,30 @@
+-- Add migration script here
+-- Every instance keeps an in-process cache of sessions. They LISTEN on this channel and drop
+-- the entries named in the payload, so that a change made through one instance applies to all:
+-- '<token_hash>' when a session is expired or deleted,
+-- 'user:<id>' when the role or lock of a user changes.
+CREATE OR REPLACE FUNCTION notify_session_invalidation() RETURNS trigger AS
+$$
+BEGIN
+    IF TG_TABLE_NAME = 'users' THEN
+        PERFORM pg_notify('session_invalidation', 'user:' || NEW.id);
+    ELSIF TG_OP = 'DELETE' THEN
+        PERFORM pg_notify('session_invalidation', OLD.token_hash);
+    ELSIF NEW.expires_at <= now() THEN
+        PERFORM pg_notify('session_invalidation', NEW.token_hash);
+    END IF;
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE OR REPLACE TRIGGER user_sessions_invalidation
+    AFTER UPDATE OF expires_at OR DELETE
+    ON user_sessions
+    FOR EACH ROW
+EXECUTE FUNCTION notify_session_invalidation();
+
+CREATE OR REPLACE TRIGGER users_session_invalidation
+    AFTER UPDATE OF role, locked_at
+    ON users
+    FOR EACH ROW
+EXECUTE FUNCTION notify_session_invalidation();
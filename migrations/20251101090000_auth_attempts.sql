@@ -0,0 +1,11 @@
+-- Add migration script here
+-- Failed attempts at guessing a secret, per key such as 'login:user:<username>' or
+-- 'unlock:day:<user id>:<day id>'. While locked_until is in the future, attempts under the key
+-- are refused without checking the secret.
+CREATE TABLE IF NOT EXISTS auth_attempts
+(
+    key             text PRIMARY KEY,
+    failures        INT         DEFAULT 0     NOT NULL,
+    last_failure_at timestamptz DEFAULT now() NOT NULL,
+    locked_until    timestamptz
+);
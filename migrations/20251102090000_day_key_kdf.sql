@@ -0,0 +1,6 @@
+-- Add migration script here
+-- Day keys are derived from the day password with Argon2id and this random salt. Protected days
+-- created before it have no salt, their day key is still derived with HKDF until the owner sets
+-- the password again.
+ALTER TABLE day_content
+    ADD COLUMN IF NOT EXISTS day_key_kdf_salt bytea;
@@ -0,0 +1,61 @@
+-- Add migration script here
+-- Every encrypted column now holds a self-describing envelope (see model::crypto::Envelope)
+-- instead of raw ChaCha20Poly1305 output next to a separate nonce column:
+--   version (1) | cipher (1 = ChaCha20Poly1305) | nonce length | nonce | kdf | ciphertext
+-- where kdf is 0 for a wrapped key, 1 | info length | info for HKDF-SHA256, or
+-- 2 | m_cost | t_cost | p_cost (big endian u32) | salt length | salt for Argon2id.
+-- The existing blobs are wrapped in place and the nonce and salt columns are dropped.
+
+CREATE FUNCTION pg_temp.short_field(field bytea) RETURNS bytea AS
+$$
+SELECT set_byte('\x00'::bytea, 0, length(field)) || field
+$$ LANGUAGE sql IMMUTABLE STRICT;
+
+CREATE FUNCTION pg_temp.envelope(nonce bytea, kdf bytea, cypher bytea) RETURNS bytea AS
+$$
+SELECT '\x0101'::bytea || pg_temp.short_field(nonce) || kdf || cypher
+$$ LANGUAGE sql IMMUTABLE STRICT;
+
+CREATE FUNCTION pg_temp.hkdf(info text) RETURNS bytea AS
+$$
+SELECT '\x01'::bytea || pg_temp.short_field(convert_to(info, 'UTF8'))
+$$ LANGUAGE sql IMMUTABLE STRICT;
+
+-- Argon2::default(), which derived every Argon2 key so far
+CREATE FUNCTION pg_temp.argon2id(salt bytea) RETURNS bytea AS
+$$
+SELECT '\x02'::bytea || int4send(19456) || int4send(2) || int4send(1) || pg_temp.short_field(salt)
+$$ LANGUAGE sql IMMUTABLE STRICT;
+
+UPDATE users
+SET content_key_encr  = pg_temp.envelope(content_key_salt, pg_temp.argon2id(master_key_salt), content_key_encr),
+    recovery_key_encr = pg_temp.envelope(recovery_key_salt, pg_temp.hkdf('recovery key'), recovery_key_encr),
+    totp_secret_encr  = pg_temp.envelope(totp_secret_salt, '\x00'::bytea, totp_secret_encr);
+ALTER TABLE users
+    DROP COLUMN master_key_salt,
+    DROP COLUMN content_key_salt,
+    DROP COLUMN recovery_key_salt,
+    DROP COLUMN totp_secret_salt;
+
+UPDATE user_sessions
+SET master_key_encr = pg_temp.envelope(master_key_salt, pg_temp.hkdf('session'), master_key_encr);
+ALTER TABLE user_sessions
+    DROP COLUMN master_key_salt;
+
+UPDATE user_days
+SET day_key_encr = pg_temp.envelope(day_key_salt, '\x00'::bytea, day_key_encr);
+ALTER TABLE user_days
+    DROP COLUMN day_key_salt;
+
+-- Days without an Argon2 salt still derive their day key with HKDF, see the day_key_kdf
+-- migration. The envelope now records which one it is.
+UPDATE day_content
+SET decryption_key_encr = pg_temp.envelope(
+        decryption_key_salt,
+        coalesce(pg_temp.argon2id(day_key_kdf_salt), pg_temp.hkdf('day key')),
+        decryption_key_encr),
+    content             = coalesce(pg_temp.envelope(content_salt, '\x00'::bytea, content), content);
+ALTER TABLE day_content
+    DROP COLUMN decryption_key_salt,
+    DROP COLUMN content_salt,
+    DROP COLUMN day_key_kdf_salt;
@@ -0,0 +1,9 @@
+-- Add migration script here
+-- Cached sessions hold the content key of their user. When it is rotated, every instance drops
+-- them, so the next request unwraps the new content key from the database:
+-- 'user:<id>' is now also sent when the content key envelope of a user changes.
+CREATE OR REPLACE TRIGGER users_session_invalidation
+    AFTER UPDATE OF role, locked_at, content_key_encr
+    ON users
+    FOR EACH ROW
+EXECUTE FUNCTION notify_session_invalidation();
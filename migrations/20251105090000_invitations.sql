@@ -0,0 +1,14 @@
+-- Add migration script here
+CREATE TABLE IF NOT EXISTS invitations
+(
+    id          SERIAL PRIMARY KEY                          NOT NULL,
+    -- Sha256 hash of the invitation code
+    code_hash   CHAR(64) UNIQUE                             NOT NULL,
+    created_by  INT REFERENCES users (id) ON DELETE CASCADE NOT NULL,
+    -- New users are subscribed to this calendar, if any
+    calendar_id INT REFERENCES calendars (id) ON DELETE CASCADE,
+    max_uses    INT                                         NOT NULL CHECK (max_uses > 0),
+    uses        INT       DEFAULT 0                         NOT NULL,
+    created_at  timestamptz DEFAULT now()                   NOT NULL,
+    expires_at  timestamptz                                 NOT NULL
+);
@@ -0,0 +1,18 @@
+-- Add migration script here
+create type api_token_scope as enum ('read','write');
+
+CREATE TABLE IF NOT EXISTS api_tokens
+(
+    -- Sha256 hash of the token
+    token_hash       CHAR(64) PRIMARY KEY,
+    id               uuid        DEFAULT gen_random_uuid()          NOT NULL UNIQUE,
+    user_id          INT REFERENCES users (id) ON DELETE CASCADE    NOT NULL,
+    name             text                                           NOT NULL,
+    scope            api_token_scope                                NOT NULL,
+    -- The user's content key, encrypted with a key derived from the token
+    content_key_encr bytea                                          NOT NULL,
+    created_at       timestamptz DEFAULT now()                      NOT NULL,
+    last_used_at     timestamptz,
+    -- Tokens without an expiry stay valid until they are revoked
+    expires_at       timestamptz
+);
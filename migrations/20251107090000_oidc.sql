@@ -0,0 +1,22 @@
+-- Add migration script here
+CREATE TABLE IF NOT EXISTS oidc_identities
+(
+    issuer     text                                               NOT NULL,
+    subject    text                                               NOT NULL,
+    -- These users log in through the provider only: their password hash is the hash of the
+    -- encryption passphrase which wraps their content key
+    user_id    INT REFERENCES users (id) ON DELETE CASCADE UNIQUE NOT NULL,
+    created_at timestamptz DEFAULT now()                          NOT NULL,
+    PRIMARY KEY (issuer, subject)
+);
+
+-- Identities vouched for by the provider, waiting for the encryption passphrase
+CREATE TABLE IF NOT EXISTS oidc_logins
+(
+    -- Sha256 hash of the login token
+    token_hash         CHAR(64) PRIMARY KEY NOT NULL,
+    issuer             text                 NOT NULL,
+    subject            text                 NOT NULL,
+    preferred_username text,
+    expires_at         timestamptz          NOT NULL
+);
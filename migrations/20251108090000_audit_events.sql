@@ -0,0 +1,42 @@
+-- Add migration script here
+create type audit_event_kind as enum (
+    'login',
+    'login_failed',
+    'password_changed',
+    'account_recovered',
+    'keys_rotated',
+    'two_factor_enabled',
+    'two_factor_disabled',
+    'session_revoked',
+    'day_password_changed',
+    'day_deleted'
+    );
+
+CREATE TABLE IF NOT EXISTS audit_events
+(
+    id         BIGSERIAL PRIMARY KEY                     NOT NULL,
+    -- Failed logins with an unknown username don't belong to anyone
+    user_id    INT REFERENCES users (id) ON DELETE CASCADE,
+    kind       audit_event_kind                          NOT NULL,
+    details    text,
+    ip_address text,
+    user_agent text,
+    created_at timestamptz DEFAULT now()                 NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS audit_events_user_id_idx ON audit_events (user_id, created_at);
+CREATE INDEX IF NOT EXISTS audit_events_created_at_idx ON audit_events (created_at);
+
+-- Events are only ever added, and deleted once they are older than the retention period
+CREATE OR REPLACE FUNCTION forbid_audit_event_update() RETURNS trigger AS
+$$
+BEGIN
+    RAISE EXCEPTION 'audit events cannot be modified';
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE OR REPLACE TRIGGER audit_events_append_only
+    BEFORE UPDATE
+    ON audit_events
+    FOR EACH ROW
+EXECUTE FUNCTION forbid_audit_event_update();
@@ -0,0 +1,15 @@
+-- Add migration script here
+ALTER TABLE calendars
+    ADD COLUMN description     text,
+    ADD COLUMN cover_image_url text;
+
+-- Messages shown on the dashboard of a user until they dismiss them
+CREATE TABLE IF NOT EXISTS notices
+(
+    id         SERIAL PRIMARY KEY                          NOT NULL,
+    user_id    INT REFERENCES users (id) ON DELETE CASCADE NOT NULL,
+    message    text                                        NOT NULL,
+    created_at timestamptz DEFAULT now()                   NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS notices_user_id ON notices (user_id);
@@ -0,0 +1,4 @@
+-- Add migration script here
+-- Archived subscriptions are hidden from the dashboard but keep their unlock history
+ALTER TABLE calendar_subscriptions
+    ADD COLUMN archived_at timestamptz;
@@ -0,0 +1,8 @@
+-- Add migration script here
+CREATE TYPE calendar_visibility AS ENUM ('private', 'unlisted', 'public');
+
+-- Existing calendars become private: their owners and subscribers keep access
+ALTER TABLE calendars
+    ADD COLUMN visibility       calendar_visibility DEFAULT 'private' NOT NULL,
+    -- SHA-256 of the secret token of the share link, if there is one
+    ADD COLUMN share_token_hash text UNIQUE;
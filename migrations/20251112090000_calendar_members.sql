@@ -0,0 +1,28 @@
+-- Add migration script here
+CREATE TYPE calendar_role AS ENUM ('owner', 'editor', 'viewer');
+
+-- Who curates a calendar besides its creator, in calendars.owner_id, and who may open it while
+-- it is private
+CREATE TABLE calendar_members
+(
+    calendar_id int REFERENCES calendars (id) ON DELETE CASCADE NOT NULL,
+    user_id     int REFERENCES users (id) ON DELETE CASCADE     NOT NULL,
+    role        calendar_role                                   NOT NULL,
+    added_at    timestamptz DEFAULT now()                       NOT NULL,
+    PRIMARY KEY (calendar_id, user_id)
+);
+
+INSERT INTO calendar_members (calendar_id, user_id, role, added_at)
+SELECT id, owner_id, 'owner', created_at
+FROM calendars;
+
+-- X25519 key pair, so that the day keys of a calendar can be handed to its editors while they
+-- are away. The private key is wrapped with the content key.
+ALTER TABLE users
+    ADD COLUMN public_key       bytea,
+    ADD COLUMN private_key_encr bytea;
+
+-- Day key sealed to the public key of an editor, until they open the day and it is wrapped with
+-- their content key in day_key_encr instead
+ALTER TABLE user_days
+    ADD COLUMN day_key_sealed bytea;
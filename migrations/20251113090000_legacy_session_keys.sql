@@ -0,0 +1,7 @@
+-- Add migration script here
+-- Session master keys are never sealed again, so the sessions still holding one sealed before
+-- envelopes were bound to their rows are ended. The cipher id is the second byte of an
+-- envelope, 1 for ChaCha20-Poly1305 which ignores the associated data.
+DELETE
+FROM user_sessions
+WHERE get_byte(master_key_encr, 1) = 1;
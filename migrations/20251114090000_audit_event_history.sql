@@ -0,0 +1,40 @@
+-- Deleting an account used to delete its audit events along with it. The events now outlive
+-- the account and keep the username, so the log still says whose they were.
+ALTER TABLE audit_events
+    ADD COLUMN IF NOT EXISTS username text;
+
+ALTER TABLE audit_events
+    DISABLE TRIGGER audit_events_append_only;
+UPDATE audit_events a
+SET username = u.username
+FROM users u
+WHERE u.id = a.user_id;
+ALTER TABLE audit_events
+    ENABLE TRIGGER audit_events_append_only;
+
+ALTER TABLE audit_events
+    DROP CONSTRAINT IF EXISTS audit_events_user_id_fkey,
+    ADD CONSTRAINT audit_events_user_id_fkey
+        FOREIGN KEY (user_id) REFERENCES users (id) ON DELETE SET NULL;
+
+-- The only change an event accepts is losing its account, and only the retention purge,
+-- which sets audit.purging for its transaction, may delete events
+CREATE OR REPLACE FUNCTION forbid_audit_event_update() RETURNS trigger AS
+$$
+BEGIN
+    IF TG_OP = 'UPDATE' AND NEW.user_id IS NULL
+        AND to_jsonb(NEW) - 'user_id' = to_jsonb(OLD) - 'user_id' THEN
+        RETURN NEW;
+    END IF;
+    IF TG_OP = 'DELETE' AND current_setting('audit.purging', true) = 'on' THEN
+        RETURN OLD;
+    END IF;
+    RAISE EXCEPTION 'audit events cannot be modified';
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE OR REPLACE TRIGGER audit_events_append_only
+    BEFORE UPDATE OR DELETE
+    ON audit_events
+    FOR EACH ROW
+EXECUTE FUNCTION forbid_audit_event_update();
@@ -0,0 +1,3 @@
+-- Logging in seals the legacy envelopes of the user again. When that fails, the login still
+-- goes through, and the failure is left in the audit log for an administrator to look into.
+ALTER TYPE audit_event_kind ADD VALUE IF NOT EXISTS 'envelopes_not_resealed';
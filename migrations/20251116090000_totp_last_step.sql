@@ -0,0 +1,4 @@
+-- The time step of the last authenticator code accepted for a user. A code is only accepted
+-- for a later step, so one that was seen can't be replayed while it is still valid.
+ALTER TABLE users
+    ADD COLUMN IF NOT EXISTS totp_last_step bigint;
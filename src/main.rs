@@ -5,31 +5,170 @@ mod templates;
 mod web;
 
 use crate::model::app_state::AppState;
-use crate::service::authentication::{authenticate_user, require_logged_in, require_logged_out};
+use crate::model::argon2_pool::Argon2Pool;
+use crate::model::crypto::reject_legacy_envelopes;
+use crate::model::invitation::RegistrationMode;
+use crate::model::session::TrustedProxies;
+use crate::persistence::postgres_session_backend::PostgresSessionBackend;
+use crate::persistence::redis_session_backend::RedisSessionBackend;
+use crate::persistence::session_backend::SessionBackend;
+use crate::service::authentication::{
+    authenticate_user, require_admin, require_logged_in, require_logged_out, require_session,
+};
 use crate::service::calendar_service::{add_calendar, add_calendar_day};
-use crate::web::authentication_handlers::{change_pass_get, change_pass_post, login_page, login_post, logout_get, signup_page, signup_post};
+use crate::service::csrf::verify_csrf;
+use crate::service::oidc::{OidcConfig, OidcProvider};
+use crate::service::session_reaper::spawn_session_reaper;
+use crate::web::account_handlers::{
+    account_get, activity_get, create_token_post, delete_account_post, export_post,
+    recovery_key_post, revoke_other_sessions_post, revoke_session_post, revoke_token_post,
+    rotate_keys_post, sessions_get, tokens_get, two_factor_disable_post, two_factor_enable_post,
+    two_factor_setup_post,
+};
+use crate::web::admin_handlers::{
+    admin_get, audit_get, change_role_post, create_invitation_post, lock_user_post,
+    revoke_invitation_post, take_down_calendar_post, unlock_user_post,
+};
+use crate::web::authentication_handlers::{
+    change_pass_get, change_pass_post, login_page, login_post, logout_get, oidc_callback_get,
+    oidc_login_get, oidc_passphrase_get, oidc_passphrase_post, recover_get, recover_post,
+    signup_page, signup_post, two_factor_get, two_factor_post,
+};
 use crate::web::calendar_handlers::{
-    add_day_post, create_calendar_get, create_calendar_post, delete_day_post, edit_pass_post,
-    edit_post, show_calendar, show_day_get, subscribe_post, unlock_get, unlock_post,
+    add_day_post, add_member_post, archive_post, create_calendar_get,
+    create_calendar_invitation_post, create_calendar_post, delete_calendar_get,
+    delete_calendar_post, delete_day_post, edit_calendar_get, edit_calendar_post, edit_pass_post,
+    edit_post, member_role_post, remove_member_post, restore_post, revoke_calendar_invitation_post,
+    revoke_share_link_post, share_link_post, shared_calendar_get, shared_subscribe_post,
+    show_calendar, show_day_get, subscribe_post, unlock_get, unlock_post, unsubscribe_post,
+    visibility_post,
 };
 use crate::web::handler::welcome_handler;
-use crate::web::member_handlers::dashboard_handler;
+use crate::web::member_handlers::{dashboard_handler, dismiss_notice_post};
+use argon2::Params;
 use axum::routing::{get_service, post};
 use axum::{middleware, routing::get, Router};
+use chrono::TimeDelta;
 use sqlx::PgPool;
 use std::env;
+use std::net::SocketAddr;
+use std::time::Duration;
 use tower_http::services::ServeDir;
 
+fn env_number<T: std::str::FromStr>(name: &str, default: T) -> T {
+    env::var(name).map_or(default, |value| {
+        value
+            .parse()
+            .unwrap_or_else(|_| panic!("{} is not a number!", name))
+    })
+}
+
 #[tokio::main]
 async fn main() {
     let db_conn = PgPool::connect(&env::var("DATABASE_URL").expect("DATABASE_URL not set!"))
         .await
         .expect("DATABASE connection failed!");
-    let state: AppState = AppState::new(&db_conn).await;
+    let session_backend: Box<dyn SessionBackend> = match env::var("SESSION_BACKEND").as_deref() {
+        Ok("redis") => Box::new(
+            RedisSessionBackend::connect(&env::var("REDIS_URL").expect("REDIS_URL not set!"))
+                .await
+                .expect("REDIS connection failed!"),
+        ),
+        Ok("postgres") | Err(_) => Box::new(PostgresSessionBackend::new(db_conn.clone())),
+        Ok(other) => panic!("Unknown SESSION_BACKEND {}, use postgres or redis", other),
+    };
+    let argon2_params = Params::new(
+        env_number("ARGON2_MEMORY_KIB", Params::DEFAULT_M_COST),
+        env_number("ARGON2_ITERATIONS", Params::DEFAULT_T_COST),
+        env_number("ARGON2_PARALLELISM", Params::DEFAULT_P_COST),
+        None,
+    )
+    .expect("Invalid Argon2 parameters!");
+    // Existing hashes and envelopes were made with the default costs or the configured ones
+    let argon2_max_params = Params::new(
+        env_number(
+            "ARGON2_MAX_MEMORY_KIB",
+            argon2_params.m_cost().max(Params::DEFAULT_M_COST),
+        ),
+        env_number(
+            "ARGON2_MAX_ITERATIONS",
+            argon2_params.t_cost().max(Params::DEFAULT_T_COST),
+        ),
+        env_number(
+            "ARGON2_MAX_PARALLELISM",
+            argon2_params.p_cost().max(Params::DEFAULT_P_COST),
+        ),
+        None,
+    )
+    .expect("Invalid Argon2 parameters!");
+    let argon2_concurrency = env_number(
+        "ARGON2_MAX_CONCURRENCY",
+        std::thread::available_parallelism().map_or(4, |threads| threads.get()),
+    );
+    let argon2_pool = Argon2Pool::new(argon2_params, argon2_max_params, argon2_concurrency);
+    let registration_mode = match env::var("REGISTRATION_MODE").as_deref() {
+        Ok("open") | Err(_) => RegistrationMode::Open,
+        Ok("invite-only") => RegistrationMode::InviteOnly,
+        Ok("closed") => RegistrationMode::Closed,
+        Ok(other) => panic!(
+            "Unknown REGISTRATION_MODE {}, use open, invite-only or closed",
+            other
+        ),
+    };
+    let oidc_provider = match env::var("OIDC_ISSUER_URL") {
+        Ok(issuer_url) => Some(
+            OidcProvider::discover(OidcConfig {
+                issuer_url,
+                client_id: env::var("OIDC_CLIENT_ID").expect("OIDC_CLIENT_ID not set!"),
+                client_secret: env::var("OIDC_CLIENT_SECRET").ok(),
+                redirect_url: env::var("OIDC_REDIRECT_URL").expect("OIDC_REDIRECT_URL not set!"),
+            })
+            .await
+            .expect("OIDC discovery failed!"),
+        ),
+        Err(_) => None,
+    };
+    let trusted_proxies = TrustedProxies::parse(&env::var("TRUSTED_PROXIES").unwrap_or_default())
+        .unwrap_or_else(|e| panic!("Invalid TRUSTED_PROXIES: {}", e));
+    let state: AppState = AppState::new(
+        &db_conn,
+        argon2_pool,
+        session_backend,
+        registration_mode,
+        oidc_provider,
+        trusted_proxies,
+    )
+    .await;
+
+    // Envelopes that no one logged in to seal again stop opening, instead of waiting for them
+    if env::var("REJECT_LEGACY_ENVELOPES").as_deref() == Ok("true") {
+        reject_legacy_envelopes();
+    }
+
+    let reaper_interval = env_number("SESSION_REAPER_INTERVAL_SECS", 3600);
+    let session_retention = env_number("SESSION_RETENTION_SECS", 86400);
+    let audit_retention = env_number("AUDIT_RETENTION_DAYS", 90);
+    spawn_session_reaper(
+        state.clone(),
+        Duration::from_secs(reaper_interval),
+        TimeDelta::seconds(session_retention),
+        TimeDelta::days(audit_retention),
+    );
 
     let login_router = Router::new()
         .route("/login", get(login_page).post(login_post))
         .route("/signup", get(signup_page).post(signup_post))
+        .route(
+            "/login/two-factor",
+            get(two_factor_get).post(two_factor_post),
+        )
+        .route("/recover", get(recover_get).post(recover_post))
+        .route("/login/oidc", get(oidc_login_get))
+        .route("/login/oidc/callback", get(oidc_callback_get))
+        .route(
+            "/login/oidc/passphrase",
+            get(oidc_passphrase_get).post(oidc_passphrase_post),
+        )
         .route_layer(middleware::from_fn(require_logged_out));
     let guest_router = Router::new()
         .route("/", get(welcome_handler))
@@ -50,18 +189,103 @@ async fn main() {
     let calendar_router = Router::new()
         .route("/{calendar_id}", get(show_calendar))
         .route("/{calendar_id}/subscribe", post(subscribe_post))
+        .route("/{calendar_id}/unsubscribe", post(unsubscribe_post))
+        .route("/{calendar_id}/archive", post(archive_post))
+        .route("/{calendar_id}/restore", post(restore_post))
+        .route("/{calendar_id}/visibility", post(visibility_post))
+        .route("/{calendar_id}/share-link", post(share_link_post))
+        .route(
+            "/{calendar_id}/share-link/revoke",
+            post(revoke_share_link_post),
+        )
+        .route(
+            "/{calendar_id}/edit",
+            get(edit_calendar_get).post(edit_calendar_post),
+        )
+        .route(
+            "/{calendar_id}/delete",
+            get(delete_calendar_get).post(delete_calendar_post),
+        )
+        .route("/{calendar_id}/members", post(add_member_post))
+        .route(
+            "/{calendar_id}/members/{member_id}/role",
+            post(member_role_post),
+        )
+        .route(
+            "/{calendar_id}/members/{member_id}/remove",
+            post(remove_member_post),
+        )
+        .route(
+            "/{calendar_id}/invitations",
+            post(create_calendar_invitation_post),
+        )
+        .route(
+            "/{calendar_id}/invitations/{invitation_id}/revoke",
+            post(revoke_calendar_invitation_post),
+        )
         .nest("/{calendar_id}/day", day_router)
         .route_layer(middleware::from_fn_with_state(state.clone(), add_calendar))
+        .route("/shared/{share_token}", get(shared_calendar_get))
+        .route(
+            "/shared/{share_token}/subscribe",
+            post(shared_subscribe_post),
+        )
         .route(
             "/create",
             get(create_calendar_get).post(create_calendar_post),
         );
 
-    let user_router = Router::new()
-        .route("/home", get(dashboard_handler))
+    // Admin actions skip the CSRF check with an API token, so they take a browser session too
+    let admin_router = Router::new()
+        .route("/", get(admin_get))
+        .route("/audit", get(audit_get))
+        .route("/users/{user_id}/lock", post(lock_user_post))
+        .route("/users/{user_id}/unlock", post(unlock_user_post))
+        .route("/users/{user_id}/role", post(change_role_post))
+        .route(
+            "/calendars/{calendar_id}/delete",
+            post(take_down_calendar_post),
+        )
+        .route("/invitations", post(create_invitation_post))
+        .route(
+            "/invitations/{invitation_id}/revoke",
+            post(revoke_invitation_post),
+        )
+        .route_layer(middleware::from_fn(require_admin))
+        .route_layer(middleware::from_fn(require_session));
+
+    // Managing the account takes a browser session, API tokens can't
+    let account_router = Router::new()
         .route("/logout", get(logout_get))
         .route("/change-password", get(change_pass_get).post(change_pass_post))
+        .route("/account", get(account_get))
+        .route("/account/recovery-key", post(recovery_key_post))
+        .route("/account/rotate-keys", post(rotate_keys_post))
+        .route("/account/export", post(export_post))
+        .route("/account/delete", post(delete_account_post))
+        .route("/account/activity", get(activity_get))
+        .route("/account/sessions", get(sessions_get))
+        .route(
+            "/account/sessions/revoke-others",
+            post(revoke_other_sessions_post),
+        )
+        .route(
+            "/account/sessions/{session_id}/revoke",
+            post(revoke_session_post),
+        )
+        .route("/account/tokens", get(tokens_get).post(create_token_post))
+        .route("/account/tokens/{token_id}/revoke", post(revoke_token_post))
+        .route("/account/two-factor/setup", post(two_factor_setup_post))
+        .route("/account/two-factor/enable", post(two_factor_enable_post))
+        .route("/account/two-factor/disable", post(two_factor_disable_post))
+        .route_layer(middleware::from_fn(require_session));
+
+    let user_router = Router::new()
+        .route("/home", get(dashboard_handler))
+        .route("/notices/{notice_id}/dismiss", post(dismiss_notice_post))
+        .merge(account_router)
         .nest("/calendar", calendar_router)
+        .nest("/admin", admin_router)
         .route_layer(middleware::from_fn(require_logged_in));
 
     // build our application with a single route
@@ -73,6 +297,7 @@ async fn main() {
             state.clone(),
             authenticate_user,
         ))
+        .layer(middleware::from_fn(verify_csrf))
         .with_state(state.clone());
 
     // run our app with hyper, listening globally on port 8080
@@ -80,5 +305,10 @@ async fn main() {
         tokio::net::TcpListener::bind(&env::var("BIND_LISTENER").expect("BIND_LISTENER not set!"))
             .await
             .unwrap();
-    axum::serve(listener, app).await.unwrap();
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .await
+    .unwrap();
 }
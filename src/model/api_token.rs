@@ -0,0 +1,98 @@
+use axum::http::Method;
+use chrono::{DateTime, Utc};
+use rand::random;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use sqlx::Type;
+use std::fmt::Display;
+use uuid::Uuid;
+use zeroize::Zeroizing;
+
+/// Prefix of every personal access token, so that they are easy to spot in scripts and logs.
+const TOKEN_PREFIX: &str = "aof_";
+
+/// What a personal access token is allowed to do.
+#[derive(Clone, Copy, Debug, PartialEq, Type, Deserialize)]
+#[sqlx(type_name = "api_token_scope", rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum ApiTokenScope {
+    /// Only requests which don't change anything
+    Read,
+    Write,
+}
+
+impl Display for ApiTokenScope {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl ApiTokenScope {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ApiTokenScope::Read => "read",
+            ApiTokenScope::Write => "write",
+        }
+    }
+
+    pub fn allows(&self, method: &Method) -> bool {
+        match self {
+            ApiTokenScope::Read => method.is_safe(),
+            ApiTokenScope::Write => true,
+        }
+    }
+}
+
+/// A personal access token, sent as `Authorization: Bearer <token>`. Only its hash is stored,
+/// so it is shown once, when it is created.
+pub struct ApiTokenSecret {
+    token: Zeroizing<String>,
+}
+
+impl ApiTokenSecret {
+    pub fn generate() -> Self {
+        let token = format!("{}{}", TOKEN_PREFIX, hex::encode(random::<[u8; 32]>()));
+        Self {
+            token: Zeroizing::new(token),
+        }
+    }
+
+    pub fn parse(token: &str) -> Option<Self> {
+        token.strip_prefix(TOKEN_PREFIX)?;
+        Some(Self {
+            token: Zeroizing::new(token.to_owned()),
+        })
+    }
+
+    pub fn hash(&self) -> String {
+        hex::encode(Sha256::digest(self.token.as_bytes()))
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        self.token.as_bytes()
+    }
+}
+
+impl Display for ApiTokenSecret {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.token)
+    }
+}
+
+/// A personal access token as listed on the account page, without any key material.
+#[derive(Clone, Debug)]
+pub struct ApiToken {
+    pub id: Uuid,
+    pub name: String,
+    pub scope: ApiTokenScope,
+    pub created_at: DateTime<Utc>,
+    pub last_used_at: Option<DateTime<Utc>>,
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+impl ApiToken {
+    pub fn is_expired(&self) -> bool {
+        self.expires_at
+            .is_some_and(|expires_at| expires_at <= Utc::now())
+    }
+}
@@ -1,6 +1,18 @@
+use crate::model::argon2_pool::Argon2Pool;
+use crate::model::invitation::RegistrationMode;
+use crate::model::session::TrustedProxies;
+use crate::persistence::api_token_repository::ApiTokenRepository;
+use crate::persistence::audit_repository::AuditRepository;
+use crate::persistence::invitation_repository::InvitationRepository;
+use crate::persistence::legacy_envelope_repository::LegacyEnvelopeRepository;
+use crate::persistence::notice_repository::NoticeRepository;
+use crate::persistence::oidc_repository::OidcRepository;
+use crate::persistence::session_backend::SessionBackend;
 use crate::persistence::session_store::SessionStore;
 use crate::persistence::user_repository::UserRepository;
 use crate::service::calendar_service::CalendarService;
+use crate::service::oidc::OidcProvider;
+use crate::service::rate_limiter::RateLimiter;
 use sqlx::PgPool;
 use std::sync::Arc;
 use tokio::sync::RwLock;
@@ -8,16 +20,53 @@ use tokio::sync::RwLock;
 #[derive(Clone)]
 pub struct AppState {
     pub user_repository: Arc<RwLock<UserRepository>>,
-    pub session_store: Arc<RwLock<SessionStore>>,
+    pub session_store: Arc<SessionStore>,
     pub calendar_service: CalendarService,
+    pub rate_limiter: RateLimiter,
+    pub invitation_repository: InvitationRepository,
+    pub api_token_repository: ApiTokenRepository,
+    pub registration_mode: RegistrationMode,
+    pub oidc_repository: OidcRepository,
+    /// Only set when an identity provider is configured
+    pub oidc_provider: Option<OidcProvider>,
+    pub audit_repository: AuditRepository,
+    pub notice_repository: NoticeRepository,
+    pub legacy_envelope_repository: LegacyEnvelopeRepository,
+    pub trusted_proxies: TrustedProxies,
 }
 
 impl AppState {
-    pub async fn new(db_conn: &PgPool) -> Self {
+    pub async fn new(
+        db_conn: &PgPool,
+        argon2_pool: Argon2Pool,
+        session_backend: Box<dyn SessionBackend>,
+        registration_mode: RegistrationMode,
+        oidc_provider: Option<OidcProvider>,
+        trusted_proxies: TrustedProxies,
+    ) -> Self {
+        let session_store = Arc::new(SessionStore::new(
+            db_conn.clone(),
+            argon2_pool.clone(),
+            session_backend,
+        ));
+        SessionStore::listen_for_invalidations(session_store.clone()).await;
         Self {
-            user_repository: Arc::new(RwLock::new(UserRepository::new(db_conn.clone()))),
-            session_store: Arc::new(RwLock::new(SessionStore::new(db_conn.clone()))),
-            calendar_service: CalendarService::new(db_conn.clone()),
+            user_repository: Arc::new(RwLock::new(UserRepository::new(
+                db_conn.clone(),
+                argon2_pool.clone(),
+            ))),
+            session_store,
+            calendar_service: CalendarService::new(db_conn.clone(), argon2_pool),
+            rate_limiter: RateLimiter::new(db_conn.clone()),
+            invitation_repository: InvitationRepository::new(db_conn.clone()),
+            api_token_repository: ApiTokenRepository::new(db_conn.clone()),
+            registration_mode,
+            oidc_repository: OidcRepository::new(db_conn.clone()),
+            oidc_provider,
+            audit_repository: AuditRepository::new(db_conn.clone()),
+            notice_repository: NoticeRepository::new(db_conn.clone()),
+            legacy_envelope_repository: LegacyEnvelopeRepository::new(db_conn.clone()),
+            trusted_proxies,
         }
     }
 }
@@ -0,0 +1,159 @@
+use crate::model::calendar::KeyHandler;
+use crate::model::crypto::{Envelope, Kdf};
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::SaltString;
+use argon2::{Algorithm, Argon2, Params, PasswordHash, PasswordHasher, PasswordVerifier, Version};
+use std::sync::Arc;
+use tokio::sync::Semaphore;
+use zeroize::Zeroizing;
+
+/// Runs Argon2, for password hashes as well as key derivation, on the blocking threads of the
+/// runtime so that it never stalls request handling. At most `max_concurrency` computations
+/// run at once, the others wait for their turn.
+///
+/// Hashes and envelopes record the costs they were made with, so a tampered row could ask for
+/// any cost. Those above `max_params` are refused without running anything.
+#[derive(Clone)]
+pub struct Argon2Pool {
+    params: Params,
+    max_params: Params,
+    permits: Arc<Semaphore>,
+}
+
+impl Argon2Pool {
+    pub fn new(params: Params, max_params: Params, max_concurrency: usize) -> Self {
+        Self {
+            params,
+            max_params,
+            permits: Arc::new(Semaphore::new(max_concurrency)),
+        }
+    }
+
+    fn check_costs(&self, m_cost: u32, t_cost: u32, p_cost: u32) -> Result<(), String> {
+        match m_cost <= self.max_params.m_cost()
+            && t_cost <= self.max_params.t_cost()
+            && p_cost <= self.max_params.p_cost()
+        {
+            true => Ok(()),
+            false => Err(String::from("Argon2 costs above the configured maximum")),
+        }
+    }
+
+    async fn run<T, F>(&self, task: F) -> Result<T, String>
+    where
+        F: FnOnce() -> Result<T, String> + Send + 'static,
+        T: Send + 'static,
+    {
+        let _permit = self
+            .permits
+            .acquire()
+            .await
+            .map_err(|_| "The Argon2 pool is closed")?;
+        tokio::task::spawn_blocking(task)
+            .await
+            .map_err(|e| format!("Argon2 computation failed: {}", e))?
+    }
+
+    /// A key derivation with the configured parameters and a fresh salt.
+    pub fn kdf(&self) -> Kdf {
+        Kdf::argon2id(&self.params)
+    }
+
+    pub async fn hash_password(&self, password: &str) -> Result<String, String> {
+        let password = Zeroizing::new(password.to_owned());
+        let params = self.params.clone();
+        self.run(move || {
+            let salt = SaltString::generate(&mut OsRng);
+            Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+                .hash_password(password.as_bytes(), &salt)
+                .map(|hash| hash.to_string())
+                .map_err(|e| format!("Could not hash the password: {}", e))
+        })
+        .await
+    }
+
+    /// Checks a password against a hash from `hash_password`, with the parameters the hash
+    /// was made with.
+    pub async fn verify_password(&self, password: &str, password_hash: &str) -> Result<(), String> {
+        let password = Zeroizing::new(password.to_owned());
+        let params = PasswordHash::new(password_hash)
+            .and_then(|password_hash| Params::try_from(&password_hash))
+            .map_err(|_| "Invalid password_hash from database")?;
+        self.check_costs(params.m_cost(), params.t_cost(), params.p_cost())?;
+        let password_hash = password_hash.to_owned();
+        self.run(move || {
+            let password_hash = PasswordHash::new(&password_hash)
+                .map_err(|_| "Invalid password_hash from database")?;
+            Argon2::default()
+                .verify_password(password.as_bytes(), &password_hash)
+                .map_err(|_| String::from("Invalid credentials"))
+        })
+        .await
+    }
+
+    /// Whether a hash from `hash_password` was made with other parameters than the configured
+    /// ones, and should be replaced the next time the password is at hand.
+    pub fn needs_rehash(&self, password_hash: &str) -> bool {
+        let Ok(password_hash) = PasswordHash::new(password_hash) else {
+            return true;
+        };
+        match Params::try_from(&password_hash) {
+            Ok(params) => {
+                password_hash.algorithm != argon2::ARGON2ID_IDENT
+                    || params.m_cost() != self.params.m_cost()
+                    || params.t_cost() != self.params.t_cost()
+                    || params.p_cost() != self.params.p_cost()
+            }
+            Err(_) => true,
+        }
+    }
+
+    /// `KeyHandler::derive` on the pool.
+    pub async fn derive(&self, secret: &[u8], kdf: Kdf) -> Result<KeyHandler, String> {
+        let secret = Zeroizing::new(secret.to_vec());
+        self.run(move || KeyHandler::derive(&secret, kdf)).await
+    }
+
+    /// Derives the key which sealed `envelope` from the secret it was derived from, on the pool.
+    pub async fn for_envelope(&self, secret: &[u8], envelope: &[u8]) -> Result<KeyHandler, String> {
+        let kdf = Envelope::from_bytes(envelope)?.kdf;
+        if let Kdf::Argon2id {
+            m_cost,
+            t_cost,
+            p_cost,
+            ..
+        } = &kdf
+        {
+            self.check_costs(*m_cost, *t_cost, *p_cost)?;
+        }
+        self.derive(secret, kdf).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::crypto::AssociatedData;
+
+    #[tokio::test]
+    async fn envelopes_above_the_maximum_costs_are_refused() {
+        let params = Params::new(8, 1, 1, None).unwrap();
+        let pool = Argon2Pool::new(params.clone(), params, 1);
+        let key = pool.derive(b"password", pool.kdf()).await.unwrap();
+        let envelope = key
+            .encrypt(b"secret", &AssociatedData::content_key(1))
+            .unwrap();
+        assert!(pool.for_envelope(b"password", &envelope).await.is_ok());
+
+        let mut tampered = Envelope::from_bytes(&envelope).unwrap();
+        tampered.kdf = Kdf::Argon2id {
+            m_cost: 4 * 1024 * 1024,
+            t_cost: 1,
+            p_cost: 1,
+            salt: vec![0; 16],
+        };
+        let tampered = tampered.to_bytes().unwrap();
+        assert!(pool.for_envelope(b"password", &tampered).await.is_err());
+        assert!(KeyHandler::for_envelope(b"password", &tampered).is_err());
+    }
+}
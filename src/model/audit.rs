@@ -0,0 +1,88 @@
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use sqlx::Type;
+use std::fmt::Display;
+
+/// A security-relevant event, as recorded in the audit log.
+#[derive(Clone, Copy, Debug, PartialEq, Type, Deserialize)]
+#[sqlx(type_name = "audit_event_kind", rename_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum AuditEventKind {
+    Login,
+    LoginFailed,
+    PasswordChanged,
+    AccountRecovered,
+    KeysRotated,
+    TwoFactorEnabled,
+    TwoFactorDisabled,
+    SessionRevoked,
+    DayPasswordChanged,
+    DayDeleted,
+    EnvelopesNotResealed,
+}
+
+impl Display for AuditEventKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl AuditEventKind {
+    pub const ALL: [AuditEventKind; 11] = [
+        AuditEventKind::Login,
+        AuditEventKind::LoginFailed,
+        AuditEventKind::PasswordChanged,
+        AuditEventKind::AccountRecovered,
+        AuditEventKind::KeysRotated,
+        AuditEventKind::TwoFactorEnabled,
+        AuditEventKind::TwoFactorDisabled,
+        AuditEventKind::SessionRevoked,
+        AuditEventKind::DayPasswordChanged,
+        AuditEventKind::DayDeleted,
+        AuditEventKind::EnvelopesNotResealed,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AuditEventKind::Login => "login",
+            AuditEventKind::LoginFailed => "login_failed",
+            AuditEventKind::PasswordChanged => "password_changed",
+            AuditEventKind::AccountRecovered => "account_recovered",
+            AuditEventKind::KeysRotated => "keys_rotated",
+            AuditEventKind::TwoFactorEnabled => "two_factor_enabled",
+            AuditEventKind::TwoFactorDisabled => "two_factor_disabled",
+            AuditEventKind::SessionRevoked => "session_revoked",
+            AuditEventKind::DayPasswordChanged => "day_password_changed",
+            AuditEventKind::DayDeleted => "day_deleted",
+            AuditEventKind::EnvelopesNotResealed => "envelopes_not_resealed",
+        }
+    }
+
+    /// How the event is described to people
+    pub fn label(&self) -> &'static str {
+        match self {
+            AuditEventKind::Login => "Logged in",
+            AuditEventKind::LoginFailed => "Failed login",
+            AuditEventKind::PasswordChanged => "Password changed",
+            AuditEventKind::AccountRecovered => "Account recovered",
+            AuditEventKind::KeysRotated => "Encryption keys replaced",
+            AuditEventKind::TwoFactorEnabled => "Two-factor authentication turned on",
+            AuditEventKind::TwoFactorDisabled => "Two-factor authentication turned off",
+            AuditEventKind::SessionRevoked => "Sessions logged out",
+            AuditEventKind::DayPasswordChanged => "Day password changed",
+            AuditEventKind::DayDeleted => "Day deleted",
+            AuditEventKind::EnvelopesNotResealed => "Old encryption could not be upgraded",
+        }
+    }
+}
+
+/// An entry of the audit log.
+#[derive(Clone, Debug)]
+pub struct AuditEvent {
+    pub username: Option<String>,
+    pub kind: AuditEventKind,
+    pub details: Option<String>,
+    pub ip_address: Option<String>,
+    pub user_agent: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
@@ -1,11 +1,12 @@
-use chacha20poly1305::aead::Aead;
-use chacha20poly1305::{ChaCha20Poly1305, KeyInit};
+use crate::model::crypto::{AssociatedData, Envelope, Kdf, KeyPair, SecretBytes};
 use chrono::{DateTime, Utc};
-use hkdf::Hkdf;
-use rand::random;
-use serde::Deserialize;
-use sha2::Sha256;
-use std::fmt::Display;
+use rand::{fill, random};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlx::Type;
+use std::fmt::{Debug, Display};
+use std::sync::Arc;
+use zeroize::Zeroizing;
 
 #[derive(Deserialize)]
 pub enum Status {
@@ -30,12 +31,206 @@ impl Status {
     }
 }
 
+/// Who can open a calendar besides its members and subscribers.
+#[derive(Clone, Copy, Debug, PartialEq, Type, Serialize, Deserialize)]
+#[sqlx(type_name = "calendar_visibility", rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum CalendarVisibility {
+    /// Nobody else
+    Private,
+    /// Whoever has its share link
+    Unlisted,
+    /// Every logged in user
+    Public,
+}
+
+impl Display for CalendarVisibility {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl CalendarVisibility {
+    pub const ALL: [CalendarVisibility; 3] = [
+        CalendarVisibility::Private,
+        CalendarVisibility::Unlisted,
+        CalendarVisibility::Public,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CalendarVisibility::Private => "private",
+            CalendarVisibility::Unlisted => "unlisted",
+            CalendarVisibility::Public => "public",
+        }
+    }
+
+    /// How the visibility is described to the owner
+    pub fn label(&self) -> &'static str {
+        match self {
+            CalendarVisibility::Private => "Private: only its members and subscribers",
+            CalendarVisibility::Unlisted => "Unlisted: anyone with the share link",
+            CalendarVisibility::Public => "Public: every member",
+        }
+    }
+}
+
+/// What a member of a calendar may do with it. The user in `calendars.owner_id` is always one of
+/// its owners.
+#[derive(Clone, Copy, Debug, PartialEq, Type, Serialize, Deserialize)]
+#[sqlx(type_name = "calendar_role", rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum CalendarRole {
+    Owner,
+    Editor,
+    Viewer,
+}
+
+impl Display for CalendarRole {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl CalendarRole {
+    pub const ALL: [CalendarRole; 3] = [
+        CalendarRole::Owner,
+        CalendarRole::Editor,
+        CalendarRole::Viewer,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CalendarRole::Owner => "owner",
+            CalendarRole::Editor => "editor",
+            CalendarRole::Viewer => "viewer",
+        }
+    }
+
+    /// How the role is described to the owners
+    pub fn label(&self) -> &'static str {
+        match self {
+            CalendarRole::Owner => "Owner: manages the calendar and its members",
+            CalendarRole::Editor => "Editor: adds and edits days",
+            CalendarRole::Viewer => "Viewer: opens the calendar even while it is private",
+        }
+    }
+
+    pub fn allows(&self, permission: CalendarPermission) -> bool {
+        match permission {
+            CalendarPermission::EditDays => {
+                matches!(self, CalendarRole::Owner | CalendarRole::Editor)
+            }
+            CalendarPermission::Manage => *self == CalendarRole::Owner,
+        }
+    }
+
+    /// Whether the members with this role hold the keys of the protected days
+    pub fn holds_day_keys(&self) -> bool {
+        self.allows(CalendarPermission::EditDays)
+    }
+}
+
+/// Something only some members of a calendar may do.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CalendarPermission {
+    /// Add, edit, protect and delete days
+    EditDays,
+    /// Edit or delete the calendar, choose who can open it and manage its members
+    Manage,
+}
+
+impl CalendarPermission {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CalendarPermission::EditDays => "edit the days of",
+            CalendarPermission::Manage => "manage",
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Calendar {
     pub id: i32,
     pub owner_id: i32,
     pub title: String,
     pub created_at: DateTime<Utc>,
+    pub description: Option<String>,
+    pub cover_image_url: Option<String>,
+    pub visibility: CalendarVisibility,
+}
+
+/// The secret of the share link of a calendar. Only its hash is stored, so it is shown once,
+/// when it is created.
+pub struct ShareToken {
+    token: [u8; 16],
+}
+
+impl ShareToken {
+    pub fn generate() -> Self {
+        Self { token: random() }
+    }
+
+    pub fn hash(&self) -> String {
+        Self::hash_input(&hex::encode(self.token))
+    }
+
+    pub fn hash_input(input: &str) -> String {
+        hex::encode(Sha256::digest(input.trim().to_lowercase().as_bytes()))
+    }
+}
+
+impl Display for ShareToken {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", hex::encode(self.token))
+    }
+}
+
+const MAX_TITLE_LENGTH: usize = 100;
+const MAX_DESCRIPTION_LENGTH: usize = 2000;
+const MAX_URL_LENGTH: usize = 2000;
+
+/// What the owner of a calendar can change about it, checked and trimmed.
+pub struct CalendarDetails {
+    pub title: String,
+    pub description: Option<String>,
+    pub cover_image_url: Option<String>,
+}
+
+impl CalendarDetails {
+    /// Empty fields are left out. The cover image is loaded by the browsers of subscribers, so
+    /// it has to be an http(s) link rather than something like a `javascript:` URL.
+    pub fn new(title: &str, description: &str, cover_image_url: &str) -> Result<Self, String> {
+        let title = title.trim();
+        if title.is_empty() || title.chars().count() > MAX_TITLE_LENGTH {
+            return Err(format!(
+                "A calendar needs a title of at most {} characters",
+                MAX_TITLE_LENGTH
+            ));
+        }
+        let description = Some(description.trim()).filter(|description| !description.is_empty());
+        if description
+            .is_some_and(|description| description.chars().count() > MAX_DESCRIPTION_LENGTH)
+        {
+            return Err(format!(
+                "The description can be at most {} characters long",
+                MAX_DESCRIPTION_LENGTH
+            ));
+        }
+        let cover_image_url = Some(cover_image_url.trim()).filter(|url| !url.is_empty());
+        if let Some(url) = cover_image_url {
+            let scheme_ok = url.starts_with("https://") || url.starts_with("http://");
+            if !scheme_ok || url.len() > MAX_URL_LENGTH || url.contains(char::is_whitespace) {
+                return Err("The cover image must be an http or https link".to_owned());
+            }
+        }
+
+        Ok(Self {
+            title: title.to_owned(),
+            description: description.map(str::to_owned),
+            cover_image_url: cover_image_url.map(str::to_owned),
+        })
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -72,11 +267,34 @@ impl UserDay {
 pub struct UserCalendar {
     pub calendar: Calendar,
     pub subscribed_at: Option<DateTime<Utc>>,
+    /// Set while the subscription is hidden from the dashboard
+    pub archived_at: Option<DateTime<Utc>>,
+    /// Set when the user is a member of the calendar
+    pub role: Option<CalendarRole>,
+}
+
+impl UserCalendar {
+    pub fn can(&self, permission: CalendarPermission) -> bool {
+        self.role.is_some_and(|role| role.allows(permission))
+    }
+}
+
+pub struct CalendarMember {
+    pub user_id: i32,
+    pub username: String,
+    pub role: CalendarRole,
+    pub added_at: DateTime<Utc>,
 }
 pub struct RichUserCalendar {
     pub calendar: UserCalendar,
     pub days: Vec<UserDay>,
 }
+pub struct CalendarOverview {
+    pub calendar: Calendar,
+    pub owner_username: String,
+    pub day_count: i64,
+    pub subscriber_count: i64,
+}
 #[derive(Debug, Clone)]
 pub struct UserDay {
     pub day: CalendarDay,
@@ -84,61 +302,121 @@ pub struct UserDay {
     pub day_key_handler: KeyHandler,
 }
 
-#[derive(Debug, Clone)]
+/// Holds a key for encrypting and decrypting envelopes. Clones share the key bytes, which are
+/// wiped from memory once the last clone is dropped.
+#[derive(Clone)]
 pub struct KeyHandler {
-    key: Option<Vec<u8>>,
+    key: Option<Arc<SecretBytes>>,
+    kdf: Kdf,
+}
+
+impl Debug for KeyHandler {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("KeyHandler")
+            .field("key", &self.key.as_ref().map(|_| "<redacted>"))
+            .field("kdf", &self.kdf)
+            .finish()
+    }
 }
 
 impl KeyHandler {
     pub fn empty() -> Self {
-        KeyHandler { key: None }
+        KeyHandler {
+            key: None,
+            kdf: Kdf::None,
+        }
     }
-    pub fn from_key(key: Vec<u8>) -> KeyHandler {
-        KeyHandler { key: Some(key) }
+    pub fn from_key(key: SecretBytes) -> KeyHandler {
+        Self::from_optional_key(Some(key))
     }
 
     pub fn from_random(size: usize) -> KeyHandler {
-        let key = (0..size).map(|_| random()).collect();
-        KeyHandler { key: Some(key) }
+        let mut key = Zeroizing::new(vec![0u8; size]);
+        fill(&mut key[..]);
+        Self::from_key(key)
     }
 
-    pub fn from_optional_key(key: Option<Vec<u8>>) -> KeyHandler {
-        KeyHandler { key }
+    pub fn from_optional_key(key: Option<SecretBytes>) -> KeyHandler {
+        KeyHandler {
+            key: key.map(Arc::new),
+            kdf: Kdf::None,
+        }
     }
 
-    pub fn from_pass(password: &str, context: &str) -> KeyHandler {
-        KeyHandler {
-            key: Some(Self::get_key_from_string(password, context)),
+    /// Derives a key from a secret such as a password. The envelopes it seals record `kdf`, so
+    /// that `for_envelope` can derive it again.
+    pub fn derive(secret: &[u8], kdf: Kdf) -> Result<KeyHandler, String> {
+        Ok(KeyHandler {
+            key: Some(Arc::new(kdf.derive(secret)?)),
+            kdf,
+        })
+    }
+
+    /// Derives the key which sealed `envelope` from the secret it was derived from. Keys derived
+    /// with Argon2 are refused, `Argon2Pool::for_envelope` derives them within bounded costs.
+    pub fn for_envelope(secret: &[u8], envelope: &[u8]) -> Result<KeyHandler, String> {
+        match Envelope::from_bytes(envelope)?.kdf {
+            Kdf::Argon2id { .. } => Err(String::from("Unexpected Argon2 key derivation")),
+            kdf => Self::derive(secret, kdf),
         }
     }
 
-    fn get_key_from_string(string: &str, context: &str) -> Vec<u8> {
-        let mut key = [0u8; 32];
-        let hk = Hkdf::<Sha256>::new(None, string.as_bytes());
-        hk.expand(context.as_bytes(), &mut key).unwrap(); // Should never fail since lengths are always the same
-        key.to_vec()
+    fn get_key(&self) -> Result<&[u8], String> {
+        self.key
+            .as_deref()
+            .map(|key| key.as_slice())
+            .ok_or(String::from("key empty"))
+    }
+
+    pub fn decrypt(
+        &self,
+        envelope: &[u8],
+        associated_data: &AssociatedData,
+    ) -> Result<SecretBytes, String> {
+        Envelope::from_bytes(envelope)?.open(self.get_key()?, associated_data)
     }
 
-    fn get_key(&self) -> Result<Vec<u8>, String> {
-        self.key.clone().ok_or(String::from("key empty"))
+    /// Decrypts an envelope, and also returns it sealed again if it is outdated, for the caller
+    /// to store in its place.
+    pub fn decrypt_and_reseal(
+        &self,
+        envelope: &[u8],
+        associated_data: &AssociatedData,
+    ) -> Result<(SecretBytes, Option<Vec<u8>>), String> {
+        let envelope = Envelope::from_bytes(envelope)?;
+        let key = self.get_key()?;
+        let secret = envelope.open(key, associated_data)?;
+        // The key is the same, so is the way it was derived
+        let resealed = match envelope.is_outdated() {
+            true => Some(Envelope::seal(key, envelope.kdf, &secret, associated_data)?.to_bytes()?),
+            false => None,
+        };
+        Ok((secret, resealed))
     }
 
-    pub fn decrypt(&self, cypher: &[u8], salt: &[u8]) -> Result<Vec<u8>, String> {
-        let cypher = ChaCha20Poly1305::new(self.get_key()?.as_slice().into())
-            .decrypt(salt.into(), cypher)
-            .map_err(|e| format!("Decryption failed, the key is probably outdated: {:?}", e))?;
-        Ok(cypher)
+    pub fn encrypt(
+        &self,
+        secret: &[u8],
+        associated_data: &AssociatedData,
+    ) -> Result<Vec<u8>, String> {
+        Envelope::seal(self.get_key()?, self.kdf.clone(), secret, associated_data)?.to_bytes()
     }
 
-    pub fn encrypt(&self, secret: &[u8], salt: &[u8]) -> Result<Vec<u8>, String> {
-        let cypher = ChaCha20Poly1305::new(self.get_key()?.as_slice().into())
-            .encrypt(salt.into(), secret)
-            .unwrap();
-        Ok(cypher)
+    pub fn get_encrypted_key(
+        &self,
+        encryption_key: &Self,
+        associated_data: &AssociatedData,
+    ) -> Result<Vec<u8>, String> {
+        encryption_key.encrypt(self.get_key()?, associated_data)
     }
 
-    pub fn get_encrypted_key(&self, encryption_key: &Self, salt: &[u8]) -> Result<Vec<u8>, String> {
-        encryption_key.encrypt(&self.get_key()?, salt)
+    /// Seals the key for the owner of `public_key`, who opens it with their `KeyPair`.
+    pub fn get_sealed_key(
+        &self,
+        public_key: &[u8],
+        associated_data: &AssociatedData,
+    ) -> Result<Vec<u8>, String> {
+        KeyPair::seal(public_key, self.get_key()?, associated_data)
     }
 }
 
@@ -154,4 +432,9 @@ impl UserDay {
             day,
         }
     }
+
+    /// The same day without its key, to hand over to templates.
+    pub fn without_key(self) -> Self {
+        Self::new(self.day, self.unlocked_at, KeyHandler::empty())
+    }
 }
@@ -0,0 +1,643 @@
+use argon2::{Algorithm, Argon2, Params, Version};
+use chacha20poly1305::aead::{Aead, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, KeyInit, XChaCha20Poly1305};
+use hkdf::Hkdf;
+use rand::{fill, random};
+use sha2::Sha256;
+use std::fmt::Display;
+use std::sync::atomic::{AtomicBool, Ordering};
+use x25519_dalek::{PublicKey, StaticSecret};
+use zeroize::Zeroizing;
+
+/// Version of the envelope layout written by `Envelope::to_bytes`.
+const ENVELOPE_VERSION: u8 = 1;
+
+const KEY_LENGTH: usize = 32;
+
+/// HKDF info of the keys which seal secrets to a public key.
+const SEALING_INFO: &str = "sealed to public key";
+
+/// Key material or a decrypted secret, wiped from memory when dropped.
+pub type SecretBytes = Zeroizing<Vec<u8>>;
+
+/// Whether envelopes sealed with `Cipher::ChaCha20Poly1305` still open, see
+/// `reject_legacy_envelopes`.
+static LEGACY_ENVELOPES_ACCEPTED: AtomicBool = AtomicBool::new(true);
+
+/// Stops opening the envelopes sealed before they were bound to their rows. They ignore their
+/// associated data, so a blob copied to another row would still open.
+pub fn reject_legacy_envelopes() {
+    LEGACY_ENVELOPES_ACCEPTED.store(false, Ordering::Relaxed);
+}
+
+pub fn legacy_envelopes_rejected() -> bool {
+    !LEGACY_ENVELOPES_ACCEPTED.load(Ordering::Relaxed)
+}
+
+/// The AEAD an envelope was sealed with.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Cipher {
+    /// Written before envelopes were bound to their rows, it ignores the associated data.
+    ChaCha20Poly1305,
+    XChaCha20Poly1305,
+}
+
+impl Cipher {
+    fn id(&self) -> u8 {
+        match self {
+            Cipher::ChaCha20Poly1305 => 1,
+            Cipher::XChaCha20Poly1305 => 2,
+        }
+    }
+
+    fn from_id(id: u8) -> Result<Self, String> {
+        match id {
+            1 => Ok(Cipher::ChaCha20Poly1305),
+            2 => Ok(Cipher::XChaCha20Poly1305),
+            _ => Err(format!("Unknown cipher {} in envelope", id)),
+        }
+    }
+
+    fn nonce_length(&self) -> usize {
+        match self {
+            Cipher::ChaCha20Poly1305 => 12,
+            Cipher::XChaCha20Poly1305 => 24,
+        }
+    }
+}
+
+/// Where an envelope is stored and what it holds. It is authenticated along with the
+/// ciphertext, so an envelope copied to another row or column no longer opens.
+pub struct AssociatedData {
+    table: &'static str,
+    row: String,
+    purpose: &'static str,
+}
+
+impl AssociatedData {
+    fn new(table: &'static str, row: impl Display, purpose: &'static str) -> Self {
+        Self {
+            table,
+            row: row.to_string(),
+            purpose,
+        }
+    }
+
+    /// `users.content_key_encr`
+    pub fn content_key(user_id: i32) -> Self {
+        Self::new("users", user_id, "content key")
+    }
+
+    /// `users.recovery_key_encr`
+    pub fn recovery_key(user_id: i32) -> Self {
+        Self::new("users", user_id, "recovery key")
+    }
+
+    /// `users.totp_secret_encr`
+    pub fn totp_secret(user_id: i32) -> Self {
+        Self::new("users", user_id, "totp secret")
+    }
+
+    /// The master key of a session, in `user_sessions` or in the Redis session hash.
+    pub fn session_master_key(token_hash: &str) -> Self {
+        Self::new("user_sessions", token_hash, "master key")
+    }
+
+    /// `api_tokens.content_key_encr`
+    pub fn api_token_content_key(token_hash: &str) -> Self {
+        Self::new("api_tokens", token_hash, "content key")
+    }
+
+    /// `users.private_key_encr`
+    pub fn private_key(user_id: i32) -> Self {
+        Self::new("users", user_id, "private key")
+    }
+
+    /// `user_days.day_key_encr`
+    pub fn day_key(user_id: i32, day_id: i32) -> Self {
+        Self::new("user_days", format!("{}:{}", user_id, day_id), "day key")
+    }
+
+    /// `user_days.day_key_sealed`
+    pub fn sealed_day_key(user_id: i32, day_id: i32) -> Self {
+        Self::new(
+            "user_days",
+            format!("{}:{}", user_id, day_id),
+            "sealed day key",
+        )
+    }
+
+    /// `day_content.decryption_key_encr`
+    pub fn decryption_key(day_id: i32) -> Self {
+        Self::new("day_content", day_id, "decryption key")
+    }
+
+    /// `day_content.content` of a protected day
+    pub fn day_content(day_id: i32) -> Self {
+        Self::new("day_content", day_id, "content")
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        [self.table, self.row.as_str(), self.purpose]
+            .iter()
+            .flat_map(|field| {
+                let length = (field.len() as u32).to_be_bytes();
+                length.into_iter().chain(field.bytes())
+            })
+            .collect()
+    }
+}
+
+/// How the key that sealed an envelope was derived from the secret the user holds, such as a
+/// password, a recovery key or a session token. Keys which are stored wrapped themselves use
+/// `Kdf::None`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Kdf {
+    None,
+    HkdfSha256 {
+        info: Vec<u8>,
+    },
+    Argon2id {
+        m_cost: u32,
+        t_cost: u32,
+        p_cost: u32,
+        salt: Vec<u8>,
+    },
+}
+
+impl Kdf {
+    /// Argon2id with the costs of `params` and a fresh random salt.
+    pub fn argon2id(params: &Params) -> Self {
+        let salt: [u8; 16] = random();
+        Kdf::Argon2id {
+            m_cost: params.m_cost(),
+            t_cost: params.t_cost(),
+            p_cost: params.p_cost(),
+            salt: salt.to_vec(),
+        }
+    }
+
+    pub fn hkdf(info: &str) -> Self {
+        Kdf::HkdfSha256 {
+            info: info.as_bytes().to_vec(),
+        }
+    }
+
+    pub fn derive(&self, secret: &[u8]) -> Result<SecretBytes, String> {
+        let mut key = Zeroizing::new(vec![0u8; KEY_LENGTH]);
+        match self {
+            Kdf::None => return Ok(Zeroizing::new(secret.to_vec())),
+            Kdf::HkdfSha256 { info } => {
+                Hkdf::<Sha256>::new(None, secret)
+                    .expand(info, &mut key[..])
+                    .map_err(|e| format!("Could not derive the key: {}", e))?;
+            }
+            Kdf::Argon2id {
+                m_cost,
+                t_cost,
+                p_cost,
+                salt,
+            } => {
+                let params = Params::new(*m_cost, *t_cost, *p_cost, Some(KEY_LENGTH))
+                    .map_err(|e| format!("Invalid Argon2 parameters: {}", e))?;
+                Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+                    .hash_password_into(secret, salt, &mut key[..])
+                    .map_err(|e| format!("Could not derive the key: {}", e))?;
+            }
+        }
+        Ok(key)
+    }
+
+    fn write(&self, bytes: &mut Vec<u8>) -> Result<(), String> {
+        match self {
+            Kdf::None => bytes.push(0),
+            Kdf::HkdfSha256 { info } => {
+                bytes.push(1);
+                write_short(bytes, info)?;
+            }
+            Kdf::Argon2id {
+                m_cost,
+                t_cost,
+                p_cost,
+                salt,
+            } => {
+                bytes.push(2);
+                bytes.extend_from_slice(&m_cost.to_be_bytes());
+                bytes.extend_from_slice(&t_cost.to_be_bytes());
+                bytes.extend_from_slice(&p_cost.to_be_bytes());
+                write_short(bytes, salt)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn read(reader: &mut Reader) -> Result<Self, String> {
+        match reader.byte()? {
+            0 => Ok(Kdf::None),
+            1 => Ok(Kdf::HkdfSha256 {
+                info: reader.short()?.to_vec(),
+            }),
+            2 => Ok(Kdf::Argon2id {
+                m_cost: reader.u32()?,
+                t_cost: reader.u32()?,
+                p_cost: reader.u32()?,
+                salt: reader.short()?.to_vec(),
+            }),
+            id => Err(format!("Unknown key derivation {} in envelope", id)),
+        }
+    }
+}
+
+/// A self-describing ciphertext, which is what every encrypted column stores. Its bytes are:
+///
+/// - the envelope version, then the cipher id
+/// - the nonce, prefixed with its length
+/// - the key derivation: 0 for none, 1 for HKDF-SHA256 followed by the info prefixed with its
+///   length, 2 for Argon2id followed by the memory, time and parallelism costs as big endian
+///   u32 and the salt prefixed with its length
+/// - the ciphertext, until the end
+#[derive(Clone, Debug, PartialEq)]
+pub struct Envelope {
+    pub cipher: Cipher,
+    pub nonce: Vec<u8>,
+    pub kdf: Kdf,
+    pub ciphertext: Vec<u8>,
+}
+
+impl Envelope {
+    /// Encrypts `plaintext` under `key` with a fresh nonce. `kdf` records how `key` was derived.
+    pub fn seal(
+        key: &[u8],
+        kdf: Kdf,
+        plaintext: &[u8],
+        associated_data: &AssociatedData,
+    ) -> Result<Self, String> {
+        let nonce: [u8; 24] = random();
+        let payload = Payload {
+            msg: plaintext,
+            aad: &associated_data.to_bytes(),
+        };
+        let ciphertext = XChaCha20Poly1305::new_from_slice(key)
+            .map_err(|_| String::from("Invalid key length"))?
+            .encrypt(&nonce.into(), payload)
+            .map_err(|e| format!("Encryption failed: {:?}", e))?;
+        Ok(Self {
+            cipher: Cipher::XChaCha20Poly1305,
+            nonce: nonce.to_vec(),
+            kdf,
+            ciphertext,
+        })
+    }
+
+    pub fn open(
+        &self,
+        key: &[u8],
+        associated_data: &AssociatedData,
+    ) -> Result<SecretBytes, String> {
+        self.open_with(key, associated_data, legacy_envelopes_rejected())
+    }
+
+    fn open_with(
+        &self,
+        key: &[u8],
+        associated_data: &AssociatedData,
+        legacy_rejected: bool,
+    ) -> Result<SecretBytes, String> {
+        let plaintext = match self.cipher {
+            Cipher::ChaCha20Poly1305 if legacy_rejected => {
+                return Err(String::from(
+                    "This envelope was sealed before envelopes were bound to their rows",
+                ));
+            }
+            Cipher::ChaCha20Poly1305 => ChaCha20Poly1305::new_from_slice(key)
+                .map_err(|_| String::from("Invalid key length"))?
+                .decrypt(self.nonce.as_slice().into(), self.ciphertext.as_slice()),
+            Cipher::XChaCha20Poly1305 => {
+                let payload = Payload {
+                    msg: &self.ciphertext,
+                    aad: &associated_data.to_bytes(),
+                };
+                XChaCha20Poly1305::new_from_slice(key)
+                    .map_err(|_| String::from("Invalid key length"))?
+                    .decrypt(self.nonce.as_slice().into(), payload)
+            }
+        };
+        plaintext
+            .map(Zeroizing::new)
+            .map_err(|e| format!("Decryption failed, the key is probably outdated: {:?}", e))
+    }
+
+    /// Whether the envelope was sealed before envelopes were bound to their rows, and should be
+    /// sealed again the next time its key is at hand.
+    pub fn is_outdated(&self) -> bool {
+        self.cipher != Cipher::XChaCha20Poly1305
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, String> {
+        let mut bytes = vec![ENVELOPE_VERSION, self.cipher.id()];
+        write_short(&mut bytes, &self.nonce)?;
+        self.kdf.write(&mut bytes)?;
+        bytes.extend_from_slice(&self.ciphertext);
+        Ok(bytes)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, String> {
+        let mut reader = Reader { bytes };
+        let version = reader.byte()?;
+        if version != ENVELOPE_VERSION {
+            return Err(format!("Unsupported envelope version {}", version));
+        }
+        let cipher = Cipher::from_id(reader.byte()?)?;
+        let nonce = reader.short()?.to_vec();
+        if nonce.len() != cipher.nonce_length() {
+            return Err(String::from("Invalid nonce length in envelope"));
+        }
+        let kdf = Kdf::read(&mut reader)?;
+        Ok(Self {
+            cipher,
+            nonce,
+            kdf,
+            ciphertext: reader.bytes.to_vec(),
+        })
+    }
+}
+
+/// An X25519 key pair. What is sealed to its public key can only be opened with its private key,
+/// so secrets can be handed to a user without holding their content key.
+pub struct KeyPair {
+    private_key: StaticSecret,
+}
+
+impl KeyPair {
+    pub fn generate() -> Self {
+        let mut private_key = Zeroizing::new([0u8; KEY_LENGTH]);
+        fill(&mut private_key[..]);
+        Self {
+            private_key: StaticSecret::from(*private_key),
+        }
+    }
+
+    pub fn from_private_key(private_key: &[u8]) -> Result<Self, String> {
+        let private_key: [u8; KEY_LENGTH] = private_key
+            .try_into()
+            .map_err(|_| "Invalid private key length")?;
+        Ok(Self {
+            private_key: StaticSecret::from(private_key),
+        })
+    }
+
+    pub fn private_key(&self) -> SecretBytes {
+        Zeroizing::new(self.private_key.to_bytes().to_vec())
+    }
+
+    pub fn public_key(&self) -> Vec<u8> {
+        PublicKey::from(&self.private_key).as_bytes().to_vec()
+    }
+
+    /// Seals `secret` for the owner of `public_key`. The result is the public key of a one-time
+    /// key pair, followed by an envelope sealed with a key derived from the secret it shares
+    /// with the recipient.
+    pub fn seal(
+        public_key: &[u8],
+        secret: &[u8],
+        associated_data: &AssociatedData,
+    ) -> Result<Vec<u8>, String> {
+        let ephemeral = Self::generate();
+        let mut sealed = ephemeral.public_key();
+        let key_material = ephemeral.key_material(public_key, &sealed, public_key)?;
+        let kdf = Kdf::hkdf(SEALING_INFO);
+        let key = kdf.derive(&key_material)?;
+        sealed.extend(Envelope::seal(&key, kdf, secret, associated_data)?.to_bytes()?);
+        Ok(sealed)
+    }
+
+    /// Opens what `seal` sealed to the public key of this key pair.
+    pub fn open(
+        &self,
+        sealed: &[u8],
+        associated_data: &AssociatedData,
+    ) -> Result<SecretBytes, String> {
+        if sealed.len() < KEY_LENGTH {
+            return Err(String::from("Truncated sealed secret"));
+        }
+        let (ephemeral_public_key, envelope) = sealed.split_at(KEY_LENGTH);
+        let envelope = Envelope::from_bytes(envelope)?;
+        if envelope.kdf != Kdf::hkdf(SEALING_INFO) {
+            return Err(String::from("Invalid key derivation in sealed secret"));
+        }
+        let key_material = self.key_material(
+            ephemeral_public_key,
+            ephemeral_public_key,
+            &self.public_key(),
+        )?;
+        let key = envelope.kdf.derive(&key_material)?;
+        envelope.open(&key, associated_data)
+    }
+
+    /// The secret shared with the owner of `other_public_key`, followed by the public keys of
+    /// both sides so that the derived key is bound to them.
+    fn key_material(
+        &self,
+        other_public_key: &[u8],
+        ephemeral_public_key: &[u8],
+        recipient_public_key: &[u8],
+    ) -> Result<SecretBytes, String> {
+        let other_public_key: [u8; KEY_LENGTH] = other_public_key
+            .try_into()
+            .map_err(|_| "Invalid public key length")?;
+        let shared_secret = self
+            .private_key
+            .diffie_hellman(&PublicKey::from(other_public_key));
+        if !shared_secret.was_contributory() {
+            return Err(String::from("Invalid public key"));
+        }
+        let mut key_material = Zeroizing::new(shared_secret.as_bytes().to_vec());
+        key_material.extend_from_slice(ephemeral_public_key);
+        key_material.extend_from_slice(recipient_public_key);
+        Ok(key_material)
+    }
+}
+
+/// Writes a field of at most 255 bytes, prefixed with its length.
+fn write_short(bytes: &mut Vec<u8>, field: &[u8]) -> Result<(), String> {
+    let length = u8::try_from(field.len()).map_err(|_| "Envelope field too long")?;
+    bytes.push(length);
+    bytes.extend_from_slice(field);
+    Ok(())
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, length: usize) -> Result<&'a [u8], String> {
+        if self.bytes.len() < length {
+            return Err(String::from("Truncated envelope"));
+        }
+        let (field, rest) = self.bytes.split_at(length);
+        self.bytes = rest;
+        Ok(field)
+    }
+
+    fn byte(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_be_bytes(bytes.try_into().unwrap())) // take returned exactly 4 bytes
+    }
+
+    fn short(&mut self) -> Result<&'a [u8], String> {
+        let length = self.byte()?;
+        self.take(length as usize)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &[u8] = b"the secret the user holds";
+    const PLAINTEXT: &[u8] = b"what the envelope protects";
+
+    fn kdfs() -> Vec<Kdf> {
+        vec![
+            Kdf::None,
+            Kdf::hkdf("test"),
+            Kdf::argon2id(&Params::new(8, 1, 1, None).unwrap()),
+        ]
+    }
+
+    /// The secret the key is derived from: `Kdf::None` takes a key as it is.
+    fn secret_for(kdf: &Kdf) -> Vec<u8> {
+        match kdf {
+            Kdf::None => vec![3u8; KEY_LENGTH],
+            _ => SECRET.to_vec(),
+        }
+    }
+
+    fn sealed_bytes(associated_data: &AssociatedData) -> (Vec<u8>, SecretBytes) {
+        let kdf = Kdf::hkdf("test");
+        let key = kdf.derive(SECRET).unwrap();
+        let envelope = Envelope::seal(&key, kdf, PLAINTEXT, associated_data).unwrap();
+        (envelope.to_bytes().unwrap(), key)
+    }
+
+    #[test]
+    fn envelopes_open_again_with_the_key_derived_the_same_way() {
+        for kdf in kdfs() {
+            let secret = secret_for(&kdf);
+            let key = kdf.derive(&secret).unwrap();
+            let associated_data = AssociatedData::day_key(1, 2);
+            let bytes = Envelope::seal(&key, kdf.clone(), PLAINTEXT, &associated_data)
+                .unwrap()
+                .to_bytes()
+                .unwrap();
+
+            let envelope = Envelope::from_bytes(&bytes).unwrap();
+            assert_eq!(envelope.kdf, kdf);
+            assert!(!envelope.is_outdated());
+            let key = envelope.kdf.derive(&secret).unwrap();
+            assert_eq!(*envelope.open(&key, &associated_data).unwrap(), PLAINTEXT);
+            assert!(envelope.open(&[4u8; KEY_LENGTH], &associated_data).is_err());
+        }
+    }
+
+    #[test]
+    fn envelopes_copied_to_another_row_or_column_are_refused() {
+        let (bytes, key) = sealed_bytes(&AssociatedData::day_key(1, 2));
+        let envelope = Envelope::from_bytes(&bytes).unwrap();
+
+        assert!(envelope.open(&key, &AssociatedData::day_key(1, 2)).is_ok());
+        assert!(envelope.open(&key, &AssociatedData::day_key(1, 3)).is_err());
+        assert!(envelope.open(&key, &AssociatedData::day_key(4, 2)).is_err());
+        assert!(envelope
+            .open(&key, &AssociatedData::sealed_day_key(1, 2))
+            .is_err());
+        assert!(envelope
+            .open(&key, &AssociatedData::decryption_key(2))
+            .is_err());
+    }
+
+    #[test]
+    fn truncated_envelopes_are_refused() {
+        let associated_data = AssociatedData::content_key(1);
+        let (bytes, key) = sealed_bytes(&associated_data);
+        for length in 0..bytes.len() {
+            let opened = Envelope::from_bytes(&bytes[..length])
+                .and_then(|envelope| envelope.open(&key, &associated_data));
+            assert!(opened.is_err(), "{} bytes opened", length);
+        }
+    }
+
+    #[test]
+    fn unknown_versions_ciphers_and_key_derivations_are_refused() {
+        let (bytes, _) = sealed_bytes(&AssociatedData::content_key(1));
+        // Version, cipher id, nonce length and nonce, then the key derivation id
+        let kdf_offset = 2 + 1 + 24;
+        for (offset, value) in [(0, 0), (0, 2), (1, 0), (1, 3), (2, 12), (kdf_offset, 3)] {
+            let mut bytes = bytes.clone();
+            bytes[offset] = value;
+            assert!(
+                Envelope::from_bytes(&bytes).is_err(),
+                "byte {} set to {} was accepted",
+                offset,
+                value
+            );
+        }
+    }
+
+    #[test]
+    fn legacy_envelopes_open_until_they_are_rejected() {
+        let key = [3u8; KEY_LENGTH];
+        let nonce = [7u8; 12];
+        let ciphertext = ChaCha20Poly1305::new_from_slice(&key)
+            .unwrap()
+            .encrypt(&nonce.into(), PLAINTEXT)
+            .unwrap();
+        let bytes = Envelope {
+            cipher: Cipher::ChaCha20Poly1305,
+            nonce: nonce.to_vec(),
+            kdf: Kdf::None,
+            ciphertext,
+        }
+        .to_bytes()
+        .unwrap();
+        let envelope = Envelope::from_bytes(&bytes).unwrap();
+        assert!(envelope.is_outdated());
+
+        // Until then they open for any row, which is why they have to go
+        for associated_data in [AssociatedData::day_key(1, 2), AssociatedData::day_key(1, 3)] {
+            let opened = envelope.open_with(&key, &associated_data, false).unwrap();
+            assert_eq!(*opened, PLAINTEXT);
+            assert!(envelope.open_with(&key, &associated_data, true).is_err());
+        }
+    }
+
+    #[test]
+    fn secrets_sealed_to_a_public_key_open_with_its_private_key_only() {
+        let recipient = KeyPair::generate();
+        let associated_data = AssociatedData::sealed_day_key(1, 2);
+        let sealed = KeyPair::seal(&recipient.public_key(), PLAINTEXT, &associated_data).unwrap();
+
+        assert_eq!(
+            *recipient.open(&sealed, &associated_data).unwrap(),
+            PLAINTEXT
+        );
+        let restored = KeyPair::from_private_key(&recipient.private_key()).unwrap();
+        assert_eq!(
+            *restored.open(&sealed, &associated_data).unwrap(),
+            PLAINTEXT
+        );
+
+        assert!(KeyPair::generate().open(&sealed, &associated_data).is_err());
+        assert!(recipient
+            .open(&sealed, &AssociatedData::sealed_day_key(1, 3))
+            .is_err());
+        for length in 0..sealed.len() {
+            assert!(recipient.open(&sealed[..length], &associated_data).is_err());
+        }
+    }
+}
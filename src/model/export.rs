@@ -0,0 +1,48 @@
+use crate::model::calendar::{CalendarRole, CalendarVisibility};
+use crate::model::user::UserRole;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+/// The archive a user downloads from their account page, with the content of every day they
+/// can read in plain text.
+#[derive(Serialize)]
+pub struct AccountExport {
+    pub exported_at: DateTime<Utc>,
+    pub account: AccountDetails,
+    pub calendars: Vec<CalendarExport>,
+}
+
+#[derive(Serialize)]
+pub struct AccountDetails {
+    pub username: String,
+    pub role: UserRole,
+    pub has_recovery_key: bool,
+    pub two_factor_enabled: bool,
+}
+
+/// A calendar the user is a member of or is subscribed to.
+#[derive(Serialize)]
+pub struct CalendarExport {
+    pub id: i32,
+    pub title: String,
+    pub description: Option<String>,
+    pub cover_image_url: Option<String>,
+    pub visibility: CalendarVisibility,
+    pub created_at: DateTime<Utc>,
+    /// Whether the user created it
+    pub owned: bool,
+    pub role: Option<CalendarRole>,
+    pub subscribed_at: Option<DateTime<Utc>>,
+    pub archived_at: Option<DateTime<Utc>>,
+    pub days: Vec<DayExport>,
+}
+
+/// A day of a calendar, whose content is only there once the user unlocked it.
+#[derive(Serialize)]
+pub struct DayExport {
+    pub id: i32,
+    pub unlocks_at: DateTime<Utc>,
+    pub protected: bool,
+    pub unlocked_at: Option<DateTime<Utc>>,
+    pub content: Option<String>,
+}
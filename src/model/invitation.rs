@@ -0,0 +1,69 @@
+use chrono::{DateTime, Utc};
+use rand::random;
+use sha2::{Digest, Sha256};
+use std::fmt::Display;
+
+/// Who may create an account, set with `REGISTRATION_MODE`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RegistrationMode {
+    Open,
+    InviteOnly,
+    Closed,
+}
+
+/// A code letting someone sign up while registration is invite-only. Only its hash is stored,
+/// so it is shown once, when it is created.
+pub struct InvitationCode {
+    code: [u8; 8],
+}
+
+impl InvitationCode {
+    pub fn generate() -> Self {
+        Self { code: random() }
+    }
+
+    pub fn hash(&self) -> String {
+        Self::hash_input(&hex::encode(self.code))
+    }
+
+    /// Hashes a code typed by the user, ignoring dashes, spaces and case.
+    pub fn hash_input(input: &str) -> String {
+        let normalized: String = input
+            .chars()
+            .filter(|c| c.is_ascii_alphanumeric())
+            .collect::<String>()
+            .to_lowercase();
+        hex::encode(Sha256::digest(normalized.as_bytes()))
+    }
+}
+
+impl Display for InvitationCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let digits = hex::encode(self.code);
+        write!(
+            f,
+            "{}-{}-{}-{}",
+            &digits[..4],
+            &digits[4..8],
+            &digits[8..12],
+            &digits[12..]
+        )
+    }
+}
+
+/// An invitation as listed to whoever can revoke it, without its code.
+#[derive(Clone, Debug)]
+pub struct Invitation {
+    pub id: i32,
+    pub created_by: String,
+    pub max_uses: i32,
+    pub uses: i32,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+}
+
+impl Invitation {
+    pub fn is_usable(&self) -> bool {
+        self.uses < self.max_uses && self.expires_at > Utc::now()
+    }
+}
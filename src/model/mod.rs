@@ -1,3 +1,13 @@
+pub mod api_token;
 pub mod app_state;
+pub mod argon2_pool;
+pub mod audit;
 pub mod calendar;
+pub mod crypto;
+pub mod export;
+pub mod invitation;
+pub mod notice;
+pub mod oidc;
+pub mod session;
+pub mod two_factor;
 pub mod user;
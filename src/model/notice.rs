@@ -0,0 +1,10 @@
+use chrono::{DateTime, Utc};
+
+/// A message left on the dashboard of a user, such as the deletion of a calendar they
+/// subscribed to.
+#[derive(Clone, Debug)]
+pub struct Notice {
+    pub id: i32,
+    pub message: String,
+    pub created_at: DateTime<Utc>,
+}
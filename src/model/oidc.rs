@@ -0,0 +1,42 @@
+/// What the login has to remember across the round trip through the identity provider.
+pub struct OidcFlow {
+    pub state: String,
+    pub nonce: String,
+    pub pkce_verifier: String,
+}
+
+impl OidcFlow {
+    /// The three values are url-safe base64, so a dot can join them into a cookie.
+    pub fn encode(&self) -> String {
+        format!("{}.{}.{}", self.state, self.nonce, self.pkce_verifier)
+    }
+
+    pub fn decode(value: &str) -> Option<Self> {
+        let mut parts = value.split('.');
+        let flow = OidcFlow {
+            state: parts.next()?.to_owned(),
+            nonce: parts.next()?.to_owned(),
+            pkce_verifier: parts.next()?.to_owned(),
+        };
+        match parts.next() {
+            Some(_) => None,
+            None => Some(flow),
+        }
+    }
+}
+
+/// A user as the identity provider vouches for them.
+pub struct OidcIdentity {
+    pub issuer: String,
+    pub subject: String,
+    pub preferred_username: Option<String>,
+}
+
+/// A login through the identity provider which still needs the encryption passphrase.
+pub struct OidcLogin {
+    pub issuer: String,
+    pub subject: String,
+    pub preferred_username: Option<String>,
+    /// The account already linked to this identity, if any
+    pub username: Option<String>,
+}
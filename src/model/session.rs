@@ -0,0 +1,123 @@
+use chrono::{DateTime, Utc};
+use std::fmt::{Display, Formatter};
+use std::net::IpAddr;
+use std::sync::Arc;
+use uuid::Uuid;
+
+/// Where a login comes from, as recorded on the session when it is created.
+#[derive(Clone, Debug, Default)]
+pub struct ClientInfo {
+    pub user_agent: Option<String>,
+    pub ip_address: Option<String>,
+}
+
+/// The reverse proxies whose `X-Forwarded-For` header is believed, as addresses or CIDR ranges.
+/// With none, the client is always the peer of the connection.
+#[derive(Clone, Debug, Default)]
+pub struct TrustedProxies(Arc<[(IpAddr, u32)]>);
+
+impl TrustedProxies {
+    /// Parses a comma separated list such as `10.0.0.2,172.16.0.0/12,::1`.
+    pub fn parse(list: &str) -> Result<Self, String> {
+        let mut proxies = Vec::new();
+        for proxy in list.split(',').map(str::trim).filter(|proxy| !proxy.is_empty()) {
+            let (address, prefix) = proxy.split_once('/').unwrap_or((proxy, ""));
+            let address: IpAddr = address
+                .parse()
+                .map_err(|_| format!("{} is not an IP address", address))?;
+            let max_prefix = match address {
+                IpAddr::V4(_) => 32,
+                IpAddr::V6(_) => 128,
+            };
+            let prefix = match prefix {
+                "" => max_prefix,
+                prefix => prefix
+                    .parse()
+                    .ok()
+                    .filter(|prefix| *prefix <= max_prefix)
+                    .ok_or(format!("{} is not a valid prefix length", prefix))?,
+            };
+            proxies.push((address, prefix));
+        }
+        Ok(Self(proxies.into()))
+    }
+
+    fn contains(&self, ip: IpAddr) -> bool {
+        self.0.iter().any(|&(network, prefix)| match (network, ip) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - prefix).unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - prefix).unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        })
+    }
+
+    /// Finds the client of a connection from `peer`. Going right to left, each hop of
+    /// `forwarded_for` was appended by the proxy before it, so it is only believed while that
+    /// proxy is trusted. Whatever the client wrote itself is further left and never reached.
+    pub fn client_address(&self, peer: IpAddr, forwarded_for: &str) -> IpAddr {
+        let mut client = peer.to_canonical();
+        for hop in forwarded_for.rsplit(',') {
+            if !self.contains(client) {
+                break;
+            }
+            match hop.trim().parse::<IpAddr>() {
+                Ok(hop) => client = hop.to_canonical(),
+                Err(_) => break,
+            }
+        }
+        client
+    }
+}
+
+/// A live session as listed on the account page, without any key material.
+#[derive(Clone, Debug)]
+pub struct ActiveSession {
+    pub id: Uuid,
+    pub created_at: DateTime<Utc>,
+    pub last_seen_at: DateTime<Utc>,
+    pub user_agent: Option<String>,
+    pub ip_address: Option<String>,
+    pub current: bool,
+}
+
+/// The anti-forgery token of a browser. It lives in a cookie and every form posts it back in
+/// a hidden field, which a cross-site form can't do since it can't read the cookie.
+#[derive(Clone, Debug)]
+pub struct CsrfToken(pub String);
+
+impl Display for CsrfToken {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn forwarded_for_is_only_believed_from_trusted_proxies() {
+        let proxies = TrustedProxies::parse("10.89.0.0/24, ::1").unwrap();
+        let client = |peer: &str, forwarded_for: &str| {
+            proxies
+                .client_address(peer.parse().unwrap(), forwarded_for)
+                .to_string()
+        };
+
+        assert_eq!(client("203.0.113.7", "198.51.100.1"), "203.0.113.7");
+        assert_eq!(client("10.89.0.5", ""), "10.89.0.5");
+        assert_eq!(client("10.89.0.5", "198.51.100.1"), "198.51.100.1");
+        // The left-most hop was written by the client, the right-most by the proxy
+        assert_eq!(
+            client("10.89.0.5", "192.0.2.1, 198.51.100.1, 10.89.0.6"),
+            "198.51.100.1"
+        );
+        assert_eq!(client("::1", "not an address"), "::1");
+        assert_eq!(client("::ffff:10.89.0.5", "198.51.100.1"), "198.51.100.1");
+    }
+}
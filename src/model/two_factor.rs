@@ -0,0 +1,120 @@
+use crate::model::crypto::SecretBytes;
+use qrcode::render::svg;
+use qrcode::QrCode;
+use rand::{fill, random};
+use sha2::{Digest, Sha256};
+use std::fmt::Display;
+use std::time::{SystemTime, UNIX_EPOCH};
+use subtle::ConstantTimeEq;
+use totp_rs::{Algorithm, TOTP};
+use zeroize::Zeroizing;
+
+const ISSUER: &str = "Advent of Faith";
+pub const BACKUP_CODE_COUNT: usize = 10;
+
+/// The shared secret of an RFC 6238 authenticator: 6 digits, 30 second steps, SHA-1,
+/// which is what every authenticator app supports.
+pub struct TotpSecret {
+    secret: SecretBytes,
+}
+
+impl TotpSecret {
+    pub fn generate() -> Self {
+        let mut secret = Zeroizing::new(vec![0u8; 20]);
+        fill(&mut secret[..]);
+        Self { secret }
+    }
+
+    pub fn from_bytes(secret: SecretBytes) -> Self {
+        Self { secret }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.secret
+    }
+
+    fn totp(&self, username: &str) -> Result<TOTP, String> {
+        TOTP::new(
+            Algorithm::SHA1,
+            6,
+            1,
+            30,
+            self.secret.to_vec(),
+            Some(ISSUER.to_owned()),
+            username.to_owned(),
+        )
+        .map_err(|e| format!("Invalid authenticator secret: {}", e))
+    }
+
+    /// The time step of the code if it is valid now, allowing for one step of clock drift
+    /// either way. Callers only accept it for a step later than the last one they accepted.
+    pub fn check(&self, username: &str, code: &str) -> Result<Option<u64>, String> {
+        let code: String = code.chars().filter(|c| !c.is_whitespace()).collect();
+        let totp = self.totp(username)?;
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_err(|e| e.to_string())?
+            .as_secs();
+        let current = now / totp.step;
+        let skew = u64::from(totp.skew);
+        let first = current.saturating_sub(skew);
+        Ok((first..=current + skew).find(|step| {
+            let expected = totp.generate(step * totp.step);
+            bool::from(expected.as_bytes().ct_eq(code.as_bytes()))
+        }))
+    }
+
+    pub fn provisioning_uri(&self, username: &str) -> Result<String, String> {
+        Ok(self.totp(username)?.get_url())
+    }
+
+    pub fn encoded(&self, username: &str) -> Result<String, String> {
+        Ok(self.totp(username)?.get_secret_base32())
+    }
+
+    pub fn qr_code_svg(&self, username: &str) -> Result<String, String> {
+        let code = QrCode::new(self.provisioning_uri(username)?).map_err(|e| e.to_string())?;
+        Ok(code
+            .render::<svg::Color>()
+            .min_dimensions(200, 200)
+            .dark_color(svg::Color("#2a1414"))
+            .light_color(svg::Color("#ffd9b3"))
+            .build())
+    }
+}
+
+/// A single-use code that can replace the authenticator once. Only its hash is stored.
+pub struct BackupCode {
+    code: [u8; 5],
+}
+
+impl BackupCode {
+    pub fn generate() -> Self {
+        Self { code: random() }
+    }
+
+    pub fn generate_set() -> Vec<Self> {
+        (0..BACKUP_CODE_COUNT).map(|_| Self::generate()).collect()
+    }
+
+    pub fn hash(&self) -> String {
+        Self::hash_input(&hex::encode(self.code))
+    }
+
+    /// Hashes a code typed by the user, ignoring dashes, spaces and case.
+    pub fn hash_input(input: &str) -> String {
+        let normalized: String = input
+            .chars()
+            .filter(|c| c.is_ascii_alphanumeric())
+            .collect::<String>()
+            .to_lowercase();
+        hex::encode(Sha256::digest(normalized.as_bytes()))
+    }
+}
+
+impl Display for BackupCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let digits = hex::encode(self.code);
+        write!(f, "{}-{}", &digits[..5], &digits[5..])
+    }
+}
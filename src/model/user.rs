@@ -1,11 +1,33 @@
 use crate::model::calendar::KeyHandler;
+use crate::model::crypto::{Kdf, SecretBytes};
+use chrono::{DateTime, Utc};
+use rand::random;
+use serde::{Deserialize, Serialize};
 use sqlx::{FromRow, Type};
+use std::fmt::Display;
+use zeroize::{Zeroize, ZeroizeOnDrop, Zeroizing};
 
-#[derive(Clone, Debug, Type)]
+#[derive(Clone, Debug, PartialEq, Type, Serialize, Deserialize)]
 #[sqlx(type_name = "user_role", rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
 pub enum UserRole {
-    ADMIN,
-    MEMBER,
+    Admin,
+    Member,
+}
+
+impl Display for UserRole {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl UserRole {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            UserRole::Admin => "admin",
+            UserRole::Member => "member",
+        }
+    }
 }
 
 #[derive(Clone, Debug, FromRow)]
@@ -14,23 +36,101 @@ pub struct User {
     pub username: String,
     pub role: UserRole,
     pub content_key_handler: KeyHandler,
-    pub master_key_salt: Vec<u8>,
 }
 
 impl User {
-    pub fn new(
-        id: i32,
-        username: String,
-        role: UserRole,
-        content_key: Vec<u8>,
-        master_key_salt: Vec<u8>,
-    ) -> User {
+    pub fn new(id: i32, username: String, role: UserRole, content_key: SecretBytes) -> User {
         Self {
             id,
             username,
             role,
             content_key_handler: KeyHandler::from_key(content_key),
-            master_key_salt,
         }
     }
+
+    pub fn is_admin(&self) -> bool {
+        self.role == UserRole::Admin
+    }
+
+    pub fn view(&self) -> UserView {
+        UserView {
+            id: self.id,
+            username: self.username.clone(),
+            role: self.role.clone(),
+        }
+    }
+}
+
+/// What templates get to see of the logged in user, without its content key.
+#[derive(Clone, Debug)]
+pub struct UserView {
+    pub id: i32,
+    pub username: String,
+    pub role: UserRole,
+}
+
+impl UserView {
+    pub fn is_admin(&self) -> bool {
+        self.role == UserRole::Admin
+    }
+}
+
+/// An account as seen from the admin console, without any key material.
+#[derive(Clone, Debug)]
+pub struct UserAccount {
+    pub id: i32,
+    pub username: String,
+    pub role: UserRole,
+    pub locked_at: Option<DateTime<Utc>>,
+    pub owned_calendars: i64,
+    pub subscriptions: i64,
+}
+
+impl UserAccount {
+    pub fn is_locked(&self) -> bool {
+        self.locked_at.is_some()
+    }
+}
+
+/// A random key handed to the user once, which can unwrap their content key if they forget
+/// their password. It is printed as groups of hex digits so it can be written down.
+#[derive(Zeroize, ZeroizeOnDrop)]
+pub struct RecoveryKey {
+    key: [u8; 16],
+}
+
+impl RecoveryKey {
+    pub fn generate() -> Self {
+        Self { key: random() }
+    }
+
+    pub fn parse(input: &str) -> Result<Self, String> {
+        let digits: String = input
+            .chars()
+            .filter(|c| !c.is_whitespace() && *c != '-')
+            .collect::<String>()
+            .to_lowercase();
+        let key = hex::decode(digits)
+            .ok()
+            .and_then(|bytes| bytes.try_into().ok())
+            .ok_or("This is not a valid recovery key")?;
+        Ok(Self { key })
+    }
+
+    pub fn key_handler(&self) -> Result<KeyHandler, String> {
+        let digits = Zeroizing::new(hex::encode(self.key));
+        KeyHandler::derive(digits.as_bytes(), Kdf::hkdf("recovery key"))
+    }
+}
+
+impl Display for RecoveryKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let digits = hex::encode_upper(self.key);
+        let groups: Vec<&str> = digits
+            .as_bytes()
+            .chunks(4)
+            .map(|chunk| std::str::from_utf8(chunk).unwrap()) // Hex digits are always ASCII
+            .collect();
+        write!(f, "{}", groups.join("-"))
+    }
 }
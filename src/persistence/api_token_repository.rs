@@ -0,0 +1,147 @@
+use crate::model::api_token::{ApiToken, ApiTokenScope, ApiTokenSecret};
+use crate::model::calendar::KeyHandler;
+use crate::model::crypto::{AssociatedData, Kdf};
+use crate::model::user::{User, UserRole};
+use chrono::{TimeDelta, Utc};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+/// How stale `last_used_at` may get before a request writes it back.
+const LAST_USED_INTERVAL: TimeDelta = TimeDelta::minutes(5);
+
+const MAX_NAME_LENGTH: usize = 100;
+const MAX_VALIDITY_DAYS: i64 = 365;
+
+#[derive(Clone)]
+pub struct ApiTokenRepository {
+    db_pool: PgPool,
+}
+
+impl ApiTokenRepository {
+    pub fn new(db_pool: PgPool) -> Self {
+        Self { db_pool }
+    }
+
+    /// Creates a token wrapping the user's content key, valid for `valid_days` or until it is
+    /// revoked, and returns it.
+    pub async fn create_token(
+        &self,
+        user: &User,
+        name: &str,
+        scope: ApiTokenScope,
+        valid_days: Option<i64>,
+    ) -> Result<ApiTokenSecret, String> {
+        let name = name.trim();
+        if name.is_empty() || name.chars().count() > MAX_NAME_LENGTH {
+            return Err(format!(
+                "A token needs a name of at most {} characters",
+                MAX_NAME_LENGTH
+            ));
+        }
+        if valid_days.is_some_and(|days| !(1..=MAX_VALIDITY_DAYS).contains(&days)) {
+            return Err(format!(
+                "A token can be valid for 1 to {} days, or until it is revoked",
+                MAX_VALIDITY_DAYS
+            ));
+        }
+
+        let secret = ApiTokenSecret::generate();
+        let token_hash = secret.hash();
+        let token_key = KeyHandler::derive(secret.as_bytes(), Kdf::hkdf("api token"))?;
+        let content_key_encr = user.content_key_handler.get_encrypted_key(
+            &token_key,
+            &AssociatedData::api_token_content_key(&token_hash),
+        )?;
+        sqlx::query!(
+            "INSERT INTO api_tokens (token_hash, user_id, name, scope, content_key_encr, expires_at)
+            VALUES ($1, $2, $3, $4, $5, $6)",
+            token_hash,
+            user.id,
+            name,
+            scope as ApiTokenScope,
+            content_key_encr,
+            valid_days.map(|days| Utc::now() + TimeDelta::days(days))
+        )
+        .execute(&self.db_pool)
+        .await
+        .map_err(|e| format!("There was an error updating the database: {:?}", e))?;
+        Ok(secret)
+    }
+
+    /// The user a live token belongs to, with the content key it wraps, and what it may do.
+    pub async fn get_user(&self, token: &str) -> Option<(User, ApiTokenScope)> {
+        let secret = ApiTokenSecret::parse(token)?;
+        let token_hash = secret.hash();
+        let record = sqlx::query!(
+            r#"
+            SELECT t.scope as "scope:ApiTokenScope", t.content_key_encr, t.last_used_at,
+                u.id, u.username, u.role as "role:UserRole"
+            FROM api_tokens t
+            JOIN users u ON u.id = t.user_id
+            WHERE t.token_hash = $1 AND (t.expires_at IS NULL OR t.expires_at > now())
+                AND u.locked_at IS NULL
+            "#,
+            token_hash
+        )
+        .fetch_optional(&self.db_pool)
+        .await
+        .ok()??;
+
+        let token_key =
+            KeyHandler::for_envelope(secret.as_bytes(), &record.content_key_encr).ok()?;
+        let content_key = token_key
+            .decrypt(
+                &record.content_key_encr,
+                &AssociatedData::api_token_content_key(&token_hash),
+            )
+            .ok()?;
+
+        let stale = record
+            .last_used_at
+            .is_none_or(|last_used_at| Utc::now() - last_used_at > LAST_USED_INTERVAL);
+        if stale {
+            sqlx::query!(
+                "UPDATE api_tokens SET last_used_at = now() WHERE token_hash = $1",
+                token_hash
+            )
+            .execute(&self.db_pool)
+            .await
+            .ok()?;
+        }
+
+        let user = User::new(record.id, record.username, record.role, content_key);
+        Some((user, record.scope))
+    }
+
+    pub async fn get_tokens(&self, user_id: i32) -> Result<Vec<ApiToken>, String> {
+        sqlx::query_as!(
+            ApiToken,
+            r#"
+            SELECT id, name, scope as "scope:ApiTokenScope", created_at, last_used_at, expires_at
+            FROM api_tokens
+            WHERE user_id = $1
+            ORDER BY created_at DESC
+            "#,
+            user_id
+        )
+        .fetch_all(&self.db_pool)
+        .await
+        .map_err(|e| e.to_string())
+    }
+
+    pub async fn revoke_token(&self, user_id: i32, token_id: Uuid) -> Result<(), String> {
+        let result = sqlx::query!(
+            "DELETE FROM api_tokens WHERE id = $1 AND user_id = $2",
+            token_id,
+            user_id
+        )
+        .execute(&self.db_pool)
+        .await
+        .map_err(|e| e.to_string())?;
+
+        match result.rows_affected() {
+            0 => Err(String::from("This token doesn't exist anymore")),
+            _ => Ok(()),
+        }
+    }
+}
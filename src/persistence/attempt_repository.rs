@@ -0,0 +1,120 @@
+use chrono::{DateTime, TimeDelta, Utc};
+use sqlx::postgres::types::PgInterval;
+use sqlx::PgPool;
+
+#[derive(Clone)]
+pub struct AttemptRepository {
+    db_pool: PgPool,
+}
+
+impl AttemptRepository {
+    pub fn new(db_pool: PgPool) -> Self {
+        Self { db_pool }
+    }
+
+    /// Counts an attempt under every key and returns how many there were in a row under each,
+    /// or returns when the last of them stops being locked without counting anything if any
+    /// of them is. The rows stay locked until the end, so parallel attempts under the same keys
+    /// are counted one after the other. `lockout` tells how long a key with that many attempts
+    /// gets locked. Attempts older than `reset_after` are forgotten.
+    pub async fn add_attempt(
+        &self,
+        keys: &[String],
+        reset_after: TimeDelta,
+        lockout: impl Fn(i32) -> Option<TimeDelta>,
+    ) -> Result<Option<DateTime<Utc>>, String> {
+        let reset_after = PgInterval::try_from(reset_after).map_err(|e| e.to_string())?;
+        let error = |_| String::from("Error from database when recording an attempt");
+        let mut tx = self.db_pool.begin().await.map_err(error)?;
+
+        sqlx::query!(
+            "INSERT INTO auth_attempts (key) SELECT unnest($1::text[]) ON CONFLICT (key) DO NOTHING",
+            keys
+        )
+        .execute(&mut *tx)
+        .await
+        .map_err(error)?;
+        let locks = sqlx::query_scalar!(
+            "SELECT locked_until FROM auth_attempts WHERE key = ANY($1) ORDER BY key FOR UPDATE",
+            keys
+        )
+        .fetch_all(&mut *tx)
+        .await
+        .map_err(error)?;
+        let locked_until = locks.into_iter().flatten().max();
+        if locked_until.is_some_and(|locked_until| locked_until > Utc::now()) {
+            return Ok(locked_until);
+        }
+
+        for key in keys {
+            let failures = sqlx::query_scalar!(
+                "UPDATE auth_attempts SET
+                    failures = CASE
+                        WHEN last_failure_at < now() - $2::interval THEN 1
+                        ELSE failures + 1
+                    END,
+                    last_failure_at = now()
+                WHERE key = $1
+                RETURNING failures",
+                key,
+                reset_after
+            )
+            .fetch_one(&mut *tx)
+            .await
+            .map_err(error)?;
+            if let Some(duration) = lockout(failures) {
+                let duration = PgInterval::try_from(duration).map_err(|e| e.to_string())?;
+                sqlx::query!(
+                    "UPDATE auth_attempts SET locked_until = now() + $2::interval WHERE key = $1",
+                    key,
+                    duration
+                )
+                .execute(&mut *tx)
+                .await
+                .map_err(error)?;
+            }
+        }
+        tx.commit().await.map_err(error)?;
+        Ok(None)
+    }
+
+    /// Takes back one attempt counted under each key, along with the lock it caused if the
+    /// count falls back to `free_attempts`.
+    pub async fn remove_attempt(&self, keys: &[String], free_attempts: i32) -> Result<(), String> {
+        sqlx::query!(
+            "UPDATE auth_attempts SET
+                failures = greatest(failures - 1, 0),
+                locked_until = CASE WHEN failures - 1 <= $2 THEN NULL ELSE locked_until END
+            WHERE key = ANY($1)",
+            keys,
+            free_attempts
+        )
+        .execute(&self.db_pool)
+        .await
+        .map_err(|_| "Error from database when recording an attempt")?;
+        Ok(())
+    }
+
+    pub async fn clear(&self, keys: &[String]) -> Result<(), String> {
+        sqlx::query!("DELETE FROM auth_attempts WHERE key = ANY($1)", keys)
+            .execute(&self.db_pool)
+            .await
+            .map_err(|_| "Error from database when clearing attempts")?;
+        Ok(())
+    }
+
+    /// Deletes the keys which are not locked and whose last failure is older than `reset_after`.
+    pub async fn purge_stale(&self, reset_after: TimeDelta) -> Result<u64, String> {
+        let reset_after = PgInterval::try_from(reset_after).map_err(|e| e.to_string())?;
+        let result = sqlx::query!(
+            "DELETE FROM auth_attempts
+            WHERE last_failure_at < now() - $1::interval
+                AND (locked_until IS NULL OR locked_until < now())",
+            reset_after
+        )
+        .execute(&self.db_pool)
+        .await
+        .map_err(|_| "Error from database when purging attempts")?;
+        Ok(result.rows_affected())
+    }
+}
@@ -0,0 +1,152 @@
+use crate::model::audit::{AuditEvent, AuditEventKind};
+use crate::model::session::ClientInfo;
+use chrono::{TimeDelta, Utc};
+use sqlx::PgPool;
+
+/// How many events a page of the audit log shows.
+const PAGE_SIZE: i64 = 200;
+
+#[derive(Clone)]
+pub struct AuditRepository {
+    db_pool: PgPool,
+}
+
+impl AuditRepository {
+    pub fn new(db_pool: PgPool) -> Self {
+        Self { db_pool }
+    }
+
+    /// Appends an event to the audit log, with the device it came from when it is known.
+    pub async fn record(
+        &self,
+        user_id: Option<i32>,
+        kind: AuditEventKind,
+        client: Option<&ClientInfo>,
+        details: Option<String>,
+    ) -> Result<(), String> {
+        sqlx::query!(
+            "INSERT INTO audit_events (user_id, username, kind, details, ip_address, user_agent)
+            VALUES ($1, (SELECT username FROM users WHERE id = $1), $2, $3, $4, $5)",
+            user_id,
+            kind as AuditEventKind,
+            details,
+            client.and_then(|client| client.ip_address.clone()),
+            client.and_then(|client| client.user_agent.clone())
+        )
+        .execute(&self.db_pool)
+        .await
+        .map_err(|e| format!("There was an error updating the audit log: {:?}", e))?;
+        Ok(())
+    }
+
+    pub async fn get_user_events(&self, user_id: i32) -> Result<Vec<AuditEvent>, String> {
+        sqlx::query_as!(
+            AuditEvent,
+            r#"
+            SELECT a.username, a.kind as "kind:AuditEventKind", a.details,
+                a.ip_address, a.user_agent, a.created_at
+            FROM audit_events a
+            WHERE a.user_id = $1
+            ORDER BY a.created_at DESC, a.id DESC
+            LIMIT $2
+            "#,
+            user_id,
+            PAGE_SIZE
+        )
+        .fetch_all(&self.db_pool)
+        .await
+        .map_err(|e| e.to_string())
+    }
+
+    /// The latest events of everyone, optionally only those of a user or of a kind.
+    pub async fn get_events(
+        &self,
+        username: Option<&str>,
+        kind: Option<AuditEventKind>,
+    ) -> Result<Vec<AuditEvent>, String> {
+        sqlx::query_as!(
+            AuditEvent,
+            r#"
+            SELECT a.username, a.kind as "kind:AuditEventKind", a.details,
+                a.ip_address, a.user_agent, a.created_at
+            FROM audit_events a
+            WHERE ($1::text IS NULL OR a.username = $1)
+                AND ($2::audit_event_kind IS NULL OR a.kind = $2)
+            ORDER BY a.created_at DESC, a.id DESC
+            LIMIT $3
+            "#,
+            username,
+            kind as Option<AuditEventKind>,
+            PAGE_SIZE
+        )
+        .fetch_all(&self.db_pool)
+        .await
+        .map_err(|e| e.to_string())
+    }
+
+    /// Deletes the events older than `retention` and returns how many there were. The table
+    /// refuses deletions outside of a transaction that sets `audit.purging`.
+    pub async fn purge_older_than(&self, retention: TimeDelta) -> Result<u64, String> {
+        let mut tx = self.db_pool.begin().await.map_err(|e| e.to_string())?;
+        sqlx::query!("SELECT set_config('audit.purging', 'on', true)")
+            .fetch_one(&mut *tx)
+            .await
+            .map_err(|e| e.to_string())?;
+        let count = sqlx::query!(
+            "DELETE FROM audit_events WHERE created_at < $1",
+            Utc::now() - retention
+        )
+        .execute(&mut *tx)
+        .await
+        .map(|result| result.rows_affected())
+        .map_err(|e| e.to_string())?;
+        tx.commit().await.map_err(|e| e.to_string())?;
+        Ok(count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::argon2_pool::Argon2Pool;
+    use crate::model::user::UserRole;
+    use crate::persistence::user_repository::{LoginCredentials, UserRepository};
+    use argon2::Params;
+
+    #[sqlx::test]
+    async fn events_outlive_their_account_until_the_purge(db_pool: PgPool) {
+        let params = Params::new(8, 1, 1, None).unwrap();
+        let argon2_pool = Argon2Pool::new(params.clone(), params, 1);
+        let users = UserRepository::new(db_pool.clone(), argon2_pool);
+        let credentials = LoginCredentials {
+            username: String::from("alice"),
+            password: String::from("correct horse battery staple"),
+        };
+        let (user, _) = users
+            .add_user(&credentials, UserRole::Member, None, None)
+            .await
+            .unwrap();
+        let user_id = user.id;
+        let audit = AuditRepository::new(db_pool.clone());
+        audit
+            .record(Some(user_id), AuditEventKind::Login, None, None)
+            .await
+            .unwrap();
+
+        sqlx::query!("DELETE FROM users WHERE id = $1", user_id)
+            .execute(&db_pool)
+            .await
+            .unwrap();
+        let events = audit.get_events(Some("alice"), None).await.unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].kind, AuditEventKind::Login);
+
+        assert!(
+            sqlx::query!("DELETE FROM audit_events")
+                .execute(&db_pool)
+                .await
+                .is_err()
+        );
+        assert_eq!(audit.purge_older_than(TimeDelta::zero()).await.unwrap(), 1);
+    }
+}
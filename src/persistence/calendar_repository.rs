@@ -1,50 +1,203 @@
+use crate::model::argon2_pool::Argon2Pool;
 use crate::model::calendar::{
-    Calendar, CalendarDay, KeyHandler, RichUserCalendar, UserCalendar, UserDay,
+    Calendar, CalendarDay, CalendarDetails, CalendarMember, CalendarOverview, CalendarRole,
+    CalendarVisibility, KeyHandler, RichUserCalendar, UserCalendar, UserDay,
 };
+use crate::model::crypto::{AssociatedData, Envelope, Kdf, KeyPair};
 use crate::model::user::User;
 use chrono::{DateTime, Utc};
-use rand::random;
-use sqlx::PgPool;
+use sqlx::{PgConnection, PgPool};
 use std::collections::HashMap;
 
 pub struct CalendarRepository {
     db_pool: PgPool,
+    argon2_pool: Argon2Pool,
 }
 
 impl CalendarRepository {
-    pub fn new(pool: PgPool) -> Self {
-        CalendarRepository { db_pool: pool }
+    pub fn new(pool: PgPool, argon2_pool: Argon2Pool) -> Self {
+        CalendarRepository {
+            db_pool: pool,
+            argon2_pool,
+        }
     }
 
     pub async fn create_calendar(&self, owner: &User, title: &str) -> Result<Calendar, String> {
-        sqlx::query_as!(
+        let mut tx = self.db_pool.begin().await.map_err(|e| e.to_string())?;
+        let calendar = sqlx::query_as!(
             Calendar,
             r#"
             INSERT INTO calendars (owner_id, title)
             VALUES ($1, $2)
-            RETURNING *
+            RETURNING id, owner_id, title, created_at, description, cover_image_url,
+                visibility as "visibility: CalendarVisibility"
             "#,
             owner.id,
             title
         )
-        .fetch_one(&self.db_pool)
+        .fetch_one(&mut *tx)
+        .await
+        .map_err(|e| e.to_string())?;
+        sqlx::query!(
+            "INSERT INTO calendar_members (calendar_id, user_id, role) VALUES ($1, $2, 'owner')",
+            calendar.id,
+            owner.id
+        )
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| e.to_string())?;
+        tx.commit().await.map_err(|e| e.to_string())?;
+        Ok(calendar)
+    }
+
+    pub async fn get_calendar_overviews(&self) -> Result<Vec<CalendarOverview>, String> {
+        let result = sqlx::query!(
+            r#"
+            SELECT c.id, c.title, c.created_at, c.owner_id, c.description, c.cover_image_url,
+                c.visibility as "visibility: CalendarVisibility", u.username as owner_username,
+                (SELECT count(*) FROM calendar_days cd WHERE cd.calendar_id = c.id) as "day_count!",
+                (SELECT count(*) FROM calendar_subscriptions cs WHERE cs.calendar_id = c.id) as "subscriber_count!"
+            FROM calendars c
+            JOIN users u ON u.id = c.owner_id
+            ORDER BY c.created_at DESC
+            "#
+        )
+        .fetch_all(&self.db_pool)
+        .await
+        .map_err(|e| e.to_string())?;
+        let result = result
+            .into_iter()
+            .map(|record| CalendarOverview {
+                calendar: Calendar {
+                    id: record.id,
+                    owner_id: record.owner_id,
+                    title: record.title,
+                    created_at: record.created_at,
+                    description: record.description,
+                    cover_image_url: record.cover_image_url,
+                    visibility: record.visibility,
+                },
+                owner_username: record.owner_username,
+                day_count: record.day_count,
+                subscriber_count: record.subscriber_count,
+            })
+            .collect();
+        Ok(result)
+    }
+
+    pub async fn update_calendar(
+        &self,
+        calendar: &Calendar,
+        details: &CalendarDetails,
+    ) -> Result<(), String> {
+        sqlx::query!(
+            "UPDATE calendars SET title = $1, description = $2, cover_image_url = $3 WHERE id = $4",
+            details.title,
+            details.description,
+            details.cover_image_url,
+            calendar.id
+        )
+        .execute(&self.db_pool)
         .await
         .map_err(|e| e.to_string())
+        .map(|_| {})
     }
 
-    pub async fn get_subscriptions(&self, user: &User) -> Result<Vec<UserCalendar>, String> {
+    /// Deletes a calendar with its days, and leaves `notice` on the dashboard of every subscriber
+    /// but the user who deleted it.
+    pub async fn delete_calendar(
+        &self,
+        calendar_id: i32,
+        deleted_by: &User,
+        notice: &str,
+    ) -> Result<(), String> {
+        let mut tx = self.db_pool.begin().await.map_err(|e| e.to_string())?;
+        sqlx::query!(
+            "INSERT INTO notices (user_id, message)
+            SELECT user_id, $1 FROM calendar_subscriptions WHERE calendar_id = $2 AND user_id <> $3",
+            notice,
+            calendar_id,
+            deleted_by.id
+        )
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| e.to_string())?;
+        let result = sqlx::query!("DELETE FROM calendars WHERE id = $1", calendar_id)
+            .execute(&mut *tx)
+            .await
+            .map_err(|e| e.to_string())?;
+
+        match result.rows_affected() {
+            0 => Err(format!("Calendar {} not found", calendar_id)),
+            _ => tx.commit().await.map_err(|e| e.to_string()),
+        }
+    }
+
+    /// The calendars a user is subscribed to, either those on their dashboard or those they
+    /// archived. Private calendars are left out unless they are a member.
+    pub async fn get_subscriptions(
+        &self,
+        user: &User,
+        archived: bool,
+    ) -> Result<Vec<UserCalendar>, String> {
         let result = sqlx::query!(
             r#"
-            SELECT calendars.id, calendars.title, calendars.created_at, calendars.owner_id, subscribed_at
+            SELECT calendars.id, calendars.title, calendars.created_at, calendars.owner_id,
+                calendars.description, calendars.cover_image_url,
+                calendars.visibility as "visibility: CalendarVisibility", subscribed_at, archived_at,
+                calendar_members.role as "role?: CalendarRole"
             FROM calendar_subscriptions
             JOIN calendars ON calendar_subscriptions.calendar_id = calendars.id
+            LEFT JOIN calendar_members ON calendar_members.calendar_id = calendars.id
+                AND calendar_members.user_id = $1
             WHERE calendar_subscriptions.user_id = $1
+                AND (calendar_subscriptions.archived_at IS NOT NULL) = $2
+                AND (calendar_members.user_id IS NOT NULL OR calendars.visibility <> 'private')
+            "#,
+            user.id,
+            archived
+        )
+        .fetch_all(&self.db_pool)
+        .await
+        .map_err(|e| e.to_string())?;
+        let result = result
+            .into_iter()
+            .map(|record| UserCalendar {
+                calendar: Calendar {
+                    id: record.id,
+                    owner_id: record.owner_id,
+                    title: record.title,
+                    created_at: record.created_at,
+                    description: record.description,
+                    cover_image_url: record.cover_image_url,
+                    visibility: record.visibility,
+                },
+                subscribed_at: record.subscribed_at,
+                archived_at: record.archived_at,
+                role: record.role,
+            })
+            .collect();
+        Ok(result)
+    }
+
+    /// The calendars a user is a member of, or is subscribed to while they are not private.
+    pub async fn get_user_calendars(&self, user: &User) -> Result<Vec<UserCalendar>, String> {
+        let result = sqlx::query!(
+            r#"
+            SELECT c.id, c.title, c.created_at, c.owner_id, c.description, c.cover_image_url,
+                c.visibility as "visibility: CalendarVisibility", cs.subscribed_at as "subscribed_at?",
+                cs.archived_at as "archived_at?", cm.role as "role?: CalendarRole"
+            FROM calendars c
+            LEFT JOIN calendar_subscriptions cs ON cs.calendar_id = c.id AND cs.user_id = $1
+            LEFT JOIN calendar_members cm ON cm.calendar_id = c.id AND cm.user_id = $1
+            WHERE cm.user_id IS NOT NULL OR (cs.user_id IS NOT NULL AND c.visibility <> 'private')
+            ORDER BY c.created_at
             "#,
             user.id
         )
-            .fetch_all(&self.db_pool)
-            .await
-            .map_err(|e| e.to_string())?;
+        .fetch_all(&self.db_pool)
+        .await
+        .map_err(|e| e.to_string())?;
         let result = result
             .into_iter()
             .map(|record| UserCalendar {
@@ -53,8 +206,13 @@ impl CalendarRepository {
                     owner_id: record.owner_id,
                     title: record.title,
                     created_at: record.created_at,
+                    description: record.description,
+                    cover_image_url: record.cover_image_url,
+                    visibility: record.visibility,
                 },
                 subscribed_at: record.subscribed_at,
+                archived_at: record.archived_at,
+                role: record.role,
             })
             .collect();
         Ok(result)
@@ -75,12 +233,71 @@ impl CalendarRepository {
         .map(|_| ())
     }
 
+    /// Removes the subscription of a user. With `purge_history`, the days they unlocked and the
+    /// day keys wrapped for them go too, otherwise subscribing again picks up where they left.
+    pub async fn unsubscribe(
+        &self,
+        user: &User,
+        calendar: &Calendar,
+        purge_history: bool,
+    ) -> Result<(), String> {
+        let mut tx = self.db_pool.begin().await.map_err(|e| e.to_string())?;
+        let result = sqlx::query!(
+            "DELETE FROM calendar_subscriptions WHERE user_id = $1 AND calendar_id = $2",
+            user.id,
+            calendar.id
+        )
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| e.to_string())?;
+        if result.rows_affected() == 0 {
+            return Err(format!(
+                "user {} is not subscribed to calendar {}",
+                user.username, calendar.title
+            ));
+        }
+
+        if purge_history {
+            sqlx::query!(
+                "DELETE FROM user_days
+                WHERE user_id = $1
+                    AND day_id IN (SELECT id FROM calendar_days WHERE calendar_id = $2)",
+                user.id,
+                calendar.id
+            )
+            .execute(&mut *tx)
+            .await
+            .map_err(|e| e.to_string())?;
+        }
+        tx.commit().await.map_err(|e| e.to_string())
+    }
+
+    pub async fn set_archived(
+        &self,
+        user: &User,
+        calendar: &Calendar,
+        archived: bool,
+    ) -> Result<(), String> {
+        sqlx::query!(
+            "UPDATE calendar_subscriptions
+            SET archived_at = CASE WHEN $3 THEN now() END
+            WHERE user_id = $1 AND calendar_id = $2",
+            user.id,
+            calendar.id,
+            archived
+        )
+        .execute(&self.db_pool)
+        .await
+        .map_err(|e| e.to_string())
+        .map(|_| {})
+    }
+
     pub async fn get_calendar_user_days(
         &self,
         user_calendar: &UserCalendar,
         user: &User,
     ) -> Result<Vec<UserDay>, String> {
-        self.get_user_days_without_key(&vec![user_calendar.calendar.id], user)
+        self.get_user_days_without_key(&[user_calendar.calendar.id], user)
             .await
     }
 
@@ -113,8 +330,7 @@ impl CalendarRepository {
                     calendar_id: record.calendar_id,
                     protected: record.protected,
                 };
-                let user_day = UserDay::new(day, record.unlocked_at, KeyHandler::empty());
-                user_day
+                UserDay::new(day, record.unlocked_at, KeyHandler::empty())
             })
             .collect();
 
@@ -129,7 +345,8 @@ impl CalendarRepository {
     ) -> Result<UserDay, String> {
         let record = sqlx::query!(
             r#"
-            SELECT unlocked_at, unlocks_at, cd.calendar_id, cd.id as day_id, protected, day_key_salt, day_key_encr
+            SELECT unlocked_at, unlocks_at, cd.calendar_id, cd.id as day_id, protected, day_key_encr,
+                day_key_sealed
             FROM calendar_days as cd
             LEFT JOIN (SELECT * FROM user_days WHERE user_id = $2) as ud ON cd.id = ud.day_id
             WHERE cd.id = $1 AND cd.calendar_id = $3
@@ -138,20 +355,42 @@ impl CalendarRepository {
             user.id,
             user_calendar.calendar.id
         )
-            .fetch_one(&self.db_pool)
-            .await
-            .map_err(|e| format!("Calendar day {} not found: {}", day_id, e.to_string()))?;
+        .fetch_one(&self.db_pool)
+        .await
+        .map_err(|e| format!("Calendar day {} not found: {}", day_id, e))?;
 
         let day_key = if record.protected && record.unlocked_at.is_some() {
-            let key = user.content_key_handler.decrypt(
-                &record
-                    .day_key_encr
-                    .ok_or(format!("Calendar day {} cypher not found", day_id))?,
-                &record
-                    .day_key_salt
-                    .ok_or(format!("Calendar day {} salt not found", day_id))?,
-            );
-            Some(key?)
+            let day_key_data = AssociatedData::day_key(user.id, day_id);
+            let (key, resealed) = match (record.day_key_encr, record.day_key_sealed) {
+                (Some(cypher), _) => user
+                    .content_key_handler
+                    .decrypt_and_reseal(&cypher, &day_key_data)?,
+                // Sealed to them by another editor, it is wrapped with their content key from
+                // now on
+                (None, Some(sealed)) => {
+                    let key = self
+                        .get_key_pair(user)
+                        .await?
+                        .ok_or("There is no key pair to open the day keys sealed to this account")?
+                        .open(&sealed, &AssociatedData::sealed_day_key(user.id, day_id))?;
+                    let cypher = user.content_key_handler.encrypt(&key, &day_key_data)?;
+                    (key, Some(cypher))
+                }
+                (None, None) => return Err(format!("Calendar day {} cypher not found", day_id)),
+            };
+            if let Some(resealed) = resealed {
+                sqlx::query!(
+                    "UPDATE user_days SET day_key_encr = $1, day_key_sealed = NULL
+                    WHERE user_id = $2 AND day_id = $3",
+                    resealed,
+                    user.id,
+                    day_id
+                )
+                .execute(&self.db_pool)
+                .await
+                .map_err(|e| e.to_string())?;
+            }
+            Some(key)
         } else {
             None
         };
@@ -170,6 +409,10 @@ impl CalendarRepository {
         Ok(user_day)
     }
 
+    /// The calendar with the subscription and the role of the user, if they may open it: they
+    /// are a member, are subscribed to it and it is not private, it is public or they are an
+    /// administrator. Otherwise it is reported as missing, so that ids can't be probed. A
+    /// subscription survives the calendar going private, and counts again once it is shared.
     pub async fn get_user_calendar(
         &self,
         cal_id: i32,
@@ -177,13 +420,21 @@ impl CalendarRepository {
     ) -> Result<UserCalendar, String> {
         let record = sqlx::query!(
             r#"
-            SELECT subscribed_at, owner_id,created_at,title
+            SELECT subscribed_at, archived_at, owner_id, created_at, title, description,
+                cover_image_url, visibility as "visibility: CalendarVisibility",
+                cm.role as "role?: CalendarRole"
             FROM calendars as c
             LEFT JOIN (SELECT * FROM calendar_subscriptions WHERE user_id = $2) as ud ON c.id = ud.calendar_id
+            LEFT JOIN calendar_members cm ON cm.calendar_id = c.id AND cm.user_id = $2
             WHERE c.id = $1
+                AND (cm.user_id IS NOT NULL
+                    OR (ud.user_id IS NOT NULL AND c.visibility <> 'private')
+                    OR c.visibility = 'public'
+                    OR $3)
             "#,
             cal_id,
-            user.id
+            user.id,
+            user.is_admin()
         )
             .fetch_one(&self.db_pool)
             .await;
@@ -194,19 +445,431 @@ impl CalendarRepository {
 
         let user_cal = UserCalendar {
             subscribed_at: record.subscribed_at,
+            archived_at: record.archived_at,
+            role: record.role,
             calendar: Calendar {
                 id: cal_id,
                 owner_id: record.owner_id,
                 title: record.title,
                 created_at: record.created_at,
+                description: record.description,
+                cover_image_url: record.cover_image_url,
+                visibility: record.visibility,
             },
         };
         Ok(user_cal)
     }
 
+    /// The calendar whose share link has the token hashing to `token_hash`, as long as it is
+    /// not private.
+    pub async fn get_shared_calendar(
+        &self,
+        token_hash: &str,
+        user: &User,
+    ) -> Result<UserCalendar, String> {
+        let record = sqlx::query!(
+            r#"
+            SELECT c.id, subscribed_at, archived_at, owner_id, created_at, title, description,
+                cover_image_url, visibility as "visibility: CalendarVisibility",
+                cm.role as "role?: CalendarRole"
+            FROM calendars as c
+            LEFT JOIN (SELECT * FROM calendar_subscriptions WHERE user_id = $2) as ud ON c.id = ud.calendar_id
+            LEFT JOIN calendar_members cm ON cm.calendar_id = c.id AND cm.user_id = $2
+            WHERE c.share_token_hash = $1 AND c.visibility <> 'private'
+            "#,
+            token_hash,
+            user.id
+        )
+        .fetch_optional(&self.db_pool)
+        .await
+        .map_err(|e| e.to_string())?
+        .ok_or("This share link does not exist or was revoked")?;
+
+        Ok(UserCalendar {
+            subscribed_at: record.subscribed_at,
+            archived_at: record.archived_at,
+            role: record.role,
+            calendar: Calendar {
+                id: record.id,
+                owner_id: record.owner_id,
+                title: record.title,
+                created_at: record.created_at,
+                description: record.description,
+                cover_image_url: record.cover_image_url,
+                visibility: record.visibility,
+            },
+        })
+    }
+
+    /// Changes who can open a calendar. Making it private also revokes its share link.
+    pub async fn set_visibility(
+        &self,
+        calendar: &Calendar,
+        visibility: CalendarVisibility,
+    ) -> Result<(), String> {
+        sqlx::query!(
+            "UPDATE calendars
+            SET visibility = $1,
+                share_token_hash = CASE WHEN $1::calendar_visibility = 'private' THEN NULL ELSE share_token_hash END
+            WHERE id = $2",
+            visibility as CalendarVisibility,
+            calendar.id
+        )
+        .execute(&self.db_pool)
+        .await
+        .map_err(|e| e.to_string())
+        .map(|_| {})
+    }
+
+    /// Replaces the share link of a calendar, or removes it when `token_hash` is `None`.
+    pub async fn set_share_token(
+        &self,
+        calendar: &Calendar,
+        token_hash: Option<&str>,
+    ) -> Result<(), String> {
+        sqlx::query!(
+            "UPDATE calendars SET share_token_hash = $1 WHERE id = $2",
+            token_hash,
+            calendar.id
+        )
+        .execute(&self.db_pool)
+        .await
+        .map_err(|e| e.to_string())
+        .map(|_| {})
+    }
+
+    pub async fn has_share_link(&self, calendar: &Calendar) -> Result<bool, String> {
+        sqlx::query_scalar!(
+            r#"SELECT share_token_hash IS NOT NULL as "has_share_link!" FROM calendars WHERE id = $1"#,
+            calendar.id
+        )
+        .fetch_one(&self.db_pool)
+        .await
+        .map_err(|e| e.to_string())
+    }
+
+    /// Unwraps the private key of the user, which opens the day keys sealed to them, if they
+    /// have one yet.
+    async fn get_key_pair(&self, user: &User) -> Result<Option<KeyPair>, String> {
+        let private_key_encr =
+            sqlx::query_scalar!("SELECT private_key_encr FROM users WHERE id = $1", user.id)
+                .fetch_one(&self.db_pool)
+                .await
+                .map_err(|e| e.to_string())?;
+        let Some(private_key_encr) = private_key_encr else {
+            return Ok(None);
+        };
+        let private_key = user
+            .content_key_handler
+            .decrypt(&private_key_encr, &AssociatedData::private_key(user.id))?;
+        KeyPair::from_private_key(&private_key).map(Some)
+    }
+
+    pub async fn get_members(&self, calendar: &Calendar) -> Result<Vec<CalendarMember>, String> {
+        sqlx::query_as!(
+            CalendarMember,
+            r#"
+            SELECT cm.user_id, u.username, cm.role as "role: CalendarRole", cm.added_at
+            FROM calendar_members cm
+            JOIN users u ON u.id = cm.user_id
+            WHERE cm.calendar_id = $1
+            ORDER BY cm.added_at
+            "#,
+            calendar.id
+        )
+        .fetch_all(&self.db_pool)
+        .await
+        .map_err(|e| e.to_string())
+    }
+
+    /// Makes the user named `username` a member of the calendar and subscribes them to it.
+    /// Returns how many protected days they could not be given, see `grant_day_keys`.
+    pub async fn add_member(
+        &self,
+        calendar: &Calendar,
+        granted_by: &User,
+        username: &str,
+        role: CalendarRole,
+    ) -> Result<usize, String> {
+        let mut tx = self.db_pool.begin().await.map_err(|e| e.to_string())?;
+        // Keeps their key pair from being rotated until the day keys sealed to it are in
+        let member = sqlx::query!(
+            "SELECT id, public_key FROM users WHERE username = $1 FOR SHARE",
+            username
+        )
+        .fetch_optional(&mut *tx)
+        .await
+        .map_err(|e| e.to_string())?
+        .ok_or(format!("There is no member named {}", username))?;
+
+        let result = sqlx::query!(
+            "INSERT INTO calendar_members (calendar_id, user_id, role) VALUES ($1, $2, $3)
+            ON CONFLICT DO NOTHING",
+            calendar.id,
+            member.id,
+            role as CalendarRole
+        )
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| e.to_string())?;
+        if result.rows_affected() == 0 {
+            return Err(format!("{} is already a member of this calendar", username));
+        }
+        sqlx::query!(
+            "INSERT INTO calendar_subscriptions (user_id, calendar_id) VALUES ($1, $2)
+            ON CONFLICT DO NOTHING",
+            member.id,
+            calendar.id
+        )
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| e.to_string())?;
+
+        let missing = match role.holds_day_keys() {
+            true => {
+                self.grant_day_keys(
+                    &mut tx,
+                    calendar,
+                    granted_by,
+                    member.id,
+                    member.public_key.as_deref(),
+                )
+                .await?
+            }
+            false => 0,
+        };
+        tx.commit().await.map_err(|e| e.to_string())?;
+        Ok(missing)
+    }
+
+    /// Changes the role of a member, handing them the day keys when they start editing days and
+    /// taking back those they haven't opened yet when they stop. Returns how many protected
+    /// days they could not be given, see `grant_day_keys`.
+    pub async fn set_member_role(
+        &self,
+        calendar: &Calendar,
+        granted_by: &User,
+        member_id: i32,
+        role: CalendarRole,
+    ) -> Result<usize, String> {
+        let mut tx = self.db_pool.begin().await.map_err(|e| e.to_string())?;
+        let member = sqlx::query!(
+            r#"
+            SELECT cm.role as "role: CalendarRole", u.public_key
+            FROM calendar_members cm
+            JOIN users u ON u.id = cm.user_id
+            WHERE cm.calendar_id = $1 AND cm.user_id = $2
+            FOR UPDATE OF cm FOR SHARE OF u
+            "#,
+            calendar.id,
+            member_id
+        )
+        .fetch_optional(&mut *tx)
+        .await
+        .map_err(|e| e.to_string())?
+        .ok_or("This user is not a member of the calendar")?;
+        sqlx::query!(
+            "UPDATE calendar_members SET role = $3 WHERE calendar_id = $1 AND user_id = $2",
+            calendar.id,
+            member_id,
+            role as CalendarRole
+        )
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| e.to_string())?;
+
+        let missing = match (member.role.holds_day_keys(), role.holds_day_keys()) {
+            (false, true) => {
+                self.grant_day_keys(
+                    &mut tx,
+                    calendar,
+                    granted_by,
+                    member_id,
+                    member.public_key.as_deref(),
+                )
+                .await?
+            }
+            (true, false) => {
+                Self::drop_sealed_day_keys(&mut tx, calendar, member_id).await?;
+                0
+            }
+            _ => 0,
+        };
+        tx.commit().await.map_err(|e| e.to_string())?;
+        Ok(missing)
+    }
+
+    /// Removes a member along with their subscription. The days they already opened stay in
+    /// their history, like when unsubscribing.
+    pub async fn remove_member(&self, calendar: &Calendar, member_id: i32) -> Result<(), String> {
+        let mut tx = self.db_pool.begin().await.map_err(|e| e.to_string())?;
+        let result = sqlx::query!(
+            "DELETE FROM calendar_members WHERE calendar_id = $1 AND user_id = $2",
+            calendar.id,
+            member_id
+        )
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| e.to_string())?;
+        if result.rows_affected() == 0 {
+            return Err("This user is not a member of the calendar".to_owned());
+        }
+        sqlx::query!(
+            "DELETE FROM calendar_subscriptions WHERE calendar_id = $1 AND user_id = $2",
+            calendar.id,
+            member_id
+        )
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| e.to_string())?;
+        Self::drop_sealed_day_keys(&mut tx, calendar, member_id).await?;
+        tx.commit().await.map_err(|e| e.to_string())
+    }
+
+    /// Gives a member every day of the calendar as unlocked, with the keys of the protected
+    /// ones sealed to their public key. The keys come from those `granted_by` holds, so the
+    /// protected days they would have to unlock with a code themselves are skipped, and their
+    /// count is returned.
+    async fn grant_day_keys(
+        &self,
+        conn: &mut PgConnection,
+        calendar: &Calendar,
+        granted_by: &User,
+        member_id: i32,
+        public_key: Option<&[u8]>,
+    ) -> Result<usize, String> {
+        let public_key = public_key
+            .ok_or("They have to log in once before they can edit the days of this calendar")?;
+        let days = sqlx::query!(
+            "SELECT cd.id, cd.protected, ud.day_key_encr as \"day_key_encr?\",
+                ud.day_key_sealed as \"day_key_sealed?\"
+            FROM calendar_days cd
+            LEFT JOIN user_days ud ON ud.day_id = cd.id AND ud.user_id = $2
+            WHERE cd.calendar_id = $1",
+            calendar.id,
+            granted_by.id
+        )
+        .fetch_all(&mut *conn)
+        .await
+        .map_err(|e| e.to_string())?;
+        let key_pair = self.get_key_pair(granted_by).await?;
+
+        let mut missing = 0;
+        for day in days {
+            let sealed = if day.protected {
+                let day_key = match (day.day_key_encr, day.day_key_sealed) {
+                    (Some(cypher), _) => granted_by
+                        .content_key_handler
+                        .decrypt(&cypher, &AssociatedData::day_key(granted_by.id, day.id))?,
+                    (None, Some(sealed)) => key_pair
+                        .as_ref()
+                        .ok_or("There is no key pair to open the day keys sealed to this account")?
+                        .open(
+                            &sealed,
+                            &AssociatedData::sealed_day_key(granted_by.id, day.id),
+                        )?,
+                    (None, None) => {
+                        missing += 1;
+                        continue;
+                    }
+                };
+                Some(KeyHandler::from_key(day_key).get_sealed_key(
+                    public_key,
+                    &AssociatedData::sealed_day_key(member_id, day.id),
+                )?)
+            } else {
+                None
+            };
+            // Keys they already unlocked with a code are kept as they are
+            sqlx::query!(
+                "INSERT INTO user_days (user_id, day_id, day_key_sealed) VALUES ($1, $2, $3)
+                ON CONFLICT (user_id, day_id) DO UPDATE SET day_key_sealed = excluded.day_key_sealed
+                WHERE user_days.day_key_encr IS NULL",
+                member_id,
+                day.id,
+                sealed
+            )
+            .execute(&mut *conn)
+            .await
+            .map_err(|e| e.to_string())?;
+        }
+        Ok(missing)
+    }
+
+    /// Takes back the day keys sealed to a member which they haven't opened yet.
+    async fn drop_sealed_day_keys(
+        conn: &mut PgConnection,
+        calendar: &Calendar,
+        member_id: i32,
+    ) -> Result<(), String> {
+        sqlx::query!(
+            "DELETE FROM user_days
+            WHERE user_id = $1 AND day_key_sealed IS NOT NULL
+                AND day_id IN (SELECT id FROM calendar_days WHERE calendar_id = $2)",
+            member_id,
+            calendar.id
+        )
+        .execute(conn)
+        .await
+        .map_err(|e| e.to_string())
+        .map(|_| {})
+    }
+
+    /// Gives the other members who edit the days of the calendar access to one of them, with its
+    /// key sealed to them if it is protected. Those without a key pair yet have to unlock it with
+    /// its code.
+    async fn share_day_with_editors(
+        conn: &mut PgConnection,
+        calendar_id: i32,
+        day_id: i32,
+        author: &User,
+        day_key: Option<&KeyHandler>,
+    ) -> Result<(), String> {
+        let members = sqlx::query!(
+            r#"
+            SELECT cm.user_id, cm.role as "role: CalendarRole", u.public_key
+            FROM calendar_members cm
+            JOIN users u ON u.id = cm.user_id
+            WHERE cm.calendar_id = $1 AND cm.user_id <> $2
+            FOR SHARE OF u
+            "#,
+            calendar_id,
+            author.id
+        )
+        .fetch_all(&mut *conn)
+        .await
+        .map_err(|e| e.to_string())?;
+
+        for member in members
+            .into_iter()
+            .filter(|member| member.role.holds_day_keys())
+        {
+            let sealed = match (day_key, member.public_key) {
+                (Some(day_key), Some(public_key)) => Some(day_key.get_sealed_key(
+                    &public_key,
+                    &AssociatedData::sealed_day_key(member.user_id, day_id),
+                )?),
+                (Some(_), None) => continue,
+                (None, _) => None,
+            };
+            sqlx::query!(
+                "INSERT INTO user_days (user_id, day_id, day_key_sealed) VALUES ($1, $2, $3)
+                ON CONFLICT (user_id, day_id)
+                    DO UPDATE SET day_key_encr = NULL, day_key_sealed = excluded.day_key_sealed",
+                member.user_id,
+                day_id,
+                sealed
+            )
+            .execute(&mut *conn)
+            .await
+            .map_err(|e| e.to_string())?;
+        }
+        Ok(())
+    }
+
     pub async fn get_content(&self, user_day: &UserDay) -> Result<String, String> {
         let record = sqlx::query!(
-            "SELECT decryption_key_salt, content_salt, content, decryption_key_encr
+            "SELECT content, decryption_key_encr
             FROM day_content
             where day_content.day_id = $1
             ",
@@ -218,20 +881,31 @@ impl CalendarRepository {
         .ok_or(format!("There is no content for day {}", user_day.day.id))?;
 
         let content = if user_day.day.protected {
-            let decr_key_salt = record
-                .decryption_key_salt
-                .ok_or("The content is protected but there is no decryption key salt")?;
             let decr_key_encr = record
                 .decryption_key_encr
                 .ok_or("The content is protected but there is no decryption key cypher")?;
-            let content_salt = record
-                .content_salt
-                .ok_or("The content is protected but there is no content salt")?;
-            let decryption_key = user_day
+            let day_id = user_day.day.id;
+            let (decryption_key, resealed_key) = user_day
                 .day_key_handler
-                .decrypt(&decr_key_encr, &decr_key_salt)?;
+                .decrypt_and_reseal(&decr_key_encr, &AssociatedData::decryption_key(day_id))?;
             let decryption_key = KeyHandler::from_key(decryption_key);
-            decryption_key.decrypt(&record.content, &content_salt)?
+            let (content, resealed_content) = decryption_key
+                .decrypt_and_reseal(&record.content, &AssociatedData::day_content(day_id))?;
+            if resealed_key.is_some() || resealed_content.is_some() {
+                sqlx::query!(
+                    "UPDATE day_content
+                        SET decryption_key_encr = coalesce($1, decryption_key_encr),
+                            content = coalesce($2, content)
+                        WHERE day_id = $3",
+                    resealed_key,
+                    resealed_content,
+                    day_id
+                )
+                .execute(&self.db_pool)
+                .await
+                .map_err(|e| e.to_string())?;
+            }
+            content.to_vec()
         } else {
             record.content
         };
@@ -242,7 +916,7 @@ impl CalendarRepository {
     }
 
     pub async fn get_dashboard_data(&self, user: &User) -> Result<Vec<RichUserCalendar>, String> {
-        let calendars = self.get_subscriptions(user).await?;
+        let calendars = self.get_subscriptions(user, false).await?;
         let calendar_ids = calendars
             .iter()
             .map(|user_calendar| user_calendar.calendar.id)
@@ -277,72 +951,87 @@ impl CalendarRepository {
         password: Option<String>,
         content: String,
     ) -> Result<(), String> {
-        let tx = self.db_pool.begin().await.map_err(|e| e.to_string())?;
+        // The envelopes are bound to the day, so its id is reserved before encrypting anything
+        let id = sqlx::query_scalar!(
+            r#"select nextval(pg_get_serial_sequence('calendar_days', 'id'))::int as "id!""#
+        )
+        .fetch_one(&self.db_pool)
+        .await
+        .map_err(|e| e.to_string())?;
+        let protected = password.is_some();
 
-        let protected;
-        let day_salt_opt;
+        let day_key_opt;
         let day_cypher_opt;
-        let dec_salt_opt;
         let dec_cypher_opt;
-        let content_salt_opt;
         let content_bytes;
         if let Some(password) = password {
-            let day_key_handler = KeyHandler::from_pass(&password, "day key");
-            let day_salt: [u8; 12] = random();
-            let day_cypher =
-                day_key_handler.get_encrypted_key(&user.content_key_handler, &day_salt)?;
-            let dec_salt: [u8; 12] = random();
+            let day_key_handler = self
+                .argon2_pool
+                .derive(password.as_bytes(), self.argon2_pool.kdf())
+                .await?;
+            let day_cypher = day_key_handler.get_encrypted_key(
+                &user.content_key_handler,
+                &AssociatedData::day_key(user.id, id),
+            )?;
             let dec_key_handler = KeyHandler::from_random(32);
-            let dec_cypher = dec_key_handler.get_encrypted_key(&day_key_handler, &dec_salt)?;
-            let content_salt: [u8; 12] = random();
-            let content = dec_key_handler.encrypt(&content.as_bytes(), &content_salt)?;
-
-            protected = true;
-            day_salt_opt = Some(day_salt.to_vec());
-            dec_salt_opt = Some(dec_salt.to_vec());
-            content_salt_opt = Some(content_salt.to_vec());
+            let dec_cypher = dec_key_handler
+                .get_encrypted_key(&day_key_handler, &AssociatedData::decryption_key(id))?;
+            let content =
+                dec_key_handler.encrypt(content.as_bytes(), &AssociatedData::day_content(id))?;
+
+            day_key_opt = Some(day_key_handler);
             day_cypher_opt = Some(day_cypher);
             dec_cypher_opt = Some(dec_cypher);
             content_bytes = content;
         } else {
-            day_salt_opt = None;
-            dec_salt_opt = None;
+            day_key_opt = None;
             day_cypher_opt = None;
             dec_cypher_opt = None;
-            content_salt_opt = None;
             content_bytes = content.as_bytes().to_vec();
-            protected = false;
         }
 
-        let id: i32 = sqlx::query!(
-            "INSERT INTO calendar_days (calendar_id, unlocks_at, protected)
-                VALUES ($1, $2, $3)
-                RETURNING id",
+        let mut tx = self.db_pool.begin().await.map_err(|e| e.to_string())?;
+        sqlx::query!(
+            "INSERT INTO calendar_days (id, calendar_id, unlocks_at, protected)
+                VALUES ($1, $2, $3, $4)",
+            id,
             user_calendar.calendar.id,
             unlocks_at,
             protected
         )
-        .fetch_one(&self.db_pool)
+        .execute(&mut *tx)
         .await
-        .map_err(|e| e.to_string())?
-        .id;
+        .map_err(|e| e.to_string())?;
 
         sqlx::query!(
-            "INSERT INTO day_content (decryption_key_salt, decryption_key_encr, content_salt, content, day_id)
-            VALUES ($1, $2, $3, $4, $5)", dec_salt_opt, dec_cypher_opt, content_salt_opt, content_bytes, id
-        ).execute(&self.db_pool).await.map_err(|e| e.to_string())?;
+            "INSERT INTO day_content (decryption_key_encr, content, day_id)
+            VALUES ($1, $2, $3)",
+            dec_cypher_opt,
+            content_bytes,
+            id
+        )
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| e.to_string())?;
 
         sqlx::query!(
-            "INSERT INTO user_days (user_id, day_id, day_key_salt,day_key_encr)
-            VALUES ($1, $2, $3, $4)",
+            "INSERT INTO user_days (user_id, day_id, day_key_encr)
+            VALUES ($1, $2, $3)",
             user.id,
             id,
-            day_salt_opt,
             day_cypher_opt
         )
-        .execute(&self.db_pool)
+        .execute(&mut *tx)
         .await
         .map_err(|e| e.to_string())?;
+        Self::share_day_with_editors(
+            &mut tx,
+            user_calendar.calendar.id,
+            id,
+            user,
+            day_key_opt.as_ref(),
+        )
+        .await?;
 
         tx.commit().await.map_err(|e| e.to_string())?;
 
@@ -356,40 +1045,33 @@ impl CalendarRepository {
         code: Option<String>,
     ) -> Result<(), String> {
         let record = sqlx::query!(
-            "SELECT decryption_key_encr, decryption_key_salt
+            "SELECT decryption_key_encr
                 FROM day_content
                 where day_id = $1",
             user_day.day.id
         )
         .fetch_one(&self.db_pool)
         .await
-        .map_err(|e| {
-            format!(
-                "This day does not exist or it can't be unlocked yet: {}",
-                e.to_string()
-            )
-        })?;
+        .map_err(|e| format!("This day does not exist or it can't be unlocked yet: {}", e))?;
 
-        let (dks, dke) = if let (Some(dke), Some(dks)) =
-            (record.decryption_key_encr, record.decryption_key_salt)
-        {
+        let dke = if let Some(dke) = record.decryption_key_encr {
             let code = code.ok_or(String::from("A code is required for this day"))?;
-            let day_key = KeyHandler::from_pass(&code, "day key");
-            day_key.decrypt(&dke, &dks)?;
-            let day_key_salt: [u8; 12] = random();
-            let day_key_encr =
-                day_key.get_encrypted_key(&user.content_key_handler, &day_key_salt)?;
-            (Some(day_key_salt.to_vec()), Some(day_key_encr))
+            let day_key = self.argon2_pool.for_envelope(code.as_bytes(), &dke).await?;
+            let day_id = user_day.day.id;
+            day_key.decrypt(&dke, &AssociatedData::decryption_key(day_id))?;
+            Some(day_key.get_encrypted_key(
+                &user.content_key_handler,
+                &AssociatedData::day_key(user.id, day_id),
+            )?)
         } else {
-            (None, None)
+            None
         };
 
         sqlx::query!(
-            "INSERT INTO user_days (user_id, day_id, day_key_salt, day_key_encr) 
-            VALUES ($1, $2, $3, $4)",
+            "INSERT INTO user_days (user_id, day_id, day_key_encr) 
+            VALUES ($1, $2, $3)",
             user.id,
             user_day.day.id,
-            dks,
             dke
         )
         .execute(&self.db_pool)
@@ -398,6 +1080,25 @@ impl CalendarRepository {
         .map(|_| ())
     }
 
+    /// Whether the key of a protected day is still derived from its password with HKDF.
+    pub async fn has_legacy_day_key(&self, user_day: &UserDay) -> Result<bool, String> {
+        if !user_day.day.protected {
+            return Ok(false);
+        }
+        let record = sqlx::query!(
+            "SELECT decryption_key_encr FROM day_content WHERE day_id = $1",
+            user_day.day.id
+        )
+        .fetch_one(&self.db_pool)
+        .await
+        .map_err(|e| format!("There is no content for day {}: {}", user_day.day.id, e))?;
+        let decr_key_encr = record
+            .decryption_key_encr
+            .ok_or("The content is protected but there is no decryption key cypher")?;
+        let envelope = Envelope::from_bytes(&decr_key_encr)?;
+        Ok(matches!(envelope.kdf, Kdf::HkdfSha256 { .. }))
+    }
+
     pub async fn delete_day(&self, user_day: &UserDay) -> Result<(), String> {
         sqlx::query!(
             "DELETE FROM public.calendar_days WHERE id = $1",
@@ -410,45 +1111,33 @@ impl CalendarRepository {
     }
 
     pub async fn edit_content(&self, user_day: &UserDay, content: String) -> Result<(), String> {
-        let (salt, content) = if user_day.day.protected {
+        let content = if user_day.day.protected {
             let record = sqlx::query!(
-                "SELECT decryption_key_encr, decryption_key_salt
+                "SELECT decryption_key_encr
             FROM day_content
             WHERE day_id = $1",
                 user_day.day.id
             )
             .fetch_one(&self.db_pool)
             .await
-            .map_err(|e| {
-                format!(
-                    "There is no content for day {}: {}",
-                    user_day.day.id,
-                    e.to_string()
-                )
-            })?;
+            .map_err(|e| format!("There is no content for day {}: {}", user_day.day.id, e))?;
 
-            let decr_key_salt = record
-                .decryption_key_salt
-                .ok_or("The content is protected but there is no decryption key salt")?;
             let decr_key_encr = record
                 .decryption_key_encr
                 .ok_or("The content is protected but there is no decryption key cypher")?;
+            let day_id = user_day.day.id;
             let decryption_key = user_day
                 .day_key_handler
-                .decrypt(&decr_key_encr, &decr_key_salt)?;
+                .decrypt(&decr_key_encr, &AssociatedData::decryption_key(day_id))?;
             let decryption_key = KeyHandler::from_key(decryption_key);
-            let content_salt: [u8; 12] = random();
-            let content = decryption_key.encrypt(&content.as_bytes(), &content_salt)?;
-
-            (Some(content_salt.to_vec()), content)
+            decryption_key.encrypt(content.as_bytes(), &AssociatedData::day_content(day_id))?
         } else {
-            (None, content.as_bytes().to_vec())
+            content.as_bytes().to_vec()
         };
 
         sqlx::query!(
-            "update day_content set content = $1, content_salt = $2 where day_id = $3",
+            "update day_content set content = $1 where day_id = $2",
             content,
-            salt,
             user_day.day.id
         )
         .execute(&self.db_pool)
@@ -463,64 +1152,57 @@ impl CalendarRepository {
         user: &User,
         password: &str,
     ) -> Result<(), String> {
-        let new_day_key = KeyHandler::from_pass(&password, "day key");
-        let new_day_salt: [u8; 12] = random();
-        let new_day_cypher =
-            new_day_key.get_encrypted_key(&user.content_key_handler, &new_day_salt)?;
+        let new_day_key = self
+            .argon2_pool
+            .derive(password.as_bytes(), self.argon2_pool.kdf())
+            .await?;
+        let new_day_cypher = new_day_key.get_encrypted_key(
+            &user.content_key_handler,
+            &AssociatedData::day_key(user.id, user_day.day.id),
+        )?;
 
+        let mut tx = self.db_pool.begin().await.map_err(|e| e.to_string())?;
         let record = sqlx::query!(
-            "SELECT decryption_key_encr, decryption_key_salt
+            "SELECT decryption_key_encr
             FROM day_content
-            WHERE day_id = $1",
+            WHERE day_id = $1
+            FOR UPDATE",
             user_day.day.id
         )
-        .fetch_one(&self.db_pool)
+        .fetch_one(&mut *tx)
         .await
-        .map_err(|e| {
-            format!(
-                "There is no content for day {}: {}",
-                user_day.day.id,
-                e.to_string()
-            )
-        })?;
+        .map_err(|e| format!("There is no content for day {}: {}", user_day.day.id, e))?;
 
-        let decr_key_salt = record
-            .decryption_key_salt
-            .ok_or("The content is protected but there is no decryption key salt")?;
         let decr_key_cypher = record
             .decryption_key_encr
             .ok_or("The content is protected but there is no decryption key cypher")?;
+        let decryption_key_data = AssociatedData::decryption_key(user_day.day.id);
         let dec_key = user_day
             .day_key_handler
-            .decrypt(&decr_key_cypher, &decr_key_salt)?;
+            .decrypt(&decr_key_cypher, &decryption_key_data)?;
         let dec_key = KeyHandler::from_key(dec_key);
-        let decr_key_salt: [u8; 12] = random();
-        let decr_key_cypher = dec_key.get_encrypted_key(&new_day_key, &decr_key_salt)?;
-
-        let tx = self.db_pool.begin().await.map_err(|e| e.to_string())?;
+        let decr_key_cypher = dec_key.get_encrypted_key(&new_day_key, &decryption_key_data)?;
 
         sqlx::query!(
             "update day_content 
-                        set decryption_key_encr = $1, decryption_key_salt = $2 
-                        where day_id = $3",
+                        set decryption_key_encr = $1
+                        where day_id = $2",
             decr_key_cypher,
-            decr_key_salt.to_vec(),
             user_day.day.id
         )
-        .execute(&self.db_pool)
+        .execute(&mut *tx)
         .await
         .map_err(|e| e.to_string())?;
 
         sqlx::query!(
             "update user_days
-                        set day_key_encr = $1, day_key_salt = $2
-                        where day_id = $3 and user_id = $4",
+                        set day_key_encr = $1
+                        where day_id = $2 and user_id = $3",
             new_day_cypher,
-            new_day_salt.to_vec(),
             user_day.day.id,
             user.id
         )
-        .execute(&self.db_pool)
+        .execute(&mut *tx)
         .await
         .map_err(|e| e.to_string())?;
 
@@ -530,9 +1212,17 @@ impl CalendarRepository {
             user_day.day.id,
             user.id
         )
-        .execute(&self.db_pool)
+        .execute(&mut *tx)
         .await
         .map_err(|e| e.to_string())?;
+        Self::share_day_with_editors(
+            &mut tx,
+            user_day.day.calendar_id,
+            user_day.day.id,
+            user,
+            Some(&new_day_key),
+        )
+        .await?;
 
         tx.commit().await.map_err(|e| e.to_string())?;
 
@@ -545,56 +1235,54 @@ impl CalendarRepository {
         user: &User,
         password: &str,
     ) -> Result<(), String> {
+        let new_day_key = self
+            .argon2_pool
+            .derive(password.as_bytes(), self.argon2_pool.kdf())
+            .await?;
+        let mut tx = self.db_pool.begin().await.map_err(|e| e.to_string())?;
         let record = sqlx::query!(
-            "SELECT content from day_content where day_id = $1",
+            "SELECT content from day_content where day_id = $1 FOR UPDATE",
             user_day.day.id
         )
-        .fetch_one(&self.db_pool)
+        .fetch_one(&mut *tx)
         .await
-        .map_err(|e| {
-            format!(
-                "There is no content for day {}: {}",
-                user_day.day.id,
-                e.to_string()
-            )
-        })?;
+        .map_err(|e| format!("There is no content for day {}: {}", user_day.day.id, e))?;
         let content = String::from_utf8(record.content).map_err(|e| e.to_string())?;
-        let new_day_key = KeyHandler::from_pass(&password, "day key");
-        let new_day_salt: [u8; 12] = random();
-        let new_day_cypher =
-            new_day_key.get_encrypted_key(&user.content_key_handler, &new_day_salt)?;
+        let new_day_cypher = new_day_key.get_encrypted_key(
+            &user.content_key_handler,
+            &AssociatedData::day_key(user.id, user_day.day.id),
+        )?;
         let dec_key = KeyHandler::from_random(32);
-        let decr_key_salt: [u8; 12] = random();
-        let decr_key_cypher = dec_key.get_encrypted_key(&new_day_key, &decr_key_salt)?;
-        let content_salt: [u8; 12] = random();
-        let content_cypher = dec_key.encrypt(&content.as_bytes(), &content_salt)?;
-
-        let tx = self.db_pool.begin().await.map_err(|e| e.to_string())?;
+        let decr_key_cypher = dec_key.get_encrypted_key(
+            &new_day_key,
+            &AssociatedData::decryption_key(user_day.day.id),
+        )?;
+        let content_cypher = dec_key.encrypt(
+            content.as_bytes(),
+            &AssociatedData::day_content(user_day.day.id),
+        )?;
 
         sqlx::query!(
             "update day_content 
-                        set decryption_key_encr = $1, decryption_key_salt = $2, content = $3, content_salt = $4 
-                        where day_id = $5",
+                        set decryption_key_encr = $1, content = $2
+                        where day_id = $3",
             decr_key_cypher,
-            decr_key_salt.to_vec(),
             content_cypher,
-            content_salt.to_vec(),
             user_day.day.id
         )
-            .execute(&self.db_pool)
-            .await
-            .map_err(|e| e.to_string())?;
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| e.to_string())?;
 
         sqlx::query!(
             "update user_days 
-                        set day_key_encr = $1, day_key_salt = $2 
-                        where day_id = $3 and user_id = $4",
+                        set day_key_encr = $1
+                        where day_id = $2 and user_id = $3",
             new_day_cypher,
-            new_day_salt.to_vec(),
             user_day.day.id,
             user.id
         )
-        .execute(&self.db_pool)
+        .execute(&mut *tx)
         .await
         .map_err(|e| e.to_string())?;
 
@@ -604,9 +1292,17 @@ impl CalendarRepository {
             user_day.day.id,
             user.id
         )
-        .execute(&self.db_pool)
+        .execute(&mut *tx)
         .await
         .map_err(|e| e.to_string())?;
+        Self::share_day_with_editors(
+            &mut tx,
+            user_day.day.calendar_id,
+            user_day.day.id,
+            user,
+            Some(&new_day_key),
+        )
+        .await?;
 
         sqlx::query!(
             "update calendar_days 
@@ -614,7 +1310,7 @@ impl CalendarRepository {
                         where id = $1",
             user_day.day.id
         )
-        .execute(&self.db_pool)
+        .execute(&mut *tx)
         .await
         .map_err(|e| e.to_string())?;
 
@@ -623,57 +1319,49 @@ impl CalendarRepository {
         Ok(())
     }
     pub async fn remove_password(&self, user_day: &UserDay) -> Result<(), String> {
+        let mut tx = self.db_pool.begin().await.map_err(|e| e.to_string())?;
         let record = sqlx::query!(
-            "SELECT decryption_key_encr, decryption_key_salt, content_salt, content
+            "SELECT decryption_key_encr, content
             FROM day_content
-            WHERE day_id = $1",
+            WHERE day_id = $1
+            FOR UPDATE",
             user_day.day.id
         )
-        .fetch_one(&self.db_pool)
+        .fetch_one(&mut *tx)
         .await
-        .map_err(|e| {
-            format!(
-                "There is no content for day {}: {}",
-                user_day.day.id,
-                e.to_string()
-            )
-        })?;
-
-        let decr_key_salt = record
-            .decryption_key_salt
-            .ok_or("The content is protected but there is no decryption key salt")?;
-        let content_salt = record
-            .content_salt
-            .ok_or("The content is protected but there is no content salt")?;
+        .map_err(|e| format!("There is no content for day {}: {}", user_day.day.id, e))?;
+
         let decr_key_cypher = record
             .decryption_key_encr
             .ok_or("The content is protected but there is no decryption key cypher")?;
-        let dec_key = user_day
-            .day_key_handler
-            .decrypt(&decr_key_cypher, &decr_key_salt)?;
+        let dec_key = user_day.day_key_handler.decrypt(
+            &decr_key_cypher,
+            &AssociatedData::decryption_key(user_day.day.id),
+        )?;
         let dec_key = KeyHandler::from_key(dec_key);
-        let content = dec_key.decrypt(&record.content, &content_salt)?;
-
-        let tx = self.db_pool.begin().await.map_err(|e| e.to_string())?;
+        let content = dec_key.decrypt(
+            &record.content,
+            &AssociatedData::day_content(user_day.day.id),
+        )?;
 
         sqlx::query!(
             "update day_content
-                        set decryption_key_encr = null, decryption_key_salt = null, content = $1, content_salt = null
+                        set decryption_key_encr = null, content = $1
                         where day_id = $2",
-            content,
+            content.as_slice(),
             user_day.day.id
         )
-            .execute(&self.db_pool)
-            .await
-            .map_err(|e| e.to_string())?;
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| e.to_string())?;
 
         sqlx::query!(
             "update user_days
-                        set day_key_encr = null, day_key_salt = null
+                        set day_key_encr = null, day_key_sealed = null
                         where day_id = $1 ",
             user_day.day.id
         )
-        .execute(&self.db_pool)
+        .execute(&mut *tx)
         .await
         .map_err(|e| e.to_string())?;
 
@@ -683,7 +1371,7 @@ impl CalendarRepository {
                         where id = $1",
             user_day.day.id
         )
-        .execute(&self.db_pool)
+        .execute(&mut *tx)
         .await
         .map_err(|e| e.to_string())?;
 
@@ -692,3 +1380,61 @@ impl CalendarRepository {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::user::UserRole;
+    use crate::persistence::user_repository::{LoginCredentials, UserRepository};
+    use argon2::Params;
+
+    #[sqlx::test]
+    async fn private_calendars_only_open_to_their_members(db_pool: PgPool) {
+        let params = Params::new(8, 1, 1, None).unwrap();
+        let argon2_pool = Argon2Pool::new(params.clone(), params, 1);
+        let users = UserRepository::new(db_pool.clone(), argon2_pool.clone());
+        let calendars = CalendarRepository::new(db_pool, argon2_pool);
+        let mut accounts = Vec::new();
+        for username in ["alice", "bob"] {
+            let credentials = LoginCredentials {
+                username: String::from(username),
+                password: String::from("correct horse battery staple"),
+            };
+            let (user, _) = users
+                .add_user(&credentials, UserRole::Member, None, None)
+                .await
+                .unwrap();
+            accounts.push(user);
+        }
+        let (alice, bob) = (&accounts[0], &accounts[1]);
+        let calendar = calendars.create_calendar(alice, "Advent").await.unwrap();
+        calendars
+            .set_visibility(&calendar, CalendarVisibility::Unlisted)
+            .await
+            .unwrap();
+        calendars.subscribe(bob, &calendar).await.unwrap();
+        assert!(calendars.get_user_calendar(calendar.id, bob).await.is_ok());
+
+        calendars
+            .set_visibility(&calendar, CalendarVisibility::Private)
+            .await
+            .unwrap();
+        assert!(calendars.get_user_calendar(calendar.id, bob).await.is_err());
+        assert!(calendars.get_user_calendars(bob).await.unwrap().is_empty());
+        assert!(calendars
+            .get_subscriptions(bob, false)
+            .await
+            .unwrap()
+            .is_empty());
+        assert!(calendars
+            .get_user_calendar(calendar.id, alice)
+            .await
+            .is_ok());
+
+        calendars
+            .add_member(&calendar, alice, "bob", CalendarRole::Viewer)
+            .await
+            .unwrap();
+        assert!(calendars.get_user_calendar(calendar.id, bob).await.is_ok());
+    }
+}
@@ -0,0 +1,96 @@
+use crate::model::invitation::{Invitation, InvitationCode};
+use crate::model::user::User;
+use chrono::{TimeDelta, Utc};
+use sqlx::PgPool;
+
+const MAX_USES: i32 = 1000;
+const MAX_VALIDITY_DAYS: i64 = 90;
+
+#[derive(Clone)]
+pub struct InvitationRepository {
+    db_pool: PgPool,
+}
+
+impl InvitationRepository {
+    pub fn new(db_pool: PgPool) -> Self {
+        Self { db_pool }
+    }
+
+    /// Creates an invitation, to a calendar if one is given, and returns its code.
+    pub async fn create_invitation(
+        &self,
+        creator: &User,
+        calendar_id: Option<i32>,
+        max_uses: i32,
+        valid_days: i64,
+    ) -> Result<InvitationCode, String> {
+        if !(1..=MAX_USES).contains(&max_uses) {
+            return Err(format!(
+                "An invitation can be used between 1 and {} times",
+                MAX_USES
+            ));
+        }
+        if !(1..=MAX_VALIDITY_DAYS).contains(&valid_days) {
+            return Err(format!(
+                "An invitation can be valid for 1 to {} days",
+                MAX_VALIDITY_DAYS
+            ));
+        }
+
+        let code = InvitationCode::generate();
+        sqlx::query!(
+            "INSERT INTO invitations (code_hash, created_by, calendar_id, max_uses, expires_at)
+            VALUES ($1, $2, $3, $4, $5)",
+            code.hash(),
+            creator.id,
+            calendar_id,
+            max_uses,
+            Utc::now() + TimeDelta::days(valid_days)
+        )
+        .execute(&self.db_pool)
+        .await
+        .map_err(|e| format!("There was an error updating the database: {:?}", e))?;
+        Ok(code)
+    }
+
+    /// The invitations to a calendar, or those that aren't tied to any calendar.
+    pub async fn get_invitations(
+        &self,
+        calendar_id: Option<i32>,
+    ) -> Result<Vec<Invitation>, String> {
+        sqlx::query_as!(
+            Invitation,
+            r#"
+            SELECT i.id, u.username as created_by, i.max_uses, i.uses, i.created_at, i.expires_at
+            FROM invitations i
+            JOIN users u ON u.id = i.created_by
+            WHERE i.calendar_id IS NOT DISTINCT FROM $1
+            ORDER BY i.created_at DESC
+            "#,
+            calendar_id
+        )
+        .fetch_all(&self.db_pool)
+        .await
+        .map_err(|e| e.to_string())
+    }
+
+    pub async fn revoke_invitation(
+        &self,
+        invitation_id: i32,
+        calendar_id: Option<i32>,
+    ) -> Result<(), String> {
+        let result = sqlx::query!(
+            "DELETE FROM invitations WHERE id = $1 AND calendar_id IS NOT DISTINCT FROM $2",
+            invitation_id,
+            calendar_id
+        )
+        .execute(&self.db_pool)
+        .await
+        .map_err(|e| e.to_string())?;
+
+        match result.rows_affected() {
+            0 => Err(format!("Invitation {} not found", invitation_id)),
+            _ => Ok(()),
+        }
+    }
+}
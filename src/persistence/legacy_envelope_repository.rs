@@ -0,0 +1,375 @@
+use crate::model::calendar::KeyHandler;
+use crate::model::crypto::AssociatedData;
+use crate::model::user::User;
+use sqlx::PgPool;
+
+/// Finds the envelopes sealed before envelopes were bound to their rows, and seals them again.
+/// Only their owners hold the keys, so they can only be sealed again while one of them is
+/// logged in. In the queries, the cipher id is the second byte of an envelope, 1 for
+/// ChaCha20-Poly1305.
+#[derive(Clone)]
+pub struct LegacyEnvelopeRepository {
+    db_pool: PgPool,
+}
+
+impl LegacyEnvelopeRepository {
+    pub fn new(db_pool: PgPool) -> Self {
+        Self { db_pool }
+    }
+
+    /// Counts the rows still holding such an envelope. Sessions don't count, since theirs are
+    /// never opened anymore.
+    pub async fn count(&self) -> Result<i64, String> {
+        sqlx::query_scalar!(
+            r#"SELECT
+                (SELECT count(*) FROM users
+                    WHERE get_byte(content_key_encr, 1) = 1
+                        OR get_byte(recovery_key_encr, 1) = 1
+                        OR get_byte(totp_secret_encr, 1) = 1)
+                + (SELECT count(*) FROM user_days WHERE get_byte(day_key_encr, 1) = 1)
+                + (SELECT count(*) FROM day_content dc JOIN calendar_days cd ON cd.id = dc.day_id
+                    WHERE cd.protected
+                        AND (get_byte(dc.decryption_key_encr, 1) = 1 OR get_byte(dc.content, 1) = 1))
+                AS "count!""#
+        )
+        .fetch_one(&self.db_pool)
+        .await
+        .map_err(|e| e.to_string())
+    }
+
+    /// Seals again every envelope that the content key of a user who just logged in opens,
+    /// directly or through the day keys they hold. Their content key envelope was already
+    /// sealed again by the login itself. A recovery key envelope can only be opened with the
+    /// recovery key, so it is dropped and the user is asked to issue a new one.
+    pub async fn reseal_for_user(&self, user: &User) -> Result<(), String> {
+        let mut tx = self.db_pool.begin().await.map_err(|e| e.to_string())?;
+
+        let record = sqlx::query!(
+            r#"SELECT totp_secret_encr,
+                coalesce(get_byte(recovery_key_encr, 1) = 1, false) AS "legacy_recovery_key!"
+            FROM users WHERE id = $1"#,
+            user.id
+        )
+        .fetch_one(&mut *tx)
+        .await
+        .map_err(|e| e.to_string())?;
+        if let Some(cypher) = record.totp_secret_encr {
+            let (_, resealed) = user
+                .content_key_handler
+                .decrypt_and_reseal(&cypher, &AssociatedData::totp_secret(user.id))?;
+            if let Some(cypher) = resealed {
+                sqlx::query!(
+                    "UPDATE users SET totp_secret_encr = $1 WHERE id = $2",
+                    cypher,
+                    user.id
+                )
+                .execute(&mut *tx)
+                .await
+                .map_err(|e| e.to_string())?;
+            }
+        }
+        if record.legacy_recovery_key {
+            sqlx::query!(
+                "UPDATE users SET recovery_key_encr = NULL WHERE id = $1",
+                user.id
+            )
+            .execute(&mut *tx)
+            .await
+            .map_err(|e| e.to_string())?;
+            sqlx::query!(
+                "INSERT INTO notices (user_id, message) VALUES ($1, $2)",
+                user.id,
+                "Your recovery key was made with an older kind of encryption and no longer works. \
+                Please issue a new one from your account page."
+            )
+            .execute(&mut *tx)
+            .await
+            .map_err(|e| e.to_string())?;
+        }
+
+        let days = sqlx::query!(
+            r#"SELECT ud.day_id, ud.day_key_encr AS "day_key_encr!", dc.decryption_key_encr,
+                dc.content
+            FROM user_days ud
+            JOIN calendar_days cd ON cd.id = ud.day_id
+            JOIN day_content dc ON dc.day_id = ud.day_id
+            WHERE ud.user_id = $1 AND cd.protected AND ud.day_key_encr IS NOT NULL
+                AND (get_byte(ud.day_key_encr, 1) = 1
+                    OR get_byte(dc.decryption_key_encr, 1) = 1
+                    OR get_byte(dc.content, 1) = 1)"#,
+            user.id
+        )
+        .fetch_all(&mut *tx)
+        .await
+        .map_err(|e| e.to_string())?;
+        for day in days {
+            let (day_key, resealed_day_key) = user.content_key_handler.decrypt_and_reseal(
+                &day.day_key_encr,
+                &AssociatedData::day_key(user.id, day.day_id),
+            )?;
+            if let Some(cypher) = resealed_day_key {
+                sqlx::query!(
+                    "UPDATE user_days SET day_key_encr = $1 WHERE user_id = $2 AND day_id = $3",
+                    cypher,
+                    user.id,
+                    day.day_id
+                )
+                .execute(&mut *tx)
+                .await
+                .map_err(|e| e.to_string())?;
+            }
+
+            let Some(decr_key_encr) = day.decryption_key_encr else {
+                continue;
+            };
+            let (decryption_key, resealed_key) = KeyHandler::from_key(day_key)
+                .decrypt_and_reseal(&decr_key_encr, &AssociatedData::decryption_key(day.day_id))?;
+            let (_, resealed_content) = KeyHandler::from_key(decryption_key)
+                .decrypt_and_reseal(&day.content, &AssociatedData::day_content(day.day_id))?;
+            if resealed_key.is_some() || resealed_content.is_some() {
+                sqlx::query!(
+                    "UPDATE day_content
+                        SET decryption_key_encr = coalesce($1, decryption_key_encr),
+                            content = coalesce($2, content)
+                        WHERE day_id = $3",
+                    resealed_key,
+                    resealed_content,
+                    day.day_id
+                )
+                .execute(&mut *tx)
+                .await
+                .map_err(|e| e.to_string())?;
+            }
+        }
+
+        tx.commit().await.map_err(|e| e.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::argon2_pool::Argon2Pool;
+    use crate::model::crypto::{Cipher, Envelope, Kdf, SecretBytes};
+    use crate::model::user::UserRole;
+    use crate::persistence::calendar_repository::CalendarRepository;
+    use crate::persistence::user_repository::{LoginCredentials, UserRepository};
+    use argon2::Params;
+    use chacha20poly1305::aead::Aead;
+    use chacha20poly1305::{ChaCha20Poly1305, KeyInit};
+    use chrono::Utc;
+
+    /// An envelope as written before row binding, which ignores the associated data.
+    fn legacy_envelope(key: &[u8], plaintext: &[u8]) -> Vec<u8> {
+        let nonce = [7u8; 12];
+        let ciphertext = ChaCha20Poly1305::new_from_slice(key)
+            .unwrap()
+            .encrypt(&nonce.into(), plaintext)
+            .unwrap();
+        Envelope {
+            cipher: Cipher::ChaCha20Poly1305,
+            nonce: nonce.to_vec(),
+            kdf: Kdf::None,
+            ciphertext,
+        }
+        .to_bytes()
+        .unwrap()
+    }
+
+    /// The content key of a user, unwrapped with their password.
+    async fn content_key(
+        db_pool: &PgPool,
+        argon2_pool: &Argon2Pool,
+        credentials: &LoginCredentials,
+        user_id: i32,
+    ) -> SecretBytes {
+        let content_key_encr =
+            sqlx::query_scalar!("SELECT content_key_encr FROM users WHERE id = $1", user_id)
+                .fetch_one(db_pool)
+                .await
+                .unwrap();
+        argon2_pool
+            .for_envelope(credentials.password.as_bytes(), &content_key_encr)
+            .await
+            .unwrap()
+            .decrypt(&content_key_encr, &AssociatedData::content_key(user_id))
+            .unwrap()
+    }
+
+    #[sqlx::test]
+    async fn logging_in_seals_legacy_envelopes_again(db_pool: PgPool) {
+        let params = Params::new(8, 1, 1, None).unwrap();
+        let argon2_pool = Argon2Pool::new(params.clone(), params, 1);
+        let users = UserRepository::new(db_pool.clone(), argon2_pool.clone());
+        let credentials = LoginCredentials {
+            username: String::from("alice"),
+            password: String::from("correct horse battery staple"),
+        };
+        let (user, _) = users
+            .add_user(&credentials, UserRole::Member, None, None)
+            .await
+            .unwrap();
+        let content_key = content_key(&db_pool, &argon2_pool, &credentials, user.id).await;
+        let secret = b"totp secret".to_vec();
+        sqlx::query!(
+            "UPDATE users SET totp_secret_encr = $1, recovery_key_encr = $2 WHERE id = $3",
+            legacy_envelope(&content_key, &secret),
+            legacy_envelope(&[1u8; 32], &content_key),
+            user.id
+        )
+        .execute(&db_pool)
+        .await
+        .unwrap();
+
+        let legacy = LegacyEnvelopeRepository::new(db_pool.clone());
+        assert_eq!(legacy.count().await.unwrap(), 1);
+        users
+            .authenticate_user(&credentials, &Default::default())
+            .await
+            .unwrap();
+        assert_eq!(legacy.count().await.unwrap(), 0);
+
+        let record = sqlx::query!(
+            "SELECT totp_secret_encr, recovery_key_encr FROM users WHERE id = $1",
+            user.id
+        )
+        .fetch_one(&db_pool)
+        .await
+        .unwrap();
+        let resealed = user
+            .content_key_handler
+            .decrypt(
+                &record.totp_secret_encr.unwrap(),
+                &AssociatedData::totp_secret(user.id),
+            )
+            .unwrap();
+        assert_eq!(*resealed, secret);
+        assert!(record.recovery_key_encr.is_none());
+    }
+
+    #[sqlx::test]
+    async fn envelopes_that_fail_to_seal_again_are_audited(db_pool: PgPool) {
+        let params = Params::new(8, 1, 1, None).unwrap();
+        let argon2_pool = Argon2Pool::new(params.clone(), params, 1);
+        let users = UserRepository::new(db_pool.clone(), argon2_pool);
+        let credentials = LoginCredentials {
+            username: String::from("alice"),
+            password: String::from("correct horse battery staple"),
+        };
+        let (user, _) = users
+            .add_user(&credentials, UserRole::Member, None, None)
+            .await
+            .unwrap();
+        // Sealed with a key that isn't theirs, so their content key can't open it
+        sqlx::query!(
+            "UPDATE users SET totp_secret_encr = $1 WHERE id = $2",
+            legacy_envelope(&[1u8; 32], b"totp secret"),
+            user.id
+        )
+        .execute(&db_pool)
+        .await
+        .unwrap();
+
+        users
+            .authenticate_user(&credentials, &Default::default())
+            .await
+            .unwrap();
+        let failures = sqlx::query_scalar!(
+            r#"SELECT count(*) AS "count!" FROM audit_events
+            WHERE user_id = $1 AND kind = 'envelopes_not_resealed'"#,
+            user.id
+        )
+        .fetch_one(&db_pool)
+        .await
+        .unwrap();
+        assert_eq!(failures, 1);
+    }
+
+    #[sqlx::test]
+    async fn resealing_a_user_leaves_no_legacy_envelope_in_their_days(db_pool: PgPool) {
+        let params = Params::new(8, 1, 1, None).unwrap();
+        let argon2_pool = Argon2Pool::new(params.clone(), params, 1);
+        let users = UserRepository::new(db_pool.clone(), argon2_pool.clone());
+        let calendars = CalendarRepository::new(db_pool.clone(), argon2_pool.clone());
+        let credentials = LoginCredentials {
+            username: String::from("alice"),
+            password: String::from("correct horse battery staple"),
+        };
+        let (user, _) = users
+            .add_user(&credentials, UserRole::Member, None, None)
+            .await
+            .unwrap();
+        let calendar = calendars.create_calendar(&user, "Advent").await.unwrap();
+        let user_calendar = calendars
+            .get_user_calendar(calendar.id, &user)
+            .await
+            .unwrap();
+        calendars
+            .add_day(
+                &user,
+                &user_calendar,
+                Utc::now(),
+                Some(String::from("day password")),
+                String::from("Behold"),
+            )
+            .await
+            .unwrap();
+
+        // Seal the day key, the decryption key and the content again the legacy way
+        let day = sqlx::query!(
+            r#"SELECT ud.day_id, ud.day_key_encr AS "day_key_encr!",
+                dc.decryption_key_encr AS "decryption_key_encr!", dc.content
+            FROM user_days ud JOIN day_content dc ON dc.day_id = ud.day_id
+            WHERE ud.user_id = $1"#,
+            user.id
+        )
+        .fetch_one(&db_pool)
+        .await
+        .unwrap();
+        let day_key = user
+            .content_key_handler
+            .decrypt(
+                &day.day_key_encr,
+                &AssociatedData::day_key(user.id, day.day_id),
+            )
+            .unwrap();
+        let decryption_key = KeyHandler::from_key(day_key.clone())
+            .decrypt(
+                &day.decryption_key_encr,
+                &AssociatedData::decryption_key(day.day_id),
+            )
+            .unwrap();
+        let content = KeyHandler::from_key(decryption_key.clone())
+            .decrypt(&day.content, &AssociatedData::day_content(day.day_id))
+            .unwrap();
+        let content_key = content_key(&db_pool, &argon2_pool, &credentials, user.id).await;
+        sqlx::query!(
+            "UPDATE user_days SET day_key_encr = $1 WHERE user_id = $2",
+            legacy_envelope(&content_key, &day_key),
+            user.id
+        )
+        .execute(&db_pool)
+        .await
+        .unwrap();
+        sqlx::query!(
+            "UPDATE day_content SET decryption_key_encr = $1, content = $2 WHERE day_id = $3",
+            legacy_envelope(&day_key, &decryption_key),
+            legacy_envelope(&decryption_key, &content),
+            day.day_id
+        )
+        .execute(&db_pool)
+        .await
+        .unwrap();
+
+        let legacy = LegacyEnvelopeRepository::new(db_pool.clone());
+        assert_eq!(legacy.count().await.unwrap(), 2);
+        legacy.reseal_for_user(&user).await.unwrap();
+        assert_eq!(legacy.count().await.unwrap(), 0);
+
+        let user_day = calendars
+            .get_user_day_with_key(&user_calendar, day.day_id, &user)
+            .await
+            .unwrap();
+        assert_eq!(calendars.get_content(&user_day).await.unwrap(), "Behold");
+    }
+}
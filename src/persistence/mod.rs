@@ -1,3 +1,13 @@
+pub mod api_token_repository;
+pub mod attempt_repository;
+pub mod audit_repository;
 pub mod calendar_repository;
+pub mod invitation_repository;
+pub mod legacy_envelope_repository;
+pub mod notice_repository;
+pub mod oidc_repository;
+pub mod postgres_session_backend;
+pub mod redis_session_backend;
+pub mod session_backend;
 pub mod session_store;
 pub mod user_repository;
@@ -0,0 +1,41 @@
+use crate::model::notice::Notice;
+use crate::model::user::User;
+use sqlx::PgPool;
+
+#[derive(Clone)]
+pub struct NoticeRepository {
+    db_pool: PgPool,
+}
+
+impl NoticeRepository {
+    pub fn new(db_pool: PgPool) -> Self {
+        Self { db_pool }
+    }
+
+    pub async fn get_notices(&self, user: &User) -> Result<Vec<Notice>, String> {
+        sqlx::query_as!(
+            Notice,
+            "SELECT id, message, created_at FROM notices WHERE user_id = $1 ORDER BY created_at DESC",
+            user.id
+        )
+        .fetch_all(&self.db_pool)
+        .await
+        .map_err(|e| e.to_string())
+    }
+
+    pub async fn dismiss_notice(&self, user: &User, notice_id: i32) -> Result<(), String> {
+        let result = sqlx::query!(
+            "DELETE FROM notices WHERE id = $1 AND user_id = $2",
+            notice_id,
+            user.id
+        )
+        .execute(&self.db_pool)
+        .await
+        .map_err(|e| e.to_string())?;
+
+        match result.rows_affected() {
+            0 => Err("This notice does not exist or was already dismissed".to_owned()),
+            _ => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,72 @@
+use crate::model::oidc::{OidcIdentity, OidcLogin};
+use chrono::{TimeDelta, Utc};
+use sha2::{Digest, Sha256};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+/// How long a user has to provide their encryption passphrase after the identity provider
+/// let them in.
+const LOGIN_LIFETIME: TimeDelta = TimeDelta::minutes(10);
+
+#[derive(Clone)]
+pub struct OidcRepository {
+    db_pool: PgPool,
+}
+
+impl OidcRepository {
+    pub fn new(db_pool: PgPool) -> Self {
+        Self { db_pool }
+    }
+
+    /// Remembers an identity the provider vouched for, until the passphrase step completes
+    /// the login. Returns the token the browser proves it with.
+    pub async fn start_login(&self, identity: &OidcIdentity) -> Result<Uuid, String> {
+        sqlx::query!("DELETE FROM oidc_logins WHERE expires_at < now()")
+            .execute(&self.db_pool)
+            .await
+            .map_err(|e| e.to_string())?;
+
+        let token = Uuid::new_v4();
+        sqlx::query!(
+            "INSERT INTO oidc_logins (token_hash, issuer, subject, preferred_username, expires_at)
+            VALUES ($1, $2, $3, $4, $5)",
+            hex::encode(Sha256::digest(token)),
+            identity.issuer,
+            identity.subject,
+            identity.preferred_username,
+            Utc::now() + LOGIN_LIFETIME
+        )
+        .execute(&self.db_pool)
+        .await
+        .map_err(|e| format!("There was an error updating the database: {:?}", e))?;
+        Ok(token)
+    }
+
+    pub async fn get_login(&self, token: Uuid) -> Option<OidcLogin> {
+        sqlx::query_as!(
+            OidcLogin,
+            r#"
+            SELECT l.issuer, l.subject, l.preferred_username, u.username as "username?"
+            FROM oidc_logins l
+            LEFT JOIN oidc_identities i ON i.issuer = l.issuer AND i.subject = l.subject
+            LEFT JOIN users u ON u.id = i.user_id
+            WHERE l.token_hash = $1 AND l.expires_at > now()
+            "#,
+            hex::encode(Sha256::digest(token))
+        )
+        .fetch_optional(&self.db_pool)
+        .await
+        .ok()?
+    }
+
+    pub async fn finish_login(&self, token: Uuid) -> Result<(), String> {
+        sqlx::query!(
+            "DELETE FROM oidc_logins WHERE token_hash = $1",
+            hex::encode(Sha256::digest(token))
+        )
+        .execute(&self.db_pool)
+        .await
+        .map_err(|e| e.to_string())?;
+        Ok(())
+    }
+}
@@ -0,0 +1,163 @@
+use crate::persistence::session_backend::{NewSession, SessionBackend, StoredSession};
+use async_trait::async_trait;
+use chrono::{DateTime, TimeDelta, Utc};
+use sqlx::postgres::types::PgInterval;
+use sqlx::PgPool;
+
+/// Keeps sessions in the `user_sessions` table. Expired rows are kept, with `expires_at` set
+/// to the time they ended.
+pub struct PostgresSessionBackend {
+    db_pool: PgPool,
+}
+
+impl PostgresSessionBackend {
+    pub fn new(db_pool: PgPool) -> Self {
+        Self { db_pool }
+    }
+}
+
+#[async_trait]
+impl SessionBackend for PostgresSessionBackend {
+    async fn insert_session(
+        &self,
+        session: NewSession,
+        lifetime: TimeDelta,
+    ) -> Result<StoredSession, String> {
+        let lifetime = PgInterval::try_from(lifetime).map_err(|e| e.to_string())?;
+        sqlx::query_as!(
+            StoredSession,
+            r#"
+            INSERT INTO user_sessions (token_hash,user_id,master_key_encr,second_factor_pending,user_agent,ip_address,expires_at)
+            VALUES ($1,$2,$3,$4,$5,$6, now() + $7)
+            RETURNING id, token_hash, user_id, master_key_encr, second_factor_pending,
+                created_at, last_seen_at, expires_at, user_agent, ip_address
+            "#,
+            session.token_hash,
+            session.user_id,
+            session.master_key_encr,
+            session.second_factor_pending,
+            session.user_agent,
+            session.ip_address,
+            lifetime,
+        )
+        .fetch_one(&self.db_pool)
+        .await
+        .map_err(|e| format!("There was an error when creating the session: {}", e))
+    }
+
+    async fn get_session(&self, token_hash: &str) -> Result<Option<StoredSession>, String> {
+        sqlx::query_as!(
+            StoredSession,
+            r#"
+            SELECT
+                id, token_hash, user_id, master_key_encr, second_factor_pending,
+                created_at, last_seen_at, expires_at, user_agent, ip_address
+            FROM user_sessions
+            WHERE token_hash = $1 AND expires_at > now()
+            "#,
+            token_hash
+        )
+        .fetch_optional(&self.db_pool)
+        .await
+        .map_err(|_| String::from("Error from database when loading the session"))
+    }
+
+    async fn touch_session(&self, token_hash: &str) -> Result<DateTime<Utc>, String> {
+        let record = sqlx::query!(
+            "UPDATE user_sessions SET last_seen_at = now() WHERE token_hash = $1 RETURNING last_seen_at",
+            token_hash
+        )
+        .fetch_one(&self.db_pool)
+        .await
+        .map_err(|_| "Error from database when updating the session")?;
+        Ok(record.last_seen_at)
+    }
+
+    async fn complete_second_factor(
+        &self,
+        token_hash: &str,
+        lifetime: TimeDelta,
+    ) -> Result<bool, String> {
+        let lifetime = PgInterval::try_from(lifetime).map_err(|e| e.to_string())?;
+        let result = sqlx::query!(
+            "UPDATE user_sessions
+            SET second_factor_pending = false, expires_at = now() + $2
+            WHERE token_hash = $1 AND second_factor_pending AND expires_at > now()",
+            token_hash,
+            lifetime
+        )
+        .execute(&self.db_pool)
+        .await
+        .map_err(|_| "Error from database when completing the login")?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn set_master_key(
+        &self,
+        token_hash: &str,
+        master_key_encr: Vec<u8>,
+    ) -> Result<bool, String> {
+        let result = sqlx::query!(
+            "UPDATE user_sessions SET master_key_encr = $2
+            WHERE token_hash = $1 AND expires_at > now()",
+            token_hash,
+            master_key_encr
+        )
+        .execute(&self.db_pool)
+        .await
+        .map_err(|_| "Error from database when updating the session")?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn expire_session(&self, token_hash: &str) -> Result<(), String> {
+        sqlx::query!(
+            "UPDATE user_sessions SET expires_at = now() WHERE token_hash = $1",
+            token_hash
+        )
+        .execute(&self.db_pool)
+        .await
+        .map_err(|_| "Error from database when expiring session")?;
+        Ok(())
+    }
+
+    async fn expire_user_sessions(&self, user_id: i32) -> Result<Vec<String>, String> {
+        sqlx::query_scalar!(
+            "UPDATE user_sessions SET expires_at = now()
+            WHERE user_id = $1 AND expires_at > now()
+            RETURNING token_hash",
+            user_id
+        )
+        .fetch_all(&self.db_pool)
+        .await
+        .map_err(|_| String::from("Error from database when expiring sessions"))
+    }
+
+    async fn get_user_sessions(&self, user_id: i32) -> Result<Vec<StoredSession>, String> {
+        sqlx::query_as!(
+            StoredSession,
+            r#"
+            SELECT
+                id, token_hash, user_id, master_key_encr, second_factor_pending,
+                created_at, last_seen_at, expires_at, user_agent, ip_address
+            FROM user_sessions
+            WHERE user_id = $1 AND expires_at > now() AND NOT second_factor_pending
+            ORDER BY last_seen_at DESC
+            "#,
+            user_id
+        )
+        .fetch_all(&self.db_pool)
+        .await
+        .map_err(|_| String::from("Error from database when listing sessions"))
+    }
+
+    async fn purge_expired_sessions(&self, retention: TimeDelta) -> Result<Vec<String>, String> {
+        let retention = PgInterval::try_from(retention).map_err(|e| e.to_string())?;
+        sqlx::query_scalar!(
+            "DELETE FROM user_sessions WHERE expires_at < now() - $1::interval RETURNING token_hash",
+            retention
+        )
+        .fetch_all(&self.db_pool)
+        .await
+        .map_err(|_| String::from("Error from database when purging sessions"))
+    }
+}
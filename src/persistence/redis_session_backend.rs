@@ -0,0 +1,445 @@
+use crate::persistence::session_backend::{
+    Invalidation, NewSession, SessionBackend, StoredSession,
+};
+use async_trait::async_trait;
+use chrono::{DateTime, TimeDelta, Utc};
+use futures_util::StreamExt;
+use redis::aio::{ConnectionManager, PubSub};
+use redis::{AsyncCommands, Client, RedisResult, Script};
+use std::collections::HashMap;
+use std::time::Duration;
+use tokio::sync::mpsc::UnboundedSender;
+use uuid::Uuid;
+
+/// Channel on which instances announce the sessions they expired.
+const INVALIDATION_CHANNEL: &str = "session_invalidation";
+
+/// Updates `last_seen_at` without bringing back a session which expired in the meantime.
+const TOUCH_SCRIPT: &str = r"
+if redis.call('EXISTS', KEYS[1]) == 0 then return 0 end
+redis.call('HSET', KEYS[1], 'last_seen_at', ARGV[1])
+return 1
+";
+
+const COMPLETE_SECOND_FACTOR_SCRIPT: &str = r"
+if redis.call('HGET', KEYS[1], 'second_factor_pending') ~= '1' then return 0 end
+redis.call('HSET', KEYS[1], 'second_factor_pending', '0', 'expires_at', ARGV[1])
+redis.call('PEXPIREAT', KEYS[1], ARGV[1])
+return 1
+";
+
+/// Replaces the master key without bringing back a session which expired in the meantime.
+const SET_MASTER_KEY_SCRIPT: &str = r"
+if redis.call('EXISTS', KEYS[1]) == 0 then return 0 end
+redis.call('HSET', KEYS[1], 'master_key_encr', ARGV[1])
+return 1
+";
+
+/// Keeps every session in a hash at `session:<token hash>`, which Redis deletes by itself when
+/// the session expires. The hashes of the sessions of a user are listed in the set at
+/// `user-sessions:<user id>`, whose members are pruned once their session is gone.
+pub struct RedisSessionBackend {
+    client: Client,
+    connection: ConnectionManager,
+}
+
+fn session_key(token_hash: &str) -> String {
+    format!("session:{}", token_hash)
+}
+
+fn user_key(user_id: i32) -> String {
+    format!("user-sessions:{}", user_id)
+}
+
+fn parse_session(
+    token_hash: &str,
+    mut fields: HashMap<String, Vec<u8>>,
+) -> Result<Option<StoredSession>, String> {
+    if fields.is_empty() {
+        return Ok(None);
+    }
+    let mut text = |name: &str| -> Result<String, String> {
+        fields
+            .remove(name)
+            .and_then(|value| String::from_utf8(value).ok())
+            .ok_or(format!("Invalid session in Redis, bad field {}", name))
+    };
+    let timestamp = |value: String| -> Result<DateTime<Utc>, String> {
+        value
+            .parse()
+            .ok()
+            .and_then(DateTime::from_timestamp_millis)
+            .ok_or(String::from("Invalid session in Redis, bad timestamp"))
+    };
+
+    let session = StoredSession {
+        id: Uuid::parse_str(&text("id")?).map_err(|e| e.to_string())?,
+        token_hash: token_hash.to_owned(),
+        user_id: text("user_id")?
+            .parse()
+            .map_err(|_| "Invalid user id in Redis")?,
+        second_factor_pending: text("second_factor_pending")? == "1",
+        created_at: timestamp(text("created_at")?)?,
+        last_seen_at: timestamp(text("last_seen_at")?)?,
+        expires_at: timestamp(text("expires_at")?)?,
+        user_agent: text("user_agent").ok(),
+        ip_address: text("ip_address").ok(),
+        master_key_encr: fields
+            .remove("master_key_encr")
+            .ok_or("Invalid session in Redis, no master key")?,
+    };
+    Ok((session.expires_at > Utc::now()).then_some(session))
+}
+
+impl RedisSessionBackend {
+    pub async fn connect(url: &str) -> Result<Self, String> {
+        let client = Client::open(url).map_err(|e| e.to_string())?;
+        let connection = client
+            .get_connection_manager()
+            .await
+            .map_err(|e| e.to_string())?;
+        Ok(Self { client, connection })
+    }
+
+    /// Returns the hashes of the sessions of a user which still exist, and forgets the others.
+    async fn live_tokens(&self, user_id: i32) -> Result<Vec<String>, String> {
+        let mut connection = self.connection.clone();
+        let tokens: Vec<String> = connection
+            .smembers(user_key(user_id))
+            .await
+            .map_err(|_| "Error from Redis when listing sessions")?;
+        if tokens.is_empty() {
+            return Ok(tokens);
+        }
+
+        let mut pipe = redis::pipe();
+        for token_hash in &tokens {
+            pipe.exists(session_key(token_hash));
+        }
+        let exists: Vec<bool> = pipe
+            .query_async(&mut connection)
+            .await
+            .map_err(|_| "Error from Redis when listing sessions")?;
+        let (live, gone): (Vec<_>, Vec<_>) = tokens
+            .into_iter()
+            .zip(exists)
+            .partition(|(_, exists)| *exists);
+
+        if !gone.is_empty() {
+            let gone: Vec<String> = gone.into_iter().map(|(token_hash, _)| token_hash).collect();
+            let _: () = connection
+                .srem(user_key(user_id), gone)
+                .await
+                .map_err(|_| "Error from Redis when listing sessions")?;
+        }
+        Ok(live.into_iter().map(|(token_hash, _)| token_hash).collect())
+    }
+
+    async fn subscribe(client: &Client) -> RedisResult<PubSub> {
+        let mut pubsub = client.get_async_pubsub().await?;
+        pubsub.subscribe(INVALIDATION_CHANNEL).await?;
+        Ok(pubsub)
+    }
+}
+
+#[async_trait]
+impl SessionBackend for RedisSessionBackend {
+    async fn insert_session(
+        &self,
+        session: NewSession,
+        lifetime: TimeDelta,
+    ) -> Result<StoredSession, String> {
+        let now = Utc::now();
+        let session = StoredSession {
+            id: Uuid::new_v4(),
+            token_hash: session.token_hash,
+            user_id: session.user_id,
+            master_key_encr: session.master_key_encr,
+            second_factor_pending: session.second_factor_pending,
+            created_at: now,
+            last_seen_at: now,
+            expires_at: now + lifetime,
+            user_agent: session.user_agent,
+            ip_address: session.ip_address,
+        };
+
+        let millis = |time: DateTime<Utc>| time.timestamp_millis().to_string().into_bytes();
+        let pending = if session.second_factor_pending {
+            "1"
+        } else {
+            "0"
+        };
+        let mut fields: Vec<(&str, Vec<u8>)> = vec![
+            ("id", session.id.to_string().into_bytes()),
+            ("user_id", session.user_id.to_string().into_bytes()),
+            ("master_key_encr", session.master_key_encr.clone()),
+            ("second_factor_pending", pending.as_bytes().to_vec()),
+            ("created_at", millis(session.created_at)),
+            ("last_seen_at", millis(session.last_seen_at)),
+            ("expires_at", millis(session.expires_at)),
+        ];
+        if let Some(user_agent) = &session.user_agent {
+            fields.push(("user_agent", user_agent.clone().into_bytes()));
+        }
+        if let Some(ip_address) = &session.ip_address {
+            fields.push(("ip_address", ip_address.clone().into_bytes()));
+        }
+
+        let key = session_key(&session.token_hash);
+        let _: () = redis::pipe()
+            .atomic()
+            .hset_multiple(&key, &fields)
+            .ignore()
+            .pexpire_at(&key, session.expires_at.timestamp_millis())
+            .ignore()
+            .sadd(user_key(session.user_id), &session.token_hash)
+            .ignore()
+            .query_async(&mut self.connection.clone())
+            .await
+            .map_err(|e| format!("There was an error when creating the session: {}", e))?;
+
+        // Drops the sessions of this user which expired since the last login
+        self.live_tokens(session.user_id).await?;
+        Ok(session)
+    }
+
+    async fn get_session(&self, token_hash: &str) -> Result<Option<StoredSession>, String> {
+        let fields: HashMap<String, Vec<u8>> = self
+            .connection
+            .clone()
+            .hgetall(session_key(token_hash))
+            .await
+            .map_err(|_| "Error from Redis when loading the session")?;
+        parse_session(token_hash, fields)
+    }
+
+    async fn touch_session(&self, token_hash: &str) -> Result<DateTime<Utc>, String> {
+        let now = Utc::now();
+        let touched: i32 = Script::new(TOUCH_SCRIPT)
+            .key(session_key(token_hash))
+            .arg(now.timestamp_millis())
+            .invoke_async(&mut self.connection.clone())
+            .await
+            .map_err(|_| "Error from Redis when updating the session")?;
+        match touched {
+            0 => Err(String::from("This session has ended")),
+            _ => Ok(now),
+        }
+    }
+
+    async fn complete_second_factor(
+        &self,
+        token_hash: &str,
+        lifetime: TimeDelta,
+    ) -> Result<bool, String> {
+        let expires_at = Utc::now() + lifetime;
+        let completed: i32 = Script::new(COMPLETE_SECOND_FACTOR_SCRIPT)
+            .key(session_key(token_hash))
+            .arg(expires_at.timestamp_millis())
+            .invoke_async(&mut self.connection.clone())
+            .await
+            .map_err(|_| "Error from Redis when completing the login")?;
+        Ok(completed == 1)
+    }
+
+    async fn set_master_key(
+        &self,
+        token_hash: &str,
+        master_key_encr: Vec<u8>,
+    ) -> Result<bool, String> {
+        let updated: i32 = Script::new(SET_MASTER_KEY_SCRIPT)
+            .key(session_key(token_hash))
+            .arg(master_key_encr)
+            .invoke_async(&mut self.connection.clone())
+            .await
+            .map_err(|_| "Error from Redis when updating the session")?;
+        Ok(updated == 1)
+    }
+
+    async fn expire_session(&self, token_hash: &str) -> Result<(), String> {
+        let mut connection = self.connection.clone();
+        let key = session_key(token_hash);
+        let user_id: Option<i32> = connection
+            .hget(&key, "user_id")
+            .await
+            .map_err(|_| "Error from Redis when expiring session")?;
+
+        let mut pipe = redis::pipe();
+        pipe.atomic().del(&key).ignore();
+        if let Some(user_id) = user_id {
+            pipe.srem(user_key(user_id), token_hash).ignore();
+        }
+        pipe.publish(INVALIDATION_CHANNEL, token_hash).ignore();
+        let _: () = pipe
+            .query_async(&mut connection)
+            .await
+            .map_err(|_| "Error from Redis when expiring session")?;
+        Ok(())
+    }
+
+    async fn expire_user_sessions(&self, user_id: i32) -> Result<Vec<String>, String> {
+        let tokens = self.live_tokens(user_id).await?;
+
+        let mut pipe = redis::pipe();
+        pipe.atomic().del(user_key(user_id)).ignore();
+        for token_hash in &tokens {
+            pipe.del(session_key(token_hash))
+                .ignore()
+                .publish(INVALIDATION_CHANNEL, token_hash)
+                .ignore();
+        }
+        let _: () = pipe
+            .query_async(&mut self.connection.clone())
+            .await
+            .map_err(|_| "Error from Redis when expiring sessions")?;
+        Ok(tokens)
+    }
+
+    async fn get_user_sessions(&self, user_id: i32) -> Result<Vec<StoredSession>, String> {
+        let tokens = self.live_tokens(user_id).await?;
+        if tokens.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let mut pipe = redis::pipe();
+        for token_hash in &tokens {
+            pipe.hgetall(session_key(token_hash));
+        }
+        let records: Vec<HashMap<String, Vec<u8>>> = pipe
+            .query_async(&mut self.connection.clone())
+            .await
+            .map_err(|_| "Error from Redis when listing sessions")?;
+
+        let mut sessions = Vec::new();
+        for (token_hash, fields) in tokens.iter().zip(records) {
+            match parse_session(token_hash, fields)? {
+                Some(session) if !session.second_factor_pending => sessions.push(session),
+                _ => {}
+            }
+        }
+        sessions.sort_by_key(|session| std::cmp::Reverse(session.last_seen_at));
+        Ok(sessions)
+    }
+
+    /// Redis already deleted expired sessions when their TTL ran out, and the sets of
+    /// session hashes are pruned whenever they are read.
+    async fn purge_expired_sessions(&self, _retention: TimeDelta) -> Result<Vec<String>, String> {
+        Ok(Vec::new())
+    }
+
+    async fn listen_for_invalidations(
+        &self,
+        invalidations: UnboundedSender<Invalidation>,
+    ) -> Result<(), String> {
+        let client = self.client.clone();
+        let mut pubsub = Self::subscribe(&client).await.map_err(|e| e.to_string())?;
+
+        tokio::spawn(async move {
+            loop {
+                let mut messages = pubsub.into_on_message();
+                while let Some(message) = messages.next().await {
+                    let Ok(token_hash) = message.get_payload() else {
+                        continue;
+                    };
+                    if invalidations
+                        .send(Invalidation::Session(token_hash))
+                        .is_err()
+                    {
+                        return;
+                    }
+                }
+
+                // The connection was lost, along with whatever was published in the meantime
+                pubsub = loop {
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                    if let Ok(pubsub) = Self::subscribe(&client).await {
+                        break pubsub;
+                    }
+                };
+                if invalidations.send(Invalidation::All).is_err() {
+                    return;
+                }
+            }
+        });
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::mpsc;
+
+    /// Connects to the Redis named by `REDIS_URL`. The tests using it are ignored by default,
+    /// run them with `REDIS_URL=redis://localhost cargo test -- --ignored`.
+    async fn connect() -> RedisSessionBackend {
+        let url = std::env::var("REDIS_URL").expect("REDIS_URL not set!");
+        RedisSessionBackend::connect(&url)
+            .await
+            .expect("REDIS connection failed!")
+    }
+
+    fn new_session(token_hash: &str) -> NewSession {
+        NewSession {
+            token_hash: token_hash.to_owned(),
+            user_id: rand::random_range(1_000_000..i32::MAX),
+            master_key_encr: vec![1, 2, 3],
+            second_factor_pending: false,
+            user_agent: Some(String::from("test")),
+            ip_address: None,
+        }
+    }
+
+    #[tokio::test]
+    #[ignore = "needs REDIS_URL"]
+    async fn sessions_are_stored_and_expired() {
+        let backend = connect().await;
+        let token_hash = hex::encode(Uuid::new_v4().as_bytes());
+        let inserted = backend
+            .insert_session(new_session(&token_hash), TimeDelta::minutes(5))
+            .await
+            .unwrap();
+
+        let loaded = backend.get_session(&token_hash).await.unwrap().unwrap();
+        assert_eq!(loaded.id, inserted.id);
+        assert_eq!(loaded.user_id, inserted.user_id);
+        assert_eq!(loaded.master_key_encr, vec![1, 2, 3]);
+        assert_eq!(loaded.user_agent.as_deref(), Some("test"));
+        assert_eq!(loaded.ip_address, None);
+        let listed = backend.get_user_sessions(inserted.user_id).await.unwrap();
+        assert_eq!(listed.len(), 1);
+
+        backend.expire_session(&token_hash).await.unwrap();
+        assert!(backend.get_session(&token_hash).await.unwrap().is_none());
+        let listed = backend.get_user_sessions(inserted.user_id).await.unwrap();
+        assert!(listed.is_empty());
+    }
+
+    #[tokio::test]
+    #[ignore = "needs REDIS_URL"]
+    async fn expiring_a_session_notifies_other_instances() {
+        let (first, second) = (connect().await, connect().await);
+        let (sender, mut invalidations) = mpsc::unbounded_channel();
+        first.listen_for_invalidations(sender).await.unwrap();
+
+        let token_hash = hex::encode(Uuid::new_v4().as_bytes());
+        second
+            .insert_session(new_session(&token_hash), TimeDelta::minutes(5))
+            .await
+            .unwrap();
+        assert!(first.get_session(&token_hash).await.unwrap().is_some());
+        second.expire_session(&token_hash).await.unwrap();
+
+        // Other tests may expire their own sessions on the same channel meanwhile
+        let notified = tokio::time::timeout(Duration::from_secs(5), async {
+            while let Some(invalidation) = invalidations.recv().await {
+                if matches!(&invalidation, Invalidation::Session(hash) if *hash == token_hash) {
+                    return;
+                }
+            }
+        })
+        .await;
+        assert!(notified.is_ok(), "no invalidation was published");
+        assert!(first.get_session(&token_hash).await.unwrap().is_none());
+    }
+}
@@ -0,0 +1,89 @@
+use async_trait::async_trait;
+use chrono::{DateTime, TimeDelta, Utc};
+use tokio::sync::mpsc::UnboundedSender;
+use uuid::Uuid;
+
+/// A session as written by `SessionStore`, before the backend assigns its id and timestamps.
+pub struct NewSession {
+    pub token_hash: String,
+    pub user_id: i32,
+    pub master_key_encr: Vec<u8>,
+    pub second_factor_pending: bool,
+    pub user_agent: Option<String>,
+    pub ip_address: Option<String>,
+}
+
+/// A session as kept by a backend. The master key is still wrapped with the session token,
+/// which the backend never sees.
+#[derive(Clone, Debug)]
+pub struct StoredSession {
+    pub id: Uuid,
+    pub token_hash: String,
+    pub user_id: i32,
+    pub master_key_encr: Vec<u8>,
+    pub second_factor_pending: bool,
+    pub created_at: DateTime<Utc>,
+    pub last_seen_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+    pub user_agent: Option<String>,
+    pub ip_address: Option<String>,
+}
+
+/// Cached sessions that another instance asked to drop.
+pub enum Invalidation {
+    Session(String),
+    All,
+}
+
+/// Where `SessionStore` keeps its sessions. Sessions are looked up by the hex encoded hash of
+/// their token, and a backend only ever returns sessions that have not expired yet.
+#[async_trait]
+pub trait SessionBackend: Send + Sync {
+    async fn insert_session(
+        &self,
+        session: NewSession,
+        lifetime: TimeDelta,
+    ) -> Result<StoredSession, String>;
+
+    async fn get_session(&self, token_hash: &str) -> Result<Option<StoredSession>, String>;
+
+    /// Records that the session was just used, and returns the new `last_seen_at`.
+    async fn touch_session(&self, token_hash: &str) -> Result<DateTime<Utc>, String>;
+
+    /// Turns a pending session into a full one which expires after `lifetime`. Returns whether
+    /// there was such a pending session.
+    async fn complete_second_factor(
+        &self,
+        token_hash: &str,
+        lifetime: TimeDelta,
+    ) -> Result<bool, String>;
+
+    /// Replaces the wrapped master key of a live session, once the password it was derived
+    /// from changed. Returns whether there was such a session.
+    async fn set_master_key(
+        &self,
+        token_hash: &str,
+        master_key_encr: Vec<u8>,
+    ) -> Result<bool, String>;
+
+    async fn expire_session(&self, token_hash: &str) -> Result<(), String>;
+
+    /// Expires every session of a user, and returns the hashes of the sessions it ended.
+    async fn expire_user_sessions(&self, user_id: i32) -> Result<Vec<String>, String>;
+
+    /// Lists the sessions of a user which passed every login step, most recently used first.
+    async fn get_user_sessions(&self, user_id: i32) -> Result<Vec<StoredSession>, String>;
+
+    /// Deletes the sessions which expired more than `retention` ago, and returns their hashes.
+    async fn purge_expired_sessions(&self, retention: TimeDelta) -> Result<Vec<String>, String>;
+
+    /// Forwards the sessions expired through other instances, so that they are dropped from
+    /// the local cache. Backends whose changes are already announced on the Postgres
+    /// `session_invalidation` channel have nothing to do here.
+    async fn listen_for_invalidations(
+        &self,
+        _invalidations: UnboundedSender<Invalidation>,
+    ) -> Result<(), String> {
+        Ok(())
+    }
+}
@@ -1,192 +1,569 @@
+use crate::model::argon2_pool::Argon2Pool;
+use crate::model::audit::AuditEventKind;
+use crate::model::calendar::KeyHandler;
+use crate::model::crypto::{AssociatedData, Envelope, Kdf, SecretBytes};
+use crate::model::session::{ActiveSession, ClientInfo};
 use crate::model::user::{User, UserRole};
-use argon2::Argon2;
-use chacha20poly1305::aead::Aead;
-use chacha20poly1305::ChaCha20Poly1305;
-use chacha20poly1305::KeyInit;
-use chrono::{DateTime, Utc};
-use hkdf::Hkdf;
+use crate::persistence::audit_repository::AuditRepository;
+use crate::persistence::session_backend::{
+    Invalidation, NewSession, SessionBackend, StoredSession,
+};
+use chrono::{DateTime, TimeDelta, Utc};
 use lru::LruCache;
-use rand::random;
 use sha2::{Digest, Sha256};
+use sqlx::postgres::PgListener;
 use sqlx::PgPool;
 use std::num::NonZeroUsize;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+use tokio::sync::mpsc;
 use uuid::Uuid;
 
+/// Postgres channel on which the database announces sessions to drop from the cache,
+/// see the `session_invalidation` migration for the payload format.
+const INVALIDATION_CHANNEL: &str = "session_invalidation";
+
+/// How stale `last_seen_at` may get before a request writes it back to the backend.
+const LAST_SEEN_INTERVAL: TimeDelta = TimeDelta::minutes(5);
+
+const SESSION_LIFETIME: TimeDelta = TimeDelta::days(1);
+
+/// How long a user has to provide their second factor after the password step.
+const PENDING_SESSION_LIFETIME: TimeDelta = TimeDelta::minutes(10);
+
 #[derive(Clone)]
 struct Session {
     id: [u8; 32],
     user: User,
-    created_at: DateTime<Utc>,
     expires_at: DateTime<Utc>,
+    last_seen_at: DateTime<Utc>,
 }
 impl Session {
     fn is_expired(&self) -> bool {
         Utc::now() > self.expires_at
     }
+
+    fn is_stale(&self) -> bool {
+        Utc::now() - self.last_seen_at > LAST_SEEN_INTERVAL
+    }
+}
+
+/// The cached sessions, along with how many evictions happened so far. A request that loaded a
+/// session without holding the lock only caches it if no eviction happened in the meantime,
+/// otherwise it could put back a session that was just revoked.
+struct SessionCache {
+    sessions: LruCache<[u8; 32], Session>,
+    evictions: u64,
+}
+impl SessionCache {
+    fn pop(&mut self, token_hash: &[u8; 32]) {
+        self.evictions += 1;
+        self.sessions.pop(token_hash);
+    }
+
+    fn clear(&mut self) {
+        self.evictions += 1;
+        self.sessions.clear();
+    }
 }
 
 pub struct SessionStore {
     db_pool: PgPool,
-    cached_sessions: LruCache<[u8; 32], Session>,
+    argon2_pool: Argon2Pool,
+    backend: Box<dyn SessionBackend>,
+    /// Only ever locked for map operations, never across an await
+    cached_sessions: Mutex<SessionCache>,
+    audit: AuditRepository,
 }
 
 impl SessionStore {
-    pub fn new(db_pool: PgPool) -> Self {
+    pub fn new(db_pool: PgPool, argon2_pool: Argon2Pool, backend: Box<dyn SessionBackend>) -> Self {
         Self {
+            audit: AuditRepository::new(db_pool.clone()),
             db_pool,
-            cached_sessions: LruCache::new(NonZeroUsize::new(100).unwrap()), // Can't fail because 100 > 0
+            argon2_pool,
+            backend,
+            cached_sessions: Mutex::new(SessionCache {
+                sessions: LruCache::new(NonZeroUsize::new(100).unwrap()), // Can't fail because 100 > 0
+                evictions: 0,
+            }),
         }
     }
 
-    async fn get_session(&mut self, token: Uuid) -> Option<Session> {
+    fn cache(&self) -> MutexGuard<'_, SessionCache> {
+        // The cache holds no invariant a panicking thread could have broken halfway
+        self.cached_sessions
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    async fn get_session(&self, token: Uuid) -> Option<Session> {
         let token_hash: [u8; 32] = Sha256::digest(token).into();
-        let hash_hex = hex::encode(token_hash);
-        if let Some(session) = self.cached_sessions.get(&token_hash) {
-            return Some(session.clone());
+        let (cached, evictions) = {
+            let mut cache = self.cache();
+            let cached = cache
+                .sessions
+                .get(&token_hash)
+                .filter(|session| !session.is_expired())
+                .cloned();
+            (cached, cache.evictions)
+        };
+        let mut session = match cached {
+            Some(session) => session,
+            None => match self.load_session(&token).await? {
+                (session, false) => session,
+                (_, true) => return None,
+            },
+        };
+        if session.is_stale() {
+            session.last_seen_at = self
+                .backend
+                .touch_session(&hex::encode(token_hash))
+                .await
+                .ok()?;
+        }
+        let mut cache = self.cache();
+        if cache.evictions == evictions {
+            cache.sessions.put(token_hash, session.clone());
         }
-        let result = sqlx::query!(
-                r#"
-                SELECT
-                    u.id as user_id, u.username, u.role as "role:UserRole", u.content_key_encr, u.content_key_salt,
-                    s.token_hash, s.created_at, s.expires_at, s.master_key_salt, s.master_key_encr
-                FROM user_sessions as s
-                JOIN users as u ON u.id = s.user_id
-                WHERE s.token_hash = $1 AND s.expires_at > NOW()
-                "#,
-                hash_hex
-            )
-            .fetch_optional(&self.db_pool)
-            .await;
-        let session: Result<Session, String> = result.ok()?.map(|record| {
-            let content_key = Self::get_content_key(
-                &token,
-                &record.master_key_encr,
-                &record.master_key_salt,
-                &record.content_key_encr,
-                &record.content_key_salt,
-            )?;
-            let user = User::new(
-                record.user_id,
-                record.username,
-                record.role,
-                content_key,
-                record.master_key_salt,
-            );
-            Ok(Session {
-                id: hex::decode(record.token_hash)
-                    .expect("Invalid hex in database")
-                    .try_into()
-                    .expect("Invalid hex in database"),
-                user,
-                created_at: record.created_at,
-                expires_at: record.expires_at,
-            })
-        })?;
-        let session = session.ok()?;
-        self.cached_sessions.put(token_hash, session.clone());
         Some(session)
     }
 
+    /// Loads a live session from the backend, along with whether it still waits for a
+    /// second factor.
+    async fn load_session(&self, token: &Uuid) -> Option<(Session, bool)> {
+        let hash_hex = hex::encode(Sha256::digest(token));
+        let stored = self.backend.get_session(&hash_hex).await.ok()??;
+        let record = sqlx::query!(
+            r#"
+            SELECT id, username, role as "role:UserRole", content_key_encr
+            FROM users
+            WHERE id = $1 AND locked_at IS NULL
+            "#,
+            stored.user_id
+        )
+        .fetch_optional(&self.db_pool)
+        .await
+        .ok()??;
+
+        let content_key =
+            Self::get_content_key(token, &stored, record.id, &record.content_key_encr).ok()?;
+        let user = User::new(record.id, record.username, record.role, content_key);
+        let session = Session {
+            id: hex::decode(stored.token_hash)
+                .expect("Invalid hex in session backend")
+                .try_into()
+                .expect("Invalid hex in session backend"),
+            user,
+            expires_at: stored.expires_at,
+            last_seen_at: stored.last_seen_at,
+        };
+        Some((session, stored.second_factor_pending))
+    }
+
     fn get_content_key(
         token: &Uuid,
-        master_key_encr: &[u8],
-        master_key_salt: &[u8],
+        stored: &StoredSession,
+        user_id: i32,
         content_key_encr: &[u8],
-        content_key_salt: &[u8],
-    ) -> Result<Vec<u8>, String> {
-        let mut token_key_dest = [0u8; 32];
-        let hk = Hkdf::<Sha256>::new(None, token.as_bytes());
-        hk.expand(b"session", &mut token_key_dest).unwrap(); // Should never fail since lengths are always the same
-
-        let master_key = ChaCha20Poly1305::new(&token_key_dest.into())
-            .decrypt(master_key_salt.into(), master_key_encr)
-            .map_err(|e| e.to_string())?;
-        let content_key = ChaCha20Poly1305::new(master_key.as_slice().into())
-            .decrypt(content_key_salt.into(), content_key_encr)
-            .map_err(|e| e.to_string())?;
-        Ok(content_key)
+    ) -> Result<SecretBytes, String> {
+        // Session master keys are never sealed again, see the legacy_session_keys migration
+        if Envelope::from_bytes(&stored.master_key_encr)?.is_outdated() {
+            return Err(String::from("This session was opened before row binding"));
+        }
+        let token_key = KeyHandler::for_envelope(token.as_bytes(), &stored.master_key_encr)?;
+        let master_key = token_key.decrypt(
+            &stored.master_key_encr,
+            &AssociatedData::session_master_key(&stored.token_hash),
+        )?;
+        KeyHandler::from_key(master_key)
+            .decrypt(content_key_encr, &AssociatedData::content_key(user_id))
     }
 
-    pub async fn get_user(&mut self, token: Uuid) -> Option<User> {
+    pub async fn get_user(&self, token: Uuid) -> Option<User> {
         self.get_session(token).await.map(|session| session.user)
     }
 
-    pub async fn add_user(&mut self, user: User, password: &str) -> Result<Uuid, String> {
+    pub async fn add_user(
+        &self,
+        user: User,
+        password: &str,
+        client: &ClientInfo,
+    ) -> Result<Uuid, String> {
+        let user_id = user.id;
+        let (token, session) = self.create_session(user, password, client, false).await?;
+        self.cache().sessions.put(session.id, session);
+        self.audit
+            .record(Some(user_id), AuditEventKind::Login, Some(client), None)
+            .await?;
+        Ok(token)
+    }
+
+    /// Opens a short-lived session for a user who passed the password step but still has to
+    /// provide a second factor. It is not accepted by `get_user` until
+    /// `complete_second_factor` is called.
+    pub async fn add_pending_user(
+        &self,
+        user: User,
+        password: &str,
+        client: &ClientInfo,
+    ) -> Result<Uuid, String> {
+        let (token, _) = self.create_session(user, password, client, true).await?;
+        Ok(token)
+    }
+
+    pub async fn get_pending_user(&self, token: Uuid) -> Option<User> {
+        match self.load_session(&token).await? {
+            (session, true) => Some(session.user),
+            (_, false) => None,
+        }
+    }
+
+    pub async fn complete_second_factor(
+        &self,
+        token: Uuid,
+        user_id: i32,
+        client: &ClientInfo,
+    ) -> Result<(), String> {
+        let hash_hex = hex::encode(Sha256::digest(token));
+        let completed = self
+            .backend
+            .complete_second_factor(&hash_hex, SESSION_LIFETIME)
+            .await?;
+
+        match completed {
+            false => Err("This login attempt has expired, please log in again".to_owned()),
+            true => {
+                self.audit
+                    .record(Some(user_id), AuditEventKind::Login, Some(client), None)
+                    .await
+            }
+        }
+    }
+
+    async fn create_session(
+        &self,
+        user: User,
+        password: &str,
+        client: &ClientInfo,
+        second_factor_pending: bool,
+    ) -> Result<(Uuid, Session), String> {
         let token = Uuid::new_v4();
         let token_hash: [u8; 32] = Sha256::digest(token).into();
-        let hash_hex = hex::encode(token_hash);
 
-        let (master_encr, master_salt) =
-            self.get_encrypted_master_key(&token, password, &user.master_key_salt);
+        let master_encr = self
+            .get_encrypted_master_key(&token, &token_hash, password, user.id)
+            .await?;
 
-        let result = sqlx::query!(
-            r#"
-                WITH inserted AS (
-                    INSERT INTO user_sessions (token_hash,user_id,master_key_salt,master_key_encr)
-                    VALUES ($1,$2,$3,$4)
-                    RETURNING token_hash, user_id, created_at, expires_at)
-                SELECT
-                    s.created_at, s.expires_at
-                FROM inserted AS s
-                JOIN users AS u ON u.id = s.user_id
-                "#,
-            hash_hex,
-            user.id,
-            &master_salt,
-            &master_encr,
-        )
-        .fetch_one(&self.db_pool)
-        .await
-        .map_err(|e| {
-            format!(
-                "There was an error when creating the session: {}",
-                e.to_string()
+        let lifetime = match second_factor_pending {
+            true => PENDING_SESSION_LIFETIME,
+            false => SESSION_LIFETIME,
+        };
+        let stored = self
+            .backend
+            .insert_session(
+                NewSession {
+                    token_hash: hex::encode(token_hash),
+                    user_id: user.id,
+                    master_key_encr: master_encr,
+                    second_factor_pending,
+                    user_agent: client.user_agent.clone(),
+                    ip_address: client.ip_address.clone(),
+                },
+                lifetime,
             )
-        })?;
+            .await?;
 
         let session = Session {
             id: token_hash,
             user,
-            created_at: result.created_at,
-            expires_at: result.expires_at,
+            expires_at: stored.expires_at,
+            last_seen_at: stored.last_seen_at,
         };
-        self.cached_sessions.put(token_hash, session);
-        Ok(token)
+        Ok((token, session))
     }
 
-    fn get_encrypted_master_key(
+    /// Derives the user's master key again from their password, with the parameters recorded
+    /// in their content key envelope, and wraps it with a key derived from the session token.
+    async fn get_encrypted_master_key(
         &self,
         token: &Uuid,
+        token_hash: &[u8; 32],
         password: &str,
-        master_key_salt: &[u8],
-    ) -> (Vec<u8>, [u8; 12]) {
-        let mut master_key = [0u8; 32];
-        Argon2::default()
-            .hash_password_into(password.as_bytes(), &master_key_salt, &mut master_key)
-            .unwrap(); //Shouldn't fail because parameters are always of the same lengths
-
-        let mut token_key_dest = [0u8; 32];
-        let hk = Hkdf::<Sha256>::new(None, token.as_bytes());
-        hk.expand(b"session", &mut token_key_dest).unwrap();
-
-        let salt: [u8; 12] = random();
-        let cypher = ChaCha20Poly1305::new(&token_key_dest.into())
-            .encrypt(&salt.into(), master_key.as_slice())
-            .unwrap();
-        (cypher, salt)
+        user_id: i32,
+    ) -> Result<Vec<u8>, String> {
+        let record = sqlx::query!("SELECT content_key_encr FROM users WHERE id = $1", user_id)
+            .fetch_one(&self.db_pool)
+            .await
+            .map_err(|_| "Error from database when creating the session")?;
+
+        let master_key = self
+            .argon2_pool
+            .for_envelope(password.as_bytes(), &record.content_key_encr)
+            .await?;
+        let token_key = KeyHandler::derive(token.as_bytes(), Kdf::hkdf("session"))?;
+        master_key.get_encrypted_key(
+            &token_key,
+            &AssociatedData::session_master_key(&hex::encode(token_hash)),
+        )
+    }
+
+    /// Wraps the master key derived from the new `password` for the session holding `token`,
+    /// so that it keeps working after a password change.
+    pub async fn rekey_session(
+        &self,
+        token: Uuid,
+        user_id: i32,
+        password: &str,
+    ) -> Result<(), String> {
+        let token_hash: [u8; 32] = Sha256::digest(token).into();
+        let master_encr = self
+            .get_encrypted_master_key(&token, &token_hash, password, user_id)
+            .await?;
+        let updated = self
+            .backend
+            .set_master_key(&hex::encode(token_hash), master_encr)
+            .await?;
+        match updated {
+            false => Err("This session has ended".to_owned()),
+            true => Ok(()),
+        }
     }
 
-    pub async fn expire_session(&mut self, session_id: Uuid) -> Result<(), String> {
+    pub async fn expire_session(&self, session_id: Uuid) -> Result<(), String> {
         let token_hash: [u8; 32] = Sha256::digest(session_id.as_bytes()).into();
-        let hash_hex = hex::encode(token_hash);
-        sqlx::query!(
-            "UPDATE user_sessions SET expires_at = now() WHERE token_hash = $1",
-            hash_hex
-        )
-        .execute(&self.db_pool)
-        .await
-        .map_err(|_| "Error from database when expiring session")?;
-        self.cached_sessions.pop(&token_hash);
+        self.backend
+            .expire_session(&hex::encode(token_hash))
+            .await?;
+        self.cache().pop(&token_hash);
+        Ok(())
+    }
+
+    pub async fn expire_user_sessions(&self, user_id: i32) -> Result<(), String> {
+        self.backend.expire_user_sessions(user_id).await?;
+        self.evict_user(user_id);
         Ok(())
     }
+
+    /// Deletes the sessions which expired more than `retention` ago, and returns how many
+    /// there were.
+    pub async fn purge_expired_sessions(&self, retention: TimeDelta) -> Result<usize, String> {
+        let purged = self.backend.purge_expired_sessions(retention).await?;
+        for token_hash in &purged {
+            self.evict_session(token_hash);
+        }
+        Ok(purged.len())
+    }
+
+    /// Drops the cached sessions of a user, so that the next request reloads them from the
+    /// database and picks up changes such as a new role.
+    pub fn evict_user(&self, user_id: i32) {
+        let mut cache = self.cache();
+        // Counts even when none is cached, since one of them may be on its way in
+        cache.evictions += 1;
+        let cached: Vec<[u8; 32]> = cache
+            .sessions
+            .iter()
+            .filter(|(_, session)| session.user.id == user_id)
+            .map(|(_, session)| session.id)
+            .collect();
+        for token_hash in cached {
+            cache.sessions.pop(&token_hash);
+        }
+    }
+
+    /// Lists the live sessions of a user, most recently used first. `current` is the token of
+    /// the request, so that its session can be told apart from the others.
+    pub async fn get_active_sessions(
+        &self,
+        user_id: i32,
+        current: Uuid,
+    ) -> Result<Vec<ActiveSession>, String> {
+        let current_hash = hex::encode(Sha256::digest(current));
+        let sessions = self.backend.get_user_sessions(user_id).await?;
+        Ok(sessions
+            .into_iter()
+            .map(|session| ActiveSession {
+                current: session.token_hash == current_hash,
+                id: session.id,
+                created_at: session.created_at,
+                last_seen_at: session.last_seen_at,
+                user_agent: session.user_agent,
+                ip_address: session.ip_address,
+            })
+            .collect())
+    }
+
+    /// Expires one session of a user by its public id.
+    pub async fn revoke_session(&self, user_id: i32, session_id: Uuid) -> Result<(), String> {
+        let sessions = self.backend.get_user_sessions(user_id).await?;
+        let revoked: StoredSession = sessions
+            .into_iter()
+            .find(|session| session.id == session_id)
+            .ok_or("This session does not exist or has already ended")?;
+        self.backend.expire_session(&revoked.token_hash).await?;
+        self.evict_session(&revoked.token_hash);
+        self.audit
+            .record(
+                Some(user_id),
+                AuditEventKind::SessionRevoked,
+                None,
+                Some(format!(
+                    "Session started {}",
+                    revoked.created_at.format("%Y-%m-%d %H:%M")
+                )),
+            )
+            .await
+    }
+
+    /// Expires every session of a user except the one holding `current`.
+    pub async fn revoke_other_sessions(
+        &self,
+        user_id: i32,
+        current: Uuid,
+    ) -> Result<u64, String> {
+        let current_hash = hex::encode(Sha256::digest(current));
+        let sessions = self.backend.get_user_sessions(user_id).await?;
+        let mut revoked = 0;
+        for session in sessions {
+            if session.token_hash != current_hash {
+                self.backend.expire_session(&session.token_hash).await?;
+                self.evict_session(&session.token_hash);
+                revoked += 1;
+            }
+        }
+        if revoked > 0 {
+            self.audit
+                .record(
+                    Some(user_id),
+                    AuditEventKind::SessionRevoked,
+                    None,
+                    Some(format!("{} other sessions", revoked)),
+                )
+                .await?;
+        }
+        Ok(revoked)
+    }
+
+    fn evict_session(&self, hash_hex: &str) {
+        if let Some(token_hash) = hex::decode(hash_hex)
+            .ok()
+            .and_then(|bytes| <[u8; 32]>::try_from(bytes).ok())
+        {
+            self.cache().pop(&token_hash);
+        }
+    }
+
+    /// Drops the cached sessions named by a notification on `INVALIDATION_CHANNEL`.
+    fn handle_invalidation(&self, payload: &str) {
+        match payload.strip_prefix("user:") {
+            Some(user_id) => {
+                if let Ok(user_id) = user_id.parse() {
+                    self.evict_user(user_id);
+                }
+            }
+            None => self.evict_session(payload),
+        }
+    }
+
+    /// Starts listening for session changes made by any instance. Entries are evicted when a
+    /// notification arrives, and the whole cache is dropped whenever a connection was lost,
+    /// since notifications sent in the meantime are gone. Changes to users always come from
+    /// Postgres, changes to sessions come from whichever backend holds them.
+    pub async fn listen_for_invalidations(session_store: Arc<SessionStore>) {
+        let db_pool = session_store.db_pool.clone();
+        let mut listener = PgListener::connect_with(&db_pool)
+            .await
+            .expect("DATABASE listener connection failed!");
+        listener
+            .listen(INVALIDATION_CHANNEL)
+            .await
+            .expect("DATABASE listener connection failed!");
+
+        let (sender, mut invalidations) = mpsc::unbounded_channel();
+        session_store
+            .backend
+            .listen_for_invalidations(sender)
+            .await
+            .expect("SESSION backend listener connection failed!");
+
+        let store = session_store.clone();
+        tokio::spawn(async move {
+            loop {
+                match listener.try_recv().await {
+                    Ok(Some(notification)) => {
+                        store.handle_invalidation(notification.payload());
+                    }
+                    Ok(None) => store.cache().clear(),
+                    Err(_) => {
+                        store.cache().clear();
+                        tokio::time::sleep(Duration::from_secs(1)).await;
+                    }
+                }
+            }
+        });
+
+        tokio::spawn(async move {
+            while let Some(invalidation) = invalidations.recv().await {
+                match invalidation {
+                    Invalidation::Session(token_hash) => session_store.evict_session(&token_hash),
+                    Invalidation::All => session_store.cache().clear(),
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::persistence::postgres_session_backend::PostgresSessionBackend;
+    use crate::persistence::user_repository::{LoginCredentials, UserRepository};
+    use argon2::Params;
+
+    async fn start_instance(db_pool: &PgPool, argon2_pool: &Argon2Pool) -> Arc<SessionStore> {
+        let backend = PostgresSessionBackend::new(db_pool.clone());
+        let store = Arc::new(SessionStore::new(
+            db_pool.clone(),
+            argon2_pool.clone(),
+            Box::new(backend),
+        ));
+        SessionStore::listen_for_invalidations(store.clone()).await;
+        store
+    }
+
+    #[sqlx::test]
+    async fn revoking_on_one_instance_evicts_on_the_other(db_pool: PgPool) {
+        let params = Params::new(8, 1, 1, None).unwrap();
+        let argon2_pool = Argon2Pool::new(params.clone(), params, 1);
+        let first = start_instance(&db_pool, &argon2_pool).await;
+        let second = start_instance(&db_pool, &argon2_pool).await;
+
+        let credentials = LoginCredentials {
+            username: String::from("alice"),
+            password: String::from("correct horse battery staple"),
+        };
+        let (user, _) = UserRepository::new(db_pool.clone(), argon2_pool.clone())
+            .add_user(&credentials, UserRole::Member, None, None)
+            .await
+            .unwrap();
+        let user_id = user.id;
+        let token = first
+            .add_user(user, &credentials.password, &ClientInfo::default())
+            .await
+            .unwrap();
+        assert!(first.get_session(token).await.is_some());
+
+        let sessions = second.get_active_sessions(user_id, token).await.unwrap();
+        second.revoke_session(user_id, sessions[0].id).await.unwrap();
+
+        // The first instance only learns about it from the notification
+        let evicted = tokio::time::timeout(Duration::from_secs(5), async {
+            while first.get_session(token).await.is_some() {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        })
+        .await;
+        assert!(evicted.is_ok(), "the revoked session is still cached");
+    }
 }
@@ -1,15 +1,35 @@
-use crate::model::user::{User, UserRole};
-use argon2::password_hash::rand_core::OsRng;
-use argon2::password_hash::SaltString;
-use argon2::{Argon2, PasswordHash, PasswordHasher, PasswordVerifier};
-use chacha20poly1305::aead::AeadMut;
-use chacha20poly1305::{ChaCha20Poly1305, KeyInit};
-use rand::random;
+use crate::model::argon2_pool::Argon2Pool;
+use crate::model::audit::AuditEventKind;
+use crate::model::calendar::KeyHandler;
+use crate::model::crypto::{AssociatedData, KeyPair, SecretBytes};
+use crate::model::invitation::InvitationCode;
+use crate::model::oidc::OidcIdentity;
+use crate::model::session::ClientInfo;
+use crate::model::two_factor::{BackupCode, TotpSecret};
+use crate::model::user::{RecoveryKey, User, UserAccount, UserRole};
+use crate::persistence::audit_repository::AuditRepository;
+use crate::persistence::legacy_envelope_repository::LegacyEnvelopeRepository;
+use chrono::{DateTime, Utc};
+use rand::fill;
 use serde::Deserialize;
 use sqlx::PgPool;
+use zeroize::Zeroizing;
 
 pub struct UserRepository {
     db_pool: PgPool,
+    argon2_pool: Argon2Pool,
+    audit: AuditRepository,
+    legacy_envelopes: LegacyEnvelopeRepository,
+}
+struct AccountRecord {
+    id: i32,
+    username: String,
+    role: UserRole,
+    password_hash: String,
+    content_key_encr: Vec<u8>,
+    locked_at: Option<DateTime<Utc>>,
+    single_sign_on: bool,
+    has_key_pair: bool,
 }
 #[derive(Deserialize)]
 pub struct LoginCredentials {
@@ -17,123 +37,302 @@ pub struct LoginCredentials {
     pub password: String,
 }
 impl UserRepository {
-    pub fn new(db_pool: PgPool) -> Self {
-        UserRepository { db_pool }
+    pub fn new(db_pool: PgPool, argon2_pool: Argon2Pool) -> Self {
+        UserRepository {
+            audit: AuditRepository::new(db_pool.clone()),
+            legacy_envelopes: LegacyEnvelopeRepository::new(db_pool.clone()),
+            db_pool,
+            argon2_pool,
+        }
+    }
+
+    pub async fn authenticate_user(
+        &self,
+        user: &LoginCredentials,
+        client: &ClientInfo,
+    ) -> Result<User, String> {
+        let res = sqlx::query_as!(
+            AccountRecord,
+            r#"select id, username, role as "role:UserRole", password_hash, content_key_encr,
+                locked_at, exists(select 1 from oidc_identities where user_id = users.id) as "single_sign_on!",
+                public_key is not null as "has_key_pair!"
+            from users where username = ($1)"#,
+            user.username
+        )
+        .fetch_optional(&self.db_pool)
+        .await
+        .map_err(|_| "Database connection failed")?;
+        let Some(res) = res else {
+            self.audit
+                .record(
+                    None,
+                    AuditEventKind::LoginFailed,
+                    Some(client),
+                    Some(format!("Unknown username {}", user.username)),
+                )
+                .await?;
+            return Err("Couldn't find this user".to_owned());
+        };
+
+        self.open_account(res, &user.password, client).await
+    }
+
+    /// Authenticates someone the identity provider vouched for with the encryption
+    /// passphrase of the account linked to their identity.
+    pub async fn authenticate_oidc_user(
+        &self,
+        issuer: &str,
+        subject: &str,
+        passphrase: &str,
+        client: &ClientInfo,
+    ) -> Result<User, String> {
+        let res = sqlx::query_as!(
+            AccountRecord,
+            r#"select u.id, u.username, u.role as "role:UserRole", u.password_hash,
+                u.content_key_encr, u.locked_at, false as "single_sign_on!",
+                u.public_key is not null as "has_key_pair!"
+            from users u join oidc_identities i on i.user_id = u.id
+            where i.issuer = $1 and i.subject = $2"#,
+            issuer,
+            subject
+        )
+        .fetch_optional(&self.db_pool)
+        .await
+        .map_err(|_| "Database connection failed")?
+        .ok_or("Couldn't find this user")?;
+
+        self.open_account(res, passphrase, client).await
     }
 
-    pub async fn authenticate_user(&self, user: &LoginCredentials) -> Result<User, String> {
-        let res = sqlx::query!(r#"select  id, username, role as "role:UserRole", password_hash, master_key_salt, content_key_salt, content_key_encr
-                                from users where username = ($1)"#, user.username)
-            .fetch_optional(&self.db_pool)
+    /// Checks the password of an account and unwraps its content key with it.
+    async fn open_account(
+        &self,
+        res: AccountRecord,
+        password: &str,
+        client: &ClientInfo,
+    ) -> Result<User, String> {
+        let refused = match self
+            .argon2_pool
+            .verify_password(password, &res.password_hash)
             .await
-            .map_err(|_| "Database connection failed")?
-            .ok_or("Couldn't find this user")?;
+        {
+            Err(e) => Some(e),
+            Ok(()) if res.locked_at.is_some() => {
+                Some("This account has been locked by an administrator".to_owned())
+            }
+            // Their password only protects their content, the identity provider logs them in
+            Ok(()) if res.single_sign_on => {
+                Some("This account logs in through single sign-on".to_owned())
+            }
+            Ok(()) => None,
+        };
+        if let Some(message) = refused {
+            self.audit
+                .record(
+                    Some(res.id),
+                    AuditEventKind::LoginFailed,
+                    Some(client),
+                    Some(message.clone()),
+                )
+                .await?;
+            return Err(message);
+        }
 
-        Argon2::default()
-            .verify_password(
-                user.password.as_bytes(),
-                &PasswordHash::new(&res.password_hash)
-                    .map_err(|_| "Invalid password_hash from database")?,
+        // The content key envelope keeps its parameters until the password changes, since
+        // the master key held by live sessions would no longer unwrap it
+        if self.argon2_pool.needs_rehash(&res.password_hash) {
+            sqlx::query!(
+                "update users set password_hash = $1 where id = $2",
+                self.argon2_pool.hash_password(password).await?,
+                res.id
             )
-            .map_err(|_| "Invalid credentials")?;
-
-        let master_key = Self::get_master_key_with_salt(&user.password, &res.master_key_salt);
+            .execute(&self.db_pool)
+            .await
+            .map_err(|e| format!("There was an error updating the database: {:?}", e))?;
+        }
 
-        let content_key =
-            Self::decrypt_content_key(&master_key, &res.content_key_encr, &res.content_key_salt);
+        let (content_key, resealed) = self
+            .unwrap_content_key(password, res.id, &res.content_key_encr)
+            .await?;
+        if let Some(content_key_encr) = resealed {
+            sqlx::query!(
+                "update users set content_key_encr = $1 where id = $2",
+                content_key_encr,
+                res.id
+            )
+            .execute(&self.db_pool)
+            .await
+            .map_err(|e| format!("There was an error updating the database: {:?}", e))?;
+        }
 
-        let user = User::new(
-            res.id,
-            res.username,
-            res.role,
-            content_key?,
-            res.master_key_salt,
-        );
+        let user = User::new(res.id, res.username, res.role, content_key);
+        // Accounts created before key pairs existed get theirs once their content key is at hand
+        if !res.has_key_pair {
+            self.create_key_pair(&user).await?;
+        }
+        // Not sealing them again yet is no reason to refuse the login, but they would keep the
+        // legacy envelopes from ever being rejected, so administrators get to see it
+        if let Err(e) = self.legacy_envelopes.reseal_for_user(&user).await {
+            let recorded = self
+                .audit
+                .record(
+                    Some(user.id),
+                    AuditEventKind::EnvelopesNotResealed,
+                    Some(client),
+                    Some(e.clone()),
+                )
+                .await;
+            if recorded.is_err() {
+                eprintln!(
+                    "Could not seal the envelopes of user {} again: {}",
+                    user.id, e
+                );
+            }
+        }
         Ok(user)
     }
 
-    pub async fn add_user(&self, user: &LoginCredentials, role: UserRole) -> Result<User, &str> {
-        let (master_key, master_salt) = Self::get_master_key_and_salt(&user.password);
-        let (content_key, content_cypher, content_salt) =
-            Self::get_content_key_cypher_and_salt(&master_key);
-        let id = sqlx::query!(
-            "insert into users (username,role,password_hash,master_key_salt,content_key_encr,content_key_salt)
-            values ($1,$2,$3,$4,$5,$6)
-            returning id",
+    /// Gives the user the key pair that the keys of protected days are sealed to when they
+    /// become an editor of a calendar.
+    async fn create_key_pair(&self, user: &User) -> Result<(), String> {
+        let key_pair = KeyPair::generate();
+        let private_key_cypher = user.content_key_handler.encrypt(
+            &key_pair.private_key(),
+            &AssociatedData::private_key(user.id),
+        )?;
+        sqlx::query!(
+            "update users set public_key = $1, private_key_encr = $2
+            where id = $3 and public_key is null",
+            key_pair.public_key(),
+            private_key_cypher,
+            user.id
+        )
+        .execute(&self.db_pool)
+        .await
+        .map_err(|e| format!("There was an error updating the database: {:?}", e))
+        .map(|_| ())
+    }
+
+    /// Creates an account, redeeming an invitation if one is given. Returns the calendar the
+    /// invitation subscribed the new user to, if any. An account linked to an identity logs in
+    /// through the provider, its password only serves as the encryption passphrase.
+    /// Concurrent signups are kept apart by the database: usernames are unique, and an
+    /// invitation is only redeemed while it has uses left, in the transaction of the insert.
+    pub async fn add_user(
+        &self,
+        user: &LoginCredentials,
+        role: UserRole,
+        invitation: Option<&str>,
+        identity: Option<&OidcIdentity>,
+    ) -> Result<(User, Option<i32>), &str> {
+        // The content key envelope is bound to the id, so it is reserved before the insert
+        let id = sqlx::query_scalar!(
+            r#"select nextval(pg_get_serial_sequence('users', 'id'))::int as "id!""#
+        )
+        .fetch_one(&self.db_pool)
+        .await
+        .map_err(|_| "Database connection failed")?;
+        let mut content_key = Zeroizing::new(vec![0u8; 32]);
+        fill(&mut content_key[..]);
+        let content_cypher = self
+            .wrap_content_key(&user.password, id, &content_key)
+            .await
+            .map_err(|_| "Could not encrypt the content key")?;
+        let password_hash = self
+            .argon2_pool
+            .hash_password(&user.password)
+            .await
+            .map_err(|_| "Could not hash the password")?;
+
+        let mut tx = self
+            .db_pool
+            .begin()
+            .await
+            .map_err(|_| "Database connection failed")?;
+        let calendar_id = match invitation {
+            Some(code) => {
+                sqlx::query!(
+                    "update invitations set uses = uses + 1
+                    where code_hash = $1 and uses < max_uses and expires_at > now()
+                    returning calendar_id",
+                    InvitationCode::hash_input(code)
+                )
+                .fetch_optional(&mut *tx)
+                .await
+                .map_err(|_| "Database connection failed")?
+                .ok_or("This invitation code is invalid or has expired")?
+                .calendar_id
+            }
+            None => None,
+        };
+        sqlx::query!(
+            "insert into users (id,username,role,password_hash,content_key_encr)
+            values ($1,$2,$3,$4,$5)",
+            id,
             user.username,
             &role as &UserRole,
-            Self::hash_password(&user.password),
-            &master_salt,
+            password_hash,
             &content_cypher,
-            &content_salt,
         )
-            .fetch_one(&self.db_pool)
+        .execute(&mut *tx)
+        .await
+        .map_err(|_| "This username is taken")?;
+        if let Some(identity) = identity {
+            sqlx::query!(
+                "insert into oidc_identities (issuer, subject, user_id) values ($1, $2, $3)",
+                identity.issuer,
+                identity.subject,
+                id
+            )
+            .execute(&mut *tx)
+            .await
+            .map_err(|_| "This identity already has an account")?;
+        }
+        if let Some(calendar_id) = calendar_id {
+            sqlx::query!(
+                "insert into calendar_subscriptions (user_id, calendar_id) values ($1, $2)",
+                id,
+                calendar_id
+            )
+            .execute(&mut *tx)
+            .await
+            .map_err(|_| "Could not subscribe to the calendar of the invitation")?;
+        }
+        tx.commit()
             .await
-            .map_err(|_| "This username is taken")?.id;
+            .map_err(|_| "Database connection failed")?;
 
-        Ok(User::new(
-            id,
-            user.username.clone(),
-            role,
-            content_key.into(),
-            master_salt.into(),
-        ))
-    }
-
-    fn hash_password(password: &str) -> String {
-        let salt = SaltString::generate(&mut OsRng);
-        Argon2::default()
-            .hash_password(password.as_bytes(), &salt)
-            .unwrap()
-            .to_string()
-    }
-
-    fn get_master_key_and_salt(password: &str) -> ([u8; 32], [u8; 12]) {
-        let salt: [u8; 12] = random();
-        let mut master_key = [0u8; 32];
-        Argon2::default()
-            .hash_password_into(password.as_bytes(), &salt, &mut master_key)
-            .unwrap();
-        (master_key, salt)
+        let user = User::new(id, user.username.clone(), role, content_key);
+        self.create_key_pair(&user)
+            .await
+            .map_err(|_| "Could not create the key pair")?;
+        Ok((user, calendar_id))
     }
 
-    fn decrypt_content_key(
-        master_key: &[u8; 32],
-        cyphertext: &[u8],
-        salt: &[u8],
+    /// Wraps the content key with a master key derived from the password with a fresh salt.
+    async fn wrap_content_key(
+        &self,
+        password: &str,
+        user_id: i32,
+        content_key: &[u8],
     ) -> Result<Vec<u8>, String> {
-        let cypher = ChaCha20Poly1305::new(master_key.into())
-            .decrypt(salt.into(), cyphertext)
-            .map_err(|e| format!("Decryption failed, the key is probably outdated: {:?}", e))?;
-        Ok(cypher)
+        self.argon2_pool
+            .derive(password.as_bytes(), self.argon2_pool.kdf())
+            .await?
+            .encrypt(content_key, &AssociatedData::content_key(user_id))
     }
 
-    fn get_master_key_with_salt(password: &str, salt: &[u8]) -> [u8; 32] {
-        let mut master_key = [0u8; 32];
-        Argon2::default()
-            .hash_password_into(password.as_bytes(), &salt, &mut master_key)
-            .unwrap();
-        master_key
-    }
-
-    fn get_content_key_cypher_and_salt(master_key: &[u8; 32]) -> ([u8; 32], Vec<u8>, [u8; 12]) {
-        let content_key: [u8; 32] = random();
-        let salt: [u8; 12] = random();
-        let cypher = ChaCha20Poly1305::new(master_key.into())
-            .encrypt(&salt.into(), content_key.as_slice())
-            .unwrap();
-        (content_key, cypher, salt)
-    }
-
-    fn get_content_cypher_and_salt(
-        master_key: &[u8; 32],
-        content_key: &[u8],
-    ) -> (Vec<u8>, [u8; 12]) {
-        let salt: [u8; 12] = random();
-        let cypher = ChaCha20Poly1305::new(master_key.into())
-            .encrypt(&salt.into(), content_key)
-            .unwrap();
-        (cypher, salt)
+    /// Unwraps the content key, along with its envelope sealed again if it was outdated.
+    async fn unwrap_content_key(
+        &self,
+        password: &str,
+        user_id: i32,
+        content_key_encr: &[u8],
+    ) -> Result<(SecretBytes, Option<Vec<u8>>), String> {
+        self.argon2_pool
+            .for_envelope(password.as_bytes(), content_key_encr)
+            .await?
+            .decrypt_and_reseal(content_key_encr, &AssociatedData::content_key(user_id))
     }
 
     pub async fn change_password(
@@ -142,45 +341,831 @@ impl UserRepository {
         old_password: &str,
         new_password: &str,
     ) -> Result<(), String> {
-        let res = sqlx::query!(r#"select  id, username, role as "role:UserRole", password_hash, master_key_salt, content_key_salt, content_key_encr
-                                from users where username = ($1)"#, user.username)
-            .fetch_optional(&self.db_pool)
-            .await
-            .map_err(|_| "Database connection failed")?
-            .ok_or("Couldn't find this user")?;
-
-        Argon2::default()
-            .verify_password(
-                old_password.as_bytes(),
-                &PasswordHash::new(&res.password_hash)
-                    .map_err(|_| "Invalid password_hash from database")?,
-            )
-            .map_err(|_| "Invalid credentials")?;
+        let res = sqlx::query!(
+            r#"select  id, username, role as "role:UserRole", password_hash, content_key_encr
+                                from users where username = ($1)"#,
+            user.username
+        )
+        .fetch_optional(&self.db_pool)
+        .await
+        .map_err(|_| "Database connection failed")?
+        .ok_or("Couldn't find this user")?;
 
-        let master_key = Self::get_master_key_with_salt(&old_password, &res.master_key_salt);
-        let content_key =
-            Self::decrypt_content_key(&master_key, &res.content_key_encr, &res.content_key_salt)?;
+        self.argon2_pool
+            .verify_password(old_password, &res.password_hash)
+            .await?;
 
-        let (new_master, new_master_salt) = Self::get_master_key_and_salt(new_password);
-        let (content_cypher, content_salt) =
-            Self::get_content_cypher_and_salt(&new_master, &content_key);
+        let (content_key, _) = self
+            .unwrap_content_key(old_password, res.id, &res.content_key_encr)
+            .await?;
+        let content_cypher = self
+            .wrap_content_key(new_password, res.id, &content_key)
+            .await?;
 
         sqlx::query!(
             "update users set
                 password_hash = $1, 
-                master_key_salt = $2,
-                content_key_encr = $3,
-                content_key_salt = $4
-            where id = $5",
-            Self::hash_password(&new_password),
-            &new_master_salt,
+                content_key_encr = $2
+            where id = $3",
+            self.argon2_pool.hash_password(new_password).await?,
             &content_cypher,
-            &content_salt,
             user.id
         )
         .execute(&self.db_pool)
         .await
-        .map_err(|e| format!("There was an error updating the database: {:?}", e))
-        .map(|_| {})
+        .map_err(|e| format!("There was an error updating the database: {:?}", e))?;
+
+        self.audit
+            .record(Some(user.id), AuditEventKind::PasswordChanged, None, None)
+            .await
+    }
+
+    pub async fn get_accounts(&self) -> Result<Vec<UserAccount>, String> {
+        sqlx::query_as!(
+            UserAccount,
+            r#"
+            SELECT u.id, u.username, u.role as "role:UserRole", u.locked_at,
+                (SELECT count(*) FROM calendars c WHERE c.owner_id = u.id) as "owned_calendars!",
+                (SELECT count(*) FROM calendar_subscriptions cs WHERE cs.user_id = u.id) as "subscriptions!"
+            FROM users u
+            ORDER BY u.username
+            "#
+        )
+        .fetch_all(&self.db_pool)
+        .await
+        .map_err(|e| e.to_string())
+    }
+
+    pub async fn set_locked(&self, user_id: i32, locked: bool) -> Result<(), String> {
+        let result = sqlx::query!(
+            "update users set locked_at = case when $1 then coalesce(locked_at, now()) end where id = $2",
+            locked,
+            user_id
+        )
+        .execute(&self.db_pool)
+        .await
+        .map_err(|e| format!("There was an error updating the database: {:?}", e))?;
+
+        match result.rows_affected() {
+            0 => Err(format!("User {} not found", user_id)),
+            _ => Ok(()),
+        }
+    }
+
+    pub async fn set_role(&self, user_id: i32, role: UserRole) -> Result<(), String> {
+        let result = sqlx::query!(
+            "update users set role = $1 where id = $2",
+            &role as &UserRole,
+            user_id
+        )
+        .execute(&self.db_pool)
+        .await
+        .map_err(|e| format!("There was an error updating the database: {:?}", e))?;
+
+        match result.rows_affected() {
+            0 => Err(format!("User {} not found", user_id)),
+            _ => Ok(()),
+        }
+    }
+
+    pub async fn has_recovery_key(&self, user: &User) -> Result<bool, String> {
+        sqlx::query!(
+            r#"select recovery_key_encr is not null as "has_recovery_key!" from users where id = $1"#,
+            user.id
+        )
+        .fetch_one(&self.db_pool)
+        .await
+        .map(|record| record.has_recovery_key)
+        .map_err(|e| e.to_string())
+    }
+
+    /// Whether the user logs in through the identity provider rather than with their password.
+    pub async fn has_single_sign_on(&self, user: &User) -> Result<bool, String> {
+        sqlx::query!(
+            r#"select exists(select 1 from oidc_identities where user_id = $1) as "single_sign_on!""#,
+            user.id
+        )
+        .fetch_one(&self.db_pool)
+        .await
+        .map(|record| record.single_sign_on)
+        .map_err(|e| e.to_string())
+    }
+
+    /// Replaces the user's recovery key with a new one. Any previously issued key stops working.
+    pub async fn set_recovery_key(&self, user: &User) -> Result<RecoveryKey, String> {
+        let recovery_key = RecoveryKey::generate();
+        let recovery_cypher = user.content_key_handler.get_encrypted_key(
+            &recovery_key.key_handler()?,
+            &AssociatedData::recovery_key(user.id),
+        )?;
+
+        sqlx::query!(
+            "update users set recovery_key_encr = $1 where id = $2",
+            &recovery_cypher,
+            user.id
+        )
+        .execute(&self.db_pool)
+        .await
+        .map_err(|e| format!("There was an error updating the database: {:?}", e))?;
+
+        Ok(recovery_key)
+    }
+
+    /// Sets a new password using the recovery key to unwrap the content key. The recovery key
+    /// is used up in the process and a new one is returned in its place.
+    pub async fn recover_account(
+        &self,
+        username: &str,
+        recovery_key: &RecoveryKey,
+        new_password: &str,
+    ) -> Result<(User, RecoveryKey), String> {
+        let res = sqlx::query!(
+            r#"select id, username, role as "role:UserRole", locked_at, recovery_key_encr
+                                from users where username = ($1)"#,
+            username
+        )
+        .fetch_optional(&self.db_pool)
+        .await
+        .map_err(|_| "Database connection failed")?
+        .ok_or("Invalid username or recovery key")?;
+
+        let Some(recovery_cypher) = res.recovery_key_encr else {
+            return Err("Invalid username or recovery key".to_owned());
+        };
+        let content_key = recovery_key
+            .key_handler()?
+            .decrypt(&recovery_cypher, &AssociatedData::recovery_key(res.id))
+            .map_err(|_| "Invalid username or recovery key")?;
+
+        if res.locked_at.is_some() {
+            return Err("This account has been locked by an administrator".to_owned());
+        }
+
+        let content_cypher = self
+            .wrap_content_key(new_password, res.id, &content_key)
+            .await?;
+
+        let user = User::new(res.id, res.username, res.role, content_key);
+        let new_recovery_key = RecoveryKey::generate();
+        let new_recovery_cypher = user.content_key_handler.get_encrypted_key(
+            &new_recovery_key.key_handler()?,
+            &AssociatedData::recovery_key(user.id),
+        )?;
+
+        sqlx::query!(
+            "update users set
+                password_hash = $1,
+                content_key_encr = $2,
+                recovery_key_encr = $3
+            where id = $4",
+            self.argon2_pool.hash_password(new_password).await?,
+            &content_cypher,
+            &new_recovery_cypher,
+            user.id
+        )
+        .execute(&self.db_pool)
+        .await
+        .map_err(|e| format!("There was an error updating the database: {:?}", e))?;
+
+        self.audit
+            .record(Some(user.id), AuditEventKind::AccountRecovered, None, None)
+            .await?;
+        Ok((user, new_recovery_key))
+    }
+
+    /// Replaces the content key with a fresh one and re-encrypts everything it protects: the
+    /// keys of the unlocked days, the authenticator secret and the content key envelope itself,
+    /// still under the master key derived from `password`. The key pair is replaced as well,
+    /// and the day keys sealed to it are sealed again to the new one. The recovery key wraps the old
+    /// content key, so a new one is returned in its place if the user had one, and the API
+    /// tokens are revoked.
+    pub async fn rotate_content_key(
+        &self,
+        user: &User,
+        password: &str,
+    ) -> Result<(User, Option<RecoveryKey>), String> {
+        let mut tx = self.db_pool.begin().await.map_err(|e| e.to_string())?;
+        let res = sqlx::query!(
+            "select password_hash, content_key_encr, recovery_key_encr, totp_secret_encr,
+                private_key_encr
+            from users where id = $1 for update",
+            user.id
+        )
+        .fetch_one(&mut *tx)
+        .await
+        .map_err(|e| e.to_string())?;
+
+        self.argon2_pool
+            .verify_password(password, &res.password_hash)
+            .await?;
+
+        let master_key = self
+            .argon2_pool
+            .for_envelope(password.as_bytes(), &res.content_key_encr)
+            .await?;
+        let new_content_key = KeyHandler::from_random(32);
+        let content_cypher = new_content_key
+            .get_encrypted_key(&master_key, &AssociatedData::content_key(user.id))?;
+        let rotated = User {
+            content_key_handler: new_content_key,
+            ..user.clone()
+        };
+
+        let days = sqlx::query!(
+            "select day_id, day_key_encr as \"day_key_encr!\" from user_days
+            where user_id = $1 and day_key_encr is not null",
+            user.id
+        )
+        .fetch_all(&mut *tx)
+        .await
+        .map_err(|e| e.to_string())?;
+        for day in days {
+            let associated_data = AssociatedData::day_key(user.id, day.day_id);
+            let day_key = user
+                .content_key_handler
+                .decrypt(&day.day_key_encr, &associated_data)?;
+            let day_cypher = rotated
+                .content_key_handler
+                .encrypt(&day_key, &associated_data)?;
+            sqlx::query!(
+                "update user_days set day_key_encr = $1 where user_id = $2 and day_id = $3",
+                day_cypher,
+                user.id,
+                day.day_id
+            )
+            .execute(&mut *tx)
+            .await
+            .map_err(|e| e.to_string())?;
+        }
+
+        let totp_cypher = match res.totp_secret_encr {
+            Some(cypher) => {
+                let associated_data = AssociatedData::totp_secret(user.id);
+                let secret = user
+                    .content_key_handler
+                    .decrypt(&cypher, &associated_data)?;
+                Some(
+                    rotated
+                        .content_key_handler
+                        .encrypt(&secret, &associated_data)?,
+                )
+            }
+            None => None,
+        };
+
+        // The key pair is replaced too, and the day keys sealed to the old one follow it
+        let (public_key, private_key_cypher) = match res.private_key_encr {
+            Some(cypher) => {
+                let associated_data = AssociatedData::private_key(user.id);
+                let old_key_pair = KeyPair::from_private_key(
+                    &user
+                        .content_key_handler
+                        .decrypt(&cypher, &associated_data)?,
+                )?;
+                let key_pair = KeyPair::generate();
+                let sealed_days = sqlx::query!(
+                    "select day_id, day_key_sealed as \"day_key_sealed!\" from user_days
+                    where user_id = $1 and day_key_sealed is not null",
+                    user.id
+                )
+                .fetch_all(&mut *tx)
+                .await
+                .map_err(|e| e.to_string())?;
+                for day in sealed_days {
+                    let associated_data = AssociatedData::sealed_day_key(user.id, day.day_id);
+                    let day_key = old_key_pair.open(&day.day_key_sealed, &associated_data)?;
+                    sqlx::query!(
+                        "update user_days set day_key_sealed = $1 where user_id = $2 and day_id = $3",
+                        KeyPair::seal(&key_pair.public_key(), &day_key, &associated_data)?,
+                        user.id,
+                        day.day_id
+                    )
+                    .execute(&mut *tx)
+                    .await
+                    .map_err(|e| e.to_string())?;
+                }
+                (
+                    Some(key_pair.public_key()),
+                    Some(
+                        rotated
+                            .content_key_handler
+                            .encrypt(&key_pair.private_key(), &associated_data)?,
+                    ),
+                )
+            }
+            None => (None, None),
+        };
+
+        let new_recovery_key = res.recovery_key_encr.map(|_| RecoveryKey::generate());
+        let recovery_cypher = match &new_recovery_key {
+            Some(recovery_key) => Some(rotated.content_key_handler.get_encrypted_key(
+                &recovery_key.key_handler()?,
+                &AssociatedData::recovery_key(user.id),
+            )?),
+            None => None,
+        };
+
+        sqlx::query!(
+            "update users set
+                content_key_encr = $1,
+                totp_secret_encr = $2,
+                recovery_key_encr = $3,
+                public_key = $4,
+                private_key_encr = $5
+            where id = $6",
+            &content_cypher,
+            totp_cypher,
+            recovery_cypher,
+            public_key,
+            private_key_cypher,
+            user.id
+        )
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| format!("There was an error updating the database: {:?}", e))?;
+
+        // API tokens wrap the old content key, they can't follow
+        sqlx::query!("delete from api_tokens where user_id = $1", user.id)
+            .execute(&mut *tx)
+            .await
+            .map_err(|e| e.to_string())?;
+        tx.commit().await.map_err(|e| e.to_string())?;
+
+        self.audit
+            .record(Some(user.id), AuditEventKind::KeysRotated, None, None)
+            .await?;
+        Ok((rotated, new_recovery_key))
+    }
+
+    /// Deletes the account along with everything that references it, calendars included.
+    /// Calendars other people subscribed to are only deleted when `delete_shared_calendars`
+    /// says so, otherwise their titles are listed in the error. Their subscribers are left a
+    /// notice, as when a calendar is deleted on its own.
+    pub async fn delete_account(
+        &self,
+        user: &User,
+        password: &str,
+        delete_shared_calendars: bool,
+    ) -> Result<(), String> {
+        let mut tx = self.db_pool.begin().await.map_err(|e| e.to_string())?;
+        let res = sqlx::query!(
+            "select password_hash from users where id = $1 for update",
+            user.id
+        )
+        .fetch_one(&mut *tx)
+        .await
+        .map_err(|e| e.to_string())?;
+
+        self.argon2_pool
+            .verify_password(password, &res.password_hash)
+            .await?;
+
+        // The calendars they own along with others are left to the longest-standing of them
+        sqlx::query!(
+            "update calendars c set owner_id = (
+                select cm.user_id from calendar_members cm
+                where cm.calendar_id = c.id and cm.role = 'owner' and cm.user_id <> $1
+                order by cm.added_at, cm.user_id
+                limit 1
+            )
+            where owner_id = $1 and exists (
+                select 1 from calendar_members cm
+                where cm.calendar_id = c.id and cm.role = 'owner' and cm.user_id <> $1
+            )",
+            user.id
+        )
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| e.to_string())?;
+
+        let shared_calendars = sqlx::query_scalar!(
+            "select title from calendars c
+            where owner_id = $1 and exists (
+                select 1 from calendar_subscriptions cs
+                where cs.calendar_id = c.id and cs.user_id <> $1
+            )
+            order by created_at",
+            user.id
+        )
+        .fetch_all(&mut *tx)
+        .await
+        .map_err(|e| e.to_string())?;
+        if !shared_calendars.is_empty() && !delete_shared_calendars {
+            return Err(format!(
+                "Other people are subscribed to {}. Confirm that these calendars should be deleted with your account",
+                shared_calendars.join(", ")
+            ));
+        }
+        sqlx::query!(
+            r#"insert into notices (user_id, message)
+            select cs.user_id,
+                format('The calendar "%s" you subscribed to was deleted along with the account of its owner.', c.title)
+            from calendar_subscriptions cs
+            join calendars c on c.id = cs.calendar_id
+            where c.owner_id = $1 and cs.user_id <> $1"#,
+            user.id
+        )
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| e.to_string())?;
+
+        sqlx::query!("delete from users where id = $1", user.id)
+            .execute(&mut *tx)
+            .await
+            .map_err(|e| format!("There was an error updating the database: {:?}", e))?;
+        tx.commit().await.map_err(|e| e.to_string())
+    }
+
+    pub async fn is_two_factor_enabled(&self, user: &User) -> Result<bool, String> {
+        sqlx::query!(
+            r#"select totp_enabled_at is not null as "enabled!" from users where id = $1"#,
+            user.id
+        )
+        .fetch_one(&self.db_pool)
+        .await
+        .map(|record| record.enabled)
+        .map_err(|e| e.to_string())
+    }
+
+    pub async fn count_backup_codes(&self, user: &User) -> Result<i64, String> {
+        sqlx::query!(
+            r#"select count(*) as "count!" from user_backup_codes where user_id = $1 and used_at is null"#,
+            user.id
+        )
+        .fetch_one(&self.db_pool)
+        .await
+        .map(|record| record.count)
+        .map_err(|e| e.to_string())
+    }
+
+    async fn get_totp_secret(
+        &self,
+        user: &User,
+    ) -> Result<(TotpSecret, Option<DateTime<Utc>>), String> {
+        let record = sqlx::query!(
+            "select totp_secret_encr, totp_enabled_at from users where id = $1",
+            user.id
+        )
+        .fetch_one(&self.db_pool)
+        .await
+        .map_err(|e| e.to_string())?;
+
+        let Some(cypher) = record.totp_secret_encr else {
+            return Err("Two-factor authentication is not set up".to_owned());
+        };
+        let (secret, resealed) = user
+            .content_key_handler
+            .decrypt_and_reseal(&cypher, &AssociatedData::totp_secret(user.id))?;
+        if let Some(cypher) = resealed {
+            sqlx::query!(
+                "update users set totp_secret_encr = $1 where id = $2",
+                cypher,
+                user.id
+            )
+            .execute(&self.db_pool)
+            .await
+            .map_err(|e| format!("There was an error updating the database: {:?}", e))?;
+        }
+        Ok((TotpSecret::from_bytes(secret), record.totp_enabled_at))
+    }
+
+    /// Stores a new, unconfirmed authenticator secret. It only takes effect once
+    /// `enable_two_factor` receives a valid code for it.
+    pub async fn start_two_factor_enrolment(&self, user: &User) -> Result<TotpSecret, String> {
+        if self.is_two_factor_enabled(user).await? {
+            return Err("Two-factor authentication is already enabled".to_owned());
+        }
+
+        let secret = TotpSecret::generate();
+        let cypher = user
+            .content_key_handler
+            .encrypt(secret.as_bytes(), &AssociatedData::totp_secret(user.id))?;
+
+        sqlx::query!(
+            "update users set totp_secret_encr = $1, totp_enabled_at = null, totp_last_step = null
+            where id = $2",
+            &cypher,
+            user.id
+        )
+        .execute(&self.db_pool)
+        .await
+        .map_err(|e| format!("There was an error updating the database: {:?}", e))?;
+
+        Ok(secret)
+    }
+
+    pub async fn get_pending_totp_secret(&self, user: &User) -> Result<TotpSecret, String> {
+        match self.get_totp_secret(user).await? {
+            (secret, None) => Ok(secret),
+            (_, Some(_)) => Err("Two-factor authentication is already enabled".to_owned()),
+        }
+    }
+
+    pub async fn enable_two_factor(
+        &self,
+        user: &User,
+        code: &str,
+    ) -> Result<Vec<BackupCode>, String> {
+        let secret = self.get_pending_totp_secret(user).await?;
+        let Some(step) = secret.check(&user.username, code)? else {
+            return Err("This code is not valid, check your authenticator app's clock".to_owned());
+        };
+
+        let backup_codes = BackupCode::generate_set();
+        let hashes: Vec<String> = backup_codes.iter().map(BackupCode::hash).collect();
+
+        let mut tx = self.db_pool.begin().await.map_err(|e| e.to_string())?;
+        sqlx::query!(
+            "update users set totp_enabled_at = now(), totp_last_step = $2 where id = $1",
+            user.id,
+            step as i64
+        )
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| e.to_string())?;
+        sqlx::query!("delete from user_backup_codes where user_id = $1", user.id)
+            .execute(&mut *tx)
+            .await
+            .map_err(|e| e.to_string())?;
+        sqlx::query!(
+            "insert into user_backup_codes (user_id, code_hash) select $1, unnest($2::text[])",
+            user.id,
+            &hashes
+        )
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| e.to_string())?;
+        tx.commit().await.map_err(|e| e.to_string())?;
+
+        self.audit
+            .record(Some(user.id), AuditEventKind::TwoFactorEnabled, None, None)
+            .await?;
+        Ok(backup_codes)
+    }
+
+    /// Accepts either a current authenticator code or an unused backup code, which is then
+    /// marked as used. An authenticator code is only accepted once.
+    pub async fn verify_second_factor(&self, user: &User, code: &str) -> Result<(), String> {
+        let (secret, enabled_at) = self.get_totp_secret(user).await?;
+        if enabled_at.is_none() {
+            return Err("Two-factor authentication is not enabled".to_owned());
+        }
+        if let Some(step) = secret.check(&user.username, code)? {
+            // Checked and moved forward in one statement, so parallel replays can't both pass
+            let result = sqlx::query!(
+                "update users set totp_last_step = $2
+                where id = $1 and (totp_last_step is null or totp_last_step < $2)",
+                user.id,
+                step as i64
+            )
+            .execute(&self.db_pool)
+            .await
+            .map_err(|e| e.to_string())?;
+            return match result.rows_affected() {
+                0 => Err("This code was already used, wait for the next one".to_owned()),
+                _ => Ok(()),
+            };
+        }
+
+        let result = sqlx::query!(
+            "update user_backup_codes set used_at = now()
+            where user_id = $1 and code_hash = $2 and used_at is null",
+            user.id,
+            BackupCode::hash_input(code)
+        )
+        .execute(&self.db_pool)
+        .await
+        .map_err(|e| e.to_string())?;
+
+        match result.rows_affected() {
+            0 => Err("Invalid authentication code".to_owned()),
+            _ => Ok(()),
+        }
+    }
+
+    pub async fn disable_two_factor(&self, user: &User, code: &str) -> Result<(), String> {
+        self.verify_second_factor(user, code).await?;
+
+        let mut tx = self.db_pool.begin().await.map_err(|e| e.to_string())?;
+        sqlx::query!(
+            "update users set totp_secret_encr = null, totp_enabled_at = null, totp_last_step = null
+            where id = $1",
+            user.id
+        )
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| e.to_string())?;
+        sqlx::query!("delete from user_backup_codes where user_id = $1", user.id)
+            .execute(&mut *tx)
+            .await
+            .map_err(|e| e.to_string())?;
+        tx.commit().await.map_err(|e| e.to_string())?;
+
+        self.audit
+            .record(Some(user.id), AuditEventKind::TwoFactorDisabled, None, None)
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::calendar::CalendarRole;
+    use crate::persistence::calendar_repository::CalendarRepository;
+    use argon2::Params;
+    use totp_rs::{Algorithm, TOTP};
+
+    #[sqlx::test]
+    async fn deleting_an_owner_leaves_shared_calendars_to_the_other_owners(db_pool: PgPool) {
+        let params = Params::new(8, 1, 1, None).unwrap();
+        let argon2_pool = Argon2Pool::new(params.clone(), params, 1);
+        let users = UserRepository::new(db_pool.clone(), argon2_pool.clone());
+        let calendars = CalendarRepository::new(db_pool.clone(), argon2_pool);
+        let mut owners = Vec::new();
+        for username in ["alice", "bob"] {
+            let credentials = LoginCredentials {
+                username: String::from(username),
+                password: String::from("correct horse battery staple"),
+            };
+            let (user, _) = users
+                .add_user(&credentials, UserRole::Member, None, None)
+                .await
+                .unwrap();
+            owners.push(user);
+        }
+        let (alice, bob) = (&owners[0], &owners[1]);
+        let calendar = calendars.create_calendar(alice, "Advent").await.unwrap();
+        calendars
+            .add_member(&calendar, alice, "bob", CalendarRole::Owner)
+            .await
+            .unwrap();
+
+        users
+            .delete_account(alice, "correct horse battery staple", false)
+            .await
+            .unwrap();
+        let owner_id =
+            sqlx::query_scalar!("SELECT owner_id FROM calendars WHERE id = $1", calendar.id)
+                .fetch_one(&db_pool)
+                .await
+                .unwrap();
+        assert_eq!(owner_id, bob.id);
+    }
+
+    #[sqlx::test]
+    async fn rotating_keys_keeps_the_day_keys_sealed_to_the_old_key_pair(db_pool: PgPool) {
+        let params = Params::new(8, 1, 1, None).unwrap();
+        let argon2_pool = Argon2Pool::new(params.clone(), params, 1);
+        let users = UserRepository::new(db_pool.clone(), argon2_pool.clone());
+        let calendars = CalendarRepository::new(db_pool.clone(), argon2_pool);
+        let mut members = Vec::new();
+        for username in ["alice", "bob"] {
+            let credentials = LoginCredentials {
+                username: String::from(username),
+                password: String::from("correct horse battery staple"),
+            };
+            let (user, _) = users
+                .add_user(&credentials, UserRole::Member, None, None)
+                .await
+                .unwrap();
+            members.push(user);
+        }
+        let (alice, bob) = (&members[0], &members[1]);
+        let calendar = calendars.create_calendar(alice, "Advent").await.unwrap();
+        let user_calendar = calendars
+            .get_user_calendar(calendar.id, alice)
+            .await
+            .unwrap();
+        calendars
+            .add_day(
+                alice,
+                &user_calendar,
+                Utc::now(),
+                Some(String::from("day password")),
+                String::from("Behold"),
+            )
+            .await
+            .unwrap();
+        calendars
+            .add_member(&calendar, alice, "bob", CalendarRole::Editor)
+            .await
+            .unwrap();
+        let public_key = sqlx::query_scalar!("SELECT public_key FROM users WHERE id = $1", bob.id)
+            .fetch_one(&db_pool)
+            .await
+            .unwrap();
+
+        let (bob, _) = users
+            .rotate_content_key(bob, "correct horse battery staple")
+            .await
+            .unwrap();
+        let rotated_public_key =
+            sqlx::query_scalar!("SELECT public_key FROM users WHERE id = $1", bob.id)
+                .fetch_one(&db_pool)
+                .await
+                .unwrap();
+        assert_ne!(rotated_public_key, public_key);
+        let user_calendar = calendars
+            .get_user_calendar(calendar.id, &bob)
+            .await
+            .unwrap();
+        let day_id = sqlx::query_scalar!(
+            "SELECT id FROM calendar_days WHERE calendar_id = $1",
+            calendar.id
+        )
+        .fetch_one(&db_pool)
+        .await
+        .unwrap();
+        let user_day = calendars
+            .get_user_day_with_key(&user_calendar, day_id, &bob)
+            .await
+            .unwrap();
+        assert_eq!(calendars.get_content(&user_day).await.unwrap(), "Behold");
+    }
+
+    #[sqlx::test]
+    async fn deleting_an_account_tells_the_subscribers_of_its_calendars(db_pool: PgPool) {
+        let params = Params::new(8, 1, 1, None).unwrap();
+        let argon2_pool = Argon2Pool::new(params.clone(), params, 1);
+        let users = UserRepository::new(db_pool.clone(), argon2_pool.clone());
+        let calendars = CalendarRepository::new(db_pool.clone(), argon2_pool);
+        let mut accounts = Vec::new();
+        for username in ["alice", "bob"] {
+            let credentials = LoginCredentials {
+                username: String::from(username),
+                password: String::from("correct horse battery staple"),
+            };
+            let (user, _) = users
+                .add_user(&credentials, UserRole::Member, None, None)
+                .await
+                .unwrap();
+            accounts.push(user);
+        }
+        let (alice, bob) = (&accounts[0], &accounts[1]);
+        let calendar = calendars.create_calendar(alice, "Advent").await.unwrap();
+        calendars.subscribe(bob, &calendar).await.unwrap();
+
+        assert!(users
+            .delete_account(alice, "correct horse battery staple", false)
+            .await
+            .is_err());
+        users
+            .delete_account(alice, "correct horse battery staple", true)
+            .await
+            .unwrap();
+        let notices = sqlx::query_scalar!("SELECT message FROM notices WHERE user_id = $1", bob.id)
+            .fetch_all(&db_pool)
+            .await
+            .unwrap();
+        assert_eq!(
+            notices,
+            ["The calendar \"Advent\" you subscribed to was deleted along with the account of its owner."]
+        );
+    }
+
+    #[sqlx::test]
+    async fn authenticator_codes_are_only_accepted_once(db_pool: PgPool) {
+        let params = Params::new(8, 1, 1, None).unwrap();
+        let argon2_pool = Argon2Pool::new(params.clone(), params, 1);
+        let users = UserRepository::new(db_pool, argon2_pool);
+        let credentials = LoginCredentials {
+            username: String::from("alice"),
+            password: String::from("correct horse battery staple"),
+        };
+        let (alice, _) = users
+            .add_user(&credentials, UserRole::Member, None, None)
+            .await
+            .unwrap();
+        let secret = users.start_two_factor_enrolment(&alice).await.unwrap();
+        let totp = TOTP::new_unchecked(
+            Algorithm::SHA1,
+            6,
+            1,
+            30,
+            secret.as_bytes().to_vec(),
+            None,
+            String::new(),
+        );
+        let now = Utc::now().timestamp() as u64;
+        let code = totp.generate(now);
+
+        users.enable_two_factor(&alice, &code).await.unwrap();
+        assert!(users.verify_second_factor(&alice, &code).await.is_err());
+        // The next code is accepted ahead of time for drifting clocks, but only once, and
+        // the codes before it no longer are
+        let next_code = totp.generate(now + 30);
+        users
+            .verify_second_factor(&alice, &next_code)
+            .await
+            .unwrap();
+        assert!(users
+            .verify_second_factor(&alice, &next_code)
+            .await
+            .is_err());
+        let previous_code = totp.generate(now - 30);
+        assert!(users
+            .verify_second_factor(&alice, &previous_code)
+            .await
+            .is_err());
     }
 }
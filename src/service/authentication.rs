@@ -1,7 +1,10 @@
+use crate::model::api_token::ApiTokenScope;
+use crate::model::session::ClientInfo;
 use crate::model::user::User;
 use crate::AppState;
-use axum::extract::{FromRequestParts, OptionalFromRequestParts};
+use axum::extract::{ConnectInfo, FromRequestParts, OptionalFromRequestParts};
 use axum::extract::{Request, State};
+use axum::http::header::{AUTHORIZATION, USER_AGENT};
 use axum::http::request::Parts;
 use axum::http::uri::PathAndQuery;
 use axum::http::StatusCode;
@@ -10,6 +13,7 @@ use axum::response::{IntoResponse, Redirect, Response};
 use axum_extra::extract::cookie::{Cookie, Expiration, SameSite};
 use axum_extra::extract::CookieJar;
 use std::convert::Infallible;
+use std::net::SocketAddr;
 use uuid::Uuid;
 
 impl<S> OptionalFromRequestParts<S> for User
@@ -30,12 +34,49 @@ where
     type Rejection = StatusCode;
 
     async fn from_request_parts(parts: &mut Parts, _: &S) -> Result<Self, Self::Rejection> {
-        Ok(parts
+        parts
             .extensions
             .get::<Option<User>>()
             .cloned()
             .flatten()
-            .ok_or(StatusCode::UNAUTHORIZED)?)
+            .ok_or(StatusCode::UNAUTHORIZED)
+    }
+}
+
+impl FromRequestParts<AppState> for ClientInfo {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        let user_agent = parts
+            .headers
+            .get(USER_AGENT)
+            .and_then(|value| value.to_str().ok())
+            .map(|value| value.chars().take(512).collect());
+        // Behind a reverse proxy the peer is the proxy itself, so the client it reports is
+        // taken instead, but only from the proxies we were told to trust
+        let forwarded_for = parts
+            .headers
+            .get_all("x-forwarded-for")
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .collect::<Vec<_>>()
+            .join(",");
+        let ip_address = parts
+            .extensions
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(addr)| {
+                state
+                    .trusted_proxies
+                    .client_address(addr.ip(), &forwarded_for)
+                    .to_string()
+            });
+        Ok(ClientInfo {
+            user_agent,
+            ip_address,
+        })
     }
 }
 
@@ -78,15 +119,52 @@ pub async fn require_logged_in(
     }
 }
 
+pub async fn require_admin(
+    user: Option<User>,
+    request: Request,
+    next: Next,
+) -> Result<Response, StatusCode> {
+    match user {
+        Some(user) if user.is_admin() => Ok(next.run(request).await),
+        Some(_) => Err(StatusCode::FORBIDDEN),
+        None => Ok(Redirect::to("/login").into_response()),
+    }
+}
+
+/// The personal access token of a request, if it is sent as `Authorization: Bearer <token>`.
+pub fn bearer_token(request: &Request) -> Option<&str> {
+    request
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+}
+
 pub async fn authenticate_user(
     State(state): State<AppState>,
     mut jar: CookieJar,
     mut req: Request,
     next: Next,
 ) -> Result<(CookieJar, Response), StatusCode> {
+    // A request with a personal access token never falls back to the session cookie, since
+    // it skips the CSRF check
+    if let Some(token) = bearer_token(&req) {
+        let (user, scope) = state
+            .api_token_repository
+            .get_user(token)
+            .await
+            .ok_or(StatusCode::UNAUTHORIZED)?;
+        if !scope.allows(req.method()) {
+            return Err(StatusCode::FORBIDDEN);
+        }
+        req.extensions_mut().insert(Some(user));
+        req.extensions_mut().insert(scope);
+        return Ok((jar, next.run(req).await));
+    }
+
     if let Some(token) = jar.get("token").map(Cookie::value) {
-        let token = Uuid::parse_str(&token).map_err(|_| StatusCode::BAD_REQUEST)?;
-        if let Some(user) = state.session_store.write().await.get_user(token).await {
+        let token = Uuid::parse_str(token).map_err(|_| StatusCode::BAD_REQUEST)?;
+        if let Some(user) = state.session_store.get_user(token).await {
             req.extensions_mut().insert(Some(user));
         } else {
             jar = jar.remove(Cookie::from("token"));
@@ -95,3 +173,50 @@ pub async fn authenticate_user(
 
     Ok((jar, next.run(req).await))
 }
+
+/// Keeps the routes managing the account itself to browser sessions, out of reach of
+/// personal access tokens.
+pub async fn require_session(request: Request, next: Next) -> Result<Response, StatusCode> {
+    if request.extensions().get::<ApiTokenScope>().is_some() {
+        Err(StatusCode::FORBIDDEN)
+    } else {
+        Ok(next.run(request).await)
+    }
+}
+
+/// Only lets through paths on this site, so that a crafted `next` cookie can't send a user
+/// who just logged in to another origin.
+pub fn safe_redirect_target(target: &str) -> &str {
+    let same_origin = target.starts_with('/')
+        && !target.starts_with("//")
+        && !target.starts_with("/\\")
+        && !target.chars().any(char::is_control);
+    if same_origin {
+        target
+    } else {
+        "/"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn redirects_stay_on_this_site() {
+        for target in [
+            "//evil",
+            "/\\evil",
+            "https://evil",
+            "evil",
+            "/\nevil",
+            "/day\t1",
+            "/\u{7f}",
+        ] {
+            assert_eq!(safe_redirect_target(target), "/", "{:?}", target);
+        }
+        for target in ["/", "/calendars/3?day=2", "/calendars/3#day-2"] {
+            assert_eq!(safe_redirect_target(target), target);
+        }
+    }
+}
@@ -1,6 +1,13 @@
 use crate::model::app_state::AppState;
-use crate::model::calendar::{Calendar, RichUserCalendar, UserCalendar, UserDay};
+use crate::model::argon2_pool::Argon2Pool;
+use crate::model::audit::AuditEventKind;
+use crate::model::calendar::{
+    Calendar, CalendarDetails, CalendarMember, CalendarOverview, CalendarPermission, CalendarRole,
+    CalendarVisibility, RichUserCalendar, ShareToken, UserCalendar, UserDay,
+};
+use crate::model::export::{CalendarExport, DayExport};
 use crate::model::user::User;
+use crate::persistence::audit_repository::AuditRepository;
 use crate::persistence::calendar_repository::CalendarRepository;
 use axum::extract::{FromRequestParts, Path, Request, State};
 use axum::http::request::Parts;
@@ -16,6 +23,7 @@ use tokio::sync::{RwLock, RwLockReadGuard};
 #[derive(Clone)]
 pub struct CalendarService {
     repo: Arc<RwLock<CalendarRepository>>,
+    audit: AuditRepository,
 }
 impl CalendarService {
     async fn get_user_calendar(&self, cal_id: i32, user: &User) -> Result<UserCalendar, String> {
@@ -33,6 +41,33 @@ impl CalendarService {
             .get_user_day_with_key(user_calendar, day_id, user)
             .await
     }
+
+    /// Every change to a calendar goes through here: what a user may do depends on their role
+    /// among its members.
+    fn authorize(
+        user_calendar: &UserCalendar,
+        user: &User,
+        permission: CalendarPermission,
+    ) -> Result<(), String> {
+        if user_calendar.can(permission) {
+            Ok(())
+        } else {
+            Err(format!(
+                "user {} cannot {} calendar {}",
+                user.username,
+                permission.as_str(),
+                user_calendar.calendar.title
+            ))
+        }
+    }
+
+    /// The user in `calendars.owner_id` created the calendar, they stay one of its owners.
+    fn check_not_creator(user_calendar: &UserCalendar, member_id: i32) -> Result<(), String> {
+        if member_id == user_calendar.calendar.owner_id {
+            return Err("The creator of a calendar stays one of its owners".to_owned());
+        }
+        Ok(())
+    }
 }
 
 impl CalendarService {
@@ -43,12 +78,7 @@ impl CalendarService {
         user: &User,
         content: String,
     ) -> Result<(), String> {
-        if user.id != user_calendar.calendar.owner_id {
-            return Err(format!(
-                "user {} cannot is not the owner of day {}",
-                user.username, user_day.day.id
-            ));
-        }
+        Self::authorize(user_calendar, user, CalendarPermission::EditDays)?;
 
         self.get_repo().await.edit_content(user_day, content).await
     }
@@ -60,35 +90,42 @@ impl CalendarService {
         user: &User,
         password: Option<String>,
     ) -> Result<(), String> {
-        if user.id != user_calendar.calendar.owner_id {
-            return Err(format!(
-                "user {} cannot is not the owner of day {}",
-                user.username, user_day.day.id
-            ));
-        }
+        Self::authorize(user_calendar, user, CalendarPermission::EditDays)?;
 
-        match password {
+        let details = match password {
             Some(password) => {
                 if user_day.day.protected {
                     self.get_repo()
                         .await
                         .update_password(user_day, user, &password)
-                        .await
+                        .await?;
                 } else {
                     self.get_repo()
                         .await
                         .set_password(user_day, user, &password)
-                        .await
+                        .await?;
                 }
+                "set"
             }
             None => {
-                if user_day.day.protected {
-                    self.get_repo().await.remove_password(user_day).await
-                } else {
-                    Ok(())
+                if !user_day.day.protected {
+                    return Ok(());
                 }
+                self.get_repo().await.remove_password(user_day).await?;
+                "removed"
             }
-        }
+        };
+        self.audit
+            .record(
+                Some(user.id),
+                AuditEventKind::DayPasswordChanged,
+                None,
+                Some(format!(
+                    "Password {} on day {} of \"{}\"",
+                    details, user_day.day.id, user_calendar.calendar.title
+                )),
+            )
+            .await
     }
 
     pub async fn unlock_day(
@@ -127,24 +164,44 @@ impl CalendarService {
             ))
         }
     }
+    /// Whether the editors should set the password of a day again, so that its key is derived
+    /// with Argon2id instead of HKDF.
+    pub async fn needs_password_upgrade(
+        &self,
+        user_calendar: &UserCalendar,
+        user_day: &UserDay,
+        user: &User,
+    ) -> Result<bool, String> {
+        if Self::authorize(user_calendar, user, CalendarPermission::EditDays).is_err() {
+            return Ok(false);
+        }
+        self.get_repo().await.has_legacy_day_key(user_day).await
+    }
     pub async fn delete_day(
         &self,
         user_calendar: &UserCalendar,
         user_day: &UserDay,
         user: &User,
     ) -> Result<(), String> {
-        if user_calendar.calendar.owner_id == user.id {
-            self.get_repo().await.delete_day(user_day).await
-        } else {
-            Err(format!(
-                "user {} is not the owner of day {}",
-                user.id, user_day.day.id
-            ))
-        }
+        Self::authorize(user_calendar, user, CalendarPermission::EditDays)?;
+
+        self.get_repo().await.delete_day(user_day).await?;
+        self.audit
+            .record(
+                Some(user.id),
+                AuditEventKind::DayDeleted,
+                None,
+                Some(format!(
+                    "Day {} of \"{}\"",
+                    user_day.day.id, user_calendar.calendar.title
+                )),
+            )
+            .await
     }
-    pub fn new(pool: PgPool) -> Self {
+    pub fn new(pool: PgPool, argon2_pool: Argon2Pool) -> Self {
         Self {
-            repo: Arc::new(RwLock::new(CalendarRepository::new(pool))),
+            audit: AuditRepository::new(pool.clone()),
+            repo: Arc::new(RwLock::new(CalendarRepository::new(pool, argon2_pool))),
         }
     }
 
@@ -173,8 +230,290 @@ impl CalendarService {
             .await
     }
 
+    pub async fn unsubscribe(
+        &self,
+        user: &User,
+        user_calendar: &UserCalendar,
+        purge_history: bool,
+    ) -> Result<(), String> {
+        // The dashboard shows the calendars of a user through their subscriptions
+        if user_calendar.role == Some(CalendarRole::Owner) {
+            return Err(format!(
+                "user {} owns calendar {}, they can delete it instead",
+                user.username, user_calendar.calendar.title
+            ));
+        }
+
+        self.get_repo()
+            .await
+            .unsubscribe(user, &user_calendar.calendar, purge_history)
+            .await
+    }
+
+    /// Hides a subscription from the dashboard, or shows it again, keeping its progress.
+    pub async fn set_archived(
+        &self,
+        user: &User,
+        user_calendar: &UserCalendar,
+        archived: bool,
+    ) -> Result<(), String> {
+        if user_calendar.subscribed_at.is_none() {
+            return Err(format!(
+                "user {} is not subscribed to calendar {}",
+                user.username, user_calendar.calendar.title
+            ));
+        }
+
+        self.get_repo()
+            .await
+            .set_archived(user, &user_calendar.calendar, archived)
+            .await
+    }
+
+    pub async fn get_archived_subscriptions(
+        &self,
+        user: &User,
+    ) -> Result<Vec<UserCalendar>, String> {
+        self.get_repo().await.get_subscriptions(user, true).await
+    }
+
+    pub async fn get_calendar_overviews(
+        &self,
+        user: &User,
+    ) -> Result<Vec<CalendarOverview>, String> {
+        if !user.is_admin() {
+            return Err(format!("user {} is not an administrator", user.username));
+        }
+
+        self.get_repo().await.get_calendar_overviews().await
+    }
+
+    pub async fn take_down_calendar(&self, user: &User, calendar_id: i32) -> Result<(), String> {
+        if !user.is_admin() {
+            return Err(format!("user {} is not an administrator", user.username));
+        }
+
+        let repo = self.get_repo().await;
+        let user_calendar = repo.get_user_calendar(calendar_id, user).await?;
+        let notice = format!(
+            "The calendar \"{}\" was taken down by an administrator.",
+            user_calendar.calendar.title
+        );
+        repo.delete_calendar(calendar_id, user, &notice).await
+    }
+
+    pub async fn edit_calendar(
+        &self,
+        user_calendar: &UserCalendar,
+        user: &User,
+        details: &CalendarDetails,
+    ) -> Result<(), String> {
+        Self::authorize(user_calendar, user, CalendarPermission::Manage)?;
+
+        self.get_repo()
+            .await
+            .update_calendar(&user_calendar.calendar, details)
+            .await
+    }
+
+    pub async fn set_visibility(
+        &self,
+        user_calendar: &UserCalendar,
+        user: &User,
+        visibility: CalendarVisibility,
+    ) -> Result<(), String> {
+        Self::authorize(user_calendar, user, CalendarPermission::Manage)?;
+
+        self.get_repo()
+            .await
+            .set_visibility(&user_calendar.calendar, visibility)
+            .await
+    }
+
+    /// Creates a new share link for the calendar, replacing the previous one, and returns its
+    /// token.
+    pub async fn create_share_link(
+        &self,
+        user_calendar: &UserCalendar,
+        user: &User,
+    ) -> Result<ShareToken, String> {
+        Self::authorize(user_calendar, user, CalendarPermission::Manage)?;
+        if user_calendar.calendar.visibility == CalendarVisibility::Private {
+            return Err("Make the calendar unlisted or public before sharing it".to_owned());
+        }
+
+        let token = ShareToken::generate();
+        self.get_repo()
+            .await
+            .set_share_token(&user_calendar.calendar, Some(&token.hash()))
+            .await?;
+        Ok(token)
+    }
+
+    pub async fn revoke_share_link(
+        &self,
+        user_calendar: &UserCalendar,
+        user: &User,
+    ) -> Result<(), String> {
+        Self::authorize(user_calendar, user, CalendarPermission::Manage)?;
+
+        self.get_repo()
+            .await
+            .set_share_token(&user_calendar.calendar, None)
+            .await
+    }
+
+    pub async fn has_share_link(&self, user_calendar: &UserCalendar) -> Result<bool, String> {
+        self.get_repo()
+            .await
+            .has_share_link(&user_calendar.calendar)
+            .await
+    }
+
+    pub async fn get_shared_calendar(
+        &self,
+        share_token: &str,
+        user: &User,
+    ) -> Result<UserCalendar, String> {
+        self.get_repo()
+            .await
+            .get_shared_calendar(&ShareToken::hash_input(share_token), user)
+            .await
+    }
+
+    pub async fn delete_calendar(
+        &self,
+        user_calendar: &UserCalendar,
+        user: &User,
+    ) -> Result<(), String> {
+        Self::authorize(user_calendar, user, CalendarPermission::Manage)?;
+
+        let notice = format!(
+            "The calendar \"{}\" you subscribed to was deleted by its owner.",
+            user_calendar.calendar.title
+        );
+        self.get_repo()
+            .await
+            .delete_calendar(user_calendar.calendar.id, user, &notice)
+            .await
+    }
+
+    pub async fn get_members(
+        &self,
+        user_calendar: &UserCalendar,
+        user: &User,
+    ) -> Result<Vec<CalendarMember>, String> {
+        Self::authorize(user_calendar, user, CalendarPermission::Manage)?;
+
+        self.get_repo()
+            .await
+            .get_members(&user_calendar.calendar)
+            .await
+    }
+
+    /// Adds a member to the calendar. Returns how many protected days an editor could not be
+    /// given the key of, since the user adding them doesn't hold it either.
+    pub async fn add_member(
+        &self,
+        user_calendar: &UserCalendar,
+        user: &User,
+        username: &str,
+        role: CalendarRole,
+    ) -> Result<usize, String> {
+        Self::authorize(user_calendar, user, CalendarPermission::Manage)?;
+
+        self.get_repo()
+            .await
+            .add_member(&user_calendar.calendar, user, username.trim(), role)
+            .await
+    }
+
+    /// Changes the role of a member. Returns how many protected days they could not be given the
+    /// key of, like `add_member`.
+    pub async fn set_member_role(
+        &self,
+        user_calendar: &UserCalendar,
+        user: &User,
+        member_id: i32,
+        role: CalendarRole,
+    ) -> Result<usize, String> {
+        Self::authorize(user_calendar, user, CalendarPermission::Manage)?;
+        Self::check_not_creator(user_calendar, member_id)?;
+
+        self.get_repo()
+            .await
+            .set_member_role(&user_calendar.calendar, user, member_id, role)
+            .await
+    }
+
+    pub async fn remove_member(
+        &self,
+        user_calendar: &UserCalendar,
+        user: &User,
+        member_id: i32,
+    ) -> Result<(), String> {
+        Self::authorize(user_calendar, user, CalendarPermission::Manage)?;
+        Self::check_not_creator(user_calendar, member_id)?;
+
+        self.get_repo()
+            .await
+            .remove_member(&user_calendar.calendar, member_id)
+            .await
+    }
+
     pub async fn get_dashboard_data(&self, user: &User) -> Result<Vec<RichUserCalendar>, String> {
-        self.get_repo().await.get_dashboard_data(&user).await
+        self.get_repo().await.get_dashboard_data(user).await
+    }
+
+    /// Every calendar of the user with the days they can see, and the content of those they
+    /// unlocked decrypted with their content key.
+    pub async fn export_calendars(&self, user: &User) -> Result<Vec<CalendarExport>, String> {
+        let repo = self.get_repo().await;
+        let calendars = repo.get_user_calendars(user).await?;
+        let calendar_ids = calendars
+            .iter()
+            .map(|user_calendar| user_calendar.calendar.id)
+            .collect::<Vec<_>>();
+        let user_days = repo.get_user_days_without_key(&calendar_ids, user).await?;
+
+        let mut exports = Vec::with_capacity(calendars.len());
+        for user_calendar in calendars {
+            let mut days = vec![];
+            for user_day in user_days
+                .iter()
+                .filter(|user_day| user_day.day.calendar_id == user_calendar.calendar.id)
+            {
+                let content = if user_day.is_unlocked() {
+                    let user_day = repo
+                        .get_user_day_with_key(&user_calendar, user_day.day.id, user)
+                        .await?;
+                    Some(repo.get_content(&user_day).await?)
+                } else {
+                    None
+                };
+                days.push(DayExport {
+                    id: user_day.day.id,
+                    unlocks_at: user_day.day.unlocks_at,
+                    protected: user_day.day.protected,
+                    unlocked_at: user_day.unlocked_at,
+                    content,
+                });
+            }
+            exports.push(CalendarExport {
+                id: user_calendar.calendar.id,
+                title: user_calendar.calendar.title,
+                description: user_calendar.calendar.description,
+                cover_image_url: user_calendar.calendar.cover_image_url,
+                visibility: user_calendar.calendar.visibility,
+                created_at: user_calendar.calendar.created_at,
+                owned: user_calendar.calendar.owner_id == user.id,
+                role: user_calendar.role,
+                subscribed_at: user_calendar.subscribed_at,
+                archived_at: user_calendar.archived_at,
+                days,
+            });
+        }
+        Ok(exports)
     }
 
     pub async fn get_calendar_user_days(
@@ -196,12 +535,7 @@ impl CalendarService {
         password: Option<String>,
         content: String,
     ) -> Result<(), String> {
-        if user.id != user_calendar.calendar.owner_id {
-            return Err(format!(
-                "user {} is not the owner of calendar {}",
-                user.username, user_calendar.calendar.title
-            ));
-        }
+        Self::authorize(user_calendar, user, CalendarPermission::EditDays)?;
 
         self.get_repo()
             .await
@@ -243,11 +577,11 @@ where
     type Rejection = StatusCode;
 
     async fn from_request_parts(parts: &mut Parts, _: &S) -> Result<Self, Self::Rejection> {
-        Ok(parts
+        parts
             .extensions
             .get::<UserCalendar>()
             .cloned()
-            .ok_or(StatusCode::UNAUTHORIZED)?)
+            .ok_or(StatusCode::UNAUTHORIZED)
     }
 }
 
@@ -279,10 +613,10 @@ where
     type Rejection = StatusCode;
 
     async fn from_request_parts(parts: &mut Parts, _: &S) -> Result<Self, Self::Rejection> {
-        Ok(parts
+        parts
             .extensions
             .get::<UserDay>()
             .cloned()
-            .ok_or(StatusCode::UNAUTHORIZED)?)
+            .ok_or(StatusCode::UNAUTHORIZED)
     }
 }
@@ -0,0 +1,150 @@
+use crate::model::session::CsrfToken;
+use crate::service::authentication::bearer_token;
+use axum::body::{to_bytes, Body};
+use axum::extract::{FromRequestParts, Request};
+use axum::http::request::Parts;
+use axum::http::StatusCode;
+use axum::middleware::Next;
+use axum::response::Response;
+use axum_extra::extract::cookie::{Cookie, Expiration, SameSite};
+use axum_extra::extract::CookieJar;
+
+const CSRF_COOKIE: &str = "csrf";
+const CSRF_FIELD: &str = "csrf_token";
+/// Same as the default body limit of the `Form` extractor
+const MAX_FORM_SIZE: usize = 2 * 1024 * 1024;
+
+impl<S> FromRequestParts<S> for CsrfToken
+where
+    S: Send + Sync,
+{
+    type Rejection = StatusCode;
+
+    async fn from_request_parts(parts: &mut Parts, _: &S) -> Result<Self, Self::Rejection> {
+        parts
+            .extensions
+            .get::<CsrfToken>()
+            .cloned()
+            .ok_or(StatusCode::INTERNAL_SERVER_ERROR)
+    }
+}
+
+/// Hands out a CSRF token to every browser and rejects state-changing requests whose form
+/// doesn't carry it back, unless they authenticate with a personal access token.
+pub async fn verify_csrf(
+    jar: CookieJar,
+    request: Request,
+    next: Next,
+) -> Result<(CookieJar, Response), StatusCode> {
+    let (jar, token) = match jar.get(CSRF_COOKIE) {
+        Some(cookie) => {
+            let token = CsrfToken(cookie.value().to_owned());
+            (jar, token)
+        }
+        None => {
+            let token = CsrfToken(hex::encode(rand::random::<[u8; 32]>()));
+            (jar.add(get_csrf_cookie(&token)), token)
+        }
+    };
+
+    // Browsers don't attach a bearer token on their own, so it is proof enough
+    let mut request = if request.method().is_safe() || bearer_token(&request).is_some() {
+        request
+    } else {
+        check_form_token(request, &token).await?
+    };
+    request.extensions_mut().insert(token);
+    Ok((jar, next.run(request).await))
+}
+
+/// Reads the whole form to find the token, and puts it back for the handler
+async fn check_form_token(request: Request, token: &CsrfToken) -> Result<Request, StatusCode> {
+    let (parts, body) = request.into_parts();
+    let bytes = to_bytes(body, MAX_FORM_SIZE)
+        .await
+        .map_err(|_| StatusCode::PAYLOAD_TOO_LARGE)?;
+    let matches = form_urlencoded::parse(&bytes)
+        .find(|(name, _)| name == CSRF_FIELD)
+        .is_some_and(|(_, value)| value == token.0.as_str());
+    if !matches {
+        return Err(StatusCode::FORBIDDEN);
+    }
+    Ok(Request::from_parts(parts, Body::from(bytes)))
+}
+
+fn get_csrf_cookie(token: &CsrfToken) -> Cookie<'static> {
+    Cookie::build((CSRF_COOKIE, token.0.clone()))
+        .secure(true)
+        .http_only(true)
+        .expires(Expiration::Session)
+        .same_site(SameSite::Lax)
+        .path("/")
+        .build()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::header::{AUTHORIZATION, CONTENT_TYPE, COOKIE};
+    use axum::middleware::from_fn;
+    use axum::routing::post;
+    use axum::Router;
+    use tower::ServiceExt;
+
+    /// Posts a form to a handler behind the check, which echoes the form it received
+    async fn post_form(
+        cookie: Option<&str>,
+        bearer: Option<&str>,
+        form: &str,
+    ) -> (StatusCode, String) {
+        let app = Router::new()
+            .route("/", post(|form: String| async move { form }))
+            .layer(from_fn(verify_csrf));
+        let mut request =
+            Request::post("/").header(CONTENT_TYPE, "application/x-www-form-urlencoded");
+        if let Some(token) = cookie {
+            request = request.header(COOKIE, format!("{}={}", CSRF_COOKIE, token));
+        }
+        if let Some(token) = bearer {
+            request = request.header(AUTHORIZATION, format!("Bearer {}", token));
+        }
+        let response = app
+            .oneshot(request.body(Body::from(form.to_owned())).unwrap())
+            .await
+            .unwrap();
+        let status = response.status();
+        let body = to_bytes(response.into_body(), MAX_FORM_SIZE).await.unwrap();
+        (status, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    #[tokio::test]
+    async fn forms_without_the_token_of_the_cookie_are_forbidden() {
+        assert_eq!(
+            post_form(Some("abc"), None, "title=x").await.0,
+            StatusCode::FORBIDDEN
+        );
+        let mismatched = post_form(Some("abc"), None, "csrf_token=abd&title=x").await;
+        assert_eq!(mismatched.0, StatusCode::FORBIDDEN);
+        // A browser without the cookie gets a fresh token, which the form can't know yet
+        let no_cookie = post_form(None, None, "csrf_token=abc&title=x").await;
+        assert_eq!(no_cookie.0, StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn forms_carrying_the_token_of_the_cookie_reach_the_handler_whole() {
+        let form = "title=x&csrf_token=abc";
+        assert_eq!(
+            post_form(Some("abc"), None, form).await,
+            (StatusCode::OK, form.to_owned())
+        );
+    }
+
+    #[tokio::test]
+    async fn requests_with_a_bearer_token_skip_the_check() {
+        let form = "title=x";
+        assert_eq!(
+            post_form(None, Some("a token"), form).await,
+            (StatusCode::OK, form.to_owned())
+        );
+    }
+}
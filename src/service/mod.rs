@@ -1,2 +1,6 @@
 pub mod authentication;
 pub mod calendar_service;
+pub mod csrf;
+pub mod oidc;
+pub mod rate_limiter;
+pub mod session_reaper;
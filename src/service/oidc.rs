@@ -0,0 +1,270 @@
+use crate::model::oidc::{OidcFlow, OidcIdentity};
+use openidconnect::core::{CoreClient, CoreProviderMetadata, CoreResponseType};
+use openidconnect::{
+    reqwest, AuthenticationFlow, AuthorizationCode, ClientId, ClientSecret, CsrfToken,
+    EndpointMaybeSet, EndpointNotSet, EndpointSet, IssuerUrl, Nonce, PkceCodeChallenge,
+    PkceCodeVerifier, RedirectUrl, Scope, TokenResponse,
+};
+use subtle::ConstantTimeEq;
+
+type Client = CoreClient<
+    EndpointSet,
+    EndpointNotSet,
+    EndpointNotSet,
+    EndpointNotSet,
+    EndpointMaybeSet,
+    EndpointMaybeSet,
+>;
+
+/// Settings of the identity provider, from the `OIDC_*` variables.
+pub struct OidcConfig {
+    pub issuer_url: String,
+    pub client_id: String,
+    pub client_secret: Option<String>,
+    /// Where the provider sends users back, the `/login/oidc/callback` route of this site
+    pub redirect_url: String,
+}
+
+/// An OpenID Connect provider users can log in with instead of a local password, through the
+/// authorization code flow.
+#[derive(Clone)]
+pub struct OidcProvider {
+    client: Client,
+    http_client: reqwest::Client,
+}
+
+impl OidcProvider {
+    pub async fn discover(config: OidcConfig) -> Result<Self, String> {
+        // Following redirects would let the provider make us request arbitrary URLs
+        let http_client = reqwest::ClientBuilder::new()
+            .redirect(reqwest::redirect::Policy::none())
+            .build()
+            .map_err(|e| e.to_string())?;
+        let issuer_url = IssuerUrl::new(config.issuer_url).map_err(|e| e.to_string())?;
+        let metadata = CoreProviderMetadata::discover_async(issuer_url, &http_client)
+            .await
+            .map_err(|e| e.to_string())?;
+        let redirect_url = RedirectUrl::new(config.redirect_url).map_err(|e| e.to_string())?;
+        let client = CoreClient::from_provider_metadata(
+            metadata,
+            ClientId::new(config.client_id),
+            config.client_secret.map(ClientSecret::new),
+        )
+        .set_redirect_uri(redirect_url);
+
+        Ok(Self {
+            client,
+            http_client,
+        })
+    }
+
+    /// The URL of the provider's login page, along with what the callback needs to check
+    /// that the user comes back from this very request.
+    pub fn authorize(&self) -> (String, OidcFlow) {
+        let (pkce_challenge, pkce_verifier) = PkceCodeChallenge::new_random_sha256();
+        let (url, state, nonce) = self
+            .client
+            .authorize_url(
+                AuthenticationFlow::<CoreResponseType>::AuthorizationCode,
+                CsrfToken::new_random,
+                Nonce::new_random,
+            )
+            .add_scope(Scope::new(String::from("profile")))
+            .set_pkce_challenge(pkce_challenge)
+            .url();
+
+        let flow = OidcFlow {
+            state: state.into_secret(),
+            nonce: nonce.secret().to_owned(),
+            pkce_verifier: pkce_verifier.into_secret(),
+        };
+        (url.to_string(), flow)
+    }
+
+    /// Trades the code the provider sent the user back with for their verified identity.
+    pub async fn identify(
+        &self,
+        flow: OidcFlow,
+        code: String,
+        state: &str,
+    ) -> Result<OidcIdentity, String> {
+        // The state guards against forged callbacks, so it mustn't leak through timing
+        if !bool::from(state.as_bytes().ct_eq(flow.state.as_bytes())) {
+            return Err(String::from(
+                "This login didn't start here, please try again",
+            ));
+        }
+
+        let response = self
+            .client
+            .exchange_code(AuthorizationCode::new(code))
+            .map_err(|e| e.to_string())?
+            .set_pkce_verifier(PkceCodeVerifier::new(flow.pkce_verifier))
+            .request_async(&self.http_client)
+            .await
+            .map_err(|_| String::from("The identity provider refused this login"))?;
+        let id_token = response
+            .id_token()
+            .ok_or("The identity provider didn't say who you are")?;
+        let claims = id_token
+            .claims(&self.client.id_token_verifier(), &Nonce::new(flow.nonce))
+            .map_err(|_| String::from("The identity provider's answer could not be verified"))?;
+
+        Ok(OidcIdentity {
+            issuer: claims.issuer().to_string(),
+            subject: claims.subject().to_string(),
+            preferred_username: claims
+                .preferred_username()
+                .map(|username| username.to_string()),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::extract::State;
+    use axum::http::header::CONTENT_TYPE;
+    use axum::routing::{get, post};
+    use axum::Router;
+    use chrono::{Duration, Utc};
+    use openidconnect::core::{CoreHmacKey, CoreIdToken, CoreIdTokenClaims, CoreJwsSigningAlgorithm};
+    use openidconnect::{
+        Audience, EmptyAdditionalClaims, EndUserUsername, StandardClaims, SubjectIdentifier,
+    };
+    use std::sync::{Arc, Mutex};
+
+    const CLIENT_ID: &str = "advent-of-faith";
+    const CLIENT_SECRET: &str = "a secret shared with the mock issuer";
+
+    /// An identity provider on a local port, which signs its ID tokens with the client secret
+    /// and puts whatever nonce the test chose in them.
+    #[derive(Clone)]
+    struct MockIssuer {
+        issuer_url: String,
+        nonce: Arc<Mutex<String>>,
+    }
+
+    impl MockIssuer {
+        async fn start() -> Self {
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let issuer = MockIssuer {
+                issuer_url: format!("http://{}", listener.local_addr().unwrap()),
+                nonce: Arc::new(Mutex::new(String::new())),
+            };
+            let router = Router::new()
+                .route("/.well-known/openid-configuration", get(discovery))
+                .route("/jwks", get(|| async { json(String::from(r#"{"keys":[]}"#)) }))
+                .route("/token", post(token))
+                .with_state(issuer.clone());
+            tokio::spawn(async move { axum::serve(listener, router).await });
+            issuer
+        }
+
+        fn set_nonce(&self, nonce: &str) {
+            *self.nonce.lock().unwrap() = nonce.to_owned();
+        }
+
+        async fn provider(&self) -> OidcProvider {
+            OidcProvider::discover(OidcConfig {
+                issuer_url: self.issuer_url.clone(),
+                client_id: String::from(CLIENT_ID),
+                client_secret: Some(String::from(CLIENT_SECRET)),
+                redirect_url: String::from("http://localhost/login/oidc/callback"),
+            })
+            .await
+            .unwrap()
+        }
+    }
+
+    fn json(body: String) -> ([(axum::http::HeaderName, &'static str); 1], String) {
+        ([(CONTENT_TYPE, "application/json")], body)
+    }
+
+    async fn discovery(State(issuer): State<MockIssuer>) -> impl axum::response::IntoResponse {
+        let url = &issuer.issuer_url;
+        json(format!(
+            r#"{{
+                "issuer": "{url}",
+                "authorization_endpoint": "{url}/authorize",
+                "token_endpoint": "{url}/token",
+                "jwks_uri": "{url}/jwks",
+                "response_types_supported": ["code"],
+                "subject_types_supported": ["public"],
+                "id_token_signing_alg_values_supported": ["HS256"]
+            }}"#
+        ))
+    }
+
+    async fn token(State(issuer): State<MockIssuer>) -> impl axum::response::IntoResponse {
+        let nonce = issuer.nonce.lock().unwrap().clone();
+        let claims = CoreIdTokenClaims::new(
+            IssuerUrl::new(issuer.issuer_url.clone()).unwrap(),
+            vec![Audience::new(String::from(CLIENT_ID))],
+            Utc::now() + Duration::minutes(5),
+            Utc::now(),
+            StandardClaims::new(SubjectIdentifier::new(String::from("alice-subject")))
+                .set_preferred_username(Some(EndUserUsername::new(String::from("alice")))),
+            EmptyAdditionalClaims {},
+        )
+        .set_nonce(Some(Nonce::new(nonce)));
+        let id_token = CoreIdToken::new(
+            claims,
+            &CoreHmacKey::new(CLIENT_SECRET),
+            CoreJwsSigningAlgorithm::HmacSha256,
+            None,
+            None,
+        )
+        .unwrap();
+        json(format!(
+            r#"{{"access_token": "access", "token_type": "bearer", "id_token": "{}"}}"#,
+            id_token.to_string()
+        ))
+    }
+
+    #[tokio::test]
+    async fn callback_with_the_matching_state_and_nonce_identifies_the_user() {
+        let issuer = MockIssuer::start().await;
+        let provider = issuer.provider().await;
+        let (_, flow) = provider.authorize();
+        issuer.set_nonce(&flow.nonce);
+
+        let state = flow.state.clone();
+        let identity = provider
+            .identify(flow, String::from("code"), &state)
+            .await
+            .unwrap();
+        assert_eq!(identity.issuer, issuer.issuer_url);
+        assert_eq!(identity.subject, "alice-subject");
+        assert_eq!(identity.preferred_username.as_deref(), Some("alice"));
+    }
+
+    #[tokio::test]
+    async fn callback_with_another_state_is_refused() {
+        let issuer = MockIssuer::start().await;
+        let provider = issuer.provider().await;
+        let (_, flow) = provider.authorize();
+        issuer.set_nonce(&flow.nonce);
+
+        let (_, other_flow) = provider.authorize();
+        assert!(provider
+            .identify(flow, String::from("code"), &other_flow.state)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn id_token_with_another_nonce_is_refused() {
+        let issuer = MockIssuer::start().await;
+        let provider = issuer.provider().await;
+        let (_, flow) = provider.authorize();
+        let (_, other_flow) = provider.authorize();
+        issuer.set_nonce(&other_flow.nonce);
+
+        let state = flow.state.clone();
+        assert!(provider
+            .identify(flow, String::from("code"), &state)
+            .await
+            .is_err());
+    }
+}
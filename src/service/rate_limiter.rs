@@ -0,0 +1,162 @@
+use crate::persistence::attempt_repository::AttemptRepository;
+use chrono::{DateTime, TimeDelta, Utc};
+use sqlx::PgPool;
+use std::fmt::Display;
+
+/// Failures allowed under a key before it starts getting locked.
+const FREE_ATTEMPTS: i32 = 5;
+
+/// Lockout after the first failure past `FREE_ATTEMPTS`, doubled with every further failure.
+const BASE_LOCKOUT: TimeDelta = TimeDelta::seconds(2);
+
+const MAX_LOCKOUT: TimeDelta = TimeDelta::minutes(15);
+
+/// Failures are forgotten when there was none for this long.
+const RESET_AFTER: TimeDelta = TimeDelta::days(1);
+
+/// What attempts at guessing a secret are counted against.
+pub enum AttemptKey<'a> {
+    LoginUser(&'a str),
+    LoginIp(&'a str),
+    TwoFactor(i32),
+    RecoverUser(&'a str),
+    RecoverIp(&'a str),
+    UnlockDay { user_id: i32, day_id: i32 },
+    UnlockIp(&'a str),
+}
+
+impl AttemptKey<'_> {
+    fn is_shared(&self) -> bool {
+        matches!(
+            self,
+            AttemptKey::LoginIp(_) | AttemptKey::RecoverIp(_) | AttemptKey::UnlockIp(_)
+        )
+    }
+}
+
+impl Display for AttemptKey<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AttemptKey::LoginUser(username) => write!(f, "login:user:{}", username),
+            AttemptKey::LoginIp(ip) => write!(f, "login:ip:{}", ip),
+            AttemptKey::TwoFactor(user_id) => write!(f, "two-factor:user:{}", user_id),
+            AttemptKey::RecoverUser(username) => write!(f, "recover:user:{}", username),
+            AttemptKey::RecoverIp(ip) => write!(f, "recover:ip:{}", ip),
+            AttemptKey::UnlockDay { user_id, day_id } => {
+                write!(f, "unlock:day:{}:{}", user_id, day_id)
+            }
+            AttemptKey::UnlockIp(ip) => write!(f, "unlock:ip:{}", ip),
+        }
+    }
+}
+
+/// Slows down online guessing of passwords and codes. Every failure is counted under a set of
+/// keys, and once a key has more than `FREE_ATTEMPTS` failures in a row it is locked for a
+/// time that doubles with each further failure.
+#[derive(Clone)]
+pub struct RateLimiter {
+    repo: AttemptRepository,
+}
+
+impl RateLimiter {
+    pub fn new(db_pool: PgPool) -> Self {
+        Self {
+            repo: AttemptRepository::new(db_pool),
+        }
+    }
+
+    fn to_strings<K: Display>(keys: &[K]) -> Vec<String> {
+        keys.iter().map(|key| key.to_string()).collect()
+    }
+
+    fn lockout(failures: i32) -> Option<TimeDelta> {
+        if failures <= FREE_ATTEMPTS {
+            return None;
+        }
+        let doublings = (failures - FREE_ATTEMPTS - 1).min(16) as u32;
+        Some((BASE_LOCKOUT * 2i32.pow(doublings)).min(MAX_LOCKOUT))
+    }
+
+    /// Counts an attempt under the keys before the secret is checked, so that parallel
+    /// guesses can't all get past the limit. Fails with a message for the user, without
+    /// counting it, when any of the keys is locked.
+    pub async fn reserve(&self, keys: &[AttemptKey<'_>]) -> Result<(), String> {
+        let locked_until = self
+            .repo
+            .add_attempt(&Self::to_strings(keys), RESET_AFTER, Self::lockout)
+            .await?;
+        match locked_until {
+            Some(locked_until) => Err(Self::lockout_message(locked_until)),
+            None => Ok(()),
+        }
+    }
+
+    /// Forgets the failures under the keys of the account, and takes back the attempt counted
+    /// under the keys shared by several accounts, such as an IP address, which one successful
+    /// login should not clear. A failed attempt needs nothing more, `reserve` counted it.
+    pub async fn record_success(&self, keys: &[AttemptKey<'_>]) -> Result<(), String> {
+        let (shared, own): (Vec<_>, Vec<_>) = keys.iter().partition(|key| key.is_shared());
+        self.repo
+            .remove_attempt(&Self::to_strings(&shared), FREE_ATTEMPTS)
+            .await?;
+        self.repo.clear(&Self::to_strings(&own)).await
+    }
+
+    pub async fn purge_stale(&self) -> Result<u64, String> {
+        self.repo.purge_stale(RESET_AFTER).await
+    }
+
+    fn lockout_message(locked_until: DateTime<Utc>) -> String {
+        let seconds = ((locked_until - Utc::now()).num_milliseconds() + 999) / 1000;
+        let seconds = seconds.max(1);
+        let minutes = (seconds + 59) / 60;
+        let wait = match seconds {
+            1 => String::from("1 second"),
+            2..60 => format!("{} seconds", seconds),
+            _ if minutes == 1 => String::from("1 minute"),
+            _ => format!("{} minutes", minutes),
+        };
+        format!("Too many failed attempts, please try again in {}", wait)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[sqlx::test]
+    async fn parallel_guesses_share_the_free_attempts(db_pool: PgPool) {
+        let rate_limiter = RateLimiter::new(db_pool);
+        let started = Utc::now();
+        let guesses = (0..20).map(|_| {
+            let rate_limiter = rate_limiter.clone();
+            tokio::spawn(async move {
+                rate_limiter
+                    .reserve(&[AttemptKey::LoginUser("alice")])
+                    .await
+                    .is_ok()
+            })
+        });
+        let mut allowed = 0;
+        for guess in guesses.collect::<Vec<_>>() {
+            allowed += guess.await.unwrap() as i32;
+        }
+        let elapsed = Utc::now() - started;
+        // The attempt past the free ones goes through and locks the key for the others. On a
+        // slow machine a lockout can run out before they are all made, letting one more through.
+        let mut bound = FREE_ATTEMPTS + 1;
+        let mut locked = TimeDelta::zero();
+        while let Some(lockout) = RateLimiter::lockout(bound) {
+            locked += lockout;
+            if locked > elapsed {
+                break;
+            }
+            bound += 1;
+        }
+        assert!(
+            (FREE_ATTEMPTS + 1..=bound).contains(&allowed),
+            "{} guesses allowed",
+            allowed
+        );
+    }
+}
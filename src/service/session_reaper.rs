@@ -0,0 +1,61 @@
+use crate::model::app_state::AppState;
+use crate::model::crypto::{legacy_envelopes_rejected, reject_legacy_envelopes};
+use chrono::TimeDelta;
+use std::time::Duration;
+
+/// Periodically deletes expired sessions, so that their encrypted master keys don't stay in
+/// storage forever, along with failed login attempts which no longer matter. Sessions are kept
+/// for `retention` after they expire, audit events for `audit_retention` after they happened.
+/// Once no envelope sealed before row binding is left, those are refused from then on.
+pub fn spawn_session_reaper(
+    state: AppState,
+    interval: Duration,
+    retention: TimeDelta,
+    audit_retention: TimeDelta,
+) {
+    tokio::spawn(async move {
+        let mut ticks = tokio::time::interval(interval);
+        loop {
+            ticks.tick().await;
+            let purged = state
+                .session_store
+                .purge_expired_sessions(retention)
+                .await;
+            match purged {
+                Ok(0) => {}
+                Ok(count) => println!("Session reaper purged {} expired sessions", count),
+                Err(e) => eprintln!("Session reaper failed: {}", e),
+            }
+
+            match state.rate_limiter.purge_stale().await {
+                Ok(0) => {}
+                Ok(count) => println!("Session reaper purged {} stale login attempts", count),
+                Err(e) => eprintln!("Session reaper failed: {}", e),
+            }
+
+            match state
+                .audit_repository
+                .purge_older_than(audit_retention)
+                .await
+            {
+                Ok(0) => {}
+                Ok(count) => println!("Session reaper purged {} old audit events", count),
+                Err(e) => eprintln!("Session reaper failed: {}", e),
+            }
+
+            if !legacy_envelopes_rejected() {
+                match state.legacy_envelope_repository.count().await {
+                    Ok(0) => {
+                        reject_legacy_envelopes();
+                        println!("No envelope from before row binding is left, refusing them");
+                    }
+                    Ok(count) => println!(
+                        "{} rows still hold envelopes from before row binding, they are sealed again when their owners log in",
+                        count
+                    ),
+                    Err(e) => eprintln!("Session reaper failed: {}", e),
+                }
+            }
+        }
+    });
+}
@@ -0,0 +1,169 @@
+use crate::model::api_token::ApiToken;
+use crate::model::audit::AuditEvent;
+use crate::model::session::{ActiveSession, CsrfToken};
+use crate::model::two_factor::BackupCode;
+use crate::model::user::{RecoveryKey, User, UserView};
+use askama::Template;
+
+#[derive(Template)]
+#[template(path = "account/index.html")]
+pub struct AccountTemplate {
+    user: UserView,
+    has_recovery_key: bool,
+    two_factor_enabled: bool,
+    backup_codes_left: i64,
+    /// The password is then only the encryption passphrase
+    single_sign_on: bool,
+    message: Option<String>,
+    csrf_token: CsrfToken,
+}
+
+impl AccountTemplate {
+    pub fn new(
+        user: &User,
+        has_recovery_key: bool,
+        two_factor_enabled: bool,
+        backup_codes_left: i64,
+        single_sign_on: bool,
+        csrf_token: CsrfToken,
+    ) -> AccountTemplate {
+        AccountTemplate {
+            user: user.view(),
+            has_recovery_key,
+            two_factor_enabled,
+            backup_codes_left,
+            single_sign_on,
+            message: None,
+            csrf_token,
+        }
+    }
+
+    pub fn with_message(mut self, message: String) -> AccountTemplate {
+        self.message = Some(message);
+        self
+    }
+}
+
+#[derive(Template)]
+#[template(path = "account/recovery-key.html")]
+pub struct RecoveryKeyTemplate {
+    recovery_key: RecoveryKey,
+    next: String,
+}
+
+impl RecoveryKeyTemplate {
+    pub fn new(recovery_key: RecoveryKey, next: String) -> RecoveryKeyTemplate {
+        RecoveryKeyTemplate { recovery_key, next }
+    }
+}
+
+#[derive(Template)]
+#[template(path = "account/two-factor-setup.html")]
+pub struct TwoFactorSetupTemplate {
+    qr_code_svg: String,
+    secret: String,
+    message: Option<String>,
+    csrf_token: CsrfToken,
+}
+
+impl TwoFactorSetupTemplate {
+    pub fn new(
+        qr_code_svg: String,
+        secret: String,
+        csrf_token: CsrfToken,
+    ) -> TwoFactorSetupTemplate {
+        TwoFactorSetupTemplate {
+            qr_code_svg,
+            secret,
+            message: None,
+            csrf_token,
+        }
+    }
+
+    pub fn with_message(mut self, message: String) -> TwoFactorSetupTemplate {
+        self.message = Some(message);
+        self
+    }
+}
+
+#[derive(Template)]
+#[template(path = "account/backup-codes.html")]
+pub struct BackupCodesTemplate {
+    backup_codes: Vec<BackupCode>,
+}
+
+impl BackupCodesTemplate {
+    pub fn new(backup_codes: Vec<BackupCode>) -> BackupCodesTemplate {
+        BackupCodesTemplate { backup_codes }
+    }
+}
+
+#[derive(Template)]
+#[template(path = "account/sessions.html")]
+pub struct SessionsTemplate {
+    user: UserView,
+    sessions: Vec<ActiveSession>,
+    message: Option<String>,
+    csrf_token: CsrfToken,
+}
+
+impl SessionsTemplate {
+    pub fn new(
+        user: &User,
+        sessions: Vec<ActiveSession>,
+        csrf_token: CsrfToken,
+    ) -> SessionsTemplate {
+        SessionsTemplate {
+            user: user.view(),
+            sessions,
+            message: None,
+            csrf_token,
+        }
+    }
+
+    pub fn with_message(mut self, message: String) -> SessionsTemplate {
+        self.message = Some(message);
+        self
+    }
+}
+
+#[derive(Template)]
+#[template(path = "account/activity.html")]
+pub struct ActivityTemplate {
+    user: UserView,
+    events: Vec<AuditEvent>,
+}
+
+impl ActivityTemplate {
+    pub fn new(user: &User, events: Vec<AuditEvent>) -> ActivityTemplate {
+        ActivityTemplate {
+            user: user.view(),
+            events,
+        }
+    }
+}
+
+#[derive(Template)]
+#[template(path = "account/tokens.html")]
+pub struct ApiTokensTemplate {
+    user: UserView,
+    tokens: Vec<ApiToken>,
+    message: Option<String>,
+    csrf_token: CsrfToken,
+}
+
+impl ApiTokensTemplate {
+    pub fn new(user: &User, tokens: Vec<ApiToken>, csrf_token: CsrfToken) -> ApiTokensTemplate {
+        ApiTokensTemplate {
+            user: user.view(),
+            tokens,
+            message: None,
+            csrf_token,
+        }
+    }
+
+    pub fn with_message(mut self, message: String) -> ApiTokensTemplate {
+        self.message = Some(message);
+        self
+    }
+}
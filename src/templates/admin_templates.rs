@@ -0,0 +1,71 @@
+use crate::model::audit::{AuditEvent, AuditEventKind};
+use crate::model::calendar::CalendarOverview;
+use crate::model::invitation::Invitation;
+use crate::model::session::CsrfToken;
+use crate::model::user::{User, UserAccount, UserView};
+use askama::Template;
+
+#[derive(Template)]
+#[template(path = "admin/dashboard.html")]
+pub struct AdminDashboardTemplate {
+    user: UserView,
+    accounts: Vec<UserAccount>,
+    calendars: Vec<CalendarOverview>,
+    invitations: Vec<Invitation>,
+    message: Option<String>,
+    csrf_token: CsrfToken,
+}
+
+impl AdminDashboardTemplate {
+    pub fn new(
+        user: &User,
+        accounts: Vec<UserAccount>,
+        calendars: Vec<CalendarOverview>,
+        invitations: Vec<Invitation>,
+        csrf_token: CsrfToken,
+    ) -> AdminDashboardTemplate {
+        AdminDashboardTemplate {
+            user: user.view(),
+            accounts,
+            calendars,
+            invitations,
+            message: None,
+            csrf_token,
+        }
+    }
+
+    pub fn with_message(mut self, message: String) -> AdminDashboardTemplate {
+        self.message = Some(message);
+        self
+    }
+}
+
+#[derive(Template)]
+#[template(path = "admin/audit.html")]
+pub struct AuditLogTemplate {
+    user: UserView,
+    events: Vec<AuditEvent>,
+    kinds: [AuditEventKind; 11],
+    filter_user: String,
+    filter_kind: String,
+}
+
+impl AuditLogTemplate {
+    pub fn new(
+        user: &User,
+        events: Vec<AuditEvent>,
+        filter_user: Option<String>,
+        filter_kind: Option<AuditEventKind>,
+    ) -> AuditLogTemplate {
+        AuditLogTemplate {
+            user: user.view(),
+            events,
+            kinds: AuditEventKind::ALL,
+            filter_user: filter_user.unwrap_or_default(),
+            filter_kind: filter_kind
+                .map(|kind| kind.as_str())
+                .unwrap_or_default()
+                .to_owned(),
+        }
+    }
+}
@@ -1,3 +1,6 @@
+use crate::model::invitation::RegistrationMode;
+use crate::model::oidc::OidcLogin;
+use crate::model::session::CsrfToken;
 use crate::persistence::user_repository::LoginCredentials;
 use askama::Template;
 
@@ -10,56 +13,179 @@ struct CredentialStatusMessage {
 #[template(path = "authentication/login.html")]
 pub struct LoginTemplate {
     status_message: Option<CredentialStatusMessage>,
+    single_sign_on: bool,
+    csrf_token: CsrfToken,
 }
 impl LoginTemplate {
-    pub fn with_message(message: String, creds: LoginCredentials) -> Self {
+    pub fn with_message(message: String, creds: LoginCredentials, csrf_token: CsrfToken) -> Self {
         Self {
             status_message: Some(CredentialStatusMessage { message, creds }),
+            single_sign_on: false,
+            csrf_token,
         }
     }
 
-    pub fn empty() -> Self {
+    pub fn empty(csrf_token: CsrfToken) -> Self {
         Self {
             status_message: None,
+            single_sign_on: false,
+            csrf_token,
         }
     }
+
+    /// Offers to log in through the identity provider
+    pub fn with_single_sign_on(mut self, single_sign_on: bool) -> Self {
+        self.single_sign_on = single_sign_on;
+        self
+    }
 }
 
 #[derive(Template)]
 #[template(path = "authentication/signup.html")]
 pub struct SignupTemplate {
     status_message: Option<CredentialStatusMessage>,
+    registration_mode: RegistrationMode,
+    invitation: Option<String>,
+    csrf_token: CsrfToken,
 }
 
 impl SignupTemplate {
-    pub fn with_message(message: String, creds: LoginCredentials) -> Self {
+    pub fn with_message(
+        message: String,
+        creds: LoginCredentials,
+        registration_mode: RegistrationMode,
+        csrf_token: CsrfToken,
+    ) -> Self {
         Self {
             status_message: Some(CredentialStatusMessage { message, creds }),
+            registration_mode,
+            invitation: None,
+            csrf_token,
         }
     }
 
-    pub fn empty() -> Self {
+    pub fn empty(registration_mode: RegistrationMode, csrf_token: CsrfToken) -> Self {
         Self {
             status_message: None,
+            registration_mode,
+            invitation: None,
+            csrf_token,
         }
     }
+
+    pub fn with_invitation(mut self, invitation: Option<String>) -> Self {
+        self.invitation = invitation;
+        self
+    }
 }
 
 #[derive(Template)]
 #[template(path = "authentication/change-password.html")]
 pub struct ChangePassTemplate {
     status_message: Option<String>,
+    csrf_token: CsrfToken,
 }
 impl ChangePassTemplate {
-    pub fn with_message(message: String) -> Self {
+    pub fn with_message(message: String, csrf_token: CsrfToken) -> Self {
+        Self {
+            status_message: Some(message),
+            csrf_token,
+        }
+    }
+
+    pub fn empty(csrf_token: CsrfToken) -> Self {
+        Self {
+            status_message: None,
+            csrf_token,
+        }
+    }
+}
+
+#[derive(Template)]
+#[template(path = "authentication/recover.html")]
+pub struct RecoverTemplate {
+    status_message: Option<String>,
+    username: Option<String>,
+    csrf_token: CsrfToken,
+}
+impl RecoverTemplate {
+    pub fn with_message(message: String, username: String, csrf_token: CsrfToken) -> Self {
+        Self {
+            status_message: Some(message),
+            username: Some(username),
+            csrf_token,
+        }
+    }
+
+    pub fn empty(csrf_token: CsrfToken) -> Self {
+        Self {
+            status_message: None,
+            username: None,
+            csrf_token,
+        }
+    }
+}
+
+#[derive(Template)]
+#[template(path = "authentication/two-factor.html")]
+pub struct TwoFactorLoginTemplate {
+    status_message: Option<String>,
+    csrf_token: CsrfToken,
+}
+impl TwoFactorLoginTemplate {
+    pub fn with_message(message: String, csrf_token: CsrfToken) -> Self {
         Self {
             status_message: Some(message),
+            csrf_token,
+        }
+    }
+
+    pub fn empty(csrf_token: CsrfToken) -> Self {
+        Self {
+            status_message: None,
+            csrf_token,
         }
     }
+}
 
-    pub fn empty() -> Self {
+#[derive(Template)]
+#[template(path = "authentication/oidc-passphrase.html")]
+pub struct OidcPassphraseTemplate {
+    status_message: Option<String>,
+    /// The account linked to the identity, or none when it is the first login
+    account: Option<String>,
+    username: String,
+    registration_mode: RegistrationMode,
+    invitation: Option<String>,
+    csrf_token: CsrfToken,
+}
+impl OidcPassphraseTemplate {
+    pub fn new(
+        login: OidcLogin,
+        registration_mode: RegistrationMode,
+        csrf_token: CsrfToken,
+    ) -> Self {
         Self {
             status_message: None,
+            account: login.username,
+            username: login.preferred_username.unwrap_or_default(),
+            registration_mode,
+            invitation: None,
+            csrf_token,
+        }
+    }
+
+    pub fn with_message(
+        mut self,
+        message: String,
+        username: Option<String>,
+        invitation: Option<String>,
+    ) -> Self {
+        self.status_message = Some(message);
+        if let Some(username) = username {
+            self.username = username;
         }
+        self.invitation = invitation;
+        self
     }
 }
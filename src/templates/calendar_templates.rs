@@ -1,16 +1,100 @@
-use crate::model::calendar::{UserCalendar, UserDay};
-use crate::model::user::User;
+use crate::model::calendar::{
+    Calendar, CalendarMember, CalendarPermission, CalendarRole, CalendarVisibility, UserCalendar,
+    UserDay,
+};
+use crate::model::invitation::Invitation;
+use crate::model::session::CsrfToken;
 use askama::Template;
 
 #[derive(Template)]
 #[template(path = "calendar/create.html")]
 pub struct CreateCalendarTemplate {
     message: Option<String>,
+    csrf_token: CsrfToken,
 }
 
 impl CreateCalendarTemplate {
-    pub fn new(message: Option<String>) -> CreateCalendarTemplate {
-        CreateCalendarTemplate { message }
+    pub fn new(message: Option<String>, csrf_token: CsrfToken) -> CreateCalendarTemplate {
+        CreateCalendarTemplate {
+            message,
+            csrf_token,
+        }
+    }
+}
+
+#[derive(Template)]
+#[template(path = "calendar/edit.html")]
+pub struct EditCalendarTemplate {
+    calendar: Calendar,
+    message: Option<String>,
+    csrf_token: CsrfToken,
+}
+
+impl EditCalendarTemplate {
+    pub fn new(calendar: Calendar, csrf_token: CsrfToken) -> EditCalendarTemplate {
+        EditCalendarTemplate {
+            calendar,
+            message: None,
+            csrf_token,
+        }
+    }
+
+    pub fn with_message(mut self, message: String) -> EditCalendarTemplate {
+        self.message = Some(message);
+        self
+    }
+}
+
+#[derive(Template)]
+#[template(path = "calendar/delete.html")]
+pub struct DeleteCalendarTemplate {
+    calendar: Calendar,
+    message: Option<String>,
+    csrf_token: CsrfToken,
+}
+
+impl DeleteCalendarTemplate {
+    pub fn new(calendar: Calendar, csrf_token: CsrfToken) -> DeleteCalendarTemplate {
+        DeleteCalendarTemplate {
+            calendar,
+            message: None,
+            csrf_token,
+        }
+    }
+
+    pub fn with_message(mut self, message: String) -> DeleteCalendarTemplate {
+        self.message = Some(message);
+        self
+    }
+}
+
+/// What someone who was sent the share link of a calendar sees before subscribing to it.
+#[derive(Template)]
+#[template(path = "calendar/shared.html")]
+pub struct SharedCalendarTemplate {
+    calendar: Calendar,
+    share_token: String,
+    message: Option<String>,
+    csrf_token: CsrfToken,
+}
+
+impl SharedCalendarTemplate {
+    pub fn new(
+        calendar: Calendar,
+        share_token: String,
+        csrf_token: CsrfToken,
+    ) -> SharedCalendarTemplate {
+        SharedCalendarTemplate {
+            calendar,
+            share_token,
+            message: None,
+            csrf_token,
+        }
+    }
+
+    pub fn with_message(mut self, message: String) -> SharedCalendarTemplate {
+        self.message = Some(message);
+        self
     }
 }
 
@@ -19,21 +103,57 @@ impl CreateCalendarTemplate {
 pub struct ShowCalendarTemplate {
     user_calendar: UserCalendar,
     days: Vec<UserDay>,
-    user: User,
+    invitations: Vec<Invitation>,
+    visibilities: [CalendarVisibility; 3],
+    has_share_link: bool,
+    members: Vec<CalendarMember>,
+    roles: [CalendarRole; 3],
+    can_edit_days: bool,
+    can_manage: bool,
+    message: Option<String>,
+    csrf_token: CsrfToken,
 }
 
 impl ShowCalendarTemplate {
     pub fn new(
         user_calendar: UserCalendar,
         days: Vec<UserDay>,
-        user: User,
+        csrf_token: CsrfToken,
     ) -> ShowCalendarTemplate {
         ShowCalendarTemplate {
+            can_edit_days: user_calendar.can(CalendarPermission::EditDays),
+            can_manage: user_calendar.can(CalendarPermission::Manage),
             user_calendar,
             days,
-            user,
+            invitations: vec![],
+            visibilities: CalendarVisibility::ALL,
+            has_share_link: false,
+            members: vec![],
+            roles: CalendarRole::ALL,
+            message: None,
+            csrf_token,
         }
     }
+
+    pub fn with_invitations(mut self, invitations: Vec<Invitation>) -> ShowCalendarTemplate {
+        self.invitations = invitations;
+        self
+    }
+
+    pub fn with_share_link(mut self, has_share_link: bool) -> ShowCalendarTemplate {
+        self.has_share_link = has_share_link;
+        self
+    }
+
+    pub fn with_members(mut self, members: Vec<CalendarMember>) -> ShowCalendarTemplate {
+        self.members = members;
+        self
+    }
+
+    pub fn with_message(mut self, message: String) -> ShowCalendarTemplate {
+        self.message = Some(message);
+        self
+    }
 }
 
 #[derive(Template)]
@@ -42,22 +162,31 @@ pub struct ShowDayTemplate {
     user_day: UserDay,
     user_calendar: UserCalendar,
     content: String,
-    user: User,
+    can_edit_days: bool,
+    needs_password_upgrade: bool,
+    csrf_token: CsrfToken,
 }
 impl ShowDayTemplate {
     pub fn new(
         user_day: UserDay,
         user_calendar: UserCalendar,
         content: String,
-        user: User,
+        csrf_token: CsrfToken,
     ) -> ShowDayTemplate {
         ShowDayTemplate {
-            user_day,
+            user_day: user_day.without_key(),
+            can_edit_days: user_calendar.can(CalendarPermission::EditDays),
             user_calendar,
             content,
-            user,
+            needs_password_upgrade: false,
+            csrf_token,
         }
     }
+
+    pub fn with_password_upgrade(mut self, needs_password_upgrade: bool) -> ShowDayTemplate {
+        self.needs_password_upgrade = needs_password_upgrade;
+        self
+    }
 }
 
 #[derive(Template)]
@@ -66,14 +195,16 @@ pub struct UnlockDayTemplate {
     code: Option<String>,
     day: UserDay,
     message: Option<String>,
+    csrf_token: CsrfToken,
 }
 
 impl UnlockDayTemplate {
-    pub fn new(code: Option<String>, day: UserDay) -> UnlockDayTemplate {
+    pub fn new(code: Option<String>, day: UserDay, csrf_token: CsrfToken) -> UnlockDayTemplate {
         UnlockDayTemplate {
             code,
             day,
             message: None,
+            csrf_token,
         }
     }
 
@@ -0,0 +1,47 @@
+use crate::model::calendar::{RichUserCalendar, UserCalendar};
+use crate::model::notice::Notice;
+use crate::model::session::CsrfToken;
+use crate::model::user::{User, UserView};
+use askama::Template;
+
+#[derive(Template)]
+#[template(path = "home.html")]
+pub struct HelloTemplate {
+    user: UserView,
+    user_calendars: Vec<RichUserCalendar>,
+    archived_calendars: Vec<UserCalendar>,
+    notices: Vec<Notice>,
+    csrf_token: CsrfToken,
+}
+
+impl HelloTemplate {
+    pub fn new(
+        user: &User,
+        user_calendars: Vec<RichUserCalendar>,
+        archived_calendars: Vec<UserCalendar>,
+        notices: Vec<Notice>,
+        csrf_token: CsrfToken,
+    ) -> Self {
+        HelloTemplate {
+            user: user.view(),
+            user_calendars,
+            archived_calendars,
+            notices,
+            csrf_token,
+        }
+    }
+}
+
+#[derive(Template)]
+#[template(path = "welcome.html")]
+pub struct HomeTemplate {
+    user: Option<UserView>,
+}
+
+impl HomeTemplate {
+    pub fn with_user(maybe_user: Option<&User>) -> Self {
+        Self {
+            user: maybe_user.map(User::view),
+        }
+    }
+}
@@ -1,3 +1,5 @@
+pub mod account_templates;
+pub mod admin_templates;
 pub mod authentication_templates;
 pub mod calendar_templates;
-pub mod templates;
+pub mod home_templates;
@@ -0,0 +1,481 @@
+use crate::model::api_token::ApiTokenScope;
+use crate::model::app_state::AppState;
+use crate::model::export::{AccountDetails, AccountExport};
+use crate::model::session::CsrfToken;
+use crate::model::two_factor::TotpSecret;
+use crate::model::user::User;
+use crate::templates::account_templates::{
+    AccountTemplate, ActivityTemplate, ApiTokensTemplate, BackupCodesTemplate, RecoveryKeyTemplate,
+    SessionsTemplate, TwoFactorSetupTemplate,
+};
+use askama::Template;
+use axum::extract::{Path, State};
+use axum::http::header::{CACHE_CONTROL, CONTENT_DISPOSITION};
+use axum::http::StatusCode;
+use axum::response::{Html, IntoResponse, Redirect, Response};
+use axum::{Form, Json};
+use axum_extra::extract::cookie::Cookie;
+use axum_extra::extract::CookieJar;
+use chrono::Utc;
+use serde::Deserialize;
+use uuid::Uuid;
+
+async fn render_account(
+    state: &AppState,
+    user: User,
+    message: Option<String>,
+    csrf_token: CsrfToken,
+) -> Result<Response, StatusCode> {
+    let user_repository = state.user_repository.read().await;
+    let has_recovery_key = user_repository
+        .has_recovery_key(&user)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let two_factor_enabled = user_repository
+        .is_two_factor_enabled(&user)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let backup_codes_left = user_repository
+        .count_backup_codes(&user)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let single_sign_on = user_repository
+        .has_single_sign_on(&user)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let template = AccountTemplate::new(
+        &user,
+        has_recovery_key,
+        two_factor_enabled,
+        backup_codes_left,
+        single_sign_on,
+        csrf_token,
+    );
+    let template = match message {
+        Some(message) => template.with_message(message),
+        None => template,
+    };
+
+    template
+        .render()
+        .map(|v| Html(v).into_response())
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+pub async fn account_get(
+    user: User,
+    State(state): State<AppState>,
+    csrf_token: CsrfToken,
+) -> Result<Response, StatusCode> {
+    render_account(&state, user, None, csrf_token).await
+}
+
+pub async fn recovery_key_post(
+    user: User,
+    State(state): State<AppState>,
+) -> Result<Response, StatusCode> {
+    let recovery_key = state
+        .user_repository
+        .read()
+        .await
+        .set_recovery_key(&user)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    RecoveryKeyTemplate::new(recovery_key, String::from("/account"))
+        .render()
+        .map(|v| Html(v).into_response())
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+#[derive(Deserialize)]
+pub struct RotateKeysForm {
+    password: String,
+}
+pub async fn rotate_keys_post(
+    user: User,
+    jar: CookieJar,
+    State(state): State<AppState>,
+    csrf_token: CsrfToken,
+    Form(form): Form<RotateKeysForm>,
+) -> Result<Response, StatusCode> {
+    let current = get_current_token(&jar)?;
+    let rotated = state
+        .user_repository
+        .read()
+        .await
+        .rotate_content_key(&user, &form.password)
+        .await;
+    let (user, recovery_key) = match rotated {
+        Ok(rotated) => rotated,
+        Err(message) => return render_account(&state, user, Some(message), csrf_token).await,
+    };
+
+    // Other sessions hold the master key, which still unwraps the new content key
+    let session_store = &state.session_store;
+    session_store
+        .revoke_other_sessions(user.id, current)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    session_store.evict_user(user.id);
+
+    match recovery_key {
+        Some(recovery_key) => RecoveryKeyTemplate::new(recovery_key, String::from("/account"))
+            .render()
+            .map(|v| Html(v).into_response())
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR),
+        None => {
+            let message = String::from(
+                "Your keys were replaced, your other sessions were logged out and your API tokens were revoked",
+            );
+            render_account(&state, user, Some(message), csrf_token).await
+        }
+    }
+}
+
+pub async fn export_post(
+    user: User,
+    State(state): State<AppState>,
+) -> Result<Response, StatusCode> {
+    let user_repository = state.user_repository.read().await;
+    let account = AccountDetails {
+        username: user.username.clone(),
+        role: user.role.clone(),
+        has_recovery_key: user_repository
+            .has_recovery_key(&user)
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?,
+        two_factor_enabled: user_repository
+            .is_two_factor_enabled(&user)
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?,
+    };
+    drop(user_repository);
+
+    let calendars = state
+        .calendar_service
+        .export_calendars(&user)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let export = AccountExport {
+        exported_at: Utc::now(),
+        account,
+        calendars,
+    };
+
+    let headers = [
+        (
+            CONTENT_DISPOSITION,
+            "attachment; filename=\"advent-of-faith-export.json\"",
+        ),
+        (CACHE_CONTROL, "no-store"),
+    ];
+    Ok((headers, Json(export)).into_response())
+}
+
+#[derive(Deserialize)]
+pub struct DeleteAccountForm {
+    password: String,
+    delete_shared_calendars: Option<String>,
+}
+pub async fn delete_account_post(
+    user: User,
+    jar: CookieJar,
+    State(state): State<AppState>,
+    csrf_token: CsrfToken,
+    Form(form): Form<DeleteAccountForm>,
+) -> Result<(CookieJar, Response), StatusCode> {
+    let deleted = state
+        .user_repository
+        .read()
+        .await
+        .delete_account(
+            &user,
+            &form.password,
+            form.delete_shared_calendars.is_some(),
+        )
+        .await;
+    if let Err(message) = deleted {
+        let response = render_account(&state, user, Some(message), csrf_token).await?;
+        return Ok((jar, response));
+    }
+
+    // The database sessions went with the account, but not those of another backend
+    let session_store = &state.session_store;
+    session_store
+        .expire_user_sessions(user.id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    session_store.evict_user(user.id);
+
+    let jar = jar.remove(Cookie::from("token"));
+    Ok((jar, Redirect::to("/").into_response()))
+}
+
+fn render_two_factor_setup(
+    user: &User,
+    secret: &TotpSecret,
+    message: Option<String>,
+    csrf_token: CsrfToken,
+) -> Result<Response, StatusCode> {
+    let qr_code_svg = secret
+        .qr_code_svg(&user.username)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let encoded = secret
+        .encoded(&user.username)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let template = TwoFactorSetupTemplate::new(qr_code_svg, encoded, csrf_token);
+    let template = match message {
+        Some(message) => template.with_message(message),
+        None => template,
+    };
+
+    template
+        .render()
+        .map(|v| Html(v).into_response())
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+pub async fn two_factor_setup_post(
+    user: User,
+    State(state): State<AppState>,
+    csrf_token: CsrfToken,
+) -> Result<Response, StatusCode> {
+    let secret = state
+        .user_repository
+        .read()
+        .await
+        .start_two_factor_enrolment(&user)
+        .await;
+
+    match secret {
+        Ok(secret) => render_two_factor_setup(&user, &secret, None, csrf_token),
+        Err(message) => render_account(&state, user, Some(message), csrf_token).await,
+    }
+}
+
+#[derive(Deserialize)]
+pub struct TwoFactorCodeForm {
+    code: String,
+}
+pub async fn two_factor_enable_post(
+    user: User,
+    State(state): State<AppState>,
+    csrf_token: CsrfToken,
+    Form(form): Form<TwoFactorCodeForm>,
+) -> Result<Response, StatusCode> {
+    let user_repository = state.user_repository.read().await;
+    match user_repository.enable_two_factor(&user, &form.code).await {
+        Ok(backup_codes) => BackupCodesTemplate::new(backup_codes)
+            .render()
+            .map(|v| Html(v).into_response())
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR),
+        Err(message) => match user_repository.get_pending_totp_secret(&user).await {
+            Ok(secret) => render_two_factor_setup(&user, &secret, Some(message), csrf_token),
+            Err(message) => render_account(&state, user, Some(message), csrf_token).await,
+        },
+    }
+}
+
+pub async fn two_factor_disable_post(
+    user: User,
+    State(state): State<AppState>,
+    csrf_token: CsrfToken,
+    Form(form): Form<TwoFactorCodeForm>,
+) -> Result<Response, StatusCode> {
+    let result = state
+        .user_repository
+        .read()
+        .await
+        .disable_two_factor(&user, &form.code)
+        .await;
+
+    match result {
+        Ok(()) => Ok(Redirect::to("/account").into_response()),
+        Err(message) => render_account(&state, user, Some(message), csrf_token).await,
+    }
+}
+
+fn get_current_token(jar: &CookieJar) -> Result<Uuid, StatusCode> {
+    let token = jar.get("token").ok_or(StatusCode::BAD_REQUEST)?.value();
+    Uuid::parse_str(token).map_err(|_| StatusCode::BAD_REQUEST)
+}
+
+async fn render_sessions(
+    state: &AppState,
+    user: User,
+    current: Uuid,
+    message: Option<String>,
+    csrf_token: CsrfToken,
+) -> Result<Response, StatusCode> {
+    let sessions = state
+        .session_store
+        .get_active_sessions(user.id, current)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let template = SessionsTemplate::new(&user, sessions, csrf_token);
+    let template = match message {
+        Some(message) => template.with_message(message),
+        None => template,
+    };
+
+    template
+        .render()
+        .map(|v| Html(v).into_response())
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+pub async fn sessions_get(
+    user: User,
+    jar: CookieJar,
+    State(state): State<AppState>,
+    csrf_token: CsrfToken,
+) -> Result<Response, StatusCode> {
+    let current = get_current_token(&jar)?;
+    render_sessions(&state, user, current, None, csrf_token).await
+}
+
+#[derive(Deserialize)]
+pub struct SessionPath {
+    session_id: Uuid,
+}
+pub async fn revoke_session_post(
+    user: User,
+    jar: CookieJar,
+    State(state): State<AppState>,
+    Path(path): Path<SessionPath>,
+    csrf_token: CsrfToken,
+) -> Result<Response, StatusCode> {
+    let current = get_current_token(&jar)?;
+    let result = state
+        .session_store
+        .revoke_session(user.id, path.session_id)
+        .await;
+
+    match result {
+        Ok(()) => Ok(Redirect::to("/account/sessions").into_response()),
+        Err(message) => render_sessions(&state, user, current, Some(message), csrf_token).await,
+    }
+}
+
+pub async fn revoke_other_sessions_post(
+    user: User,
+    jar: CookieJar,
+    State(state): State<AppState>,
+    csrf_token: CsrfToken,
+) -> Result<Response, StatusCode> {
+    let current = get_current_token(&jar)?;
+    let result = state
+        .session_store
+        .revoke_other_sessions(user.id, current)
+        .await;
+
+    let message = match result {
+        Ok(0) => String::from("There were no other sessions to log out"),
+        Ok(1) => String::from("Logged out 1 other session"),
+        Ok(count) => format!("Logged out {} other sessions", count),
+        Err(message) => message,
+    };
+    render_sessions(&state, user, current, Some(message), csrf_token).await
+}
+
+async fn render_tokens(
+    state: &AppState,
+    user: User,
+    message: Option<String>,
+    csrf_token: CsrfToken,
+) -> Result<Response, StatusCode> {
+    let tokens = state
+        .api_token_repository
+        .get_tokens(user.id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let template = ApiTokensTemplate::new(&user, tokens, csrf_token);
+    let template = match message {
+        Some(message) => template.with_message(message),
+        None => template,
+    };
+
+    template
+        .render()
+        .map(|v| Html(v).into_response())
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+pub async fn activity_get(
+    user: User,
+    State(state): State<AppState>,
+) -> Result<Response, StatusCode> {
+    let events = state
+        .audit_repository
+        .get_user_events(user.id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    ActivityTemplate::new(&user, events)
+        .render()
+        .map(|v| Html(v).into_response())
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+pub async fn tokens_get(
+    user: User,
+    State(state): State<AppState>,
+    csrf_token: CsrfToken,
+) -> Result<Response, StatusCode> {
+    render_tokens(&state, user, None, csrf_token).await
+}
+
+#[derive(Deserialize)]
+pub struct CreateTokenForm {
+    name: String,
+    scope: ApiTokenScope,
+    /// Left empty for a token that lives until it is revoked
+    valid_days: String,
+}
+pub async fn create_token_post(
+    user: User,
+    State(state): State<AppState>,
+    csrf_token: CsrfToken,
+    Form(form): Form<CreateTokenForm>,
+) -> Result<Response, StatusCode> {
+    let valid_days = match form.valid_days.trim() {
+        "" => None,
+        days => Some(days.parse::<i64>().map_err(|_| StatusCode::BAD_REQUEST)?),
+    };
+    let result = state
+        .api_token_repository
+        .create_token(&user, &form.name, form.scope, valid_days)
+        .await;
+
+    let message = match result {
+        Ok(secret) => format!("New token: {secret}. Copy it now, it won't be shown again"),
+        Err(message) => message,
+    };
+    render_tokens(&state, user, Some(message), csrf_token).await
+}
+
+#[derive(Deserialize)]
+pub struct TokenPath {
+    token_id: Uuid,
+}
+pub async fn revoke_token_post(
+    user: User,
+    State(state): State<AppState>,
+    Path(path): Path<TokenPath>,
+    csrf_token: CsrfToken,
+) -> Result<Response, StatusCode> {
+    let result = state
+        .api_token_repository
+        .revoke_token(user.id, path.token_id)
+        .await;
+
+    match result {
+        Ok(()) => Ok(Redirect::to("/account/tokens").into_response()),
+        Err(message) => render_tokens(&state, user, Some(message), csrf_token).await,
+    }
+}
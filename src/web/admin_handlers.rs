@@ -0,0 +1,233 @@
+use crate::model::app_state::AppState;
+use crate::model::audit::AuditEventKind;
+use crate::model::session::CsrfToken;
+use crate::model::user::{User, UserRole};
+use crate::service::calendar_service::CalendarPath;
+use crate::templates::admin_templates::{AdminDashboardTemplate, AuditLogTemplate};
+use askama::Template;
+use axum::extract::{Path, Query, State};
+use axum::http::StatusCode;
+use axum::response::{Html, IntoResponse, Redirect, Response};
+use axum::Form;
+use serde::Deserialize;
+
+async fn render_dashboard(
+    state: &AppState,
+    user: User,
+    message: Option<String>,
+    csrf_token: CsrfToken,
+) -> Result<Response, StatusCode> {
+    let accounts = state
+        .user_repository
+        .read()
+        .await
+        .get_accounts()
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let calendars = state
+        .calendar_service
+        .get_calendar_overviews(&user)
+        .await
+        .map_err(|_| StatusCode::FORBIDDEN)?;
+
+    let invitations = state
+        .invitation_repository
+        .get_invitations(None)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let template = AdminDashboardTemplate::new(&user, accounts, calendars, invitations, csrf_token);
+    let template = match message {
+        Some(message) => template.with_message(message),
+        None => template,
+    };
+
+    template
+        .render()
+        .map(|v| Html(v).into_response())
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+async fn redirect_or_render(
+    state: &AppState,
+    user: User,
+    result: Result<(), String>,
+    csrf_token: CsrfToken,
+) -> Result<Response, StatusCode> {
+    match result {
+        Ok(()) => Ok(Redirect::to("/admin").into_response()),
+        Err(message) => render_dashboard(state, user, Some(message), csrf_token).await,
+    }
+}
+
+pub async fn admin_get(
+    user: User,
+    State(state): State<AppState>,
+    csrf_token: CsrfToken,
+) -> Result<Response, StatusCode> {
+    render_dashboard(&state, user, None, csrf_token).await
+}
+
+/// Filters of the audit log, an empty field matches everything.
+#[derive(Deserialize)]
+pub struct AuditQuery {
+    #[serde(default)]
+    user: String,
+    #[serde(default)]
+    kind: String,
+}
+pub async fn audit_get(
+    user: User,
+    State(state): State<AppState>,
+    Query(query): Query<AuditQuery>,
+) -> Result<Response, StatusCode> {
+    let username = Some(query.user.trim().to_owned()).filter(|username| !username.is_empty());
+    let kind = AuditEventKind::ALL
+        .into_iter()
+        .find(|kind| kind.as_str() == query.kind);
+    let events = state
+        .audit_repository
+        .get_events(username.as_deref(), kind)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    AuditLogTemplate::new(&user, events, username, kind)
+        .render()
+        .map(|v| Html(v).into_response())
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+#[derive(Deserialize)]
+pub struct UserPath {
+    pub user_id: i32,
+}
+pub async fn lock_user_post(
+    user: User,
+    State(state): State<AppState>,
+    Path(UserPath { user_id }): Path<UserPath>,
+    csrf_token: CsrfToken,
+) -> Result<Response, StatusCode> {
+    let result = if user_id == user.id {
+        Err(String::from("You can't lock your own account"))
+    } else {
+        match state
+            .user_repository
+            .read()
+            .await
+            .set_locked(user_id, true)
+            .await
+        {
+            Ok(()) => {
+                state
+                    .session_store
+                    .expire_user_sessions(user_id)
+                    .await
+            }
+            Err(e) => Err(e),
+        }
+    };
+
+    redirect_or_render(&state, user, result, csrf_token).await
+}
+
+pub async fn unlock_user_post(
+    user: User,
+    State(state): State<AppState>,
+    Path(UserPath { user_id }): Path<UserPath>,
+    csrf_token: CsrfToken,
+) -> Result<Response, StatusCode> {
+    let result = state
+        .user_repository
+        .read()
+        .await
+        .set_locked(user_id, false)
+        .await;
+
+    redirect_or_render(&state, user, result, csrf_token).await
+}
+
+#[derive(Deserialize)]
+pub struct ChangeRoleForm {
+    role: UserRole,
+}
+pub async fn change_role_post(
+    user: User,
+    State(state): State<AppState>,
+    Path(UserPath { user_id }): Path<UserPath>,
+    csrf_token: CsrfToken,
+    Form(form): Form<ChangeRoleForm>,
+) -> Result<Response, StatusCode> {
+    let result = if user_id == user.id {
+        Err(String::from("You can't change your own role"))
+    } else {
+        let result = state
+            .user_repository
+            .read()
+            .await
+            .set_role(user_id, form.role)
+            .await;
+        if result.is_ok() {
+            state.session_store.evict_user(user_id);
+        }
+        result
+    };
+
+    redirect_or_render(&state, user, result, csrf_token).await
+}
+
+pub async fn take_down_calendar_post(
+    user: User,
+    State(state): State<AppState>,
+    Path(CalendarPath { calendar_id }): Path<CalendarPath>,
+    csrf_token: CsrfToken,
+) -> Result<Response, StatusCode> {
+    let result = state
+        .calendar_service
+        .take_down_calendar(&user, calendar_id)
+        .await;
+
+    redirect_or_render(&state, user, result, csrf_token).await
+}
+
+#[derive(Deserialize)]
+pub struct InvitationForm {
+    pub max_uses: i32,
+    pub valid_days: i64,
+}
+pub async fn create_invitation_post(
+    user: User,
+    State(state): State<AppState>,
+    csrf_token: CsrfToken,
+    Form(form): Form<InvitationForm>,
+) -> Result<Response, StatusCode> {
+    let code = state
+        .invitation_repository
+        .create_invitation(&user, None, form.max_uses, form.valid_days)
+        .await;
+
+    let message = match code {
+        Ok(code) => format!(
+            "New invitation code: {code}. Share it or the link /signup?invitation={code}, it won't be shown again"
+        ),
+        Err(message) => message,
+    };
+    render_dashboard(&state, user, Some(message), csrf_token).await
+}
+
+#[derive(Deserialize)]
+pub struct InvitationPath {
+    pub invitation_id: i32,
+}
+pub async fn revoke_invitation_post(
+    user: User,
+    State(state): State<AppState>,
+    Path(InvitationPath { invitation_id }): Path<InvitationPath>,
+    csrf_token: CsrfToken,
+) -> Result<Response, StatusCode> {
+    let result = state
+        .invitation_repository
+        .revoke_invitation(invitation_id, None)
+        .await;
+
+    redirect_or_render(&state, user, result, csrf_token).await
+}
@@ -1,11 +1,20 @@
 use crate::model::app_state::AppState;
+use crate::model::audit::AuditEventKind;
+use crate::model::invitation::RegistrationMode;
+use crate::model::oidc::{OidcFlow, OidcIdentity, OidcLogin};
+use crate::model::session::{ClientInfo, CsrfToken};
+use crate::model::user::RecoveryKey;
 use crate::model::user::{User, UserRole};
 use crate::persistence::user_repository::LoginCredentials;
+use crate::service::authentication::safe_redirect_target;
+use crate::service::rate_limiter::AttemptKey;
+use crate::templates::account_templates::RecoveryKeyTemplate;
 use crate::templates::authentication_templates::{
-    ChangePassTemplate, LoginTemplate, SignupTemplate,
+    ChangePassTemplate, LoginTemplate, OidcPassphraseTemplate, RecoverTemplate, SignupTemplate,
+    TwoFactorLoginTemplate,
 };
 use askama::Template;
-use axum::extract::State;
+use axum::extract::{Query, State};
 use axum::http::StatusCode;
 use axum::response::{Html, IntoResponse, Redirect, Response};
 use axum::Form;
@@ -14,8 +23,12 @@ use axum_extra::extract::CookieJar;
 use serde::Deserialize;
 use uuid::Uuid;
 
-pub async fn login_page() -> Result<impl IntoResponse, StatusCode> {
-    LoginTemplate::empty()
+pub async fn login_page(
+    State(state): State<AppState>,
+    csrf_token: CsrfToken,
+) -> Result<impl IntoResponse, StatusCode> {
+    LoginTemplate::empty(csrf_token)
+        .with_single_sign_on(state.oidc_provider.is_some())
         .render()
         .map(|v| Html(v).into_response())
         .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
@@ -26,44 +39,54 @@ pub struct LoginForm {
     username: String,
     password: String,
 }
-impl Into<LoginCredentials> for LoginForm {
-    fn into(self) -> LoginCredentials {
+impl From<LoginForm> for LoginCredentials {
+    fn from(val: LoginForm) -> Self {
         LoginCredentials {
-            username: self.username,
-            password: self.password,
+            username: val.username,
+            password: val.password,
         }
     }
 }
 
 pub async fn login_post(
     State(state): State<AppState>,
+    client: ClientInfo,
     jar: CookieJar,
+    csrf_token: CsrfToken,
     Form(login): Form<LoginForm>,
 ) -> Result<(CookieJar, Response), StatusCode> {
     let credentials = login.clone().into();
+    let mut attempt_keys = vec![AttemptKey::LoginUser(&login.username)];
+    if let Some(ip_address) = &client.ip_address {
+        attempt_keys.push(AttemptKey::LoginIp(ip_address));
+    }
+    if let Err(message) = state.rate_limiter.reserve(&attempt_keys).await {
+        let response = LoginTemplate::with_message(message, credentials, csrf_token)
+            .with_single_sign_on(state.oidc_provider.is_some())
+            .render()
+            .map(|v| Html(v).into_response())
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        return Ok((jar, response));
+    }
+
     let user_repo_lock = state.user_repository.read().await;
-    let user = user_repo_lock.authenticate_user(&credentials).await;
+    let user = user_repo_lock
+        .authenticate_user(&credentials, &client)
+        .await;
 
     Ok(match user {
         Ok(user) => {
-            let token = state
-                .session_store
-                .write()
-                .await
-                .add_user(user, &login.password)
+            state
+                .rate_limiter
+                .record_success(&attempt_keys)
                 .await
                 .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-
-            let (jar, target) = match jar.get("next") {
-                Some(next) => (jar.clone().remove(next.clone()), next.value()),
-                None => (jar, "/"),
-            };
-            let jar = jar.add(get_cookie(token.to_string()));
-            let response = Redirect::to(target).into_response();
-            (jar, response)
+            drop(user_repo_lock);
+            open_session(&state, user, &login.password, &client, jar).await?
         }
         Err(msg) => {
-            let response = LoginTemplate::with_message(msg.to_owned(), credentials)
+            let response = LoginTemplate::with_message(msg.to_owned(), credentials, csrf_token)
+                .with_single_sign_on(state.oidc_provider.is_some())
                 .render()
                 .map(|v| Html(v).into_response())
                 .unwrap_or(StatusCode::INTERNAL_SERVER_ERROR.into_response());
@@ -72,52 +95,381 @@ pub async fn login_post(
     })
 }
 
-pub async fn signup_page() -> Result<Response, StatusCode> {
-    SignupTemplate::empty()
+/// Logs in an authenticated user, or sends them to the second factor step if they have one.
+async fn open_session(
+    state: &AppState,
+    user: User,
+    password: &str,
+    client: &ClientInfo,
+    jar: CookieJar,
+) -> Result<(CookieJar, Response), StatusCode> {
+    let two_factor_enabled = state
+        .user_repository
+        .read()
+        .await
+        .is_two_factor_enabled(&user)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    if two_factor_enabled {
+        let token = state
+            .session_store
+            .add_pending_user(user, password, client)
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        let jar = jar.add(get_pending_cookie(token.to_string()));
+        return Ok((jar, Redirect::to("/login/two-factor").into_response()));
+    }
+
+    let token = state
+        .session_store
+        .add_user(user, password, client)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let (jar, target) = take_redirect_target(jar);
+    let jar = jar.add(get_cookie(token.to_string()));
+    Ok((jar, Redirect::to(&target).into_response()))
+}
+
+/// Logs in a user who just signed up and hands them their recovery key.
+async fn open_new_account(
+    state: &AppState,
+    user: User,
+    calendar_id: Option<i32>,
+    password: &str,
+    client: &ClientInfo,
+    jar: CookieJar,
+) -> Result<(CookieJar, Response), StatusCode> {
+    let recovery_key = state
+        .user_repository
+        .read()
+        .await
+        .set_recovery_key(&user)
+        .await;
+
+    let token = state
+        .session_store
+        .add_user(user, password, client)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let (jar, target) = take_redirect_target(jar);
+    // Without anywhere else to go, the calendar of the invitation is shown first
+    let target = match calendar_id {
+        Some(calendar_id) if target == "/" => format!("/calendar/{}", calendar_id),
+        _ => target,
+    };
+    let jar = jar.add(get_cookie(token.to_string()));
+    let response = match recovery_key {
+        Ok(recovery_key) => RecoveryKeyTemplate::new(recovery_key, target)
+            .render()
+            .map(|v| Html(v).into_response())
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?,
+        Err(_) => Redirect::to(&target).into_response(),
+    };
+    Ok((jar, response))
+}
+
+#[derive(Deserialize)]
+pub struct SignupQuery {
+    invitation: Option<String>,
+}
+pub async fn signup_page(
+    State(state): State<AppState>,
+    csrf_token: CsrfToken,
+    Query(query): Query<SignupQuery>,
+) -> Result<Response, StatusCode> {
+    SignupTemplate::empty(state.registration_mode, csrf_token)
+        .with_invitation(query.invitation)
         .render()
         .map(|v| Html(v).into_response())
         .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
 }
 
+#[derive(Deserialize)]
+pub struct SignupForm {
+    username: String,
+    password: String,
+    invitation: Option<String>,
+}
 pub async fn signup_post(
     State(state): State<AppState>,
+    client: ClientInfo,
     jar: CookieJar,
-    Form(login): Form<LoginForm>,
+    csrf_token: CsrfToken,
+    Form(form): Form<SignupForm>,
 ) -> Result<(CookieJar, Response), StatusCode> {
-    let credentials = login.clone().into();
+    let credentials = LoginCredentials {
+        username: form.username,
+        password: form.password,
+    };
+    let invitation = form
+        .invitation
+        .as_deref()
+        .map(str::trim)
+        .filter(|code| !code.is_empty());
     let user_repo_lock = state.user_repository.write().await;
-    let user = user_repo_lock
-        .add_user(&credentials, UserRole::MEMBER)
-        .await;
+    let user = match (state.registration_mode, invitation) {
+        (RegistrationMode::Closed, _) => Err("Registration is closed"),
+        (RegistrationMode::InviteOnly, None) => Err("You need an invitation code to sign up"),
+        _ => {
+            user_repo_lock
+                .add_user(&credentials, UserRole::Member, invitation, None)
+                .await
+        }
+    };
 
     Ok(match user {
-        Ok(user) => {
-            let token = state
-                .session_store
-                .write()
-                .await
-                .add_user(user, &login.password)
-                .await
+        Ok((user, calendar_id)) => {
+            drop(user_repo_lock);
+            open_new_account(
+                &state,
+                user,
+                calendar_id,
+                &credentials.password,
+                &client,
+                jar,
+            )
+            .await?
+        }
+        Err(message) => {
+            let invitation = invitation.map(str::to_owned);
+            let redirect = SignupTemplate::with_message(
+                message.to_owned(),
+                credentials,
+                state.registration_mode,
+                csrf_token,
+            )
+            .with_invitation(invitation)
+            .render()
+            .map(|v| Html(v).into_response())
+            .unwrap_or(StatusCode::INTERNAL_SERVER_ERROR.into_response());
+
+            let cookie_jar = CookieJar::new();
+            (cookie_jar, redirect)
+        }
+    })
+}
+
+fn get_oidc_cookie(name: &'static str, value: String) -> Cookie<'static> {
+    Cookie::build((name, value))
+        .secure(true)
+        .http_only(true)
+        .expires(Expiration::Session)
+        .same_site(SameSite::Lax)
+        .path("/login/oidc")
+        .build()
+}
+
+/// Sends the user to the identity provider's login page.
+pub async fn oidc_login_get(
+    State(state): State<AppState>,
+    jar: CookieJar,
+) -> Result<(CookieJar, Redirect), StatusCode> {
+    let provider = state.oidc_provider.as_ref().ok_or(StatusCode::NOT_FOUND)?;
+    let (url, flow) = provider.authorize();
+    let jar = jar.add(get_oidc_cookie("oidc_flow", flow.encode()));
+    Ok((jar, Redirect::to(&url)))
+}
+
+#[derive(Deserialize)]
+pub struct OidcCallbackQuery {
+    code: Option<String>,
+    state: Option<String>,
+}
+/// Where the identity provider sends the user back. Once the identity is verified, the login
+/// continues with the encryption passphrase.
+pub async fn oidc_callback_get(
+    State(state): State<AppState>,
+    jar: CookieJar,
+    csrf_token: CsrfToken,
+    Query(query): Query<OidcCallbackQuery>,
+) -> Result<(CookieJar, Response), StatusCode> {
+    let provider = state.oidc_provider.as_ref().ok_or(StatusCode::NOT_FOUND)?;
+    let flow = jar
+        .get("oidc_flow")
+        .and_then(|cookie| OidcFlow::decode(cookie.value()));
+    let jar = jar.remove(get_oidc_cookie("oidc_flow", String::new()));
+
+    let identity = match (flow, query.code, query.state) {
+        (Some(flow), Some(code), Some(oidc_state)) => {
+            provider.identify(flow, code, &oidc_state).await
+        }
+        _ => Err(String::from(
+            "The login through your identity provider didn't complete",
+        )),
+    };
+    let token = match identity {
+        Ok(identity) => state.oidc_repository.start_login(&identity).await,
+        Err(e) => Err(e),
+    };
+
+    match token {
+        Ok(token) => {
+            let jar = jar.add(get_oidc_cookie("oidc_login", token.to_string()));
+            Ok((jar, Redirect::to("/login/oidc/passphrase").into_response()))
+        }
+        Err(message) => {
+            let credentials = LoginCredentials {
+                username: String::new(),
+                password: String::new(),
+            };
+            let response = LoginTemplate::with_message(message, credentials, csrf_token)
+                .with_single_sign_on(true)
+                .render()
+                .map(|v| Html(v).into_response())
                 .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+            Ok((jar, response))
+        }
+    }
+}
 
-            let (jar, target) = match jar.get("next") {
-                Some(next) => (jar.clone().remove(next.clone()), next.value()),
-                None => (jar, "/"),
+async fn get_oidc_login(state: &AppState, jar: &CookieJar) -> Option<(Uuid, OidcLogin)> {
+    let token = Uuid::parse_str(jar.get("oidc_login")?.value()).ok()?;
+    let login = state.oidc_repository.get_login(token).await?;
+    Some((token, login))
+}
+
+pub async fn oidc_passphrase_get(
+    State(state): State<AppState>,
+    jar: CookieJar,
+    csrf_token: CsrfToken,
+) -> Result<Response, StatusCode> {
+    let Some((_, login)) = get_oidc_login(&state, &jar).await else {
+        return Ok(Redirect::to("/login").into_response());
+    };
+
+    OidcPassphraseTemplate::new(login, state.registration_mode, csrf_token)
+        .render()
+        .map(|v| Html(v).into_response())
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+#[derive(Deserialize)]
+pub struct OidcPassphraseForm {
+    passphrase: String,
+    /// The fields below are only sent on the first login, to create the account
+    username: Option<String>,
+    passphrase_confirmation: Option<String>,
+    invitation: Option<String>,
+}
+pub async fn oidc_passphrase_post(
+    State(state): State<AppState>,
+    client: ClientInfo,
+    jar: CookieJar,
+    csrf_token: CsrfToken,
+    Form(form): Form<OidcPassphraseForm>,
+) -> Result<(CookieJar, Response), StatusCode> {
+    let Some((token, login)) = get_oidc_login(&state, &jar).await else {
+        return Ok((jar, Redirect::to("/login").into_response()));
+    };
+    // Until the login succeeds, it can be attempted again with the same identity
+    let done_jar = jar
+        .clone()
+        .remove(get_oidc_cookie("oidc_login", String::new()));
+
+    let opened = match &login.username {
+        Some(username) => {
+            let mut attempt_keys = vec![AttemptKey::LoginUser(username)];
+            if let Some(ip_address) = &client.ip_address {
+                attempt_keys.push(AttemptKey::LoginIp(ip_address));
+            }
+            match state.rate_limiter.reserve(&attempt_keys).await {
+                Ok(()) => {
+                    let user = state
+                        .user_repository
+                        .read()
+                        .await
+                        .authenticate_oidc_user(
+                            &login.issuer,
+                            &login.subject,
+                            &form.passphrase,
+                            &client,
+                        )
+                        .await;
+                    if user.is_ok() {
+                        state
+                            .rate_limiter
+                            .record_success(&attempt_keys)
+                            .await
+                            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+                    }
+                    match user {
+                        Ok(user) => {
+                            Ok(
+                                open_session(&state, user, &form.passphrase, &client, done_jar)
+                                    .await?,
+                            )
+                        }
+                        Err(e) => Err(e),
+                    }
+                }
+                Err(e) => Err(e),
+            }
+        }
+        None => {
+            let invitation = form
+                .invitation
+                .as_deref()
+                .map(str::trim)
+                .filter(|code| !code.is_empty());
+            let credentials = LoginCredentials {
+                username: form.username.clone().unwrap_or_default(),
+                password: form.passphrase.clone(),
             };
-            let jar = jar.add(get_cookie(token.to_string()));
-            let response = Redirect::to(target).into_response();
-            (jar, response)
+            let identity = OidcIdentity {
+                issuer: login.issuer.clone(),
+                subject: login.subject.clone(),
+                preferred_username: login.preferred_username.clone(),
+            };
+            let user_repo_lock = state.user_repository.write().await;
+            let user = match (state.registration_mode, invitation) {
+                (RegistrationMode::Closed, _) => Err(String::from("Registration is closed")),
+                (RegistrationMode::InviteOnly, None) => {
+                    Err(String::from("You need an invitation code to sign up"))
+                }
+                _ if form.passphrase_confirmation.as_deref() != Some(&form.passphrase) => {
+                    Err(String::from("The two passphrases don't match"))
+                }
+                _ => user_repo_lock
+                    .add_user(&credentials, UserRole::Member, invitation, Some(&identity))
+                    .await
+                    .map_err(str::to_owned),
+            };
+            drop(user_repo_lock);
+            match user {
+                Ok((user, calendar_id)) => Ok(open_new_account(
+                    &state,
+                    user,
+                    calendar_id,
+                    &form.passphrase,
+                    &client,
+                    done_jar,
+                )
+                .await?),
+                Err(e) => Err(e),
+            }
+        }
+    };
+
+    match opened {
+        Ok(opened) => {
+            state
+                .oidc_repository
+                .finish_login(token)
+                .await
+                .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+            Ok(opened)
         }
         Err(message) => {
-            let redirect = SignupTemplate::with_message(message.to_owned(), credentials)
+            let response = OidcPassphraseTemplate::new(login, state.registration_mode, csrf_token)
+                .with_message(message, form.username, form.invitation)
                 .render()
                 .map(|v| Html(v).into_response())
-                .unwrap_or(StatusCode::INTERNAL_SERVER_ERROR.into_response());
-
-            let cookie_jar = CookieJar::new();
-            (cookie_jar, redirect)
+                .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+            Ok((jar, response))
         }
-    })
+    }
 }
 
 pub async fn logout_get(
@@ -125,13 +477,11 @@ pub async fn logout_get(
     jar: CookieJar,
 ) -> Result<(CookieJar, Redirect), StatusCode> {
     let session_id = jar.get("token").ok_or(StatusCode::BAD_REQUEST)?.value();
-    let uuid = Uuid::parse_str(&session_id).map_err(|_| StatusCode::BAD_REQUEST)?;
+    let uuid = Uuid::parse_str(session_id).map_err(|_| StatusCode::BAD_REQUEST)?;
     let jar = jar.remove(Cookie::from("token"));
 
     state
         .session_store
-        .write()
-        .await
         .expire_session(uuid)
         .await
         .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
@@ -148,8 +498,124 @@ fn get_cookie(token: String) -> Cookie<'static> {
         .build()
 }
 
-pub async fn change_pass_get() -> Result<Response, StatusCode> {
-    ChangePassTemplate::empty()
+/// Where to go after logging in, as remembered by `require_logged_in`
+fn take_redirect_target(jar: CookieJar) -> (CookieJar, String) {
+    match jar.get("next") {
+        Some(next) => {
+            let target = safe_redirect_target(next.value()).to_owned();
+            (jar.clone().remove(next.clone()), target)
+        }
+        None => (jar, String::from("/")),
+    }
+}
+
+fn get_pending_cookie(token: String) -> Cookie<'static> {
+    Cookie::build(("pending_token", token))
+        .secure(true)
+        .http_only(true)
+        .expires(Expiration::Session)
+        .same_site(SameSite::Lax)
+        .path("/")
+        .build()
+}
+
+pub async fn two_factor_get(jar: CookieJar, csrf_token: CsrfToken) -> Result<Response, StatusCode> {
+    if jar.get("pending_token").is_none() {
+        return Ok(Redirect::to("/login").into_response());
+    }
+
+    TwoFactorLoginTemplate::empty(csrf_token)
+        .render()
+        .map(|v| Html(v).into_response())
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+#[derive(Deserialize)]
+pub struct TwoFactorForm {
+    code: String,
+}
+pub async fn two_factor_post(
+    State(state): State<AppState>,
+    client: ClientInfo,
+    jar: CookieJar,
+    csrf_token: CsrfToken,
+    Form(form): Form<TwoFactorForm>,
+) -> Result<(CookieJar, Response), StatusCode> {
+    let token = jar
+        .get("pending_token")
+        .and_then(|cookie| Uuid::parse_str(cookie.value()).ok());
+    let user = match token {
+        Some(token) => {
+            state
+                .session_store
+                .get_pending_user(token)
+                .await
+        }
+        None => None,
+    };
+    let (Some(token), Some(user)) = (token, user) else {
+        let jar = jar.remove(Cookie::from("pending_token"));
+        return Ok((jar, Redirect::to("/login").into_response()));
+    };
+
+    let attempt_keys = [AttemptKey::TwoFactor(user.id)];
+    let verified = match state.rate_limiter.reserve(&attempt_keys).await {
+        Ok(()) => {
+            let verified = state
+                .user_repository
+                .read()
+                .await
+                .verify_second_factor(&user, &form.code)
+                .await;
+            if verified.is_ok() {
+                state
+                    .rate_limiter
+                    .record_success(&attempt_keys)
+                    .await
+                    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+            }
+            if let Err(message) = &verified {
+                state
+                    .audit_repository
+                    .record(
+                        Some(user.id),
+                        AuditEventKind::LoginFailed,
+                        Some(&client),
+                        Some(message.clone()),
+                    )
+                    .await
+                    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+            }
+            verified
+        }
+        Err(e) => Err(e),
+    };
+    let completed = match verified {
+        Ok(()) => {
+            state
+                .session_store
+                .complete_second_factor(token, user.id, &client)
+                .await
+        }
+        Err(e) => Err(e),
+    };
+    if let Err(message) = completed {
+        let response = TwoFactorLoginTemplate::with_message(message, csrf_token)
+            .render()
+            .map(|v| Html(v).into_response())
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        return Ok((jar, response));
+    }
+
+    let jar = jar.remove(Cookie::from("pending_token"));
+    let (jar, target) = take_redirect_target(jar);
+    let jar = jar.add(get_cookie(token.to_string()));
+    let response = Redirect::to(&target).into_response();
+    Ok((jar, response))
+}
+
+pub async fn change_pass_get(csrf_token: CsrfToken) -> Result<Response, StatusCode> {
+    ChangePassTemplate::empty(csrf_token)
         .render()
         .map(|v| Html(v).into_response())
         .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
@@ -164,31 +630,152 @@ pub async fn change_pass_post(
     State(state): State<AppState>,
     jar: CookieJar,
     user: User,
+    csrf_token: CsrfToken,
     Form(form): Form<ChangePasswordForm>,
 ) -> Result<Response, StatusCode> {
+    let session_id = jar.get("token").ok_or(StatusCode::BAD_REQUEST)?.value();
+    let uuid = Uuid::parse_str(session_id).map_err(|_| StatusCode::BAD_REQUEST)?;
+
     let res: Result<(), String> = state
         .user_repository
         .read()
         .await
         .change_password(&user, &form.old_password, &form.new_password)
         .await;
+    if let Err(message) = res {
+        return ChangePassTemplate::with_message(message, csrf_token)
+            .render()
+            .map(|v| Html(v).into_response())
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR);
+    }
 
-    let session_id = jar.get("token").ok_or(StatusCode::BAD_REQUEST)?.value();
-    let uuid = Uuid::parse_str(&session_id).map_err(|_| StatusCode::BAD_REQUEST)?;
+    // Every session holds the master key derived from the old password: this one gets the new
+    // master key, the others are logged out
+    let session_store = &state.session_store;
+    if session_store
+        .rekey_session(uuid, user.id, &form.new_password)
+        .await
+        .is_err()
+    {
+        session_store
+            .expire_session(uuid)
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        return Ok(Redirect::to("/login").into_response());
+    }
+    let revoked = session_store.revoke_other_sessions(user.id, uuid).await;
 
-    state
-        .session_store
-        .write()
+    let message = match revoked {
+        Ok(0) => String::from("Your password was changed"),
+        Ok(1) => String::from("Your password was changed and 1 other session was logged out"),
+        Ok(count) => format!(
+            "Your password was changed and {} other sessions were logged out",
+            count
+        ),
+        Err(_) => String::from(
+            "Your password was changed, but your other sessions could not be logged out",
+        ),
+    };
+    ChangePassTemplate::with_message(message, csrf_token)
+        .render()
+        .map(|v| Html(v).into_response())
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+pub async fn recover_get(csrf_token: CsrfToken) -> Result<Response, StatusCode> {
+    RecoverTemplate::empty(csrf_token)
+        .render()
+        .map(|v| Html(v).into_response())
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+#[derive(Deserialize)]
+pub struct RecoverForm {
+    username: String,
+    recovery_key: String,
+    new_password: String,
+}
+pub async fn recover_post(
+    State(state): State<AppState>,
+    client: ClientInfo,
+    jar: CookieJar,
+    csrf_token: CsrfToken,
+    Form(form): Form<RecoverForm>,
+) -> Result<(CookieJar, Response), StatusCode> {
+    let mut attempt_keys = vec![AttemptKey::RecoverUser(&form.username)];
+    if let Some(ip_address) = &client.ip_address {
+        attempt_keys.push(AttemptKey::RecoverIp(ip_address));
+    }
+    let result = match state.rate_limiter.reserve(&attempt_keys).await {
+        Ok(()) => match RecoveryKey::parse(&form.recovery_key) {
+            Ok(recovery_key) => {
+                let recovered = state
+                    .user_repository
+                    .read()
+                    .await
+                    .recover_account(&form.username, &recovery_key, &form.new_password)
+                    .await;
+                if recovered.is_ok() {
+                    state
+                        .rate_limiter
+                        .record_success(&attempt_keys)
+                        .await
+                        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+                }
+                recovered
+            }
+            Err(e) => Err(e),
+        },
+        Err(e) => Err(e),
+    };
+
+    let (user, recovery_key) = match result {
+        Ok(recovered) => recovered,
+        Err(message) => {
+            let response = RecoverTemplate::with_message(message, form.username, csrf_token)
+                .render()
+                .map(|v| Html(v).into_response())
+                .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+            return Ok((jar, response));
+        }
+    };
+
+    let two_factor_enabled = state
+        .user_repository
+        .read()
         .await
-        .expire_session(uuid)
+        .is_two_factor_enabled(&user)
         .await
         .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
 
-    match res {
-        Ok(()) => Ok(Redirect::to("/").into_response()),
-        Err(message) => ChangePassTemplate::with_message(message)
-            .render()
-            .map(|v| Html(v).into_response())
-            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR),
-    }
+    // Sessions opened with the old password can't unwrap the new master key anymore
+    let session_store = &state.session_store;
+    session_store
+        .expire_user_sessions(user.id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    // The recovery key replaces the password, not the second factor
+    let (jar, next) = if two_factor_enabled {
+        let token = session_store
+            .add_pending_user(user, &form.new_password, &client)
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        (
+            jar.add(get_pending_cookie(token.to_string())),
+            "/login/two-factor",
+        )
+    } else {
+        let token = session_store
+            .add_user(user, &form.new_password, &client)
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        (jar.add(get_cookie(token.to_string())), "/home")
+    };
+
+    let response = RecoveryKeyTemplate::new(recovery_key, String::from(next))
+        .render()
+        .map(|v| Html(v).into_response())
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    Ok((jar, response))
 }
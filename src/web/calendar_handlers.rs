@@ -1,11 +1,17 @@
 use crate::model::app_state::AppState;
-use crate::model::calendar::{UserCalendar, UserDay};
+use crate::model::calendar::{
+    CalendarDetails, CalendarPermission, CalendarRole, CalendarVisibility, UserCalendar, UserDay,
+};
+use crate::model::session::{ClientInfo, CsrfToken};
 use crate::model::user::User;
+use crate::service::rate_limiter::AttemptKey;
 use crate::templates::calendar_templates::{
-    CreateCalendarTemplate, ShowCalendarTemplate, ShowDayTemplate, UnlockDayTemplate,
+    CreateCalendarTemplate, DeleteCalendarTemplate, EditCalendarTemplate, SharedCalendarTemplate,
+    ShowCalendarTemplate, ShowDayTemplate, UnlockDayTemplate,
 };
+use crate::web::admin_handlers::{InvitationForm, InvitationPath};
 use askama::Template;
-use axum::extract::{Query, State};
+use axum::extract::{Path, Query, State};
 use axum::http::StatusCode;
 use axum::response::{Html, IntoResponse, Redirect, Response};
 use axum::Form;
@@ -19,6 +25,7 @@ pub struct CalendarCreationRequest {
 pub async fn create_calendar_post(
     user: User,
     State(state): State<AppState>,
+    csrf_token: CsrfToken,
     Form(req): Form<CalendarCreationRequest>,
 ) -> impl IntoResponse {
     let result = state
@@ -29,7 +36,12 @@ pub async fn create_calendar_post(
     let calendar_id = result.map(|calendar| calendar.id);
     match calendar_id {
         Ok(calendar_id) => Redirect::to(&format!("/calendar/{calendar_id}")).into_response(),
-        Err(e) => Html(CreateCalendarTemplate::new(Some(e)).render().unwrap()).into_response(),
+        Err(e) => Html(
+            CreateCalendarTemplate::new(Some(e), csrf_token)
+                .render()
+                .unwrap(),
+        )
+        .into_response(),
     }
 }
 
@@ -37,6 +49,7 @@ pub async fn subscribe_post(
     user: User,
     user_calendar: UserCalendar,
     State(state): State<AppState>,
+    csrf_token: CsrfToken,
 ) -> impl IntoResponse {
     let result = state
         .calendar_service
@@ -45,12 +58,80 @@ pub async fn subscribe_post(
 
     match result {
         Ok(()) => Redirect::to(&format!("/calendar/{}", user_calendar.calendar.id)).into_response(),
-        Err(e) => Html(CreateCalendarTemplate::new(Some(e)).render().unwrap()).into_response(),
+        Err(e) => Html(
+            CreateCalendarTemplate::new(Some(e), csrf_token)
+                .render()
+                .unwrap(),
+        )
+        .into_response(),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct UnsubscribeForm {
+    /// Set when the checkbox to forget the unlocked days is ticked
+    purge_history: Option<String>,
+}
+pub async fn unsubscribe_post(
+    user: User,
+    user_calendar: UserCalendar,
+    State(state): State<AppState>,
+    csrf_token: CsrfToken,
+    Form(form): Form<UnsubscribeForm>,
+) -> Response {
+    let result = state
+        .calendar_service
+        .unsubscribe(&user, &user_calendar, form.purge_history.is_some())
+        .await;
+
+    match result {
+        Ok(()) => Redirect::to("/home").into_response(),
+        Err(message) => {
+            render_calendar(&state, user_calendar, user, Some(message), csrf_token).await
+        }
+    }
+}
+
+pub async fn archive_post(
+    user: User,
+    user_calendar: UserCalendar,
+    State(state): State<AppState>,
+    csrf_token: CsrfToken,
+) -> Response {
+    let result = state
+        .calendar_service
+        .set_archived(&user, &user_calendar, true)
+        .await;
+
+    match result {
+        Ok(()) => Redirect::to("/home").into_response(),
+        Err(message) => {
+            render_calendar(&state, user_calendar, user, Some(message), csrf_token).await
+        }
+    }
+}
+
+pub async fn restore_post(
+    user: User,
+    user_calendar: UserCalendar,
+    State(state): State<AppState>,
+    csrf_token: CsrfToken,
+) -> Response {
+    let result = state
+        .calendar_service
+        .set_archived(&user, &user_calendar, false)
+        .await;
+
+    match result {
+        Ok(()) => Redirect::to(&format!("/calendar/{}", user_calendar.calendar.id)).into_response(),
+        Err(message) => {
+            render_calendar(&state, user_calendar, user, Some(message), csrf_token).await
+        }
     }
 }
 
-pub async fn create_calendar_get() -> impl IntoResponse {
-    let content = CreateCalendarTemplate::new(None)
+pub async fn create_calendar_get(csrf_token: CsrfToken) -> impl IntoResponse {
+    let content = CreateCalendarTemplate::new(None, csrf_token)
         .render()
         .map_err(|err| format!("There was an error rendering this page {err}"));
     Html(content)
@@ -67,8 +148,12 @@ pub async fn add_day_post(
     user_calendar: UserCalendar,
     user: User,
     Form(add_day_form): Form<AddDayForm>,
-) -> impl IntoResponse {
-    state
+) -> Result<Response, Response> {
+    if !user_calendar.can(CalendarPermission::EditDays) {
+        return Err(StatusCode::FORBIDDEN.into_response());
+    }
+
+    let res = state
         .calendar_service
         .add_day(
             &user,
@@ -77,16 +162,27 @@ pub async fn add_day_post(
             add_day_form.password,
             add_day_form.content,
         )
-        .await
-        .expect("asda");
-    Redirect::to(&format!("/calendar/{}", user_calendar.calendar.id))
+        .await;
+
+    match res {
+        Ok(()) => {
+            Ok(Redirect::to(&format!("/calendar/{}", user_calendar.calendar.id)).into_response())
+        }
+        Err(e) => Err(Response::builder()
+            .status(StatusCode::INTERNAL_SERVER_ERROR)
+            .body(e)
+            .unwrap()
+            .into_response()),
+    }
 }
 
-pub async fn show_calendar(
+async fn render_calendar(
+    state: &AppState,
     user_calendar: UserCalendar,
     user: User,
-    State(state): State<AppState>,
-) -> impl IntoResponse {
+    message: Option<String>,
+    csrf_token: CsrfToken,
+) -> Response {
     let res = state
         .calendar_service
         .get_calendar_user_days(&user_calendar, &user)
@@ -103,18 +199,427 @@ pub async fn show_calendar(
         }
     };
 
-    let content = ShowCalendarTemplate::new(user_calendar, days, user)
-        .render()
-        .unwrap();
+    // Only the owners hand out invitations and share links to the calendar, and see its members
+    let (invitations, has_share_link, members) = if user_calendar.can(CalendarPermission::Manage) {
+        let invitations = state
+            .invitation_repository
+            .get_invitations(Some(user_calendar.calendar.id))
+            .await;
+        let has_share_link = state.calendar_service.has_share_link(&user_calendar).await;
+        let members = state
+            .calendar_service
+            .get_members(&user_calendar, &user)
+            .await;
+        match (invitations, has_share_link, members) {
+            (Ok(invitations), Ok(has_share_link), Ok(members)) => {
+                (invitations, has_share_link, members)
+            }
+            _ => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+        }
+    } else {
+        (vec![], false, vec![])
+    };
+
+    let template = ShowCalendarTemplate::new(user_calendar, days, csrf_token)
+        .with_invitations(invitations)
+        .with_share_link(has_share_link)
+        .with_members(members);
+    let template = match message {
+        Some(message) => template.with_message(message),
+        None => template,
+    };
+    let content = template.render().unwrap();
 
     Html(content).into_response()
 }
 
+pub async fn show_calendar(
+    user_calendar: UserCalendar,
+    user: User,
+    State(state): State<AppState>,
+    csrf_token: CsrfToken,
+) -> impl IntoResponse {
+    render_calendar(&state, user_calendar, user, None, csrf_token).await
+}
+
+pub async fn edit_calendar_get(
+    user_calendar: UserCalendar,
+    csrf_token: CsrfToken,
+) -> Result<Response, StatusCode> {
+    if !user_calendar.can(CalendarPermission::Manage) {
+        return Err(StatusCode::FORBIDDEN);
+    }
+
+    EditCalendarTemplate::new(user_calendar.calendar, csrf_token)
+        .render()
+        .map(|v| Html(v).into_response())
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+#[derive(Deserialize)]
+pub struct EditCalendarForm {
+    title: String,
+    description: String,
+    cover_image_url: String,
+}
+pub async fn edit_calendar_post(
+    user_calendar: UserCalendar,
+    user: User,
+    State(state): State<AppState>,
+    csrf_token: CsrfToken,
+    Form(form): Form<EditCalendarForm>,
+) -> Result<Response, StatusCode> {
+    let details = CalendarDetails::new(&form.title, &form.description, &form.cover_image_url);
+    let result = match &details {
+        Ok(details) => {
+            state
+                .calendar_service
+                .edit_calendar(&user_calendar, &user, details)
+                .await
+        }
+        Err(message) => Err(message.clone()),
+    };
+
+    match result {
+        Ok(()) => {
+            Ok(Redirect::to(&format!("/calendar/{}", user_calendar.calendar.id)).into_response())
+        }
+        Err(message) => {
+            // Show the form again with what they typed
+            let mut calendar = user_calendar.calendar;
+            calendar.title = form.title;
+            calendar.description = Some(form.description);
+            calendar.cover_image_url = Some(form.cover_image_url);
+            EditCalendarTemplate::new(calendar, csrf_token)
+                .with_message(message)
+                .render()
+                .map(|v| Html(v).into_response())
+                .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+pub async fn delete_calendar_get(
+    user_calendar: UserCalendar,
+    csrf_token: CsrfToken,
+) -> Result<Response, StatusCode> {
+    if !user_calendar.can(CalendarPermission::Manage) {
+        return Err(StatusCode::FORBIDDEN);
+    }
+
+    DeleteCalendarTemplate::new(user_calendar.calendar, csrf_token)
+        .render()
+        .map(|v| Html(v).into_response())
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+#[derive(Deserialize)]
+pub struct DeleteCalendarForm {
+    /// The title of the calendar, typed again to confirm
+    confirm_title: String,
+}
+pub async fn delete_calendar_post(
+    user_calendar: UserCalendar,
+    user: User,
+    State(state): State<AppState>,
+    csrf_token: CsrfToken,
+    Form(form): Form<DeleteCalendarForm>,
+) -> Result<Response, StatusCode> {
+    let result = if form.confirm_title.trim() != user_calendar.calendar.title {
+        Err("Type the title of the calendar to confirm".to_owned())
+    } else {
+        state
+            .calendar_service
+            .delete_calendar(&user_calendar, &user)
+            .await
+    };
+
+    match result {
+        Ok(()) => Ok(Redirect::to("/home").into_response()),
+        Err(message) => DeleteCalendarTemplate::new(user_calendar.calendar, csrf_token)
+            .with_message(message)
+            .render()
+            .map(|v| Html(v).into_response())
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct VisibilityForm {
+    visibility: CalendarVisibility,
+}
+pub async fn visibility_post(
+    user_calendar: UserCalendar,
+    user: User,
+    State(state): State<AppState>,
+    csrf_token: CsrfToken,
+    Form(form): Form<VisibilityForm>,
+) -> Response {
+    let result = state
+        .calendar_service
+        .set_visibility(&user_calendar, &user, form.visibility)
+        .await;
+
+    match result {
+        Ok(()) => Redirect::to(&format!("/calendar/{}", user_calendar.calendar.id)).into_response(),
+        Err(message) => {
+            render_calendar(&state, user_calendar, user, Some(message), csrf_token).await
+        }
+    }
+}
+
+pub async fn share_link_post(
+    user_calendar: UserCalendar,
+    user: User,
+    State(state): State<AppState>,
+    csrf_token: CsrfToken,
+) -> Response {
+    let token = state
+        .calendar_service
+        .create_share_link(&user_calendar, &user)
+        .await;
+
+    let message = match token {
+        Ok(token) => format!(
+            "New share link: /calendar/shared/{token}. Copy it now, it won't be shown again. Any previous link stopped working"
+        ),
+        Err(message) => message,
+    };
+    render_calendar(&state, user_calendar, user, Some(message), csrf_token).await
+}
+
+pub async fn revoke_share_link_post(
+    user_calendar: UserCalendar,
+    user: User,
+    State(state): State<AppState>,
+    csrf_token: CsrfToken,
+) -> Response {
+    let result = state
+        .calendar_service
+        .revoke_share_link(&user_calendar, &user)
+        .await;
+
+    match result {
+        Ok(()) => Redirect::to(&format!("/calendar/{}", user_calendar.calendar.id)).into_response(),
+        Err(message) => {
+            render_calendar(&state, user_calendar, user, Some(message), csrf_token).await
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct SharePath {
+    share_token: String,
+}
+pub async fn shared_calendar_get(
+    user: User,
+    State(state): State<AppState>,
+    Path(SharePath { share_token }): Path<SharePath>,
+    csrf_token: CsrfToken,
+) -> Result<Response, StatusCode> {
+    let user_calendar = state
+        .calendar_service
+        .get_shared_calendar(&share_token, &user)
+        .await
+        .map_err(|_| StatusCode::NOT_FOUND)?;
+
+    // Those who can already open it don't need the link anymore
+    let has_access = user_calendar.role.is_some()
+        || user_calendar.subscribed_at.is_some()
+        || user_calendar.calendar.visibility == CalendarVisibility::Public;
+    if has_access {
+        return Ok(
+            Redirect::to(&format!("/calendar/{}", user_calendar.calendar.id)).into_response(),
+        );
+    }
+
+    SharedCalendarTemplate::new(user_calendar.calendar, share_token, csrf_token)
+        .render()
+        .map(|v| Html(v).into_response())
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+pub async fn shared_subscribe_post(
+    user: User,
+    State(state): State<AppState>,
+    Path(SharePath { share_token }): Path<SharePath>,
+    csrf_token: CsrfToken,
+) -> Result<Response, StatusCode> {
+    let user_calendar = state
+        .calendar_service
+        .get_shared_calendar(&share_token, &user)
+        .await
+        .map_err(|_| StatusCode::NOT_FOUND)?;
+
+    let result = state
+        .calendar_service
+        .subscribe(&user, &user_calendar)
+        .await;
+
+    match result {
+        Ok(()) => {
+            Ok(Redirect::to(&format!("/calendar/{}", user_calendar.calendar.id)).into_response())
+        }
+        Err(message) => {
+            SharedCalendarTemplate::new(user_calendar.calendar, share_token, csrf_token)
+                .with_message(message)
+                .render()
+                .map(|v| Html(v).into_response())
+                .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+pub async fn create_calendar_invitation_post(
+    user_calendar: UserCalendar,
+    user: User,
+    State(state): State<AppState>,
+    csrf_token: CsrfToken,
+    Form(form): Form<InvitationForm>,
+) -> Result<Response, StatusCode> {
+    if !user_calendar.can(CalendarPermission::Manage) {
+        return Err(StatusCode::FORBIDDEN);
+    }
+
+    let code = state
+        .invitation_repository
+        .create_invitation(
+            &user,
+            Some(user_calendar.calendar.id),
+            form.max_uses,
+            form.valid_days,
+        )
+        .await;
+
+    let message = match code {
+        Ok(code) => format!(
+            "New invitation code: {code}. Share it or the link /signup?invitation={code}, it won't be shown again"
+        ),
+        Err(message) => message,
+    };
+    Ok(render_calendar(&state, user_calendar, user, Some(message), csrf_token).await)
+}
+
+pub async fn revoke_calendar_invitation_post(
+    user_calendar: UserCalendar,
+    user: User,
+    State(state): State<AppState>,
+    Path(InvitationPath { invitation_id }): Path<InvitationPath>,
+    csrf_token: CsrfToken,
+) -> Result<Response, StatusCode> {
+    if !user_calendar.can(CalendarPermission::Manage) {
+        return Err(StatusCode::FORBIDDEN);
+    }
+
+    let result = state
+        .invitation_repository
+        .revoke_invitation(invitation_id, Some(user_calendar.calendar.id))
+        .await;
+
+    Ok(match result {
+        Ok(()) => Redirect::to(&format!("/calendar/{}", user_calendar.calendar.id)).into_response(),
+        Err(message) => {
+            render_calendar(&state, user_calendar, user, Some(message), csrf_token).await
+        }
+    })
+}
+
+#[derive(Deserialize)]
+pub struct AddMemberForm {
+    username: String,
+    role: CalendarRole,
+}
+pub async fn add_member_post(
+    user_calendar: UserCalendar,
+    user: User,
+    State(state): State<AppState>,
+    csrf_token: CsrfToken,
+    Form(form): Form<AddMemberForm>,
+) -> Response {
+    let result = state
+        .calendar_service
+        .add_member(&user_calendar, &user, &form.username, form.role)
+        .await;
+
+    let message = match result {
+        Ok(missing) => member_message(
+            format!("{} is now a member of this calendar", form.username.trim()),
+            missing,
+        ),
+        Err(message) => message,
+    };
+    render_calendar(&state, user_calendar, user, Some(message), csrf_token).await
+}
+
+#[derive(Deserialize)]
+pub struct MemberPath {
+    member_id: i32,
+}
+#[derive(Deserialize)]
+pub struct MemberRoleForm {
+    role: CalendarRole,
+}
+pub async fn member_role_post(
+    user_calendar: UserCalendar,
+    user: User,
+    State(state): State<AppState>,
+    Path(MemberPath { member_id }): Path<MemberPath>,
+    csrf_token: CsrfToken,
+    Form(form): Form<MemberRoleForm>,
+) -> Response {
+    let result = state
+        .calendar_service
+        .set_member_role(&user_calendar, &user, member_id, form.role)
+        .await;
+
+    match result {
+        Ok(0) => Redirect::to(&format!("/calendar/{}", user_calendar.calendar.id)).into_response(),
+        Ok(missing) => {
+            let message = member_message(format!("The role is now {}", form.role), missing);
+            render_calendar(&state, user_calendar, user, Some(message), csrf_token).await
+        }
+        Err(message) => {
+            render_calendar(&state, user_calendar, user, Some(message), csrf_token).await
+        }
+    }
+}
+
+pub async fn remove_member_post(
+    user_calendar: UserCalendar,
+    user: User,
+    State(state): State<AppState>,
+    Path(MemberPath { member_id }): Path<MemberPath>,
+    csrf_token: CsrfToken,
+) -> Response {
+    let result = state
+        .calendar_service
+        .remove_member(&user_calendar, &user, member_id)
+        .await;
+
+    match result {
+        Ok(()) => Redirect::to(&format!("/calendar/{}", user_calendar.calendar.id)).into_response(),
+        Err(message) => {
+            render_calendar(&state, user_calendar, user, Some(message), csrf_token).await
+        }
+    }
+}
+
+/// Warns about the protected days whose key the user did not hold, so could not hand over.
+fn member_message(message: String, missing: usize) -> String {
+    match missing {
+        0 => message,
+        missing => format!(
+            "{message}. You haven't unlocked {missing} of the protected days, so they will need the password of those"
+        ),
+    }
+}
+
 pub async fn show_day_get(
     user_calendar: UserCalendar,
     user_day: UserDay,
     user: User,
     State(state): State<AppState>,
+    csrf_token: CsrfToken,
 ) -> impl IntoResponse {
     let res = state
         .calendar_service
@@ -132,7 +637,14 @@ pub async fn show_day_get(
         }
     };
 
-    let content = ShowDayTemplate::new(user_day, user_calendar, content, user)
+    let needs_password_upgrade = state
+        .calendar_service
+        .needs_password_upgrade(&user_calendar, &user_day, &user)
+        .await
+        .unwrap_or(false);
+
+    let content = ShowDayTemplate::new(user_day, user_calendar, content, csrf_token)
+        .with_password_upgrade(needs_password_upgrade)
         .render()
         .unwrap();
 
@@ -147,12 +659,45 @@ pub async fn unlock_post(
     user_calendar: UserCalendar,
     user_day: UserDay,
     user: User,
+    client: ClientInfo,
+    csrf_token: CsrfToken,
     Form(unlock_form): Form<UnlockDayForm>,
 ) -> Result<Response, Response> {
-    let res = state
-        .calendar_service
-        .unlock_day(&user_day, &user, unlock_form.code.clone())
-        .await;
+    // Only codes of protected days that can be unlocked right now are worth guessing
+    let guessable = user_day.day.protected && user_day.is_available() && !user_day.is_unlocked();
+    let mut attempt_keys = vec![AttemptKey::UnlockDay {
+        user_id: user.id,
+        day_id: user_day.day.id,
+    }];
+    if let Some(ip_address) = &client.ip_address {
+        attempt_keys.push(AttemptKey::UnlockIp(ip_address));
+    }
+
+    let res = match guessable {
+        true => match state.rate_limiter.reserve(&attempt_keys).await {
+            Ok(()) => {
+                let res = state
+                    .calendar_service
+                    .unlock_day(&user_day, &user, unlock_form.code.clone())
+                    .await;
+                if res.is_ok() {
+                    state
+                        .rate_limiter
+                        .record_success(&attempt_keys)
+                        .await
+                        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR.into_response())?;
+                }
+                res
+            }
+            Err(e) => Err(e),
+        },
+        false => {
+            state
+                .calendar_service
+                .unlock_day(&user_day, &user, unlock_form.code.clone())
+                .await
+        }
+    };
 
     let output = match res {
         Ok(_) => Redirect::to(&format!(
@@ -161,7 +706,7 @@ pub async fn unlock_post(
         ))
         .into_response(),
         Err(e) => Html(
-            UnlockDayTemplate::new(unlock_form.code, user_day)
+            UnlockDayTemplate::new(unlock_form.code, user_day, csrf_token)
                 .with_message(e)
                 .render()
                 .unwrap(),
@@ -175,6 +720,7 @@ pub async fn unlock_post(
 pub async fn unlock_get(
     user_calendar: UserCalendar,
     user_day: UserDay,
+    csrf_token: CsrfToken,
     Query(unlock_form): Query<UnlockDayForm>,
 ) -> impl IntoResponse {
     if user_day.unlocked_at.is_some() {
@@ -185,7 +731,7 @@ pub async fn unlock_get(
         .into_response();
     }
 
-    let content = UnlockDayTemplate::new(unlock_form.code, user_day)
+    let content = UnlockDayTemplate::new(unlock_form.code, user_day, csrf_token)
         .render()
         .unwrap();
     Html(content).into_response()
@@ -1,16 +1,39 @@
 use crate::model::app_state::AppState;
+use crate::model::calendar::{RichUserCalendar, UserCalendar};
+use crate::model::notice::Notice;
+use crate::model::session::CsrfToken;
 use crate::model::user::User;
-use crate::templates::templates::HelloTemplate;
+use crate::templates::home_templates::HelloTemplate;
 use askama::Template;
-use axum::extract::State;
-use axum::response::{Html, IntoResponse};
+use axum::extract::{Path, State};
+use axum::http::StatusCode;
+use axum::response::{Html, IntoResponse, Redirect, Response};
+use serde::Deserialize;
 
-pub async fn dashboard_handler(user: User, State(state): State<AppState>) -> impl IntoResponse {
-    let subscriptions = state.calendar_service.get_dashboard_data(&user).await;
-    let content = match subscriptions {
-        Ok(subscriptions) => HelloTemplate::new(user, subscriptions)
-            .render()
-            .map_err(|_| "There was an error rendering this page".to_owned()),
+async fn get_dashboard(
+    state: &AppState,
+    user: &User,
+) -> Result<(Vec<RichUserCalendar>, Vec<UserCalendar>, Vec<Notice>), String> {
+    let subscriptions = state.calendar_service.get_dashboard_data(user).await?;
+    let archived = state
+        .calendar_service
+        .get_archived_subscriptions(user)
+        .await?;
+    let notices = state.notice_repository.get_notices(user).await?;
+    Ok((subscriptions, archived, notices))
+}
+
+pub async fn dashboard_handler(
+    user: User,
+    State(state): State<AppState>,
+    csrf_token: CsrfToken,
+) -> impl IntoResponse {
+    let content = match get_dashboard(&state, &user).await {
+        Ok((subscriptions, archived, notices)) => {
+            HelloTemplate::new(&user, subscriptions, archived, notices, csrf_token)
+                .render()
+                .map_err(|_| "There was an error rendering this page".to_owned())
+        }
         Err(e) => Err(format!("There was an error getting your dashboard: {e}")),
     };
 
@@ -19,3 +42,20 @@ pub async fn dashboard_handler(user: User, State(state): State<AppState>) -> imp
         Err(msg) => Html(msg),
     }
 }
+
+#[derive(Deserialize)]
+pub struct NoticePath {
+    notice_id: i32,
+}
+pub async fn dismiss_notice_post(
+    user: User,
+    State(state): State<AppState>,
+    Path(NoticePath { notice_id }): Path<NoticePath>,
+) -> Result<Response, StatusCode> {
+    state
+        .notice_repository
+        .dismiss_notice(&user, notice_id)
+        .await
+        .map_err(|_| StatusCode::NOT_FOUND)?;
+    Ok(Redirect::to("/home").into_response())
+}
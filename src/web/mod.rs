@@ -1,3 +1,5 @@
+pub mod account_handlers;
+pub mod admin_handlers;
 pub mod authentication_handlers;
 pub mod calendar_handlers;
 pub mod handler;